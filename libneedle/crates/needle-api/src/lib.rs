@@ -1,6 +1,7 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
+pub mod listener;
 pub mod middleware;
 pub mod routes;
 pub mod state;
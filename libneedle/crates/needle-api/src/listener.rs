@@ -0,0 +1,37 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use needle_core::listener::ClientListener;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+/// Serves the API from a [`ClientListener`], so handlers see the client
+/// behind a PROXY protocol load balancer rather than the balancer.
+pub struct ApiListener(pub ClientListener);
+
+impl Listener for ApiListener {
+    type Io = TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        self.0.accept().await
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.0.local_addr())
+    }
+}
+
+/// The address a connection came from, as `ConnectInfo`. axum only
+/// provides `SocketAddr` for its own listener, hence the wrapper.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+impl Connected<IncomingStream<'_, ApiListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, ApiListener>) -> Self {
+        Self(*stream.remote_addr())
+    }
+}
@@ -0,0 +1,37 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use axum::Extension;
+use axum::Json;
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use needle_core::abuse::AbuseTracker;
+use needle_core::metrics;
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::middleware::client_ip::ClientIp;
+
+/// Turns away banned IPs before anything else runs, so a client that got
+/// itself banned for credential stuffing can't keep hammering the login
+/// route or burning database queries.
+pub async fn reject_banned(
+    State(abuse): State<Arc<AbuseTracker>>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Err(e) = abuse.check(ip) {
+        debug!(ip = %ip, "rejected request from banned ip");
+        metrics::connection_blocked("api");
+        return (
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
@@ -46,16 +46,11 @@ pub async fn require_auth(
         StatusCode::UNAUTHORIZED
     })?;
 
-    let key = DecodingKey::from_secret(state.jwt_secret.as_bytes());
-    let validation = Validation::default();
-
-    let token_data = decode::<Claims>(token, &key, &validation).map_err(|_| {
+    let claims = verify_token(token, &state.jwt_secret).map_err(|_| {
         metrics::auth_failure("api", "invalid_token");
         StatusCode::UNAUTHORIZED
     })?;
 
-    let claims = token_data.claims;
-
     // Check if token is revoked
     match is_token_revoked(&state, &claims.jti).await {
         Ok(true) => {
@@ -76,6 +71,13 @@ pub async fn require_auth(
     Ok(next.run(request).await)
 }
 
+/// Checks a JWT's signature and expiry. Revocation is up to the caller,
+/// since that takes a database round trip.
+pub(crate) fn verify_token(token: &str, secret: &str) -> jsonwebtoken::errors::Result<Claims> {
+    let key = DecodingKey::from_secret(secret.as_bytes());
+    decode::<Claims>(token, &key, &Validation::default()).map(|data| data.claims)
+}
+
 /// Check if a token has been revoked by querying the revoked_tokens table
 async fn is_token_revoked(state: &AppState, jti: &str) -> Result<bool, Box<dyn std::error::Error>> {
     let response = state
@@ -89,3 +91,24 @@ async fn is_token_revoked(state: &AppState, jti: &str) -> Result<bool, Box<dyn s
         .map(|arr| !arr.is_empty())
         .unwrap_or(false))
 }
+
+/// Only lets through users listed in ADMIN_EMAILS. Runs after
+/// `require_auth`, so the claims are already in the request extensions.
+pub async fn require_admin(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let is_admin = request.extensions().get::<Claims>().is_some_and(|claims| {
+        state
+            .admin_emails
+            .contains(&claims.email.to_ascii_lowercase())
+    });
+
+    if !is_admin {
+        metrics::auth_failure("api", "not_admin");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(next.run(request).await)
+}
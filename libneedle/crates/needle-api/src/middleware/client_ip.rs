@@ -0,0 +1,31 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use axum::extract::{ConnectInfo, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use needle_core::listener::TrustedProxies;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::listener::PeerAddr;
+
+/// The client's real address, worked out once per request and stored in
+/// the request extensions for the middleware and handlers after us.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+/// Resolves who's really on the other end: the connection's address
+/// (already taken from the PROXY protocol header if there was one), or
+/// the client named in `X-Forwarded-For` when that connection is from a
+/// trusted proxy. Runs before everything that bans or limits by IP.
+pub async fn resolve_client_ip(
+    State(trusted): State<Arc<TrustedProxies>>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let ip = trusted.client_ip(peer.ip(), request.headers());
+    request.extensions_mut().insert(ClientIp(ip));
+    next.run(request).await
+}
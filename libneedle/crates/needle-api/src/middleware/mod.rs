@@ -1,5 +1,7 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
+pub mod abuse;
 pub mod auth;
+pub mod client_ip;
 pub mod rate_limit;
@@ -1,60 +1,173 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
-use axum::Json;
-use axum::extract::{ConnectInfo, Request, State};
-use axum::http::StatusCode;
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, StatusCode, header};
 use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
-use std::net::SocketAddr;
+use axum::{Extension, Json};
+use needle_common::rate_limit::{RateLimiter, RateLimiterStore};
+use needle_core::abuse::{AbuseTracker, Offense};
+use needle_core::config::{NeedleConfig, RateLimitSettings};
+use needle_core::metrics;
+use needle_core::proxy::http::set_rate_limit_headers;
+use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::warn;
+use std::time::Duration;
+use tracing::{debug, warn};
+use uuid::Uuid;
 
-use needle_common::rate_limit::RateLimiter;
+use crate::middleware::auth::{Claims, verify_token};
+use crate::middleware::client_ip::ClientIp;
 
-/// Shared rate limiter state keyed by client IP address.
-/// Each IP gets its own bucket so one abusive client can't
-/// starve others.
-pub type RateLimiterMap = Arc<RwLock<std::collections::HashMap<String, RateLimiter>>>;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
 
-const REQUESTS_PER_SECOND: f64 = 10.0;
-const BURST_SIZE: f64 = 30.0;
+/// Which limit a request counts against. The sign-in routes get their
+/// own, much tighter buckets so guessing passwords or mass-registering
+/// accounts is slow even for a client well within the general limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LimitClass {
+    Api,
+    Login,
+    Register,
+}
+
+impl LimitClass {
+    pub fn for_path(path: &str) -> Self {
+        match path {
+            "/api/auth/login" => Self::Login,
+            "/api/auth/register" => Self::Register,
+            _ => Self::Api,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Api => "api",
+            Self::Login => "login",
+            Self::Register => "register",
+        }
+    }
+}
+
+/// Who a bucket belongs to: the signed-in user when the request carries
+/// a valid token, otherwise the client's IP.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Client {
+    User(Uuid),
+    Ip(IpAddr),
+}
+
+pub type LimiterKey = (LimitClass, Client);
+
+/// What the rate limit middleware needs: the buckets and the limits for
+/// each class, the JWT secret to tell users apart, plus the abuse tracker
+/// so clients that keep hitting the limit end up banned.
+#[derive(Clone)]
+pub struct RateLimitState {
+    pub limiters: Arc<RateLimiterStore<LimiterKey>>,
+    pub api: RateLimitSettings,
+    pub login: RateLimitSettings,
+    pub register: RateLimitSettings,
+    pub jwt_secret: String,
+    pub abuse: Arc<AbuseTracker>,
+}
+
+impl RateLimitState {
+    pub fn new(config: &NeedleConfig, abuse: Arc<AbuseTracker>) -> Self {
+        Self {
+            limiters: Arc::new(RateLimiterStore::new(config.api_rate_limit_max_entries)),
+            api: config.api_rate_limit,
+            login: config.login_rate_limit,
+            register: config.register_rate_limit,
+            jwt_secret: config.jwt_secret.clone(),
+            abuse,
+        }
+    }
 
-/// Creates a new empty rate limiter map.
-pub fn new_rate_limiter_map() -> RateLimiterMap {
-    Arc::new(RwLock::new(std::collections::HashMap::new()))
+    fn settings(&self, class: LimitClass) -> RateLimitSettings {
+        match class {
+            LimitClass::Api => self.api,
+            LimitClass::Login => self.login,
+            LimitClass::Register => self.register,
+        }
+    }
 }
 
-/// Axum middleware that enforces per-IP rate limiting using
-/// the token bucket algorithm from needle-common. If a client
-/// exceeds the limit, they get a 429 with a helpful message.
+/// Axum middleware that enforces rate limits using the token bucket
+/// algorithm from needle-common. Signed-in users get a bucket of their
+/// own wherever they connect from; everyone else shares one per IP. If a
+/// client exceeds the limit, they get a 429 with a helpful message.
+/// Every response carries `RateLimit-*` headers so clients can pace
+/// themselves.
 pub async fn rate_limit(
-    State(limiter_map): State<RateLimiterMap>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<RateLimitState>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
     request: Request,
     next: Next,
 ) -> Response {
-    let ip = addr.ip().to_string();
-
-    let allowed = {
-        let mut map = limiter_map.write().await;
-        let limiter = map
-            .entry(ip.clone())
-            .or_insert_with(|| RateLimiter::new(REQUESTS_PER_SECOND, BURST_SIZE));
-        limiter.allow()
+    let class = LimitClass::for_path(request.uri().path());
+
+    // Sign-in routes always go by IP, or every stolen token would buy
+    // another round of guesses. Revoked tokens still count here; the
+    // auth middleware turns them away afterwards.
+    let user = match class {
+        LimitClass::Api => bearer_claims(request.headers(), &state.jwt_secret),
+        LimitClass::Login | LimitClass::Register => None,
     };
+    let (client, tier) = match &user {
+        Some(claims) => (Client::User(claims.sub), claims.tier.as_str()),
+        None => (Client::Ip(ip), "anonymous"),
+    };
+
+    let settings = state.settings(class);
+    let status = state.limiters.check(&(class, client.clone()), || {
+        RateLimiter::new(settings.requests_per_second, settings.burst)
+    });
 
-    if !allowed {
-        warn!(ip = %ip, "rate limit exceeded");
-        return (
+    let mut response = if status.allowed {
+        next.run(request).await
+    } else {
+        warn!(ip = %ip, client = ?client, class = class.as_str(), "rate limit exceeded");
+        metrics::rate_limit_hit(class.as_str(), tier);
+        state.abuse.record(ip, Offense::RateLimited);
+        (
             StatusCode::TOO_MANY_REQUESTS,
             Json(serde_json::json!({
                 "error": "too many requests, please slow down"
             })),
         )
-            .into_response();
+            .into_response()
+    };
+
+    set_rate_limit_headers(response.headers_mut(), &status);
+    response
+}
+
+/// Background task that drops buckets for clients who've gone quiet long
+/// enough for their bucket to refill. Runs forever.
+pub async fn sweep(limiters: Arc<RateLimiterStore<LimiterKey>>) {
+    let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+
+        let evicted = limiters.sweep();
+        if evicted > 0 {
+            debug!(
+                evicted,
+                remaining = limiters.len(),
+                "swept idle rate limiters"
+            );
+        }
     }
+}
 
-    next.run(request).await
+fn bearer_claims(headers: &HeaderMap, secret: &str) -> Option<Claims> {
+    let token = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?;
+    verify_token(token, secret).ok()
 }
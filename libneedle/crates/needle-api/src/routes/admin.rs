@@ -0,0 +1,86 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use axum::Json;
+use axum::extract::{Extension, Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::IpAddr;
+use std::time::Duration;
+use tracing::info;
+
+use crate::middleware::auth::Claims;
+use crate::state::AppState;
+
+// Manual bans last a day unless the admin says otherwise
+const DEFAULT_MANUAL_BAN_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Deserialize)]
+pub struct CreateBanRequest {
+    pub ip: String,
+    pub duration_secs: Option<u64>,
+    pub reason: Option<String>,
+}
+
+/// Lists every IP ban currently in force, automatic and manual.
+pub async fn list_bans(State(state): State<AppState>) -> impl IntoResponse {
+    (StatusCode::OK, Json(json!({ "bans": state.abuse.bans() })))
+}
+
+/// Bans an IP by hand, replacing any ban it already has.
+pub async fn create_ban(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<CreateBanRequest>,
+) -> impl IntoResponse {
+    let Ok(ip) = payload.ip.parse::<IpAddr>() else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid ip address" })),
+        );
+    };
+
+    let duration = match payload.duration_secs {
+        Some(0) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "duration_secs must be greater than zero" })),
+            );
+        }
+        secs => Duration::from_secs(secs.unwrap_or(DEFAULT_MANUAL_BAN_SECS)),
+    };
+
+    let reason = payload.reason.as_deref().unwrap_or("manual");
+    let ban = state.abuse.ban(ip, duration, reason);
+    info!(ip = %ip, admin = %claims.email, "ip ban created via api");
+
+    (StatusCode::CREATED, Json(json!(ban)))
+}
+
+/// Lifts a ban and clears the IP's offense history.
+pub async fn delete_ban(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(ip): Path<String>,
+) -> impl IntoResponse {
+    let Ok(ip) = ip.parse::<IpAddr>() else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid ip address" })),
+        )
+            .into_response();
+    };
+
+    if !state.abuse.unban(ip) {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "ip is not banned" })),
+        )
+            .into_response();
+    }
+
+    info!(ip = %ip, admin = %claims.email, "ip ban lifted via api");
+    StatusCode::NO_CONTENT.into_response()
+}
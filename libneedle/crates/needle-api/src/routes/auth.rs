@@ -8,12 +8,15 @@ use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use chrono::Utc;
 use jsonwebtoken::{EncodingKey, Header, encode};
+use needle_core::abuse::Offense;
+use needle_core::metrics;
 use serde::Deserialize;
 use serde_json::json;
 use tracing::info;
 use uuid::Uuid;
 
 use crate::middleware::auth::Claims;
+use crate::middleware::client_ip::ClientIp;
 use crate::state::AppState;
 
 const TOKEN_EXPIRY_HOURS: i64 = 24;
@@ -93,13 +96,19 @@ pub async fn register(
     }
 }
 
+/// Exchanges email and password for a JWT. Every bad attempt counts
+/// against the caller's IP in the abuse tracker, so credential stuffing
+/// ends in a ban instead of an unlimited supply of guesses.
 pub async fn login(
     State(state): State<AppState>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
     Json(payload): Json<LoginRequest>,
 ) -> impl IntoResponse {
     let user = match needle_db::queries::users::find_by_email(&state.db, &payload.email).await {
         Ok(Some(u)) => u,
         Ok(None) => {
+            metrics::auth_failure("api", "unknown_email");
+            state.abuse.record(ip, Offense::ApiLoginFailure);
             return (
                 StatusCode::UNAUTHORIZED,
                 Json(json!({ "error": "invalid credentials" })),
@@ -115,6 +124,8 @@ pub async fn login(
 
     // Verify password hash
     if !verify_password(&payload.password, &user.password_hash) {
+        metrics::auth_failure("api", "bad_password");
+        state.abuse.record(ip, Offense::ApiLoginFailure);
         return (
             StatusCode::UNAUTHORIZED,
             Json(json!({ "error": "invalid credentials" })),
@@ -1,10 +1,13 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
+pub mod admin;
 pub mod analytics;
 pub mod api_keys;
 pub mod auth;
 pub mod health;
 pub mod inspector;
 pub mod metrics;
+pub mod shares;
+pub mod subdomains;
 pub mod tunnels;
@@ -0,0 +1,135 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use axum::Json;
+use axum::extract::{Extension, Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use needle_core::tunnel::share::{DEFAULT_SHARE_TTL, MAX_SHARE_TTL, SHARE_PARAM};
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::middleware::auth::Claims;
+use crate::state::AppState;
+
+#[derive(Deserialize, Default)]
+pub struct CreateShareRequest {
+    /// Seconds the link stays valid. Defaults to a day, capped at 30 days.
+    pub expires_in_secs: Option<u64>,
+    /// How many visitors may open the link. Omit for no limit.
+    pub max_uses: Option<u32>,
+}
+
+/// Mints a signed link that lets whoever opens it past the tunnel's
+/// access policy until it expires or runs out of uses.
+pub async fn create(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(subdomain): Path<String>,
+    payload: Option<Json<CreateShareRequest>>,
+) -> Response {
+    let Json(payload) = payload.unwrap_or_default();
+    let ttl = match payload.expires_in_secs {
+        Some(0) => return bad_request("expires_in_secs must be greater than zero"),
+        Some(secs) => Duration::from_secs(secs),
+        None => DEFAULT_SHARE_TTL,
+    };
+    if ttl > MAX_SHARE_TTL {
+        return bad_request("expires_in_secs can be at most 30 days");
+    }
+    if payload.max_uses == Some(0) {
+        return bad_request("max_uses must be greater than zero");
+    }
+
+    let Some(tunnel_id) = owned_tunnel_id(&state, &subdomain, claims.sub).await else {
+        return not_found();
+    };
+
+    match state
+        .shares
+        .create(tunnel_id, claims.sub, ttl, payload.max_uses)
+        .await
+    {
+        Ok((link, token)) => (
+            StatusCode::CREATED,
+            Json(json!({
+                "id": link.id,
+                "url": format!("https://{}.{}/?{}={}", subdomain, state.domain, SHARE_PARAM, token),
+                "expires_at": link.expires_at,
+                "max_uses": link.max_uses,
+            })),
+        )
+            .into_response(),
+        Err(e) => {
+            error!(error = %e, subdomain = %subdomain, "failed to create share link");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "failed to create share link" })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Lists a tunnel's live share links. The URLs aren't included, since
+/// we never keep the tokens.
+pub async fn list(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(subdomain): Path<String>,
+) -> Response {
+    let Some(tunnel_id) = owned_tunnel_id(&state, &subdomain, claims.sub).await else {
+        return not_found();
+    };
+
+    let links = state.shares.list(tunnel_id);
+    (StatusCode::OK, Json(json!({ "share_links": links }))).into_response()
+}
+
+/// Revokes a share link. Anyone who already opened it loses access on
+/// their next request.
+pub async fn revoke(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((subdomain, link_id)): Path<(String, Uuid)>,
+) -> Response {
+    let Some(tunnel_id) = owned_tunnel_id(&state, &subdomain, claims.sub).await else {
+        return not_found();
+    };
+
+    match state.shares.revoke(tunnel_id, link_id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => not_found(),
+        Err(e) => {
+            error!(error = %e, link_id = %link_id, "failed to revoke share link");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "failed to revoke share link" })),
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn owned_tunnel_id(state: &AppState, subdomain: &str, user_id: Uuid) -> Option<Uuid> {
+    state
+        .tunnel_manager
+        .read()
+        .await
+        .owned_tunnel_id(subdomain, user_id)
+}
+
+fn bad_request(msg: &str) -> Response {
+    (StatusCode::BAD_REQUEST, Json(json!({ "error": msg }))).into_response()
+}
+
+fn not_found() -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({ "error": "tunnel or share link not found" })),
+    )
+        .into_response()
+}
@@ -0,0 +1,87 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use axum::Json;
+use axum::extract::{Extension, Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, info};
+
+use crate::middleware::auth::Claims;
+use crate::routes::tunnels::status_for;
+use crate::state::AppState;
+
+#[derive(Deserialize)]
+pub struct ReserveSubdomainRequest {
+    pub name: String,
+}
+
+/// Lists the subdomains the authenticated user has reserved.
+pub async fn list(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> impl IntoResponse {
+    let manager = state.tunnel_manager.read().await;
+    match manager.reserved_subdomains(claims.sub).await {
+        Ok(reserved) => (StatusCode::OK, Json(json!({ "subdomains": reserved }))).into_response(),
+        Err(e) => {
+            error!(error = %e, "failed to list reserved subdomains");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "failed to list reserved subdomains" })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Reserves a subdomain so it stays the user's even while no tunnel is
+/// running on it. Tunnels created later with that name (over SSH or the
+/// API) are only allowed for the owner.
+pub async fn create(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<ReserveSubdomainRequest>,
+) -> impl IntoResponse {
+    let name = payload.name.to_ascii_lowercase();
+    let manager = state.tunnel_manager.read().await;
+
+    match manager.claim_subdomain(claims.sub, &name).await {
+        Ok(reserved) => {
+            info!(name = %reserved.name, "subdomain reserved via api");
+            (
+                StatusCode::CREATED,
+                Json(json!({
+                    "name": reserved.name,
+                    "url": format!("https://{}.{}", reserved.name, state.domain),
+                    "created_at": reserved.created_at,
+                })),
+            )
+                .into_response()
+        }
+        Err(e) => (status_for(&e), Json(json!({ "error": e.to_string() }))).into_response(),
+    }
+}
+
+/// Releases one of the user's reserved subdomains.
+pub async fn delete(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let manager = state.tunnel_manager.read().await;
+    match manager.release_subdomain(claims.sub, &name).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!(error = %e, "failed to release reserved subdomain");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "failed to release subdomain" })),
+            )
+                .into_response()
+        }
+    }
+}
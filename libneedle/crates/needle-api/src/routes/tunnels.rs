@@ -6,8 +6,22 @@ use axum::Json;
 use axum::extract::{Path, State};
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
+use needle_common::cidr::Cidr;
+use needle_common::error::NeedleError;
+use needle_core::config::RateLimitSettings;
+use needle_core::proxy::compression::CompressionSettings;
+use needle_core::proxy::http::UpstreamProtocol;
+use needle_core::tunnel::access::{AccessPolicy, OidcRule, PeerRule};
+use needle_core::tunnel::balancer::LoadBalancing;
+use needle_core::tunnel::cors::CorsPolicy;
+use needle_core::tunnel::manager::TunnelOptions;
+use needle_core::tunnel::rewrite::RewriteRules;
+use needle_core::tunnel::settings::{
+    HealthCheck, MirrorSettings, SizeLimits, TimeoutSettings, TunnelSettings,
+};
 use serde::Deserialize;
 use serde_json::json;
+use std::time::Duration;
 use tracing::info;
 
 use crate::middleware::auth::Claims;
@@ -19,6 +33,124 @@ pub struct CreateTunnelRequest {
     pub target_port: Option<u32>,
     pub protocol: Option<String>,
     pub is_persistent: Option<bool>,
+    /// Seconds until the tunnel is closed. Free-tier tunnels are capped.
+    pub ttl_secs: Option<u64>,
+    /// Who may reach the tunnel. Omit to leave it open to everyone.
+    pub access: Option<AccessRequest>,
+    /// A lower request rate than the tier's. Higher values are capped.
+    pub rate_limit: Option<RateLimitSettings>,
+    /// The Host header the local app should see, e.g. `localhost:3000`.
+    /// Omit to pass the public hostname through.
+    pub upstream_host: Option<String>,
+    /// `h2c` to speak HTTP/2 to the local app, e.g. for gRPC.
+    pub upstream_protocol: Option<UpstreamProtocol>,
+    /// Header, path and redirect rules the edge applies to requests.
+    pub rewrites: Option<RewriteRules>,
+    /// Lets browser pages on other origins call the tunnel.
+    pub cors: Option<CorsPolicy>,
+    /// Whether the edge compresses responses. On by default.
+    pub compression: Option<CompressionSettings>,
+    /// Smaller request, response and WebSocket size limits than the
+    /// tier's. Higher values are capped.
+    pub limits: Option<SizeLimits>,
+    /// Different proxy timeouts than the server's. Values above the
+    /// tier's ceiling are capped.
+    pub timeouts: Option<TimeoutSettings>,
+    /// `round_robin` or `least_connections` to let several of your SSH
+    /// clients serve the tunnel at once.
+    pub load_balancing: Option<LoadBalancing>,
+    /// A request the server sends the app every so often. While it
+    /// fails, visitors get the offline page.
+    pub health_check: Option<HealthCheck>,
+    /// Copies a share of requests to another of your tunnels, for
+    /// comparing a new version of the app against real traffic.
+    pub mirror: Option<MirrorSettings>,
+}
+
+#[derive(Deserialize)]
+pub struct AccessRequest {
+    pub basic_auth: Option<BasicAuthRequest>,
+    pub bearer_token: Option<String>,
+    #[serde(default)]
+    pub allow_ips: Vec<String>,
+    #[serde(default)]
+    pub deny_ips: Vec<String>,
+    pub oidc: Option<OidcRequest>,
+    /// Emails, or `@domain`s, of other users who may reach a private
+    /// tunnel over SSH.
+    #[serde(default)]
+    pub peers: Vec<String>,
+}
+
+/// Sign-in through an OIDC provider. Leave out `issuer` and the client
+/// fields to use the server's own provider.
+#[derive(Deserialize)]
+pub struct OidcRequest {
+    pub issuer: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    #[serde(default)]
+    pub allowed_emails: Vec<String>,
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct BasicAuthRequest {
+    pub username: String,
+    pub password: String,
+}
+
+impl AccessRequest {
+    /// Hashes the credentials and parses the CIDR lists into a policy.
+    fn into_policy(self) -> Result<AccessPolicy, String> {
+        let mut policy = AccessPolicy::default();
+        policy.allow = parse_cidrs(&self.allow_ips)?;
+        policy.deny = parse_cidrs(&self.deny_ips)?;
+
+        if let Some(basic) = self.basic_auth {
+            if basic.username.is_empty()
+                || basic.username.contains(':')
+                || basic.password.is_empty()
+            {
+                return Err("basic_auth needs a username without ':' and a password".to_string());
+            }
+            policy.set_basic_auth(&basic.username, &basic.password);
+        }
+        if let Some(token) = self.bearer_token {
+            if token.is_empty() {
+                return Err("bearer_token cannot be empty".to_string());
+            }
+            policy.set_bearer_token(&token);
+        }
+        if !self.peers.is_empty() {
+            policy.peers = Some(PeerRule::from_entries(
+                self.peers.iter().map(String::as_str),
+            ));
+        }
+        if let Some(oidc) = self.oidc {
+            policy.oidc = Some(OidcRule {
+                issuer: oidc.issuer,
+                client_id: oidc.client_id,
+                client_secret: oidc.client_secret,
+                allowed_emails: lowercase(oidc.allowed_emails),
+                allowed_domains: lowercase(oidc.allowed_domains),
+            });
+        }
+
+        Ok(policy)
+    }
+}
+
+fn lowercase(list: Vec<String>) -> Vec<String> {
+    list.into_iter()
+        .map(|s| s.trim().trim_start_matches('@').to_ascii_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn parse_cidrs(list: &[String]) -> Result<Vec<Cidr>, String> {
+    list.iter().map(|s| s.parse()).collect()
 }
 
 pub async fn list(
@@ -42,9 +174,9 @@ pub async fn create(
     Extension(claims): Extension<Claims>,
     Json(payload): Json<CreateTunnelRequest>,
 ) -> impl IntoResponse {
-    // Validate and handle custom subdomain if provided
+    // Validate custom subdomain format if provided. Availability is settled
+    // by the manager and the database, not a separate lookup that could race.
     let custom_subdomain = if let Some(ref subdomain) = payload.subdomain {
-        // Validate custom subdomain format
         if !needle_common::subdomain::is_valid_custom(subdomain) {
             return (
                 StatusCode::BAD_REQUEST,
@@ -53,41 +185,97 @@ pub async fn create(
                 .into_response();
         }
 
-        // Check if subdomain is already taken
-        match needle_db::queries::tunnels::find_by_subdomain(&state.db, subdomain).await {
-            Ok(Some(_)) => {
-                return (
-                    StatusCode::CONFLICT,
-                    Json(json!({ "error": "subdomain already taken" })),
-                )
-                    .into_response();
-            }
-            Ok(None) => {}
-            Err(e) => {
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({ "error": e.to_string() })),
-                )
-                    .into_response();
-            }
-        }
-
         Some(subdomain.clone())
     } else {
         None
     };
 
     let mut manager = state.tunnel_manager.write().await;
+    let target_port = payload.target_port.unwrap_or(80) as i32;
+    let protocol = payload.protocol.unwrap_or_else(|| "http".to_string());
+    let ttl = match payload.ttl_secs {
+        Some(0) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "ttl_secs must be greater than zero" })),
+            )
+                .into_response();
+        }
+        ttl_secs => ttl_secs.map(Duration::from_secs),
+    };
+    let access = match payload.access.map(AccessRequest::into_policy).transpose() {
+        Ok(access) => access.unwrap_or_default(),
+        Err(msg) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": msg }))).into_response();
+        }
+    };
+    let access_summary = access.summary();
+    let options = TunnelOptions {
+        subdomain: custom_subdomain,
+        target_port,
+        protocol,
+        ttl,
+        access,
+        rate_limit: payload.rate_limit,
+        settings: TunnelSettings {
+            upstream_host: payload.upstream_host,
+            upstream_protocol: payload.upstream_protocol.unwrap_or_default(),
+            rewrites: payload.rewrites.unwrap_or_default(),
+            cors: payload.cors,
+            compression: payload.compression.unwrap_or_default(),
+            limits: payload.limits.unwrap_or_default(),
+            timeouts: payload.timeouts.unwrap_or_default(),
+            load_balancing: payload.load_balancing,
+            health_check: payload.health_check,
+            mirror: payload.mirror,
+        },
+        ..TunnelOptions::default()
+    };
+
+    // Persistent tunnels need a name to reattach to, and start out
+    // offline until the owner connects over SSH with that name.
+    if payload.is_persistent.unwrap_or(false) {
+        let Some(subdomain) = options.subdomain.clone() else {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "persistent tunnels require a subdomain" })),
+            )
+                .into_response();
+        };
+
+        return match manager.reserve(claims.sub, options).await {
+            Ok(reservation) => {
+                info!(subdomain = %subdomain, "persistent tunnel reserved via api");
+                (
+                    StatusCode::CREATED,
+                    Json(json!({
+                        "subdomain": subdomain,
+                        "url": format!("https://{}.{}", subdomain, state.domain),
+                        "status": "offline",
+                        "reserved_until": reservation.expires_at,
+                        "expires_at": manager.expires_at(&subdomain),
+                        "access": access_summary,
+                        "rate_limit": reservation.rate_limit,
+                        "upstream_host": reservation.settings.upstream_host,
+                        "upstream_protocol": reservation.settings.upstream_protocol,
+                        "rewrites": reservation.settings.rewrites,
+                        "cors": reservation.settings.cors,
+                        "compression": reservation.settings.compression,
+                        "limits": reservation.settings.limits,
+                        "timeouts": reservation.settings.timeouts,
+                        "load_balancing": reservation.settings.load_balancing,
+                        "health_check": reservation.settings.health_check,
+                        "mirror": reservation.settings.mirror,
+                    })),
+                )
+                    .into_response()
+            }
+            Err(e) => (status_for(&e), Json(json!({ "error": e.to_string() }))).into_response(),
+        };
+    }
 
     let tunnel = manager
-        .create(
-            &claims.sub.to_string(),
-            claims.sub,
-            custom_subdomain,
-            payload.target_port.unwrap_or(80) as i32,
-            &payload.protocol.unwrap_or_else(|| "http".to_string()),
-            payload.is_persistent.unwrap_or(false),
-        )
+        .create(&claims.sub.to_string(), claims.sub, options, None)
         .await;
 
     match tunnel {
@@ -99,15 +287,24 @@ pub async fn create(
                     "subdomain": t.subdomain,
                     "url": format!("https://{}.{}", t.subdomain, state.domain),
                     "bind_addr": t.bind_addr.to_string(),
+                    "expires_at": manager.expires_at(&t.subdomain),
+                    "access": access_summary,
+                    "rate_limit": t.rate_limit,
+                    "upstream_host": t.settings.upstream_host,
+                    "upstream_protocol": t.settings.upstream_protocol,
+                    "rewrites": t.settings.rewrites,
+                    "cors": t.settings.cors,
+                    "compression": t.settings.compression,
+                    "limits": t.body_limits,
+                    "timeouts": t.timeouts,
+                    "load_balancing": t.settings.load_balancing,
+                    "health_check": t.settings.health_check,
+                    "mirror": t.settings.mirror,
                 })),
             )
                 .into_response()
         }
-        Err(e) => (
-            StatusCode::BAD_REQUEST,
-            Json(json!({ "error": e.to_string() })),
-        )
-            .into_response(),
+        Err(e) => (status_for(&e), Json(json!({ "error": e.to_string() }))).into_response(),
     }
 }
 
@@ -141,7 +338,7 @@ pub async fn delete(
 
     let mut manager = state.tunnel_manager.write().await;
 
-    match manager.remove(&subdomain).await {
+    match manager.remove(&subdomain, "user_deleted").await {
         Ok(()) => {
             info!(subdomain = %subdomain, "tunnel deleted via api");
             StatusCode::NO_CONTENT.into_response()
@@ -149,3 +346,112 @@ pub async fn delete(
         Err(_) => StatusCode::NOT_FOUND.into_response(),
     }
 }
+
+/// Lists the clients serving one of the user's tunnels. A load-balanced
+/// tunnel can have several; a tunnel waiting for its client has none.
+pub async fn backends(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(subdomain): Path<String>,
+) -> impl IntoResponse {
+    let manager = state.tunnel_manager.read().await;
+    if manager.owned_tunnel_id(&subdomain, claims.sub).is_none() {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "tunnel not found" })),
+        )
+            .into_response();
+    }
+
+    let (load_balancing, backends) = match manager.get(&subdomain) {
+        Some(tunnel) => {
+            let backends: Vec<_> = tunnel
+                .backends
+                .all()
+                .iter()
+                .map(|b| {
+                    json!({
+                        "id": b.id,
+                        "client_ip": b.client_ip,
+                        "connected_at": b.connected_at,
+                        "in_flight": b.in_flight(),
+                        "healthy": b.is_healthy(),
+                        "health": b.health_status(),
+                    })
+                })
+                .collect();
+            (tunnel.settings.load_balancing, backends)
+        }
+        None => (None, Vec::new()),
+    };
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "subdomain": subdomain,
+            "load_balancing": load_balancing,
+            "backends": backends,
+        })),
+    )
+        .into_response()
+}
+
+/// Sets the page visitors see while a persistent tunnel's client is
+/// away. The body is the HTML itself, and can use the same placeholders
+/// as the operator's error templates, like `{{request_id}}`.
+pub async fn set_offline_page(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(subdomain): Path<String>,
+    body: String,
+) -> impl IntoResponse {
+    if body.trim().is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "offline page is empty" })),
+        )
+            .into_response();
+    }
+
+    let mut manager = state.tunnel_manager.write().await;
+    match manager
+        .set_offline_page(&subdomain, claims.sub, Some(body))
+        .await
+    {
+        Ok(()) => {
+            info!(subdomain = %subdomain, "offline page set via api");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => (status_for(&e), Json(json!({ "error": e.to_string() }))).into_response(),
+    }
+}
+
+/// Goes back to the standard offline page.
+pub async fn delete_offline_page(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(subdomain): Path<String>,
+) -> impl IntoResponse {
+    let mut manager = state.tunnel_manager.write().await;
+    match manager.set_offline_page(&subdomain, claims.sub, None).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => (status_for(&e), Json(json!({ "error": e.to_string() }))).into_response(),
+    }
+}
+
+/// Maps tunnel and subdomain errors onto HTTP status codes, so clients can
+/// tell "someone has that name" from "you've hit your limit".
+pub(crate) fn status_for(e: &NeedleError) -> StatusCode {
+    match e {
+        NeedleError::SubdomainTaken(_) => StatusCode::CONFLICT,
+        NeedleError::TunnelNotFound(_) => StatusCode::NOT_FOUND,
+        NeedleError::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        NeedleError::TierLimitExceeded { .. } | NeedleError::ReservationLimitExceeded { .. } => {
+            StatusCode::FORBIDDEN
+        }
+        NeedleError::Supabase(_) | NeedleError::Io(_) | NeedleError::Http(_) => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
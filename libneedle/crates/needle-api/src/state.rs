@@ -1,7 +1,9 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
+use needle_core::abuse::AbuseTracker;
 use needle_core::tunnel::manager::TunnelManager;
+use needle_core::tunnel::share::ShareRegistry;
 use needle_db::client::SupabaseClient;
 use std::sync::Arc;
 use tokio::sync::RwLock;
@@ -16,4 +18,7 @@ pub struct AppState {
     pub db: SupabaseClient,
     pub jwt_secret: String,
     pub domain: String,
+    pub abuse: Arc<AbuseTracker>,
+    pub admin_emails: Vec<String>,
+    pub shares: Arc<ShareRegistry>,
 }
@@ -0,0 +1,63 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+//! Framing for UDP tunnels. SSH channels are byte streams, so each
+//! datagram is sent as a 2-byte big-endian length followed by that many
+//! bytes of payload. A zero length is an empty datagram, which UDP
+//! allows. Every remote peer gets its own channel, so frames carry no
+//! addresses: the server knows who a channel belongs to, and the client
+//! only ever answers on the channel a datagram came in on.
+
+/// Bytes in the length prefix in front of every datagram.
+pub const HEADER_LEN: usize = 2;
+
+/// The largest payload a frame can carry.
+pub const MAX_PAYLOAD: usize = u16::MAX as usize;
+
+/// Frames one datagram. Returns `None` if it's too big to describe in
+/// the length prefix, which can't happen for a datagram read off a real
+/// UDP socket over IPv4.
+pub fn encode(payload: &[u8]) -> Option<Vec<u8>> {
+    let len = u16::try_from(payload.len()).ok()?;
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(payload);
+    Some(frame)
+}
+
+/// Pulls the first complete frame off the front of `buf`, returning its
+/// payload and how many bytes of `buf` it used. `None` means more bytes
+/// are needed before the next datagram is whole.
+pub fn decode(buf: &[u8]) -> Option<(&[u8], usize)> {
+    let header = buf.get(..HEADER_LEN)?;
+    let len = u16::from_be_bytes([header[0], header[1]]) as usize;
+    let payload = buf.get(HEADER_LEN..HEADER_LEN + len)?;
+    Some((payload, HEADER_LEN + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip_across_partial_reads() {
+        let mut stream = encode(b"hello").unwrap();
+        stream.extend(encode(b"").unwrap());
+        stream.extend(encode(&[7; 300]).unwrap());
+        assert_eq!(&stream[..2], &[0, 5]);
+
+        assert_eq!(decode(&stream[..1]), None);
+        assert_eq!(decode(&stream[..6]), None);
+
+        let (payload, used) = decode(&stream).unwrap();
+        assert_eq!((payload, used), (&b"hello"[..], 7));
+        let rest = &stream[used..];
+        let (payload, used) = decode(rest).unwrap();
+        assert_eq!((payload, used), (&b""[..], 2));
+        let (payload, _) = decode(&rest[used..]).unwrap();
+        assert_eq!(payload.len(), 300);
+
+        assert!(encode(&vec![0; MAX_PAYLOAD]).is_some());
+        assert!(encode(&vec![0; MAX_PAYLOAD + 1]).is_none());
+    }
+}
@@ -53,6 +53,9 @@ pub enum NeedleError {
     #[error("tier limit exceeded: {tier} tier allows max {limit} tunnels")]
     TierLimitExceeded { tier: String, limit: usize },
 
+    #[error("reservation limit exceeded: {tier} tier allows max {limit} reserved subdomains")]
+    ReservationLimitExceeded { tier: String, limit: usize },
+
     #[error("invalid SSH port: {port} (must be >= {min})")]
     InvalidPort { port: u16, min: u16 },
 
@@ -1,6 +1,8 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
+pub mod cidr;
+pub mod datagram;
 pub mod error;
 pub mod rate_limit;
 pub mod subdomain;
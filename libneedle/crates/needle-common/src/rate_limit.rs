@@ -1,8 +1,13 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
-use std::sync::Mutex;
-use std::time::Instant;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Enough that requests for different clients rarely wait on each other
+const SHARDS: usize = 16;
 
 /// A token bucket rate limiter that smoothly controls request throughput.
 ///
@@ -19,6 +24,26 @@ pub struct RateLimiter {
     state: Mutex<RateLimiterState>,
 }
 
+/// The outcome of one [`RateLimiter::check`], with enough detail to fill
+/// in `RateLimit-*` response headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitStatus {
+    pub allowed: bool,
+    /// The bucket size, i.e. the most requests a client can send at once.
+    pub limit: u64,
+    /// Whole tokens left after this request.
+    pub remaining: u64,
+    /// How long until the next token arrives. Zero when allowed.
+    pub retry_after: Duration,
+}
+
+impl RateLimitStatus {
+    /// `retry_after` rounded up to whole seconds, as Retry-After wants.
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs_f64().ceil() as u64
+    }
+}
+
 struct RateLimiterState {
     tokens: f64,
     max_tokens: f64,
@@ -45,6 +70,12 @@ impl RateLimiter {
     /// accumulated since the last call. This way we don't need a background
     /// timer -- the bucket refills lazily on each check.
     pub fn allow(&self) -> bool {
+        self.check().allowed
+    }
+
+    /// Like [`allow`](Self::allow), but also reports what's left in the
+    /// bucket and, when denied, how long the caller should back off.
+    pub fn check(&self) -> RateLimitStatus {
         let mut state = self.state.lock().expect("rate limiter lock poisoned");
 
         let now = Instant::now();
@@ -53,20 +84,189 @@ impl RateLimiter {
         state.tokens = (state.tokens + elapsed * state.refill_rate).min(state.max_tokens);
         state.last_refill = now;
 
-        if state.tokens >= 1.0 {
+        let allowed = state.tokens >= 1.0;
+        let retry_after = if allowed {
             state.tokens -= 1.0;
-            true
+            Duration::ZERO
         } else {
-            false
+            Duration::from_secs_f64((1.0 - state.tokens) / state.refill_rate)
+        };
+
+        RateLimitStatus {
+            allowed,
+            limit: state.max_tokens as u64,
+            remaining: state.tokens as u64,
+            retry_after,
+        }
+    }
+
+    /// Whether the bucket has refilled all the way. A full bucket is the
+    /// same as a brand new one, so it can be thrown away without letting
+    /// its client through any sooner.
+    pub fn is_full(&self) -> bool {
+        let state = self.state.lock().expect("rate limiter lock poisoned");
+        let elapsed = state.last_refill.elapsed().as_secs_f64();
+        state.tokens + elapsed * state.refill_rate >= state.max_tokens
+    }
+}
+
+/// One token bucket per key (a client IP, a user, ...), created on first
+/// use.
+///
+/// The table is split into shards, each behind its own lock, and a shard
+/// is only locked long enough to find or insert the bucket, so busy
+/// clients don't queue up behind each other. It's bounded two ways:
+/// [`sweep`](Self::sweep) drops buckets that have refilled completely,
+/// and once `max_entries` is reached a new key pushes out the least
+/// recently seen one in its shard.
+pub struct RateLimiterStore<K> {
+    shards: Vec<Mutex<Shard<K>>>,
+    hasher: RandomState,
+    max_per_shard: usize,
+}
+
+struct StoreEntry {
+    limiter: Arc<RateLimiter>,
+    // When the key was last seen, by its shard's clock
+    seen: u64,
+}
+
+/// One shard's buckets, plus the order their keys were last seen in so
+/// a full shard can find the one to push out without scanning. Seeing a
+/// key again queues it afresh and leaves its old place behind as a stale
+/// entry, which eviction skips and compaction clears out.
+struct Shard<K> {
+    entries: HashMap<K, StoreEntry>,
+    recency: VecDeque<(K, u64)>,
+    clock: u64,
+}
+
+impl<K: Hash + Eq + Clone> Shard<K> {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            recency: VecDeque::new(),
+            clock: 0,
+        }
+    }
+
+    fn touch(&mut self, key: &K) -> u64 {
+        self.clock += 1;
+        self.recency.push_back((key.clone(), self.clock));
+        // Stale entries can't outnumber live ones by much, so this runs
+        // rarely enough to cost O(1) per request overall
+        if self.recency.len() > 2 * self.entries.len() + SHARDS {
+            self.compact();
+        }
+        self.clock
+    }
+
+    fn is_current(&self, key: &K, seen: u64) -> bool {
+        self.entries
+            .get(key)
+            .is_some_and(|entry| entry.seen == seen)
+    }
+
+    fn compact(&mut self) {
+        let mut recency = std::mem::take(&mut self.recency);
+        recency.retain(|(key, seen)| self.is_current(key, *seen));
+        self.recency = recency;
+    }
+
+    /// Drops the least recently seen key.
+    fn evict_oldest(&mut self) {
+        while let Some((key, seen)) = self.recency.pop_front() {
+            if self.is_current(&key, seen) {
+                self.entries.remove(&key);
+                return;
+            }
         }
     }
 }
 
+impl<K: Hash + Eq + Clone> RateLimiterStore<K> {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(Shard::new())).collect(),
+            hasher: RandomState::new(),
+            max_per_shard: max_entries.div_ceil(SHARDS).max(1),
+        }
+    }
+
+    /// Takes a token from `key`'s bucket, creating the bucket with
+    /// `create` if the key hasn't been seen (or has been evicted).
+    pub fn check(&self, key: &K, create: impl FnOnce() -> RateLimiter) -> RateLimitStatus {
+        let limiter = {
+            let mut shard = self
+                .shard(key)
+                .lock()
+                .expect("rate limiter store lock poisoned");
+            if shard.entries.contains_key(key) {
+                let seen = shard.touch(key);
+                let entry = shard.entries.get_mut(key).expect("entry just found");
+                entry.seen = seen;
+                entry.limiter.clone()
+            } else {
+                if shard.entries.len() >= self.max_per_shard {
+                    shard.evict_oldest();
+                }
+                let limiter = Arc::new(create());
+                let seen = shard.touch(key);
+                shard.entries.insert(
+                    key.clone(),
+                    StoreEntry {
+                        limiter: limiter.clone(),
+                        seen,
+                    },
+                );
+                limiter
+            }
+        };
+
+        limiter.check()
+    }
+
+    /// Drops every bucket that has refilled completely. Returns how many
+    /// went.
+    pub fn sweep(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let mut shard = shard.lock().expect("rate limiter store lock poisoned");
+                let before = shard.entries.len();
+                shard.entries.retain(|_, entry| !entry.limiter.is_full());
+                shard.compact();
+                before - shard.entries.len()
+            })
+            .sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .lock()
+                    .expect("rate limiter store lock poisoned")
+                    .entries
+                    .len()
+            })
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<Shard<K>> {
+        &self.shards[self.hasher.hash_one(key) as usize % SHARDS]
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::thread;
-    use std::time::Duration;
 
     #[test]
     fn allows_burst_then_limits() {
@@ -92,6 +292,77 @@ mod tests {
         assert!(limiter.allow(), "should allow after refill time");
     }
 
+    #[test]
+    fn reports_remaining_and_retry_after() {
+        let limiter = RateLimiter::new(2.0, 2.0);
+
+        let first = limiter.check();
+        assert!(first.allowed);
+        assert_eq!((first.limit, first.remaining), (2, 1));
+
+        assert!(limiter.check().allowed);
+        let denied = limiter.check();
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 0);
+        assert!(denied.retry_after > Duration::ZERO);
+        assert_eq!(denied.retry_after_secs(), 1);
+    }
+
+    #[test]
+    fn store_keeps_one_bucket_per_key() {
+        let store = RateLimiterStore::new(100);
+
+        assert!(store.check(&"a", || RateLimiter::new(1.0, 1.0)).allowed);
+        assert!(!store.check(&"a", || RateLimiter::new(1.0, 1.0)).allowed);
+        assert!(store.check(&"b", || RateLimiter::new(1.0, 1.0)).allowed);
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn store_sweeps_refilled_buckets_and_stays_bounded() {
+        // Roomy enough that the two keys can't evict each other, whichever
+        // shards they land in
+        let store = RateLimiterStore::new(SHARDS * 4);
+        store.check(&0, || RateLimiter::new(1000.0, 1.0));
+        store.check(&1, || RateLimiter::new(0.001, 1.0));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(store.sweep(), 1, "only the refilled bucket goes");
+        assert_eq!(store.len(), 1);
+
+        let store = RateLimiterStore::new(SHARDS);
+        for key in 0..1000 {
+            store.check(&key, || RateLimiter::new(0.001, 1.0));
+        }
+        assert!(store.len() <= SHARDS);
+    }
+
+    #[test]
+    fn full_store_evicts_the_least_recently_seen_key() {
+        let mut shard = Shard::new();
+        for key in ["a", "b", "c"] {
+            let seen = shard.touch(&key);
+            shard.entries.insert(
+                key,
+                StoreEntry {
+                    limiter: Arc::new(RateLimiter::new(1.0, 1.0)),
+                    seen,
+                },
+            );
+        }
+        // Seeing "a" again makes "b" the oldest
+        for _ in 0..100 {
+            let seen = shard.touch(&"a");
+            shard.entries.get_mut(&"a").unwrap().seen = seen;
+        }
+        assert!(shard.recency.len() <= 2 * shard.entries.len() + SHARDS);
+
+        shard.evict_oldest();
+        assert!(!shard.entries.contains_key(&"b"));
+        shard.evict_oldest();
+        assert!(!shard.entries.contains_key(&"c"));
+        assert!(shard.entries.contains_key(&"a"));
+    }
+
     #[test]
     fn does_not_exceed_max_tokens() {
         let limiter = RateLimiter::new(100.0, 3.0);
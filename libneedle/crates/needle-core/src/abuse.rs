@@ -0,0 +1,326 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::config::NeedleConfig;
+use crate::metrics;
+use chrono::{DateTime, Utc};
+use needle_common::error::{NeedleError, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// Once we track this many IPs, forget the ones that have gone quiet
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Something an IP did that counts against it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offense {
+    SshAuthFailure,
+    ApiLoginFailure,
+    RateLimited,
+}
+
+impl Offense {
+    /// How many strike points this offense costs. A wrong password is
+    /// much stronger evidence of abuse than an over-eager client hitting
+    /// a rate limit, so it weighs more.
+    fn weight(self) -> u32 {
+        match self {
+            Offense::SshAuthFailure | Offense::ApiLoginFailure => 4,
+            Offense::RateLimited => 1,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Offense::SshAuthFailure => "ssh_auth_failure",
+            Offense::ApiLoginFailure => "api_login_failure",
+            Offense::RateLimited => "rate_limited",
+        }
+    }
+}
+
+/// An active ban on one IP.
+#[derive(Debug, Clone, Serialize)]
+pub struct Ban {
+    pub ip: IpAddr,
+    pub until: DateTime<Utc>,
+    pub reason: String,
+    pub manual: bool,
+}
+
+/// Everything we remember about one misbehaving IP.
+struct Record {
+    strikes: u32,
+    window_start: DateTime<Utc>,
+    // Automatic bans so far, which sets how long the next one lasts
+    bans_issued: u32,
+    ban: Option<Ban>,
+    last_seen: DateTime<Utc>,
+}
+
+/// Counts offenses per IP and hands out temporary bans.
+///
+/// Each offense adds strike points inside a rolling window; crossing the
+/// threshold bans the IP for `ban_duration`, doubling with every repeat
+/// ban up to `max_ban_duration`. An IP that stays clean for a full
+/// `max_ban_duration` after its last ban starts over from the base length.
+///
+/// State lives in memory only, so a restart lifts every ban. Shared by
+/// the SSH server, the API and the edge, which all call [`check`] before
+/// doing any real work for a connection.
+///
+/// [`check`]: AbuseTracker::check
+pub struct AbuseTracker {
+    records: Mutex<HashMap<IpAddr, Record>>,
+    threshold: u32,
+    window: chrono::Duration,
+    ban_duration: chrono::Duration,
+    max_ban_duration: chrono::Duration,
+    allowlist: Vec<IpAddr>,
+}
+
+impl AbuseTracker {
+    pub fn new(config: &NeedleConfig) -> Self {
+        let allowlist = config
+            .abuse_allowlist
+            .iter()
+            .filter_map(|ip| ip.parse().ok())
+            .collect();
+
+        Self {
+            records: Mutex::new(HashMap::new()),
+            threshold: config.abuse_ban_threshold,
+            window: to_chrono(config.abuse_window),
+            ban_duration: to_chrono(config.abuse_ban_duration),
+            max_ban_duration: to_chrono(config.abuse_max_ban_duration),
+            allowlist,
+        }
+    }
+
+    /// Returns `IpBlocked` if this IP is currently banned.
+    pub fn check(&self, ip: IpAddr) -> Result<()> {
+        let records = self.records.lock().unwrap();
+        match records.get(&ip).and_then(|r| r.ban.as_ref()) {
+            Some(ban) if ban.until > Utc::now() => {
+                Err(NeedleError::IpBlocked(ban.until.to_rfc3339()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts one offense against an IP. Returns the ban if this was the
+    /// offense that tipped it over the threshold.
+    pub fn record(&self, ip: IpAddr, offense: Offense) -> Option<Ban> {
+        if self.allowlist.contains(&ip) {
+            return None;
+        }
+
+        let ban = self.record_at(ip, offense, Utc::now());
+        if let Some(ban) = &ban {
+            warn!(ip = %ip, until = %ban.until, reason = %ban.reason, "ip banned");
+            metrics::ip_banned(offense.as_str());
+        }
+        ban
+    }
+
+    /// Bans an IP by hand for a fixed duration, replacing any current ban.
+    pub fn ban(&self, ip: IpAddr, duration: Duration, reason: &str) -> Ban {
+        let now = Utc::now();
+        let until = now
+            .checked_add_signed(to_chrono(duration))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        let ban = Ban {
+            ip,
+            until,
+            reason: reason.to_string(),
+            manual: true,
+        };
+
+        let mut records = self.records.lock().unwrap();
+        let record = records.entry(ip).or_insert_with(|| Record::new(now));
+        record.ban = Some(ban.clone());
+        record.last_seen = now;
+
+        info!(ip = %ip, until = %until, reason = %reason, "ip banned manually");
+        metrics::ip_banned("manual");
+        ban
+    }
+
+    /// Lifts a ban and forgets the IP's history. Returns false if it
+    /// wasn't banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        let removed = self.records.lock().unwrap().remove(&ip);
+        let was_banned = removed
+            .and_then(|r| r.ban)
+            .is_some_and(|ban| ban.until > Utc::now());
+
+        if was_banned {
+            info!(ip = %ip, "ip unbanned");
+        }
+        was_banned
+    }
+
+    /// Every ban still in force, soonest to lift first.
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = Utc::now();
+        let mut bans: Vec<Ban> = self
+            .records
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|r| r.ban.clone())
+            .filter(|ban| ban.until > now)
+            .collect();
+        bans.sort_by_key(|ban| ban.until);
+        bans
+    }
+
+    fn record_at(&self, ip: IpAddr, offense: Offense, now: DateTime<Utc>) -> Option<Ban> {
+        let mut records = self.records.lock().unwrap();
+        if records.len() >= PRUNE_THRESHOLD {
+            self.prune(&mut records, now);
+        }
+
+        let record = records.entry(ip).or_insert_with(|| Record::new(now));
+        record.last_seen = now;
+
+        match &record.ban {
+            // Already banned, nothing more to count
+            Some(ban) if ban.until > now => return None,
+            // A long clean stretch since the last ban wipes the slate
+            Some(ban) if now - ban.until > self.max_ban_duration => {
+                record.ban = None;
+                record.bans_issued = 0;
+            }
+            _ => {}
+        }
+
+        if now - record.window_start > self.window {
+            record.strikes = 0;
+            record.window_start = now;
+        }
+
+        record.strikes += offense.weight();
+        if record.strikes < self.threshold {
+            return None;
+        }
+
+        let length = self.ban_length(record.bans_issued);
+        let ban = Ban {
+            ip,
+            until: now
+                .checked_add_signed(length)
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+            reason: offense.as_str().to_string(),
+            manual: false,
+        };
+
+        record.strikes = 0;
+        record.window_start = now;
+        record.bans_issued = record.bans_issued.saturating_add(1);
+        record.ban = Some(ban.clone());
+        Some(ban)
+    }
+
+    /// Base ban length doubled once per earlier ban, capped at the max.
+    fn ban_length(&self, bans_issued: u32) -> chrono::Duration {
+        let factor = 1i32.checked_shl(bans_issued.min(30)).unwrap_or(i32::MAX);
+        self.ban_duration
+            .checked_mul(factor)
+            .map_or(self.max_ban_duration, |d| d.min(self.max_ban_duration))
+    }
+
+    /// Drops IPs that aren't banned and haven't offended in a long while.
+    fn prune(&self, records: &mut HashMap<IpAddr, Record>, now: DateTime<Utc>) {
+        let horizon = self.window.max(self.max_ban_duration);
+        records.retain(|_, r| {
+            r.ban.as_ref().is_some_and(|ban| ban.until > now) || now - r.last_seen < horizon
+        });
+    }
+}
+
+impl Record {
+    fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            strikes: 0,
+            window_start: now,
+            bans_issued: 0,
+            ban: None,
+            last_seen: now,
+        }
+    }
+}
+
+fn to_chrono(d: Duration) -> chrono::Duration {
+    chrono::Duration::from_std(d).unwrap_or(chrono::Duration::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> AbuseTracker {
+        AbuseTracker {
+            records: Mutex::new(HashMap::new()),
+            threshold: 20,
+            window: chrono::Duration::minutes(5),
+            ban_duration: chrono::Duration::minutes(15),
+            max_ban_duration: chrono::Duration::hours(1),
+            allowlist: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn bans_after_threshold_with_backoff() {
+        let tracker = tracker();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let start = Utc::now();
+
+        for _ in 0..4 {
+            assert!(
+                tracker
+                    .record_at(ip, Offense::SshAuthFailure, start)
+                    .is_none()
+            );
+        }
+        let first = tracker
+            .record_at(ip, Offense::SshAuthFailure, start)
+            .unwrap();
+        assert_eq!(first.until - start, chrono::Duration::minutes(15));
+        assert!(tracker.check(ip).is_err());
+
+        let later = first.until + chrono::Duration::seconds(1);
+        for _ in 0..4 {
+            tracker.record_at(ip, Offense::ApiLoginFailure, later);
+        }
+        let second = tracker
+            .record_at(ip, Offense::ApiLoginFailure, later)
+            .unwrap();
+        assert_eq!(second.until - later, chrono::Duration::minutes(30));
+    }
+
+    #[test]
+    fn ban_length_is_capped() {
+        let tracker = tracker();
+        assert_eq!(tracker.ban_length(0), chrono::Duration::minutes(15));
+        assert_eq!(tracker.ban_length(2), chrono::Duration::hours(1));
+        assert_eq!(tracker.ban_length(u32::MAX), chrono::Duration::hours(1));
+    }
+
+    #[test]
+    fn strikes_reset_outside_window() {
+        let tracker = tracker();
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let start = Utc::now();
+
+        for _ in 0..19 {
+            tracker.record_at(ip, Offense::RateLimited, start);
+        }
+        let later = start + chrono::Duration::minutes(6);
+        assert!(tracker.record_at(ip, Offense::RateLimited, later).is_none());
+    }
+}
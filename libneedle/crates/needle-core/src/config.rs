@@ -1,21 +1,181 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::env;
 use std::time::Duration;
 use tracing::{info, warn};
 
 const DEFAULT_API_ADDR: &str = "0.0.0.0:3000";
 const DEFAULT_SSH_ADDR: &str = "0.0.0.0:2222";
+const DEFAULT_EDGE_ADDR: &str = "0.0.0.0:8080";
 const DEFAULT_DOMAIN: &str = "localhost";
 const DEFAULT_MAX_TUNNELS_PER_IP: usize = 5;
 const DEFAULT_GLOBAL_TUNNEL_LIMIT: usize = 1000;
 const DEFAULT_HTTP_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_PROXY_CONNECT_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_PROXY_HEADER_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_PROXY_TOTAL_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_FREE_MAX_PROXY_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_PRO_MAX_PROXY_TIMEOUT_SECS: u64 = 10 * 60;
+const DEFAULT_ENTERPRISE_MAX_PROXY_TIMEOUT_SECS: u64 = 60 * 60;
 const DEFAULT_FREE_TIER_LIMIT: usize = 3;
 const DEFAULT_PRO_TIER_LIMIT: usize = 50;
 const DEFAULT_ENTERPRISE_TIER_LIMIT: usize = 500;
 const MIN_ALLOWED_SSH_PORT: u16 = 1024;
+const DEFAULT_FREE_RESERVATION_SECS: u64 = 60 * 60;
+const DEFAULT_PRO_RESERVATION_SECS: u64 = 7 * 24 * 60 * 60;
+const DEFAULT_ENTERPRISE_RESERVATION_SECS: u64 = 30 * 24 * 60 * 60;
+const DEFAULT_FREE_RESERVED_SUBDOMAINS: usize = 1;
+const DEFAULT_PRO_RESERVED_SUBDOMAINS: usize = 10;
+const DEFAULT_ENTERPRISE_RESERVED_SUBDOMAINS: usize = 100;
+const DEFAULT_FREE_MAX_LIFETIME_SECS: u64 = 8 * 60 * 60;
+const DEFAULT_ABUSE_BAN_THRESHOLD: u32 = 20;
+const DEFAULT_ABUSE_WINDOW_SECS: u64 = 5 * 60;
+const DEFAULT_ABUSE_BAN_SECS: u64 = 15 * 60;
+const DEFAULT_ABUSE_MAX_BAN_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_PUBLIC_SCHEME: &str = "https";
+const DEFAULT_EDGE_SESSION_SECS: u64 = 12 * 60 * 60;
+const DEFAULT_UDP_BIND_HOST: &str = "0.0.0.0";
+const DEFAULT_UDP_PORT_MIN: u16 = 40000;
+const DEFAULT_UDP_PORT_MAX: u16 = 40999;
+const DEFAULT_UDP_PEER_IDLE_SECS: u64 = 60;
+const DEFAULT_FREE_RATE_LIMIT: RateLimitSettings = RateLimitSettings::new(10.0, 20.0);
+const DEFAULT_PRO_RATE_LIMIT: RateLimitSettings = RateLimitSettings::new(50.0, 100.0);
+const DEFAULT_ENTERPRISE_RATE_LIMIT: RateLimitSettings = RateLimitSettings::new(200.0, 400.0);
+const DEFAULT_API_RATE_LIMIT: RateLimitSettings = RateLimitSettings::new(10.0, 30.0);
+// Five login attempts a minute and three sign-ups an hour, per IP
+const DEFAULT_LOGIN_RATE_LIMIT: RateLimitSettings = RateLimitSettings::new(5.0 / 60.0, 5.0);
+const DEFAULT_REGISTER_RATE_LIMIT: RateLimitSettings = RateLimitSettings::new(3.0 / 3600.0, 3.0);
+const DEFAULT_API_RATE_LIMIT_MAX_ENTRIES: usize = 100_000;
+const MIB: u64 = 1024 * 1024;
+// Responses are buffered at the edge, so their limits stay modest
+const DEFAULT_FREE_BODY_LIMITS: BodyLimits = BodyLimits::new(10 * MIB, 50 * MIB, 100 * MIB);
+const DEFAULT_PRO_BODY_LIMITS: BodyLimits = BodyLimits::new(100 * MIB, 100 * MIB, 1024 * MIB);
+const DEFAULT_ENTERPRISE_BODY_LIMITS: BodyLimits =
+    BodyLimits::new(500 * MIB, 250 * MIB, 10 * 1024 * MIB);
+
+// Listeners that can sit behind a load balancer speaking PROXY protocol
+const PROXY_PROTOCOL_LISTENERS: &[&str] = &["api", "ssh", "edge"];
+
+// Names nobody gets to claim, since they'd shadow our own services or look
+// official. Operators can replace the list with SUBDOMAIN_BLOCKLIST.
+const DEFAULT_SUBDOMAIN_BLOCKLIST: &[&str] = &[
+    "admin",
+    "api",
+    "app",
+    "auth",
+    "blog",
+    "cdn",
+    "dashboard",
+    "docs",
+    "ftp",
+    "help",
+    "imap",
+    "mail",
+    "needle",
+    "ns1",
+    "ns2",
+    "pop",
+    "root",
+    "smtp",
+    "ssh",
+    "static",
+    "status",
+    "support",
+    "www",
+];
+
+/// A token bucket's shape: the steady rate it refills at, and how many
+/// requests it lets through at once.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitSettings {
+    pub requests_per_second: f64,
+    pub burst: f64,
+}
+
+impl RateLimitSettings {
+    pub const fn new(requests_per_second: f64, burst: f64) -> Self {
+        Self {
+            requests_per_second,
+            burst,
+        }
+    }
+
+    /// Whether a bucket with these settings would ever let anything
+    /// through. NaN and infinity don't count.
+    pub fn is_valid(&self) -> bool {
+        self.requests_per_second.is_finite()
+            && self.requests_per_second > 0.0
+            && self.burst.is_finite()
+            && self.burst >= 1.0
+    }
+
+    /// Each value capped at the matching one in `ceiling`.
+    pub fn min(self, ceiling: Self) -> Self {
+        Self::new(
+            self.requests_per_second.min(ceiling.requests_per_second),
+            self.burst.min(ceiling.burst),
+        )
+    }
+}
+
+/// How much data the edge lets through for one tunnel, in bytes: a
+/// request body, a response body, and what flows each way over one
+/// WebSocket session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BodyLimits {
+    pub max_request_bytes: u64,
+    pub max_response_bytes: u64,
+    pub max_websocket_bytes: u64,
+}
+
+impl BodyLimits {
+    pub const fn new(
+        max_request_bytes: u64,
+        max_response_bytes: u64,
+        max_websocket_bytes: u64,
+    ) -> Self {
+        Self {
+            max_request_bytes,
+            max_response_bytes,
+            max_websocket_bytes,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.max_request_bytes > 0 && self.max_response_bytes > 0 && self.max_websocket_bytes > 0
+    }
+}
+
+/// How long the edge waits on a tunnel's app, in seconds: to connect,
+/// for the response headers once the request is on its way, for the
+/// next piece of a body while one is moving, and for the whole exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyTimeouts {
+    pub connect_secs: u64,
+    pub header_secs: u64,
+    pub idle_secs: u64,
+    pub total_secs: u64,
+}
+
+impl ProxyTimeouts {
+    pub fn connect(&self) -> Duration {
+        Duration::from_secs(self.connect_secs)
+    }
+
+    pub fn header(&self) -> Duration {
+        Duration::from_secs(self.header_secs)
+    }
+
+    pub fn idle(&self) -> Duration {
+        Duration::from_secs(self.idle_secs)
+    }
+
+    pub fn total(&self) -> Duration {
+        Duration::from_secs(self.total_secs)
+    }
+}
 
 /// Runtime configuration assembled from environment variables.
 ///
@@ -32,18 +192,104 @@ pub struct NeedleConfig {
     pub domain: String,
     pub api_addr: String,
     pub ssh_addr: String,
+    pub edge_addr: String,
     pub max_tunnels_per_ip: usize,
     pub global_tunnel_limit: usize,
 
-    // HTTP proxy timeouts
+    // HTTP proxy timeouts. The read timeout is how long a visitor gets
+    // to send their request headers; the write timeout is how long a
+    // body moving through the edge may stall, either way.
     pub http_read_timeout: Duration,
     pub http_write_timeout: Duration,
+    pub proxy_connect_timeout: Duration,
+    pub proxy_header_timeout: Duration,
+    pub proxy_total_timeout: Duration,
+
+    // How far tunnels on each tier may raise any proxy timeout
+    pub free_max_proxy_timeout: Duration,
+    pub pro_max_proxy_timeout: Duration,
+    pub enterprise_max_proxy_timeout: Duration,
 
     // Tier limits
     pub free_tier_limit: usize,
     pub pro_tier_limit: usize,
     pub enterprise_tier_limit: usize,
 
+    // How long a disconnected persistent tunnel keeps its subdomain
+    pub free_reservation_ttl: Duration,
+    pub pro_reservation_ttl: Duration,
+    pub enterprise_reservation_ttl: Duration,
+
+    // How many subdomains each tier may reserve without a live tunnel
+    pub free_reserved_subdomains: usize,
+    pub pro_reserved_subdomains: usize,
+    pub enterprise_reserved_subdomains: usize,
+
+    // Names that can't be claimed as tunnel subdomains or reservations
+    pub subdomain_blocklist: Vec<String>,
+
+    // Longest a free-tier tunnel may live, None for no cap
+    pub free_tier_max_lifetime: Option<Duration>,
+
+    // Automatic IP bans for repeated auth failures and rate-limit hits
+    pub abuse_ban_threshold: u32,
+    pub abuse_window: Duration,
+    pub abuse_ban_duration: Duration,
+    pub abuse_max_ban_duration: Duration,
+    pub abuse_allowlist: Vec<String>,
+
+    // Load balancers allowed to tell us who the client is, and which
+    // listeners expect a PROXY protocol header from them
+    pub trusted_proxies: Vec<String>,
+    pub proxy_protocol: Vec<String>,
+
+    // Accounts allowed to use the admin API
+    pub admin_emails: Vec<String>,
+
+    // Scheme visitors use to reach tunnels, for redirect URLs and cookies
+    pub public_scheme: String,
+
+    // Default OIDC provider for tunnels that require a login
+    pub oidc_issuer_url: Option<String>,
+    pub oidc_client_id: Option<String>,
+    pub oidc_client_secret: Option<String>,
+
+    // How long an edge login lasts before visitors sign in again
+    pub edge_session_ttl: Duration,
+
+    // Directory of HTML templates that replace the edge's error pages
+    pub error_pages_dir: Option<String>,
+
+    // PEM certificate chain and private key for the edge to terminate
+    // TLS itself, instead of leaving it to a load balancer
+    pub edge_tls_cert: Option<String>,
+    pub edge_tls_key: Option<String>,
+
+    // Where UDP tunnels get their public ports, and how long a remote
+    // peer may go quiet before its channel is closed
+    pub udp_bind_host: String,
+    pub udp_port_min: u16,
+    pub udp_port_max: u16,
+    pub udp_peer_idle: Duration,
+
+    // Per-tunnel request rate at the edge, by the owner's tier
+    pub free_rate_limit: RateLimitSettings,
+    pub pro_rate_limit: RateLimitSettings,
+    pub enterprise_rate_limit: RateLimitSettings,
+
+    // Per-client request rate on the REST API, with tighter limits on
+    // the routes password guessers and sign-up bots go for
+    pub api_rate_limit: RateLimitSettings,
+    pub login_rate_limit: RateLimitSettings,
+    pub register_rate_limit: RateLimitSettings,
+    // Most API clients tracked at once before the least recent are dropped
+    pub api_rate_limit_max_entries: usize,
+
+    // Request, response and WebSocket size ceilings, by the owner's tier
+    pub free_body_limits: BodyLimits,
+    pub pro_body_limits: BodyLimits,
+    pub enterprise_body_limits: BodyLimits,
+
     // SSH security
     pub min_ssh_port: u16,
 }
@@ -63,6 +309,7 @@ impl NeedleConfig {
             domain: env::var("DOMAIN").unwrap_or_else(|_| DEFAULT_DOMAIN.to_string()),
             api_addr: env::var("API_ADDR").unwrap_or_else(|_| DEFAULT_API_ADDR.to_string()),
             ssh_addr: env::var("SSH_ADDR").unwrap_or_else(|_| DEFAULT_SSH_ADDR.to_string()),
+            edge_addr: env::var("EDGE_ADDR").unwrap_or_else(|_| DEFAULT_EDGE_ADDR.to_string()),
             max_tunnels_per_ip: parse_usize_env("MAX_TUNNELS_PER_IP", DEFAULT_MAX_TUNNELS_PER_IP),
             global_tunnel_limit: parse_usize_env(
                 "GLOBAL_TUNNEL_LIMIT",
@@ -76,12 +323,128 @@ impl NeedleConfig {
                 "HTTP_WRITE_TIMEOUT_SECS",
                 DEFAULT_HTTP_TIMEOUT_SECS,
             )),
+            proxy_connect_timeout: Duration::from_secs(parse_u64_env(
+                "PROXY_CONNECT_TIMEOUT_SECS",
+                DEFAULT_PROXY_CONNECT_TIMEOUT_SECS,
+            )),
+            proxy_header_timeout: Duration::from_secs(parse_u64_env(
+                "PROXY_HEADER_TIMEOUT_SECS",
+                DEFAULT_PROXY_HEADER_TIMEOUT_SECS,
+            )),
+            proxy_total_timeout: Duration::from_secs(parse_u64_env(
+                "PROXY_TOTAL_TIMEOUT_SECS",
+                DEFAULT_PROXY_TOTAL_TIMEOUT_SECS,
+            )),
+            free_max_proxy_timeout: Duration::from_secs(parse_u64_env(
+                "FREE_TIER_MAX_PROXY_TIMEOUT_SECS",
+                DEFAULT_FREE_MAX_PROXY_TIMEOUT_SECS,
+            )),
+            pro_max_proxy_timeout: Duration::from_secs(parse_u64_env(
+                "PRO_TIER_MAX_PROXY_TIMEOUT_SECS",
+                DEFAULT_PRO_MAX_PROXY_TIMEOUT_SECS,
+            )),
+            enterprise_max_proxy_timeout: Duration::from_secs(parse_u64_env(
+                "ENTERPRISE_TIER_MAX_PROXY_TIMEOUT_SECS",
+                DEFAULT_ENTERPRISE_MAX_PROXY_TIMEOUT_SECS,
+            )),
             free_tier_limit: parse_usize_env("FREE_TIER_LIMIT", DEFAULT_FREE_TIER_LIMIT),
             pro_tier_limit: parse_usize_env("PRO_TIER_LIMIT", DEFAULT_PRO_TIER_LIMIT),
             enterprise_tier_limit: parse_usize_env(
                 "ENTERPRISE_TIER_LIMIT",
                 DEFAULT_ENTERPRISE_TIER_LIMIT,
             ),
+            free_reservation_ttl: Duration::from_secs(parse_u64_env(
+                "FREE_TIER_RESERVATION_SECS",
+                DEFAULT_FREE_RESERVATION_SECS,
+            )),
+            pro_reservation_ttl: Duration::from_secs(parse_u64_env(
+                "PRO_TIER_RESERVATION_SECS",
+                DEFAULT_PRO_RESERVATION_SECS,
+            )),
+            enterprise_reservation_ttl: Duration::from_secs(parse_u64_env(
+                "ENTERPRISE_TIER_RESERVATION_SECS",
+                DEFAULT_ENTERPRISE_RESERVATION_SECS,
+            )),
+            free_reserved_subdomains: parse_usize_env(
+                "FREE_TIER_RESERVED_SUBDOMAINS",
+                DEFAULT_FREE_RESERVED_SUBDOMAINS,
+            ),
+            pro_reserved_subdomains: parse_usize_env(
+                "PRO_TIER_RESERVED_SUBDOMAINS",
+                DEFAULT_PRO_RESERVED_SUBDOMAINS,
+            ),
+            enterprise_reserved_subdomains: parse_usize_env(
+                "ENTERPRISE_TIER_RESERVED_SUBDOMAINS",
+                DEFAULT_ENTERPRISE_RESERVED_SUBDOMAINS,
+            ),
+            subdomain_blocklist: parse_list_env("SUBDOMAIN_BLOCKLIST", DEFAULT_SUBDOMAIN_BLOCKLIST),
+            free_tier_max_lifetime: match parse_u64_env(
+                "FREE_TIER_MAX_LIFETIME_SECS",
+                DEFAULT_FREE_MAX_LIFETIME_SECS,
+            ) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            abuse_ban_threshold: parse_u32_env("ABUSE_BAN_THRESHOLD", DEFAULT_ABUSE_BAN_THRESHOLD),
+            abuse_window: Duration::from_secs(parse_u64_env(
+                "ABUSE_WINDOW_SECS",
+                DEFAULT_ABUSE_WINDOW_SECS,
+            )),
+            abuse_ban_duration: Duration::from_secs(parse_u64_env(
+                "ABUSE_BAN_SECS",
+                DEFAULT_ABUSE_BAN_SECS,
+            )),
+            abuse_max_ban_duration: Duration::from_secs(parse_u64_env(
+                "ABUSE_MAX_BAN_SECS",
+                DEFAULT_ABUSE_MAX_BAN_SECS,
+            )),
+            abuse_allowlist: parse_list_env("ABUSE_ALLOWLIST", &[]),
+            trusted_proxies: parse_list_env("TRUSTED_PROXIES", &[]),
+            proxy_protocol: parse_list_env("PROXY_PROTOCOL", &[]),
+            admin_emails: parse_list_env("ADMIN_EMAILS", &[]),
+            public_scheme: env::var("PUBLIC_SCHEME")
+                .map(|s| s.to_ascii_lowercase())
+                .unwrap_or_else(|_| DEFAULT_PUBLIC_SCHEME.to_string()),
+            oidc_issuer_url: optional("OIDC_ISSUER_URL"),
+            oidc_client_id: optional("OIDC_CLIENT_ID"),
+            oidc_client_secret: optional("OIDC_CLIENT_SECRET"),
+            edge_session_ttl: Duration::from_secs(parse_u64_env(
+                "EDGE_SESSION_SECS",
+                DEFAULT_EDGE_SESSION_SECS,
+            )),
+            error_pages_dir: optional("ERROR_PAGES_DIR"),
+            edge_tls_cert: optional("EDGE_TLS_CERT"),
+            edge_tls_key: optional("EDGE_TLS_KEY"),
+            udp_bind_host: env::var("UDP_BIND_HOST")
+                .unwrap_or_else(|_| DEFAULT_UDP_BIND_HOST.to_string()),
+            udp_port_min: parse_u16_env("UDP_PORT_MIN", DEFAULT_UDP_PORT_MIN),
+            udp_port_max: parse_u16_env("UDP_PORT_MAX", DEFAULT_UDP_PORT_MAX),
+            udp_peer_idle: Duration::from_secs(parse_u64_env(
+                "UDP_PEER_IDLE_SECS",
+                DEFAULT_UDP_PEER_IDLE_SECS,
+            )),
+            free_rate_limit: parse_rate_limit_env("FREE_TIER_RATE_LIMIT", DEFAULT_FREE_RATE_LIMIT),
+            pro_rate_limit: parse_rate_limit_env("PRO_TIER_RATE_LIMIT", DEFAULT_PRO_RATE_LIMIT),
+            enterprise_rate_limit: parse_rate_limit_env(
+                "ENTERPRISE_TIER_RATE_LIMIT",
+                DEFAULT_ENTERPRISE_RATE_LIMIT,
+            ),
+            api_rate_limit: parse_rate_limit_env("API_RATE_LIMIT", DEFAULT_API_RATE_LIMIT),
+            login_rate_limit: parse_rate_limit_env("LOGIN_RATE_LIMIT", DEFAULT_LOGIN_RATE_LIMIT),
+            register_rate_limit: parse_rate_limit_env(
+                "REGISTER_RATE_LIMIT",
+                DEFAULT_REGISTER_RATE_LIMIT,
+            ),
+            api_rate_limit_max_entries: parse_usize_env(
+                "API_RATE_LIMIT_MAX_ENTRIES",
+                DEFAULT_API_RATE_LIMIT_MAX_ENTRIES,
+            ),
+            free_body_limits: parse_body_limits_env("FREE_TIER", DEFAULT_FREE_BODY_LIMITS),
+            pro_body_limits: parse_body_limits_env("PRO_TIER", DEFAULT_PRO_BODY_LIMITS),
+            enterprise_body_limits: parse_body_limits_env(
+                "ENTERPRISE_TIER",
+                DEFAULT_ENTERPRISE_BODY_LIMITS,
+            ),
             min_ssh_port: parse_u16_env("MIN_SSH_PORT", MIN_ALLOWED_SSH_PORT),
         };
 
@@ -93,19 +456,99 @@ impl NeedleConfig {
         info!(
             api = %config.api_addr,
             ssh = %config.ssh_addr,
+            edge = %config.edge_addr,
             domain = %config.domain,
             max_per_ip = config.max_tunnels_per_ip,
             global_limit = config.global_tunnel_limit,
             http_timeout_secs = config.http_read_timeout.as_secs(),
             free_limit = config.free_tier_limit,
             pro_limit = config.pro_tier_limit,
+            free_rps = config.free_rate_limit.requests_per_second,
+            api_rps = config.api_rate_limit.requests_per_second,
             min_ssh_port = config.min_ssh_port,
+            blocklisted_subdomains = config.subdomain_blocklist.len(),
             "loaded configuration"
         );
 
         config
     }
 
+    /// The defaults as they stand, with no environment involved, so tests
+    /// don't depend on the shell they run in.
+    #[cfg(test)]
+    pub(crate) fn for_tests(supabase_url: &str) -> Self {
+        Self {
+            supabase_url: supabase_url.to_string(),
+            supabase_anon_key: "anon".to_string(),
+            supabase_service_key: "service".to_string(),
+            jwt_secret: "secret".to_string(),
+            domain: DEFAULT_DOMAIN.to_string(),
+            api_addr: DEFAULT_API_ADDR.to_string(),
+            ssh_addr: DEFAULT_SSH_ADDR.to_string(),
+            edge_addr: DEFAULT_EDGE_ADDR.to_string(),
+            max_tunnels_per_ip: DEFAULT_MAX_TUNNELS_PER_IP,
+            global_tunnel_limit: DEFAULT_GLOBAL_TUNNEL_LIMIT,
+            http_read_timeout: Duration::from_secs(DEFAULT_HTTP_TIMEOUT_SECS),
+            http_write_timeout: Duration::from_secs(DEFAULT_HTTP_TIMEOUT_SECS),
+            proxy_connect_timeout: Duration::from_secs(DEFAULT_PROXY_CONNECT_TIMEOUT_SECS),
+            proxy_header_timeout: Duration::from_secs(DEFAULT_PROXY_HEADER_TIMEOUT_SECS),
+            proxy_total_timeout: Duration::from_secs(DEFAULT_PROXY_TOTAL_TIMEOUT_SECS),
+            free_max_proxy_timeout: Duration::from_secs(DEFAULT_FREE_MAX_PROXY_TIMEOUT_SECS),
+            pro_max_proxy_timeout: Duration::from_secs(DEFAULT_PRO_MAX_PROXY_TIMEOUT_SECS),
+            enterprise_max_proxy_timeout: Duration::from_secs(
+                DEFAULT_ENTERPRISE_MAX_PROXY_TIMEOUT_SECS,
+            ),
+            free_tier_limit: DEFAULT_FREE_TIER_LIMIT,
+            pro_tier_limit: DEFAULT_PRO_TIER_LIMIT,
+            enterprise_tier_limit: DEFAULT_ENTERPRISE_TIER_LIMIT,
+            free_reservation_ttl: Duration::from_secs(DEFAULT_FREE_RESERVATION_SECS),
+            pro_reservation_ttl: Duration::from_secs(DEFAULT_PRO_RESERVATION_SECS),
+            enterprise_reservation_ttl: Duration::from_secs(DEFAULT_ENTERPRISE_RESERVATION_SECS),
+            free_reserved_subdomains: DEFAULT_FREE_RESERVED_SUBDOMAINS,
+            pro_reserved_subdomains: DEFAULT_PRO_RESERVED_SUBDOMAINS,
+            enterprise_reserved_subdomains: DEFAULT_ENTERPRISE_RESERVED_SUBDOMAINS,
+            subdomain_blocklist: DEFAULT_SUBDOMAIN_BLOCKLIST
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            free_tier_max_lifetime: match DEFAULT_FREE_MAX_LIFETIME_SECS {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            abuse_ban_threshold: DEFAULT_ABUSE_BAN_THRESHOLD,
+            abuse_window: Duration::from_secs(DEFAULT_ABUSE_WINDOW_SECS),
+            abuse_ban_duration: Duration::from_secs(DEFAULT_ABUSE_BAN_SECS),
+            abuse_max_ban_duration: Duration::from_secs(DEFAULT_ABUSE_MAX_BAN_SECS),
+            abuse_allowlist: Vec::new(),
+            trusted_proxies: Vec::new(),
+            proxy_protocol: Vec::new(),
+            admin_emails: Vec::new(),
+            public_scheme: DEFAULT_PUBLIC_SCHEME.to_string(),
+            oidc_issuer_url: None,
+            oidc_client_id: None,
+            oidc_client_secret: None,
+            edge_session_ttl: Duration::from_secs(DEFAULT_EDGE_SESSION_SECS),
+            error_pages_dir: None,
+            edge_tls_cert: None,
+            edge_tls_key: None,
+            udp_bind_host: DEFAULT_UDP_BIND_HOST.to_string(),
+            udp_port_min: DEFAULT_UDP_PORT_MIN,
+            udp_port_max: DEFAULT_UDP_PORT_MAX,
+            udp_peer_idle: Duration::from_secs(DEFAULT_UDP_PEER_IDLE_SECS),
+            free_rate_limit: DEFAULT_FREE_RATE_LIMIT,
+            pro_rate_limit: DEFAULT_PRO_RATE_LIMIT,
+            enterprise_rate_limit: DEFAULT_ENTERPRISE_RATE_LIMIT,
+            api_rate_limit: DEFAULT_API_RATE_LIMIT,
+            login_rate_limit: DEFAULT_LOGIN_RATE_LIMIT,
+            register_rate_limit: DEFAULT_REGISTER_RATE_LIMIT,
+            api_rate_limit_max_entries: DEFAULT_API_RATE_LIMIT_MAX_ENTRIES,
+            free_body_limits: DEFAULT_FREE_BODY_LIMITS,
+            pro_body_limits: DEFAULT_PRO_BODY_LIMITS,
+            enterprise_body_limits: DEFAULT_ENTERPRISE_BODY_LIMITS,
+            min_ssh_port: MIN_ALLOWED_SSH_PORT,
+        }
+    }
+
     /// Validate configuration values
     fn validate(&self) -> Result<(), String> {
         // Validate domain format (basic DNS check)
@@ -123,6 +566,9 @@ impl NeedleConfig {
         if self.ssh_addr.parse::<std::net::SocketAddr>().is_err() {
             return Err(format!("invalid SSH address: {}", self.ssh_addr));
         }
+        if self.edge_addr.parse::<std::net::SocketAddr>().is_err() {
+            return Err(format!("invalid edge address: {}", self.edge_addr));
+        }
 
         // Validate positive limits
         if self.max_tunnels_per_ip == 0 {
@@ -153,6 +599,117 @@ impl NeedleConfig {
                 self.http_read_timeout.as_secs()
             );
         }
+        for (name, timeout) in [
+            ("http_write_timeout", self.http_write_timeout),
+            ("proxy_connect_timeout", self.proxy_connect_timeout),
+            ("proxy_header_timeout", self.proxy_header_timeout),
+            ("proxy_total_timeout", self.proxy_total_timeout),
+            ("free_max_proxy_timeout", self.free_max_proxy_timeout),
+            ("pro_max_proxy_timeout", self.pro_max_proxy_timeout),
+            (
+                "enterprise_max_proxy_timeout",
+                self.enterprise_max_proxy_timeout,
+            ),
+        ] {
+            if timeout.as_secs() == 0 {
+                return Err(format!("{name} must be > 0"));
+            }
+        }
+
+        // Validate abuse settings
+        if self.abuse_ban_threshold == 0 {
+            return Err("abuse_ban_threshold must be > 0".to_string());
+        }
+        if self.abuse_max_ban_duration < self.abuse_ban_duration {
+            return Err("abuse_max_ban_duration must be >= abuse_ban_duration".to_string());
+        }
+        if let Some(bad) = self
+            .abuse_allowlist
+            .iter()
+            .find(|ip| ip.parse::<std::net::IpAddr>().is_err())
+        {
+            return Err(format!("invalid IP in abuse allowlist: {bad}"));
+        }
+
+        // Validate proxy settings
+        if let Some(bad) = self
+            .trusted_proxies
+            .iter()
+            .find(|cidr| cidr.parse::<needle_common::cidr::Cidr>().is_err())
+        {
+            return Err(format!("invalid CIDR in trusted proxies: {bad}"));
+        }
+        if let Some(bad) = self
+            .proxy_protocol
+            .iter()
+            .find(|name| !PROXY_PROTOCOL_LISTENERS.contains(&name.as_str()))
+        {
+            return Err(format!(
+                "unknown listener in proxy_protocol: {bad} (expected api, ssh or edge)"
+            ));
+        }
+        if !self.proxy_protocol.is_empty() && self.trusted_proxies.is_empty() {
+            return Err("proxy_protocol needs trusted_proxies to say who may send it".to_string());
+        }
+
+        // Validate edge login settings
+        if self.public_scheme != "http" && self.public_scheme != "https" {
+            return Err(format!(
+                "public_scheme must be http or https, got {}",
+                self.public_scheme
+            ));
+        }
+        if self.edge_tls_cert.is_some() != self.edge_tls_key.is_some() {
+            return Err("edge_tls_cert and edge_tls_key must be set together".to_string());
+        }
+
+        // Validate UDP tunnel ports
+        if self.udp_port_min < 1024 || self.udp_port_min > self.udp_port_max {
+            return Err(format!(
+                "udp ports must be a range within 1024-65535, got {}-{}",
+                self.udp_port_min, self.udp_port_max
+            ));
+        }
+        if self.udp_peer_idle.as_secs() == 0 {
+            return Err("udp_peer_idle must be > 0".to_string());
+        }
+        if self.oidc_issuer_url.is_some() != self.oidc_client_id.is_some() {
+            return Err("oidc_issuer_url and oidc_client_id must be set together".to_string());
+        }
+        if self.edge_session_ttl.as_secs() == 0 {
+            return Err("edge_session_ttl must be > 0".to_string());
+        }
+
+        // Validate rate limits
+        for (name, limit) in [
+            ("free_rate_limit", self.free_rate_limit),
+            ("pro_rate_limit", self.pro_rate_limit),
+            ("enterprise_rate_limit", self.enterprise_rate_limit),
+            ("api_rate_limit", self.api_rate_limit),
+            ("login_rate_limit", self.login_rate_limit),
+            ("register_rate_limit", self.register_rate_limit),
+        ] {
+            if !limit.is_valid() {
+                return Err(format!(
+                    "{name} needs requests_per_second > 0 and burst >= 1, got {} and {}",
+                    limit.requests_per_second, limit.burst
+                ));
+            }
+        }
+
+        if self.api_rate_limit_max_entries == 0 {
+            return Err("api_rate_limit_max_entries must be > 0".to_string());
+        }
+
+        for (name, limits) in [
+            ("free_body_limits", self.free_body_limits),
+            ("pro_body_limits", self.pro_body_limits),
+            ("enterprise_body_limits", self.enterprise_body_limits),
+        ] {
+            if !limits.is_valid() {
+                return Err(format!("{name} must all be > 0"));
+            }
+        }
 
         // Validate SSH port restrictions
         if self.min_ssh_port < 1024 {
@@ -174,6 +731,90 @@ impl NeedleConfig {
             _ => self.free_tier_limit, // Default to free tier for unknown tiers
         }
     }
+
+    /// How long a persistent tunnel's subdomain stays reserved for its
+    /// owner after the SSH client disconnects.
+    pub fn reservation_ttl(&self, tier: &str) -> Duration {
+        match tier {
+            "pro" => self.pro_reservation_ttl,
+            "enterprise" => self.enterprise_reservation_ttl,
+            _ => self.free_reservation_ttl,
+        }
+    }
+
+    /// How many subdomains a user on the given tier may hold in
+    /// `reserved_subdomains` at once.
+    pub fn reserved_subdomain_limit(&self, tier: &str) -> usize {
+        match tier {
+            "pro" => self.pro_reserved_subdomains,
+            "enterprise" => self.enterprise_reserved_subdomains,
+            _ => self.free_reserved_subdomains,
+        }
+    }
+
+    /// How long a new tunnel lives given the TTL its owner asked for.
+    /// Free-tier tunnels are capped at `free_tier_max_lifetime` and get it
+    /// by default; paid tiers live as long as they asked, or forever.
+    pub fn tunnel_lifetime(&self, tier: &str, requested: Option<Duration>) -> Option<Duration> {
+        match (tier, self.free_tier_max_lifetime) {
+            ("pro" | "enterprise", _) | (_, None) => requested,
+            (_, Some(max)) => Some(requested.map_or(max, |ttl| ttl.min(max))),
+        }
+    }
+
+    /// The default request rate for tunnels owned by a user on the given
+    /// tier. Per-tunnel overrides can only lower it.
+    pub fn tunnel_rate_limit(&self, tier: &str) -> RateLimitSettings {
+        match tier {
+            "pro" => self.pro_rate_limit,
+            "enterprise" => self.enterprise_rate_limit,
+            _ => self.free_rate_limit,
+        }
+    }
+
+    /// The timeouts tunnels get unless they ask for others.
+    pub fn proxy_timeouts(&self) -> ProxyTimeouts {
+        ProxyTimeouts {
+            connect_secs: self.proxy_connect_timeout.as_secs(),
+            header_secs: self.proxy_header_timeout.as_secs(),
+            idle_secs: self.http_write_timeout.as_secs(),
+            total_secs: self.proxy_total_timeout.as_secs(),
+        }
+    }
+
+    /// The longest any proxy timeout can be for a tunnel owned by a user
+    /// on the given tier, defaults included.
+    pub fn tunnel_max_proxy_timeout(&self, tier: &str) -> Duration {
+        match tier {
+            "pro" => self.pro_max_proxy_timeout,
+            "enterprise" => self.enterprise_max_proxy_timeout,
+            _ => self.free_max_proxy_timeout,
+        }
+    }
+
+    /// The largest request, response and WebSocket session a tunnel
+    /// owned by a user on the given tier may carry. Per-tunnel settings
+    /// can only lower them.
+    pub fn tunnel_body_limits(&self, tier: &str) -> BodyLimits {
+        match tier {
+            "pro" => self.pro_body_limits,
+            "enterprise" => self.enterprise_body_limits,
+            _ => self.free_body_limits,
+        }
+    }
+
+    /// Whether connections to the named listener ("api", "ssh" or
+    /// "edge") start with a PROXY protocol header.
+    pub fn proxy_protocol_on(&self, listener: &str) -> bool {
+        self.proxy_protocol.iter().any(|name| name == listener)
+    }
+
+    /// Whether the operator has blocked this name from being claimed.
+    pub fn is_blocked_subdomain(&self, name: &str) -> bool {
+        self.subdomain_blocklist
+            .iter()
+            .any(|blocked| blocked == name)
+    }
 }
 
 fn required(key: &str) -> String {
@@ -182,6 +823,11 @@ fn required(key: &str) -> String {
     })
 }
 
+/// Reads a variable that may be left out. Set-but-empty counts as unset.
+fn optional(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|v| !v.trim().is_empty())
+}
+
 fn parse_usize_env(key: &str, default: usize) -> usize {
     env::var(key)
         .ok()
@@ -189,6 +835,13 @@ fn parse_usize_env(key: &str, default: usize) -> usize {
         .unwrap_or(default)
 }
 
+fn parse_u32_env(key: &str, default: u32) -> u32 {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
 fn parse_u64_env(key: &str, default: u64) -> u64 {
     env::var(key)
         .ok()
@@ -196,6 +849,58 @@ fn parse_u64_env(key: &str, default: u64) -> u64 {
         .unwrap_or(default)
 }
 
+/// Reads a comma-separated list, lowercased and trimmed. An unset
+/// variable falls back to `default`; an empty one means an empty list.
+fn parse_list_env(key: &str, default: &[&str]) -> Vec<String> {
+    match env::var(key) {
+        Ok(v) => parse_list(&v),
+        Err(_) => default.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim().to_ascii_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn parse_f64_env(key: &str, default: f64) -> f64 {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Reads `<prefix>_RPS` and `<prefix>_BURST`, each falling back to the
+/// matching default on its own.
+fn parse_rate_limit_env(prefix: &str, default: RateLimitSettings) -> RateLimitSettings {
+    RateLimitSettings::new(
+        parse_f64_env(&format!("{prefix}_RPS"), default.requests_per_second),
+        parse_f64_env(&format!("{prefix}_BURST"), default.burst),
+    )
+}
+
+/// Reads `<prefix>_MAX_REQUEST_BYTES`, `<prefix>_MAX_RESPONSE_BYTES` and
+/// `<prefix>_MAX_WEBSOCKET_BYTES`, each falling back on its own.
+fn parse_body_limits_env(prefix: &str, default: BodyLimits) -> BodyLimits {
+    BodyLimits::new(
+        parse_u64_env(
+            &format!("{prefix}_MAX_REQUEST_BYTES"),
+            default.max_request_bytes,
+        ),
+        parse_u64_env(
+            &format!("{prefix}_MAX_RESPONSE_BYTES"),
+            default.max_response_bytes,
+        ),
+        parse_u64_env(
+            &format!("{prefix}_MAX_WEBSOCKET_BYTES"),
+            default.max_websocket_bytes,
+        ),
+    )
+}
+
 fn parse_u16_env(key: &str, default: u16) -> u16 {
     env::var(key)
         .ok()
@@ -211,17 +916,113 @@ mod tests {
     fn defaults_are_sane() {
         assert_eq!(DEFAULT_API_ADDR, "0.0.0.0:3000");
         assert_eq!(DEFAULT_SSH_ADDR, "0.0.0.0:2222");
+        assert_eq!(DEFAULT_EDGE_ADDR, "0.0.0.0:8080");
         assert_eq!(DEFAULT_MAX_TUNNELS_PER_IP, 5);
         assert_eq!(DEFAULT_GLOBAL_TUNNEL_LIMIT, 1000);
         assert_eq!(DEFAULT_HTTP_TIMEOUT_SECS, 10);
         assert_eq!(DEFAULT_FREE_TIER_LIMIT, 3);
         assert_eq!(DEFAULT_PRO_TIER_LIMIT, 50);
         assert_eq!(MIN_ALLOWED_SSH_PORT, 1024);
+        assert!(
+            NeedleConfig::for_tests("http://127.0.0.1")
+                .validate()
+                .is_ok()
+        );
     }
 
     #[test]
     fn tier_limits_are_hierarchical() {
-        assert!(DEFAULT_PRO_TIER_LIMIT > DEFAULT_FREE_TIER_LIMIT);
-        assert!(DEFAULT_ENTERPRISE_TIER_LIMIT > DEFAULT_PRO_TIER_LIMIT);
+        const { assert!(DEFAULT_PRO_TIER_LIMIT > DEFAULT_FREE_TIER_LIMIT) };
+        const { assert!(DEFAULT_ENTERPRISE_TIER_LIMIT > DEFAULT_PRO_TIER_LIMIT) };
+        const { assert!(DEFAULT_PRO_RESERVATION_SECS > DEFAULT_FREE_RESERVATION_SECS) };
+        const { assert!(DEFAULT_ENTERPRISE_RESERVATION_SECS > DEFAULT_PRO_RESERVATION_SECS) };
+        const { assert!(DEFAULT_PRO_RESERVED_SUBDOMAINS > DEFAULT_FREE_RESERVED_SUBDOMAINS) };
+        const { assert!(DEFAULT_ENTERPRISE_RESERVED_SUBDOMAINS > DEFAULT_PRO_RESERVED_SUBDOMAINS) };
+        const { assert!(DEFAULT_PRO_MAX_PROXY_TIMEOUT_SECS > DEFAULT_FREE_MAX_PROXY_TIMEOUT_SECS) };
+        const { assert!(DEFAULT_ENTERPRISE_MAX_PROXY_TIMEOUT_SECS > DEFAULT_PRO_MAX_PROXY_TIMEOUT_SECS) };
+        // Free tunnels get the defaults as they are
+        const { assert!(DEFAULT_PROXY_TOTAL_TIMEOUT_SECS <= DEFAULT_FREE_MAX_PROXY_TIMEOUT_SECS) };
+    }
+
+    #[test]
+    fn rate_limit_overrides_only_lower_the_ceiling() {
+        for limit in [
+            DEFAULT_FREE_RATE_LIMIT,
+            DEFAULT_PRO_RATE_LIMIT,
+            DEFAULT_ENTERPRISE_RATE_LIMIT,
+            DEFAULT_API_RATE_LIMIT,
+            DEFAULT_LOGIN_RATE_LIMIT,
+            DEFAULT_REGISTER_RATE_LIMIT,
+        ] {
+            assert!(limit.is_valid());
+        }
+        assert!(!RateLimitSettings::new(0.0, 10.0).is_valid());
+        assert!(!RateLimitSettings::new(5.0, 0.5).is_valid());
+        assert!(!RateLimitSettings::new(f64::NAN, 10.0).is_valid());
+
+        let capped = RateLimitSettings::new(500.0, 5.0).min(DEFAULT_FREE_RATE_LIMIT);
+        assert_eq!(capped, RateLimitSettings::new(10.0, 5.0));
+    }
+
+    #[test]
+    fn size_limits_only_lower_the_tier_ceiling() {
+        for limits in [
+            DEFAULT_FREE_BODY_LIMITS,
+            DEFAULT_PRO_BODY_LIMITS,
+            DEFAULT_ENTERPRISE_BODY_LIMITS,
+        ] {
+            assert!(limits.is_valid());
+        }
+
+        let requested = crate::tunnel::settings::SizeLimits {
+            max_request_bytes: Some(MIB),
+            max_response_bytes: Some(1024 * MIB),
+            max_websocket_bytes: None,
+        };
+        assert_eq!(
+            requested.within(DEFAULT_FREE_BODY_LIMITS),
+            BodyLimits::new(MIB, 50 * MIB, 100 * MIB)
+        );
+    }
+
+    #[test]
+    fn proxy_timeouts_can_rise_to_the_tier_ceiling() {
+        let defaults = ProxyTimeouts {
+            connect_secs: 5,
+            header_secs: 30,
+            idle_secs: 10,
+            total_secs: 60,
+        };
+        let requested = crate::tunnel::settings::TimeoutSettings {
+            header_secs: Some(120),
+            total_secs: Some(7200),
+            ..Default::default()
+        };
+        let timeouts = requested.within(defaults, DEFAULT_PRO_MAX_PROXY_TIMEOUT_SECS);
+        assert_eq!(timeouts.connect_secs, 5);
+        assert_eq!(timeouts.header_secs, 120);
+        assert_eq!(timeouts.total_secs, DEFAULT_PRO_MAX_PROXY_TIMEOUT_SECS);
+
+        // The server's own defaults are held to the ceiling too
+        let generous = ProxyTimeouts {
+            total_secs: 600,
+            ..defaults
+        };
+        let timeouts = crate::tunnel::settings::TimeoutSettings::default()
+            .within(generous, DEFAULT_FREE_MAX_PROXY_TIMEOUT_SECS);
+        assert_eq!(timeouts.total_secs, DEFAULT_FREE_MAX_PROXY_TIMEOUT_SECS);
+    }
+
+    #[test]
+    fn default_blocklist_covers_common_names() {
+        for name in ["admin", "www", "api", "mail"] {
+            assert!(DEFAULT_SUBDOMAIN_BLOCKLIST.contains(&name));
+        }
+    }
+
+    #[test]
+    fn parses_comma_separated_lists() {
+        assert_eq!(parse_list(" Admin, www ,,api"), vec!["admin", "www", "api"]);
+        assert!(parse_list("").is_empty());
     }
 }
@@ -1,7 +1,9 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
+pub mod abuse;
 pub mod config;
+pub mod listener;
 pub mod metrics;
 pub mod proxy;
 pub mod ssh;
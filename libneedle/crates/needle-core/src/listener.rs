@@ -0,0 +1,300 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::config::NeedleConfig;
+use hyper::HeaderMap;
+use needle_common::cidr::Cidr;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info};
+
+// A load balancer sends the header straight away; anything slower is
+// either misconfigured or trying to hold the socket open
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// Longest possible v1 line, CRLF included
+const V1_MAX_LEN: usize = 107;
+const ACCEPT_BACKLOG: usize = 128;
+
+/// The load balancers and reverse proxies we take a client's address
+/// from. Empty unless the operator sets `TRUSTED_PROXIES`, in which case
+/// only these may send PROXY protocol headers or `X-Forwarded-For`.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    cidrs: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    pub fn new(config: &NeedleConfig) -> Self {
+        Self {
+            cidrs: config
+                .trusted_proxies
+                .iter()
+                .map(|s| s.parse().expect("trusted proxies are validated at startup"))
+                .collect(),
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// The address a request really came from. If the connection is from
+    /// a trusted proxy we walk `X-Forwarded-For` from the right, skipping
+    /// our own proxies, and stop at the first hop we don't trust -- any
+    /// hops left of that were written by the client and prove nothing.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+
+        let mut client = peer;
+        let hops = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect::<Vec<_>>();
+        for hop in hops.into_iter().rev() {
+            let Some(ip) = parse_hop(hop.trim()) else {
+                break;
+            };
+            client = ip;
+            if !self.contains(ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// Proxies write hops as bare addresses, but ports and IPv6 brackets
+/// turn up too.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| hop.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+/// A TCP listener that hands out connections along with the client's
+/// real address.
+///
+/// With the PROXY protocol on, each connection from a trusted proxy
+/// starts with a v1 or v2 header naming the client, which we read and
+/// strip before anyone else sees the stream. Headers are read on their
+/// own task so one slow proxy connection doesn't hold up accepting the
+/// rest. Connections from anywhere else are dropped, since they can't
+/// be telling us the truth about who they are.
+pub struct ClientListener {
+    incoming: mpsc::Receiver<(TcpStream, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl ClientListener {
+    /// Binds `addr`. `name` labels log lines, e.g. "edge".
+    pub async fn bind(
+        addr: &str,
+        name: &'static str,
+        proxy_protocol: bool,
+        trusted: TrustedProxies,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (tx, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        if proxy_protocol {
+            info!(listener = name, "expecting PROXY protocol headers");
+        }
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        error!(listener = name, error = %e, "failed to accept connection");
+                        continue;
+                    }
+                };
+
+                if !proxy_protocol {
+                    if tx.send((stream, peer)).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+
+                if !trusted.contains(peer.ip()) {
+                    debug!(listener = name, peer = %peer, "dropped connection from untrusted proxy");
+                    continue;
+                }
+
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut stream = stream;
+                    match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+                        Ok(Ok(client)) => {
+                            let _ = tx.send((stream, client.unwrap_or(peer))).await;
+                        }
+                        Ok(Err(e)) => {
+                            debug!(listener = name, peer = %peer, error = %e, "bad PROXY protocol header");
+                        }
+                        Err(_) => {
+                            debug!(listener = name, peer = %peer, "timed out waiting for PROXY protocol header");
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            incoming,
+            local_addr,
+        })
+    }
+
+    /// Waits for the next connection and the address of the client
+    /// behind it.
+    pub async fn accept(&mut self) -> (TcpStream, SocketAddr) {
+        self.incoming
+            .recv()
+            .await
+            .expect("accept loop runs as long as the listener")
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+/// Reads a PROXY protocol v1 or v2 header off the front of a stream.
+/// Returns the client address it names, or None for connections the
+/// proxy made on its own behalf (v1 `UNKNOWN`, v2 `LOCAL`, or an address
+/// family we don't handle), which should be taken at face value.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // Twelve bytes is the v2 signature, and shorter than any v1 line
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        return read_v2(stream).await;
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("missing PROXY protocol header"));
+    }
+
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(&line[..line.len() - 2])
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad PROXY v1 address"))?;
+            let port: u16 = sport.parse().map_err(|_| invalid("bad PROXY v1 port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    let [version_command, family, len_hi, len_lo] = head;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    // The address block is followed by optional TLVs we don't use, but
+    // they still have to come off the stream
+    let mut body = vec![0u8; u16::from_be_bytes([len_hi, len_lo]) as usize];
+    stream.read_exact(&mut body).await?;
+
+    match version_command & 0x0f {
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+
+    let addr = match family >> 4 {
+        0x1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([body[8], body[9]]))
+        }
+        0x2 if body.len() >= 36 => {
+            let octets: [u8; 16] = body[..16].try_into().expect("sixteen bytes");
+            let ip = Ipv6Addr::from(octets);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([body[32], body[33]]))
+        }
+        0x1 | 0x2 => return Err(invalid("truncated PROXY v2 address")),
+        _ => return Ok(None),
+    };
+    Ok(Some(addr))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[tokio::test]
+    async fn reads_v1_and_v2_headers_and_leaves_the_payload() {
+        let mut stream: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\nGET /";
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.7:56324".parse().unwrap()));
+        assert_eq!(stream, b"GET /");
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        v2.extend_from_slice(&[198, 51, 100, 9, 10, 0, 0, 1, 0x1f, 0x90, 0x01, 0xbb]);
+        v2.extend_from_slice(b"SSH-2.0");
+        let mut stream = v2.as_slice();
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("198.51.100.9:8080".parse().unwrap()));
+        assert_eq!(stream, b"SSH-2.0");
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+    }
+
+    #[test]
+    fn forwarded_for_is_only_believed_from_trusted_proxies() {
+        let trusted = TrustedProxies {
+            cidrs: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.1.1.1, 203.0.113.7, 10.0.0.2"),
+        );
+
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let stranger: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(
+            trusted.client_ip(proxy, &headers),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(trusted.client_ip(stranger, &headers), stranger);
+        assert_eq!(trusted.client_ip(proxy, &HeaderMap::new()), proxy);
+    }
+}
@@ -59,9 +59,113 @@ lazy_static! {
     pub static ref RATE_LIMIT_HITS: CounterVec = register_counter_vec!(
         "needle_rate_limit_hits_total",
         "Total number of rate limit hits",
-        &["limit_type"]
+        &["limit_type", "tier"]
     )
     .expect("failed to register needle_rate_limit_hits_total metric");
+
+    /// Counter tracking IP bans, automatic or manual
+    pub static ref IP_BANS: CounterVec = register_counter_vec!(
+        "needle_ip_bans_total",
+        "Total number of IP bans issued",
+        &["reason"]
+    )
+    .expect("failed to register needle_ip_bans_total metric");
+
+    /// Counter tracking connections turned away because their IP is banned
+    pub static ref BLOCKED_CONNECTIONS: CounterVec = register_counter_vec!(
+        "needle_blocked_connections_total",
+        "Total number of connections rejected from banned IPs",
+        &["listener"]
+    )
+    .expect("failed to register needle_blocked_connections_total metric");
+
+    /// Counter tracking tunnel responses the edge compressed
+    pub static ref COMPRESSED_RESPONSES: CounterVec = register_counter_vec!(
+        "needle_compressed_responses_total",
+        "Total number of tunnel responses compressed at the edge",
+        &["encoding"]
+    )
+    .expect("failed to register needle_compressed_responses_total metric");
+
+    /// Counter tracking bytes edge compression kept off the wire
+    pub static ref COMPRESSION_BYTES_SAVED: CounterVec = register_counter_vec!(
+        "needle_compression_bytes_saved_total",
+        "Total bytes saved by compressing tunnel responses at the edge",
+        &["encoding"]
+    )
+    .expect("failed to register needle_compression_bytes_saved_total metric");
+
+    /// Counter tracking requests and responses cut off for being too big
+    pub static ref BODY_LIMIT_HITS: CounterVec = register_counter_vec!(
+        "needle_body_limit_hits_total",
+        "Total number of request or response bodies over a tunnel's size limit",
+        &["direction", "tier"]
+    )
+    .expect("failed to register needle_body_limit_hits_total metric");
+
+    /// Counter tracking requests the edge gave up on, by which timeout fired
+    pub static ref PROXY_TIMEOUTS: CounterVec = register_counter_vec!(
+        "needle_proxy_timeouts_total",
+        "Total number of tunnel requests that timed out at the edge",
+        &["kind", "tier"]
+    )
+    .expect("failed to register needle_proxy_timeouts_total metric");
+
+    /// Counter tracking datagrams relayed through UDP tunnels
+    pub static ref UDP_DATAGRAMS: CounterVec = register_counter_vec!(
+        "needle_udp_datagrams_total",
+        "Total number of datagrams relayed through UDP tunnels",
+        &["direction"]
+    )
+    .expect("failed to register needle_udp_datagrams_total metric");
+
+    /// Counter tracking TLS connections spliced through to tunnel apps
+    pub static ref TLS_PASSTHROUGH_CONNECTIONS: CounterVec = register_counter_vec!(
+        "needle_tls_passthrough_connections_total",
+        "Total number of TLS connections passed through to tunnels",
+        &["tier"]
+    )
+    .expect("failed to register needle_tls_passthrough_connections_total metric");
+
+    /// Counter tracking SSH local forwards into private tunnels
+    pub static ref PRIVATE_CONNECTIONS: CounterVec = register_counter_vec!(
+        "needle_private_tunnel_connections_total",
+        "Total number of connections to private tunnels over SSH",
+        &["tier"]
+    )
+    .expect("failed to register needle_private_tunnel_connections_total metric");
+
+    /// Counter tracking load-balanced backends taken out of rotation
+    pub static ref BACKEND_FAILURES: CounterVec = register_counter_vec!(
+        "needle_tunnel_backend_failures_total",
+        "Total number of times a tunnel backend was taken out of rotation after failing",
+        &["tier"]
+    )
+    .expect("failed to register needle_tunnel_backend_failures_total metric");
+
+    /// Counter tracking health checks sent to tunnel apps
+    pub static ref HEALTH_CHECKS: CounterVec = register_counter_vec!(
+        "needle_health_checks_total",
+        "Total number of health checks sent to tunnel apps",
+        &["result"]
+    )
+    .expect("failed to register needle_health_checks_total metric");
+
+    /// Counter tracking tunnels changing health status
+    pub static ref HEALTH_TRANSITIONS: CounterVec = register_counter_vec!(
+        "needle_tunnel_health_transitions_total",
+        "Total number of times a tunnel's health status changed",
+        &["status"]
+    )
+    .expect("failed to register needle_tunnel_health_transitions_total metric");
+
+    /// Counter tracking requests copied to mirror tunnels
+    pub static ref MIRRORED_REQUESTS: CounterVec = register_counter_vec!(
+        "needle_mirrored_requests_total",
+        "Total number of requests copied to mirror tunnels, by how the mirror's answer compared",
+        &["outcome"]
+    )
+    .expect("failed to register needle_mirrored_requests_total metric");
 }
 
 /// Increment tunnel creation counter
@@ -93,7 +197,74 @@ pub fn error_occurred(error_type: &str) {
     ERRORS.with_label_values(&[error_type]).inc();
 }
 
-/// Increment rate limit hit counter
-pub fn rate_limit_hit(limit_type: &str) {
-    RATE_LIMIT_HITS.with_label_values(&[limit_type]).inc();
+/// Increment rate limit hit counter. `tier` is the tier whose limit was
+/// hit, or "anonymous" when we couldn't tell who the client was.
+pub fn rate_limit_hit(limit_type: &str, tier: &str) {
+    RATE_LIMIT_HITS.with_label_values(&[limit_type, tier]).inc();
+}
+
+/// Increment IP ban counter
+pub fn ip_banned(reason: &str) {
+    IP_BANS.with_label_values(&[reason]).inc();
+}
+
+/// Increment blocked connection counter
+pub fn connection_blocked(listener: &str) {
+    BLOCKED_CONNECTIONS.with_label_values(&[listener]).inc();
+}
+
+/// Increment body size limit counter
+pub fn body_limit_hit(direction: &str, tier: &str) {
+    BODY_LIMIT_HITS.with_label_values(&[direction, tier]).inc();
+}
+
+/// Increment proxy timeout counter
+pub fn proxy_timeout(kind: &str, tier: &str) {
+    PROXY_TIMEOUTS.with_label_values(&[kind, tier]).inc();
+}
+
+/// Increment UDP datagram counter. `direction` is "inbound" for
+/// datagrams from the internet and "outbound" for the app's replies.
+pub fn udp_datagram(direction: &str) {
+    UDP_DATAGRAMS.with_label_values(&[direction]).inc();
+}
+
+/// Increment TLS passthrough connection counter
+pub fn tls_passthrough(tier: &str) {
+    TLS_PASSTHROUGH_CONNECTIONS.with_label_values(&[tier]).inc();
+}
+
+/// Increment private tunnel connection counter
+pub fn private_connection(tier: &str) {
+    PRIVATE_CONNECTIONS.with_label_values(&[tier]).inc();
+}
+
+/// Increment backend failure counter
+pub fn backend_failed(tier: &str) {
+    BACKEND_FAILURES.with_label_values(&[tier]).inc();
+}
+
+/// Increment health check counter. `result` is "pass" or "fail".
+pub fn health_check(result: &str) {
+    HEALTH_CHECKS.with_label_values(&[result]).inc();
+}
+
+/// Increment health transition counter. `status` is the new status.
+pub fn health_transition(status: &str) {
+    HEALTH_TRANSITIONS.with_label_values(&[status]).inc();
+}
+
+/// Increment mirrored request counter. `outcome` is "match" when the
+/// mirror answered with the same status, "mismatch" when it didn't, and
+/// "error" when it didn't answer at all.
+pub fn mirrored_request(outcome: &str) {
+    MIRRORED_REQUESTS.with_label_values(&[outcome]).inc();
+}
+
+/// Record a response compressed at the edge
+pub fn response_compressed(encoding: &str, original: usize, compressed: usize) {
+    COMPRESSED_RESPONSES.with_label_values(&[encoding]).inc();
+    COMPRESSION_BYTES_SAVED
+        .with_label_values(&[encoding])
+        .inc_by(original.saturating_sub(compressed) as f64);
 }
@@ -0,0 +1,358 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::metrics;
+use crate::proxy::http::{ProxyError, ResponseBody};
+use bytes::Bytes;
+use hyper::body::Body;
+use hyper::header::{self, HeaderValue};
+use hyper::{HeaderMap, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use tracing::debug;
+
+const DEFAULT_MIN_SIZE: usize = 1024;
+const MAX_CONTENT_TYPES: usize = 32;
+const DEFAULT_CONTENT_TYPES: &[&str] = &[
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+/// Whether and what the edge compresses for a tunnel. Compression only
+/// happens between the edge and the visitor, for responses the app sent
+/// uncompressed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionSettings {
+    pub enabled: bool,
+    /// Bodies smaller than this many bytes go out as they are; the
+    /// headers would eat most of the saving.
+    pub min_size: usize,
+    /// Media types to compress, without parameters. `text/*` covers a
+    /// whole top-level type.
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_size: DEFAULT_MIN_SIZE,
+            content_types: DEFAULT_CONTENT_TYPES
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl CompressionSettings {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Checks settings that came from a client. Returns a message fit to
+    /// show them if something's wrong.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.content_types.len() > MAX_CONTENT_TYPES {
+            return Err(format!(
+                "compression content_types can have at most {MAX_CONTENT_TYPES} entries"
+            ));
+        }
+        for media_type in &self.content_types {
+            let valid = media_type
+                .split_once('/')
+                .is_some_and(|(kind, sub)| is_token(kind) && (sub == "*" || is_token(sub)));
+            if !valid {
+                return Err(format!(
+                    "compression content types look like text/html or text/*, got {media_type}"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn wants(&self, content_type: &str) -> bool {
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let Some((kind, _)) = media_type.split_once('/') else {
+            return false;
+        };
+
+        self.content_types.iter().any(|allowed| {
+            let allowed = allowed.to_ascii_lowercase();
+            match allowed.strip_suffix("/*") {
+                Some(allowed_kind) => allowed_kind == kind,
+                None => allowed == media_type,
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    // Our preference when a client likes several equally
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Picks what to use from an `Accept-Encoding` header: the highest
+    /// q-value wins, and ties go to whichever compresses best.
+    pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        let mut wildcard = None;
+        let mut weights: Vec<(&str, f32)> = Vec::new();
+        for item in accept_encoding.split(',') {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if name == "*" {
+                wildcard = Some(q);
+            } else {
+                weights.push((name, q));
+            }
+        }
+
+        let weight = |encoding: Encoding| {
+            weights
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(encoding.as_str()))
+                .map(|(_, q)| *q)
+                .or(wildcard)
+                .unwrap_or(0.0)
+        };
+
+        let mut best: Option<(Encoding, f32)> = None;
+        for encoding in Self::ALL {
+            let q = weight(encoding);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    /// Levels are picked for speed over ratio, since this runs on every
+    /// response and the body is already sitting in memory.
+    fn compress(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                    writer.write_all(body)?;
+                }
+                Ok(out)
+            }
+            Encoding::Zstd => zstd::encode_all(body, 3),
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(6));
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Compresses an app's response for the visitor if the tunnel allows it,
+/// the visitor accepts something we speak, and the body is worth it.
+/// Anything that doesn't qualify comes back untouched, still streaming.
+///
+/// Only bodies that declare their length are compressed, since that
+/// means reading them in full first. A stream with no length, like
+/// server-sent events, may not end for a long time and goes through as
+/// it arrives. Reading can fail like any app response, so that's what
+/// the error is.
+pub async fn compress_response(
+    settings: &CompressionSettings,
+    accept_encoding: Option<&HeaderValue>,
+    response: Response<ResponseBody>,
+) -> Result<Response<ResponseBody>, ProxyError> {
+    if !settings.enabled || !is_compressible(settings, response.status(), response.headers()) {
+        return Ok(response);
+    }
+    let Some(len) = response.body().size_hint().exact() else {
+        return Ok(response);
+    };
+    if len < settings.min_size as u64 {
+        return Ok(response);
+    }
+    let Some(encoding) = accept_encoding
+        .and_then(|v| v.to_str().ok())
+        .and_then(Encoding::negotiate)
+    else {
+        return Ok(response);
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let (body, trailers) = body.read().await?;
+    let unchanged = |parts, body| {
+        Ok(Response::from_parts(
+            parts,
+            ResponseBody::Read {
+                data: Some(body),
+                trailers: trailers.clone(),
+            },
+        ))
+    };
+
+    let original = body.clone();
+    let compressed = tokio::task::spawn_blocking(move || encoding.compress(&original)).await;
+    let compressed = match compressed {
+        Ok(Ok(compressed)) if compressed.len() < body.len() => compressed,
+        Ok(Ok(_)) => return unchanged(parts, body),
+        Ok(Err(e)) => {
+            debug!(encoding = encoding.as_str(), error = %e, "compression failed");
+            return unchanged(parts, body);
+        }
+        Err(e) => {
+            debug!(encoding = encoding.as_str(), error = %e, "compression task failed");
+            return unchanged(parts, body);
+        }
+    };
+
+    metrics::response_compressed(encoding.as_str(), body.len(), compressed.len());
+
+    let headers = &mut parts.headers;
+    headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    headers.remove(header::CONTENT_LENGTH);
+    headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    // The bytes changed, so a strong validator would be a lie
+    if let Some(etag) = headers.get(header::ETAG).and_then(|v| v.to_str().ok())
+        && !etag.starts_with("W/")
+        && let Ok(weak) = HeaderValue::from_str(&format!("W/{etag}"))
+    {
+        headers.insert(header::ETAG, weak);
+    }
+
+    Ok(Response::from_parts(
+        parts,
+        ResponseBody::Read {
+            data: Some(Bytes::from(compressed)),
+            trailers,
+        },
+    ))
+}
+
+fn is_compressible(
+    settings: &CompressionSettings,
+    status: StatusCode,
+    headers: &HeaderMap,
+) -> bool {
+    // Partial content has byte offsets that refer to the original body
+    if status == StatusCode::PARTIAL_CONTENT || headers.contains_key(header::CONTENT_RANGE) {
+        return false;
+    }
+    if headers.contains_key(header::CONTENT_ENCODING) {
+        return false;
+    }
+    let no_transform = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+    if no_transform {
+        return false;
+    }
+
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|content_type| settings.wants(content_type))
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Full;
+
+    #[test]
+    fn negotiates_by_weight_then_preference() {
+        assert_eq!(
+            Encoding::negotiate("gzip, deflate, br, zstd"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(Encoding::negotiate("br;q=0.5, gzip"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("*;q=0.1, br;q=0"), Some(Encoding::Zstd));
+        assert_eq!(Encoding::negotiate("identity"), None);
+        assert_eq!(Encoding::negotiate("gzip;q=0"), None);
+    }
+
+    #[tokio::test]
+    async fn compresses_only_what_qualifies() {
+        let settings = CompressionSettings::default();
+        let html = Bytes::from("<p>hello</p>".repeat(200));
+        let response = |content_type: &str, body: Bytes| {
+            Response::builder()
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::ETAG, "\"v1\"")
+                .body(ResponseBody::from(Full::new(body)))
+                .unwrap()
+        };
+        let gzip = HeaderValue::from_static("gzip");
+
+        let out = compress_response(
+            &settings,
+            Some(&gzip),
+            response("text/html; charset=utf-8", html.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(out.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(out.headers()[header::ETAG], "W/\"v1\"");
+        assert_eq!(out.headers()[header::VARY], "accept-encoding");
+
+        let out = compress_response(&settings, Some(&gzip), response("image/png", html.clone()))
+            .await
+            .unwrap();
+        assert!(!out.headers().contains_key(header::CONTENT_ENCODING));
+
+        let small = Bytes::from_static(b"<p>hi</p>");
+        let out = compress_response(&settings, Some(&gzip), response("text/html", small))
+            .await
+            .unwrap();
+        assert!(!out.headers().contains_key(header::CONTENT_ENCODING));
+
+        let off = CompressionSettings {
+            enabled: false,
+            ..CompressionSettings::default()
+        };
+        let out = compress_response(&off, Some(&gzip), response("text/html", html))
+            .await
+            .unwrap();
+        assert!(!out.headers().contains_key(header::CONTENT_ENCODING));
+    }
+}
@@ -0,0 +1,161 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use hyper::HeaderMap;
+use hyper::header::{self, HeaderValue};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs small JSON payloads so the edge can hand them to browsers and
+/// trust them when they come back.
+///
+/// Tokens look like `<payload>.<mac>`, both base64url. The key is derived
+/// from the server's JWT secret plus a purpose string, so a token minted
+/// for one job (say, a login session) is useless for another, and none
+/// of them can pass as an API JWT.
+#[derive(Clone)]
+pub struct Signer {
+    key: Vec<u8>,
+}
+
+impl Signer {
+    pub fn new(secret: &str, purpose: &str) -> Self {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("hmac takes any key");
+        mac.update(purpose.as_bytes());
+        Self {
+            key: mac.finalize().into_bytes().to_vec(),
+        }
+    }
+
+    pub fn sign<T: Serialize>(&self, payload: &T) -> String {
+        let json = serde_json::to_vec(payload).expect("payload serializes");
+        let body = URL_SAFE_NO_PAD.encode(json);
+        let mac = URL_SAFE_NO_PAD.encode(self.mac(body.as_bytes()).finalize().into_bytes());
+        format!("{body}.{mac}")
+    }
+
+    /// Returns the payload if the token is intact and was signed by us.
+    /// Expiry is up to the caller, since payloads carry it differently.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Option<T> {
+        let (body, mac) = token.split_once('.')?;
+        let mac = URL_SAFE_NO_PAD.decode(mac).ok()?;
+        self.mac(body.as_bytes()).verify_slice(&mac).ok()?;
+
+        let json = URL_SAFE_NO_PAD.decode(body).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    fn mac(&self, data: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("hmac takes any key");
+        mac.update(data);
+        mac
+    }
+}
+
+/// Finds a cookie by name across every Cookie header on the request.
+pub fn get<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Removes our own cookies before a request goes on to the developer's
+/// app. They're credentials for the edge, and the app has no use for them.
+pub fn strip(headers: &mut HeaderMap, names: &[&str]) {
+    let kept: Vec<String> = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .map(str::trim)
+        .filter(|pair| {
+            let key = pair.split_once('=').map_or(*pair, |(key, _)| key);
+            !pair.is_empty() && !names.contains(&key)
+        })
+        .map(str::to_string)
+        .collect();
+
+    headers.remove(header::COOKIE);
+    if kept.is_empty() {
+        return;
+    }
+    if let Ok(value) = HeaderValue::from_str(&kept.join("; ")) {
+        headers.insert(header::COOKIE, value);
+    }
+}
+
+/// Builds a Set-Cookie value. There's no Domain attribute, so the cookie
+/// only goes back to the exact tunnel host that set it. A zero max age
+/// deletes the cookie.
+pub fn set(name: &str, value: &str, max_age_secs: i64, secure: bool) -> HeaderValue {
+    let secure = if secure { "; Secure" } else { "" };
+    HeaderValue::from_str(&format!(
+        "{name}={value}; Path=/; Max-Age={max_age_secs}; HttpOnly; SameSite=Lax{secure}"
+    ))
+    .expect("cookie names and signed values are header-safe")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Payload {
+        email: String,
+    }
+
+    #[test]
+    fn signed_tokens_round_trip_and_reject_tampering() {
+        let signer = Signer::new("secret", "session");
+        let payload = Payload {
+            email: "alice@example.com".to_string(),
+        };
+        let token = signer.sign(&payload);
+        assert_eq!(signer.verify::<Payload>(&token), Some(payload));
+
+        let (body, mac) = token.split_once('.').unwrap();
+        let forged = URL_SAFE_NO_PAD.encode(br#"{"email":"mallory@example.com"}"#);
+        assert!(
+            signer
+                .verify::<Payload>(&format!("{forged}.{mac}"))
+                .is_none()
+        );
+        assert!(signer.verify::<Payload>(body).is_none());
+
+        // Same secret, different purpose: not interchangeable
+        let other = Signer::new("secret", "share");
+        assert!(other.verify::<Payload>(&token).is_none());
+    }
+
+    #[test]
+    fn reads_and_strips_cookies() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::COOKIE,
+            HeaderValue::from_static("a=1; needle_session=xyz"),
+        );
+        headers.append(header::COOKIE, HeaderValue::from_static("b=2"));
+
+        assert_eq!(get(&headers, "needle_session"), Some("xyz"));
+        assert_eq!(get(&headers, "b"), Some("2"));
+        assert_eq!(get(&headers, "missing"), None);
+
+        strip(&mut headers, &["needle_session"]);
+        assert_eq!(headers.get(header::COOKIE).unwrap(), "a=1; b=2");
+
+        strip(&mut headers, &["a", "b"]);
+        assert!(headers.get(header::COOKIE).is_none());
+    }
+}
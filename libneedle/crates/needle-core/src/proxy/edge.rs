@@ -0,0 +1,1068 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::abuse::AbuseTracker;
+use crate::listener::{ClientListener, TrustedProxies};
+use crate::metrics;
+use crate::proxy::compression;
+use crate::proxy::cookie;
+use crate::proxy::error_page::{EdgeError, ErrorPage, ErrorPages};
+use crate::proxy::forwarded::{self, Origin};
+use crate::proxy::http::{
+    ProxyError, ResponseBody, error_page, forward_request, open_upgrade, query_param,
+    redirect_response, set_rate_limit_headers, with_error_page,
+};
+use crate::proxy::mirror::{self, Mirror};
+use crate::proxy::oidc::{self, OidcGate};
+use crate::proxy::passthrough;
+use crate::proxy::tls;
+use crate::proxy::websocket;
+use crate::tunnel::access::AccessPolicy;
+use crate::tunnel::access::{AccessDecision, DenyReason};
+use crate::tunnel::balancer::{Backend, BackendGuard};
+use crate::tunnel::manager::{ActiveTunnel, TunnelLookup, TunnelManager};
+use crate::tunnel::private;
+use crate::tunnel::share::{self, ShareRegistry};
+use crate::tunnel::udp;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Either, Full, LengthLimitError, Limited};
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper::upgrade::OnUpgrade;
+use hyper::{Method, Request, Response, StatusCode, Uri, header};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use needle_common::error::NeedleError;
+use needle_db::client::SupabaseClient;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+// Cookies the edge sets for itself, never passed on to tunnel apps
+const EDGE_COOKIES: &[&str] = &[
+    oidc::SESSION_COOKIE,
+    oidc::LOGIN_COOKIE,
+    share::SHARE_COOKIE,
+];
+
+/// Everything a request handler needs, shared across connections.
+struct EdgeState {
+    domain: String,
+    tunnel_manager: Arc<RwLock<TunnelManager>>,
+    db: SupabaseClient,
+    oidc: OidcGate,
+    shares: Arc<ShareRegistry>,
+    secure_cookies: bool,
+    scheme: String,
+    abuse: Arc<AbuseTracker>,
+    trusted: TrustedProxies,
+    error_pages: ErrorPages,
+    /// How long a visitor gets to send their request headers.
+    read_timeout: Duration,
+}
+
+/// Runs the public HTTP edge that receives traffic for tunnel subdomains.
+///
+/// Each request is routed by its Host header: `brave-eagle-a1b2c3d4.example.com`
+/// resolves to that tunnel in the TunnelManager and gets proxied to its
+/// local listener. Persistent tunnels whose client is away get an offline
+/// page rather than a 404, so visitors can tell the difference between
+/// "gone" and "back soon". Connections from banned IPs are closed as soon
+/// as they're accepted. Behind a load balancer the client's address comes
+/// from the PROXY protocol or, for trusted proxies, `X-Forwarded-For`.
+/// Apps behind a tunnel get the usual forwarding headers and a request
+/// ID, and never see hop-by-hop headers meant for the edge. Tunnels
+/// with a CORS policy have their preflights answered here too.
+///
+/// TLS connections whose SNI names a `tls` tunnel are spliced through
+/// to the developer's app untouched, on the same port as everything
+/// else. With `EDGE_TLS_CERT` and `EDGE_TLS_KEY` set the edge terminates
+/// the rest itself and offers HTTP/2 in ALPN. Cleartext clients can speak HTTP/2
+/// too by opening with its preface (h2c with prior knowledge), which is
+/// how gRPC clients talk to a plain-text endpoint.
+/// This function blocks forever.
+pub async fn run(
+    addr: &str,
+    domain: String,
+    tunnel_manager: Arc<RwLock<TunnelManager>>,
+    abuse: Arc<AbuseTracker>,
+    shares: Arc<ShareRegistry>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (db, oidc, scheme, proxy_protocol, trusted, error_pages, read_timeout, tls_files) = {
+        let manager = tunnel_manager.read().await;
+        let config = manager.config();
+        (
+            manager.db_client().clone(),
+            OidcGate::new(config),
+            config.public_scheme.clone(),
+            config.proxy_protocol_on("edge"),
+            TrustedProxies::new(config),
+            ErrorPages::load(config.error_pages_dir.as_deref()),
+            config.http_read_timeout,
+            config
+                .edge_tls_cert
+                .clone()
+                .zip(config.edge_tls_key.clone()),
+        )
+    };
+    let tls = match tls_files {
+        Some((cert, key)) => {
+            let acceptor = tls::load_acceptor(&cert, &key)?;
+            info!(cert = %cert, "edge terminating tls");
+            Some(acceptor)
+        }
+        None => None,
+    };
+    let mut listener = ClientListener::bind(addr, "edge", proxy_protocol, trusted.clone()).await?;
+    info!(addr = %addr, "edge listening");
+
+    let state = Arc::new(EdgeState {
+        domain,
+        tunnel_manager,
+        db,
+        oidc,
+        shares,
+        secure_cookies: scheme == "https",
+        scheme,
+        abuse: abuse.clone(),
+        trusted,
+        error_pages,
+        read_timeout,
+    });
+
+    loop {
+        let (stream, peer_addr) = listener.accept().await;
+
+        if abuse.check(peer_addr.ip()).is_err() {
+            debug!(peer = %peer_addr, "dropped edge connection from banned ip");
+            metrics::connection_blocked("edge");
+            continue;
+        }
+
+        let state = state.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let mut stream = stream;
+            let first = passthrough::read_first_record(&mut stream);
+            let record = match tokio::time::timeout(state.read_timeout, first).await {
+                Ok(Ok(record)) => record,
+                Ok(Err(e)) => {
+                    debug!(peer = %peer_addr, error = %e, "edge connection closed early");
+                    return;
+                }
+                Err(_) => {
+                    debug!(peer = %peer_addr, "edge connection sent nothing");
+                    return;
+                }
+            };
+            if let Some(tunnel) = passthrough_tunnel(&state, &record).await {
+                pass_through(&tunnel, peer_addr, stream, &record).await;
+                return;
+            }
+
+            let stream = passthrough::Replay::new(record, stream);
+            let Some(acceptor) = tls else {
+                serve_connection(state, peer_addr, stream).await;
+                return;
+            };
+            match tokio::time::timeout(state.read_timeout, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => serve_connection(state, peer_addr, stream).await,
+                Ok(Err(e)) => {
+                    debug!(peer = %peer_addr, error = %e, "edge tls handshake failed");
+                }
+                Err(_) => debug!(peer = %peer_addr, "edge tls handshake timed out"),
+            }
+        });
+    }
+}
+
+/// The passthrough tunnel a connection's ClientHello names, if any.
+/// Everything else, including passthrough tunnels whose client is away,
+/// goes on to be served over HTTP.
+async fn passthrough_tunnel(state: &EdgeState, record: &[u8]) -> Option<Arc<ActiveTunnel>> {
+    let name = passthrough::server_name(record)?;
+    let subdomain = subdomain_from_host(&name, &state.domain)?;
+    match state.tunnel_manager.read().await.lookup(&subdomain) {
+        TunnelLookup::Active(tunnel) if tunnel.protocol == passthrough::PROTOCOL => Some(tunnel),
+        _ => None,
+    }
+}
+
+/// Hands a visitor's TLS connection to a passthrough tunnel as it is.
+/// The edge can't see inside, so only the tunnel's IP rules and rate
+/// limit apply; a tunnel that also wants credentials is refused rather
+/// than left open.
+async fn pass_through(
+    tunnel: &ActiveTunnel,
+    peer_addr: SocketAddr,
+    stream: tokio::net::TcpStream,
+    record: &[u8],
+) {
+    let policy = tunnel.access_policy();
+    if let Err(reason) = policy.check_ip(peer_addr.ip()) {
+        debug!(subdomain = %tunnel.subdomain, ip = %peer_addr.ip(), reason = reason.as_str(), "passthrough connection denied by access policy");
+        metrics::auth_failure("tunnel", reason.as_str());
+        return;
+    }
+    if policy.needs_credentials() {
+        debug!(subdomain = %tunnel.subdomain, "passthrough tunnel wants credentials the edge can't check");
+        metrics::auth_failure("tunnel", "passthrough_credentials");
+        return;
+    }
+    if !tunnel.rate_limiter.check().allowed {
+        metrics::rate_limit_hit("tunnel", &tunnel.tier);
+        return;
+    }
+
+    metrics::tls_passthrough(&tunnel.tier);
+    if let Err(e) = passthrough::splice(tunnel, peer_addr, stream, record).await {
+        debug!(subdomain = %tunnel.subdomain, peer = %peer_addr, error = %e, "passthrough connection failed");
+    }
+}
+
+/// Serves one visitor connection over HTTP/1.1 or HTTP/2, whichever the
+/// visitor speaks. HTTP/1.1 connections can switch to WebSocket.
+async fn serve_connection<I>(state: Arc<EdgeState>, peer_addr: SocketAddr, io: I)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let read_timeout = state.read_timeout;
+    let service = service_fn(move |req| route(state.clone(), peer_addr.ip(), req));
+
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    // Visitors who trickle their headers in don't get to hold a
+    // connection open forever
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(read_timeout);
+    builder.http2().timer(TokioTimer::new());
+
+    if let Err(e) = builder
+        .serve_connection_with_upgrades(TokioIo::new(io), service)
+        .await
+    {
+        debug!(peer = %peer_addr, error = %e, "edge connection closed with error");
+    }
+}
+
+/// Handles one request and dresses up any error along the way as a page
+/// the visitor can read. Every response carries the request's ID, so a
+/// visitor reporting a problem can be matched to the logs.
+async fn route(
+    state: Arc<EdgeState>,
+    peer_ip: IpAddr,
+    req: Request<Incoming>,
+) -> Result<Response<ResponseBody>, Infallible> {
+    let request_id = Uuid::new_v4().to_string();
+    let accept = req.headers().get(header::ACCEPT).cloned();
+
+    let mut response = dispatch(&state, peer_ip, req, &request_id).await;
+    if let Some(error) = response.extensions_mut().remove::<EdgeError>() {
+        response = state
+            .error_pages
+            .render(&error, response, accept.as_ref(), &request_id)
+            .map(ResponseBody::from);
+    }
+    if let Ok(value) = header::HeaderValue::from_str(&request_id) {
+        response
+            .headers_mut()
+            .insert(forwarded::REQUEST_ID_HEADER, value);
+    }
+    Ok(response)
+}
+
+/// Resolves the tunnel for one request and forwards it, turning every
+/// failure into an error response.
+async fn dispatch(
+    state: &Arc<EdgeState>,
+    peer_ip: IpAddr,
+    req: Request<Incoming>,
+    request_id: &str,
+) -> Response<ResponseBody> {
+    // The connection was already checked for a ban; a client behind a
+    // trusted proxy only shows up here
+    let client_ip = state.trusted.client_ip(peer_ip, req.headers());
+    if client_ip != peer_ip && state.abuse.check(client_ip).is_err() {
+        debug!(ip = %client_ip, "rejected request from banned ip");
+        metrics::connection_blocked("edge");
+        return error_page(
+            ErrorPage::AccessDenied,
+            StatusCode::FORBIDDEN,
+            "your address is temporarily blocked",
+        )
+        .map(Into::into);
+    }
+
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().host())
+        .unwrap_or_default()
+        .to_string();
+
+    let Some(subdomain) = subdomain_from_host(&host, &state.domain) else {
+        return error_page(
+            ErrorPage::NotFound,
+            StatusCode::NOT_FOUND,
+            "tunnel not found",
+        )
+        .map(Into::into);
+    };
+
+    let lookup = state.tunnel_manager.read().await.lookup(&subdomain);
+    let tunnel = match lookup {
+        TunnelLookup::Active(tunnel) => tunnel,
+        TunnelLookup::Offline(custom_html) => {
+            return with_error_page(
+                EdgeError {
+                    page: ErrorPage::Offline,
+                    message: "tunnel offline: waiting for its client to reconnect".to_string(),
+                    custom_html,
+                },
+                StatusCode::SERVICE_UNAVAILABLE,
+            )
+            .map(Into::into);
+        }
+        TunnelLookup::Unhealthy(custom_html) => {
+            return with_error_page(
+                EdgeError {
+                    page: ErrorPage::Offline,
+                    message: "tunnel offline: its app isn't passing health checks".to_string(),
+                    custom_html,
+                },
+                StatusCode::SERVICE_UNAVAILABLE,
+            )
+            .map(Into::into);
+        }
+        TunnelLookup::Expired => {
+            return error_page(
+                ErrorPage::Expired,
+                StatusCode::GONE,
+                "tunnel expired: its owner set a time limit that has run out",
+            )
+            .map(Into::into);
+        }
+        TunnelLookup::Missing => {
+            return error_page(
+                ErrorPage::NotFound,
+                StatusCode::NOT_FOUND,
+                "tunnel not found",
+            )
+            .map(Into::into);
+        }
+    };
+
+    // Private tunnels don't exist as far as the public is concerned
+    if tunnel.protocol == private::PROTOCOL {
+        return error_page(
+            ErrorPage::NotFound,
+            StatusCode::NOT_FOUND,
+            "tunnel not found",
+        )
+        .map(Into::into);
+    }
+
+    // UDP and passthrough tunnels have nothing to say to HTTP; the
+    // latter are only reached through their own TLS
+    if tunnel.protocol == udp::PROTOCOL || tunnel.protocol == passthrough::PROTOCOL {
+        return error_page(
+            ErrorPage::NotFound,
+            StatusCode::NOT_FOUND,
+            &format!("tunnel not found: it carries {}, not http", tunnel.protocol),
+        )
+        .map(Into::into);
+    }
+
+    let cors = tunnel.settings.cors.clone();
+    if let Some(cors) = &cors
+        && let Some(response) = cors.preflight(&req)
+    {
+        return response.map(Into::into);
+    }
+
+    let origin = req.headers().get(header::ORIGIN).cloned();
+    let visit = Visit {
+        peer_ip,
+        client_ip,
+        host: &host,
+        subdomain: &subdomain,
+        request_id,
+    };
+    let mut response = serve(state, &tunnel, visit, req).await;
+    if let Some(cors) = &cors {
+        cors.decorate(origin.as_ref(), response.headers_mut());
+    }
+    response
+}
+
+/// Who a request is from and what it's for, once its tunnel is found.
+struct Visit<'a> {
+    peer_ip: IpAddr,
+    client_ip: IpAddr,
+    host: &'a str,
+    subdomain: &'a str,
+    request_id: &'a str,
+}
+
+/// Everything after the tunnel is found: access checks, rate limiting
+/// and the trip to the app.
+async fn serve(
+    state: &EdgeState,
+    tunnel: &Arc<ActiveTunnel>,
+    visit: Visit<'_>,
+    mut req: Request<Incoming>,
+) -> Response<ResponseBody> {
+    let Visit {
+        peer_ip,
+        client_ip,
+        host,
+        subdomain,
+        request_id,
+    } = visit;
+
+    // Ahead of the access checks, so guessing credentials costs the
+    // guesser their share of the tunnel's rate like any other request
+    let limit = tunnel.rate_limiter.check();
+    if !limit.allowed {
+        metrics::rate_limit_hit("tunnel", &tunnel.tier);
+        let mut response = error_page(
+            ErrorPage::RateLimited,
+            StatusCode::TOO_MANY_REQUESTS,
+            "too many requests, please slow down",
+        );
+        set_rate_limit_headers(response.headers_mut(), &limit);
+        return response.map(Into::into);
+    }
+
+    let policy = tunnel.access_policy();
+
+    // The provider sends visitors back here after they sign in
+    if let Some(rule) = &policy.oidc
+        && req.uri().path() == oidc::CALLBACK_PATH
+    {
+        return state
+            .oidc
+            .finish_login(rule, tunnel.tunnel_id, host, req.uri(), req.headers())
+            .await
+            .map(Into::into);
+    }
+
+    // A share link is swapped for a cookie, then the visitor is sent on
+    // to the same page without the token in the address bar
+    if let Some(token) = query_param(req.uri(), share::SHARE_PARAM)
+        && matches!(*req.method(), Method::GET | Method::HEAD)
+    {
+        return redeem_share(state, tunnel, &policy, client_ip, &req, &token).map(Into::into);
+    }
+
+    let shared = cookie::get(req.headers(), share::SHARE_COOKIE)
+        .is_some_and(|token| state.shares.is_valid(token, tunnel.tunnel_id));
+    let decision = if shared {
+        match policy.check_ip(client_ip) {
+            Ok(()) => AccessDecision::Allow {
+                consumed_authorization: false,
+            },
+            Err(reason) => AccessDecision::Deny(reason),
+        }
+    } else {
+        let session_email = policy
+            .oidc
+            .as_ref()
+            .and_then(|_| state.oidc.session_email(req.headers(), tunnel.tunnel_id));
+        let authorization = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let password_to_check = authorization
+            .as_deref()
+            .is_some_and(|value| policy.checks_password(value));
+        let checked = policy.clone();
+        let evaluate = move || {
+            checked.evaluate(
+                client_ip,
+                authorization.as_deref(),
+                session_email.as_deref(),
+            )
+        };
+        // Checking a password against its hash is slow on purpose, so it
+        // stays off the workers serving other requests
+        if password_to_check {
+            tokio::task::spawn_blocking(evaluate)
+                .await
+                .unwrap_or(AccessDecision::Deny(DenyReason::BadCredentials))
+        } else {
+            evaluate()
+        }
+    };
+
+    match decision {
+        AccessDecision::Allow {
+            consumed_authorization,
+        } => {
+            // The credentials were for us, not the developer's app
+            if consumed_authorization {
+                req.headers_mut().remove(header::AUTHORIZATION);
+            }
+            cookie::strip(req.headers_mut(), EDGE_COOKIES);
+        }
+        // Browsers get sent off to sign in; API clients get a plain 401
+        AccessDecision::Deny(DenyReason::LoginRequired)
+            if matches!(*req.method(), Method::GET | Method::HEAD) =>
+        {
+            let rule = policy.oidc.as_ref().expect("login required implies oidc");
+            return state
+                .oidc
+                .start_login(rule, tunnel.tunnel_id, host, req.uri())
+                .await
+                .map(Into::into);
+        }
+        AccessDecision::Deny(reason) => {
+            debug!(subdomain = %subdomain, ip = %client_ip, reason = reason.as_str(), "request denied by access policy");
+            metrics::auth_failure("tunnel", reason.as_str());
+            let response = denied_response(reason, policy.wants_basic_auth());
+            log_denied(state, tunnel, &req, client_ip, response.status(), reason);
+            return response.map(Into::into);
+        }
+    }
+
+    // Refuse a body we already know is too big before anything reaches
+    // the app; one without a length is cut off as it streams instead
+    let declared_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared_length.is_some_and(|length| length > tunnel.body_limits.max_request_bytes) {
+        return request_too_large(tunnel).map(Into::into);
+    }
+
+    let rewrites = &tunnel.settings.rewrites;
+    if let Some((status, location)) = rewrites.redirect_for(req.uri()) {
+        let mut response = redirect_response(&location);
+        *response.status_mut() = StatusCode::from_u16(status).unwrap_or(StatusCode::FOUND);
+        return response.map(Into::into);
+    }
+
+    // The edge answers the Upgrade itself once the app has, so the
+    // visitor's end of it has to be claimed before the request moves on
+    let visitor_upgrade = websocket::is_upgrade(&req).then(|| hyper::upgrade::on(&mut req));
+
+    forwarded::strip_hop_by_hop(req.headers_mut());
+    if visitor_upgrade.is_some() {
+        websocket::restore_upgrade_headers(&mut req);
+    }
+    forwarded::add_forwarding_headers(
+        req.headers_mut(),
+        &Origin {
+            client_ip,
+            peer_ip,
+            via_trusted_proxy: state.trusted.contains(peer_ip),
+            proto: &state.scheme,
+            host,
+            request_id,
+        },
+    );
+
+    // A request picked for mirroring is read up front, since its body
+    // has to go two places. A WebSocket can't be in two places at once.
+    let target = match visitor_upgrade {
+        Some(_) => None,
+        None => mirror_target(state, tunnel).await,
+    };
+    let mut mirror_answer = None;
+    let mut req = match target {
+        Some(target) => {
+            let (parts, body) = req.into_parts();
+            let body = match read_body(tunnel, body).await {
+                Ok(body) => body,
+                Err(response) => return response.map(Into::into),
+            };
+            let copy = Request::from_parts(parts.clone(), Full::new(body.clone()));
+            let host = format!("{}.{}", target.subdomain, state.domain);
+            let mirror = Mirror {
+                source: tunnel,
+                target,
+                host,
+                client_ip,
+            };
+            mirror_answer = mirror::send(state.db.clone(), mirror, copy);
+            Request::from_parts(parts, Either::Right(Full::new(body)))
+        }
+        None => req.map(Either::Left),
+    };
+
+    // Some apps only answer to the name they're configured with, e.g.
+    // a dev server that checks Host against localhost
+    if let Some(upstream_host) = &tunnel.settings.upstream_host
+        && let Ok(value) = header::HeaderValue::from_str(upstream_host)
+    {
+        req.headers_mut().insert(header::HOST, value);
+    }
+    rewrites.apply_request_headers(req.headers_mut());
+    if let Some(uri) = rewrites.rewrite_path(req.uri()) {
+        *req.uri_mut() = uri;
+    }
+
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let accept_encoding = req.headers().get(header::ACCEPT_ENCODING).cloned();
+    let started = Instant::now();
+
+    // Held until the response is back, so least-connections can see it
+    let Some(backend) = tunnel.pick_backend() else {
+        return error_page(
+            ErrorPage::BackendUnreachable,
+            StatusCode::BAD_GATEWAY,
+            "tunnel backend unreachable",
+        )
+        .map(Into::into);
+    };
+    let forwarded = fetch(
+        tunnel,
+        backend.bind_addr,
+        req,
+        visitor_upgrade.is_some(),
+        accept_encoding.as_ref(),
+    );
+    let mut app_upgrade = None;
+    let mut response = match forwarded.await {
+        Ok((mut response, upgrade)) => {
+            app_upgrade = upgrade;
+            rewrites.apply_response_headers(response.headers_mut());
+            response
+        }
+        Err(e) => match e {
+            ProxyError::RequestTooLarge => request_too_large(tunnel),
+            ProxyError::ResponseTooLarge => {
+            warn!(subdomain = %subdomain, limit = tunnel.body_limits.max_response_bytes, "tunnel response too large");
+            metrics::body_limit_hit("response", &tunnel.tier);
+            error_page(
+                ErrorPage::TooLarge,
+                StatusCode::BAD_GATEWAY,
+                "the tunnel's response was larger than it's allowed to send",
+            )
+        }
+            e => match e.timeout_kind() {
+            Some(kind) => {
+                warn!(subdomain = %subdomain, kind, error = %e, "tunnel timed out");
+                if kind == "connect" {
+                    sit_out(tunnel, &backend);
+                }
+                metrics::error_occurred("proxy_timeout");
+                metrics::proxy_timeout(kind, &tunnel.tier);
+                let response = error_page(
+                    ErrorPage::Timeout,
+                    StatusCode::GATEWAY_TIMEOUT,
+                    &format!("tunnel timed out ({kind} timeout)"),
+                );
+                log_timeout(
+                    state,
+                    tunnel,
+                    Timeout {
+                        method: &method,
+                        path: &path,
+                        client_ip,
+                        elapsed: started.elapsed(),
+                        kind,
+                    },
+                );
+                response
+            }
+            None => {
+                warn!(subdomain = %subdomain, error = %e, "tunnel backend unreachable");
+                sit_out(tunnel, &backend);
+                metrics::error_occurred("proxy_backend_error");
+                error_page(
+                    ErrorPage::BackendUnreachable,
+                    StatusCode::BAD_GATEWAY,
+                    "tunnel backend unreachable",
+                )
+            }
+        },
+        }
+        .map(ResponseBody::from),
+    };
+
+    if let Some(answered) = mirror_answer {
+        let _ = answered.send(mirror::Answer {
+            status: response.status(),
+            latency: started.elapsed(),
+        });
+    }
+
+    match visitor_upgrade.zip(app_upgrade) {
+        Some((visitor, app)) => {
+            tokio::spawn(websocket_session(tunnel.clone(), backend, visitor, app));
+        }
+        // The edge frames the body for the visitor's own connection
+        None => forwarded::strip_hop_by_hop(response.headers_mut()),
+    }
+    set_rate_limit_headers(response.headers_mut(), &limit);
+    metrics::http_request_duration(
+        &method,
+        response.status().as_u16(),
+        started.elapsed().as_secs_f64(),
+    );
+
+    response
+}
+
+/// Sends a request on to one of the tunnel's backends and gets the
+/// answer ready for the visitor: streaming as it arrives, compressed if
+/// it qualifies, or with the app's end of a WebSocket if one was asked
+/// for and the app agreed.
+async fn fetch(
+    tunnel: &ActiveTunnel,
+    bind_addr: SocketAddr,
+    req: Request<Either<Incoming, Full<Bytes>>>,
+    upgrade: bool,
+    accept_encoding: Option<&header::HeaderValue>,
+) -> Result<(Response<ResponseBody>, Option<OnUpgrade>), ProxyError> {
+    let (response, app_upgrade) = if upgrade {
+        open_upgrade(bind_addr, req, tunnel.body_limits, tunnel.timeouts).await?
+    } else {
+        let response = forward_request(
+            bind_addr,
+            req,
+            tunnel.settings.upstream_protocol,
+            tunnel.body_limits,
+            tunnel.timeouts,
+        )
+        .await?;
+        (response, None)
+    };
+
+    // A body that turns out too big once it's streaming can only be cut
+    // off, but it still counts
+    let subdomain = tunnel.subdomain.clone();
+    let tier = tunnel.tier.clone();
+    let limit = tunnel.body_limits.max_response_bytes;
+    let response = response.map(|body| {
+        ResponseBody::Upstream(body.on_overflow(move || {
+            warn!(subdomain = %subdomain, limit, "tunnel response too large, cut off mid-stream");
+            metrics::body_limit_hit("response", &tier);
+        }))
+    });
+    if app_upgrade.is_some() {
+        return Ok((response, app_upgrade));
+    }
+
+    let response =
+        compression::compress_response(&tunnel.settings.compression, accept_encoding, response)
+            .await?;
+    Ok((response, None))
+}
+
+/// Carries a WebSocket once the visitor and the app have both switched
+/// over, within the tunnel's `max_websocket_bytes`. The backend counts
+/// as in flight until the session ends, so least-connections sees open
+/// sockets as well as requests.
+async fn websocket_session(
+    tunnel: Arc<ActiveTunnel>,
+    _backend: BackendGuard,
+    visitor: OnUpgrade,
+    app: OnUpgrade,
+) {
+    let (visitor, app) = match tokio::try_join!(visitor, app) {
+        Ok(both) => both,
+        Err(e) => {
+            debug!(subdomain = %tunnel.subdomain, error = %e, "websocket upgrade failed");
+            return;
+        }
+    };
+    let stats = websocket::bridge(
+        TokioIo::new(visitor),
+        TokioIo::new(app),
+        tunnel.timeouts,
+        tunnel.body_limits.max_websocket_bytes,
+    )
+    .await;
+    if stats.hit_limit {
+        metrics::body_limit_hit("websocket", &tunnel.tier);
+    }
+}
+
+/// The tunnel this request should be copied to, if the tunnel mirrors
+/// and this request is one of the ones it copies. Nothing is copied
+/// while the mirror is offline or isn't one `may_mirror_to` allows.
+async fn mirror_target(state: &EdgeState, tunnel: &ActiveTunnel) -> Option<Arc<ActiveTunnel>> {
+    let settings = tunnel.settings.mirror.as_ref()?;
+    if !settings.sample() {
+        return None;
+    }
+    match state
+        .tunnel_manager
+        .read()
+        .await
+        .lookup(&settings.subdomain)
+    {
+        TunnelLookup::Active(target) if mirror::may_mirror_to(tunnel, &target) => Some(target),
+        _ => {
+            debug!(subdomain = %tunnel.subdomain, mirror = %settings.subdomain, "mirror tunnel unavailable, not copying request");
+            None
+        }
+    }
+}
+
+/// Reads a visitor's whole request body, within the tunnel's size limit
+/// and total timeout.
+async fn read_body(tunnel: &ActiveTunnel, body: Incoming) -> Result<Bytes, Response<Full<Bytes>>> {
+    let limit = usize::try_from(tunnel.body_limits.max_request_bytes).unwrap_or(usize::MAX);
+    let read = tokio::time::timeout(tunnel.timeouts.total(), Limited::new(body, limit).collect());
+    match read.await {
+        Ok(Ok(collected)) => Ok(collected.to_bytes()),
+        Ok(Err(e)) if e.downcast_ref::<LengthLimitError>().is_some() => {
+            Err(request_too_large(tunnel))
+        }
+        Ok(Err(e)) => {
+            debug!(subdomain = %tunnel.subdomain, error = %e, "failed to read request body");
+            Err(error_page(
+                ErrorPage::Error,
+                StatusCode::BAD_REQUEST,
+                "failed to read request body",
+            ))
+        }
+        Err(_) => Err(error_page(
+            ErrorPage::Timeout,
+            StatusCode::REQUEST_TIMEOUT,
+            "request body took too long to arrive",
+        )),
+    }
+}
+
+/// Takes a backend the edge couldn't reach out of rotation for a while,
+/// so a load-balanced tunnel's traffic fails over to its other clients.
+/// A tunnel with just the one has nowhere else to send it.
+fn sit_out(tunnel: &ActiveTunnel, backend: &Backend) {
+    if tunnel.backends.len() < 2 {
+        return;
+    }
+    backend.mark_failed();
+    metrics::backend_failed(&tunnel.tier);
+    warn!(subdomain = %tunnel.subdomain, backend = %backend.id, "backend taken out of rotation");
+}
+
+fn request_too_large(tunnel: &ActiveTunnel) -> Response<Full<Bytes>> {
+    debug!(subdomain = %tunnel.subdomain, limit = tunnel.body_limits.max_request_bytes, "request body too large");
+    metrics::body_limit_hit("request", &tunnel.tier);
+    error_page(
+        ErrorPage::TooLarge,
+        StatusCode::PAYLOAD_TOO_LARGE,
+        &NeedleError::BodyTooLarge.to_string(),
+    )
+}
+
+/// Counts a use of a share link and turns it into a cookie for this
+/// tunnel. IP rules still apply to whoever holds the link.
+fn redeem_share(
+    state: &EdgeState,
+    tunnel: &ActiveTunnel,
+    policy: &AccessPolicy,
+    client_ip: IpAddr,
+    req: &Request<Incoming>,
+    token: &str,
+) -> Response<Full<Bytes>> {
+    if let Err(reason) = policy.check_ip(client_ip) {
+        metrics::auth_failure("tunnel", reason.as_str());
+        let response = denied_response(reason, false);
+        log_denied(state, tunnel, req, client_ip, response.status(), reason);
+        return response;
+    }
+
+    let Some(max_age) = state.shares.redeem(token, tunnel.tunnel_id) else {
+        metrics::auth_failure("tunnel", "share_link_invalid");
+        return error_page(
+            ErrorPage::AccessDenied,
+            StatusCode::FORBIDDEN,
+            "this share link has expired, been used up or been revoked",
+        );
+    };
+
+    let mut response = redirect_response(&without_share_param(req.uri()));
+    response.headers_mut().append(
+        header::SET_COOKIE,
+        cookie::set(share::SHARE_COOKIE, token, max_age, state.secure_cookies),
+    );
+    response
+}
+
+/// The request's path and query with the share token taken out, leaving
+/// every other parameter exactly as it was sent.
+fn without_share_param(uri: &Uri) -> String {
+    let prefix = format!("{}=", share::SHARE_PARAM);
+    let query: Vec<&str> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty() && !pair.starts_with(&prefix))
+        .collect();
+
+    if query.is_empty() {
+        uri.path().to_string()
+    } else {
+        format!("{}?{}", uri.path(), query.join("&"))
+    }
+}
+
+/// The error page for a request the access policy refused. Credential
+/// failures get a 401, with a Basic challenge when the tunnel uses a
+/// password so browsers show their login prompt.
+fn denied_response(reason: DenyReason, basic_challenge: bool) -> Response<Full<Bytes>> {
+    if !reason.is_auth_failure() {
+        return error_page(
+            ErrorPage::AccessDenied,
+            StatusCode::FORBIDDEN,
+            "access to this tunnel is restricted",
+        );
+    }
+
+    let mut response = error_page(
+        ErrorPage::AccessDenied,
+        StatusCode::UNAUTHORIZED,
+        "this tunnel requires credentials",
+    );
+    if basic_challenge {
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            header::HeaderValue::from_static("Basic realm=\"needle\", charset=\"UTF-8\""),
+        );
+    }
+    response
+}
+
+/// Records a denied request in the tunnel's inspector log. Runs in the
+/// background so a slow database doesn't hold up the error page.
+fn log_denied(
+    state: &EdgeState,
+    tunnel: &ActiveTunnel,
+    req: &Request<Incoming>,
+    client_ip: IpAddr,
+    status: StatusCode,
+    reason: DenyReason,
+) {
+    let db = state.db.clone();
+    let tunnel_id = tunnel.tunnel_id.to_string();
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+
+    tokio::spawn(async move {
+        if let Err(e) = needle_db::queries::requests::log_denied(
+            &db,
+            &tunnel_id,
+            &method,
+            &path,
+            status.as_u16(),
+            &client_ip.to_string(),
+            reason.as_str(),
+        )
+        .await
+        {
+            warn!(error = %e, "failed to log denied request");
+        }
+    });
+}
+
+/// A request that timed out, for the inspector log.
+struct Timeout<'a> {
+    method: &'a str,
+    path: &'a str,
+    client_ip: IpAddr,
+    elapsed: Duration,
+    kind: &'static str,
+}
+
+/// Records a timed-out request in the tunnel's inspector log, in the
+/// background like `log_denied`.
+fn log_timeout(state: &EdgeState, tunnel: &ActiveTunnel, timeout: Timeout<'_>) {
+    let db = state.db.clone();
+    let tunnel_id = tunnel.tunnel_id.to_string();
+    let method = timeout.method.to_string();
+    let path = timeout.path.to_string();
+    let client_ip = timeout.client_ip.to_string();
+    let latency_ms = u32::try_from(timeout.elapsed.as_millis()).unwrap_or(u32::MAX);
+    let kind = timeout.kind;
+
+    tokio::spawn(async move {
+        if let Err(e) = needle_db::queries::requests::log_timeout(
+            &db,
+            &tunnel_id,
+            &method,
+            &path,
+            StatusCode::GATEWAY_TIMEOUT.as_u16(),
+            latency_ms,
+            &client_ip,
+            kind,
+        )
+        .await
+        {
+            warn!(error = %e, "failed to log timed out request");
+        }
+    });
+}
+
+/// Extracts the tunnel subdomain from a Host header value. Only single-label
+/// names directly under our domain count, so `a.b.example.com` and the bare
+/// domain itself both return None.
+fn subdomain_from_host(host: &str, domain: &str) -> Option<String> {
+    let host = host.rsplit_once(':').map_or(host, |(name, port)| {
+        if port.chars().all(|c| c.is_ascii_digit()) {
+            name
+        } else {
+            host
+        }
+    });
+
+    let host = host.to_ascii_lowercase();
+    let label = host.strip_suffix(domain)?.strip_suffix('.')?;
+
+    if label.is_empty() || label.contains('.') {
+        return None;
+    }
+
+    Some(label.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_subdomain_from_host() {
+        assert_eq!(
+            subdomain_from_host("brave-eagle-a1b2c3d4.example.com", "example.com"),
+            Some("brave-eagle-a1b2c3d4".to_string())
+        );
+        assert_eq!(
+            subdomain_from_host("MyApp.Example.com:8080", "example.com"),
+            Some("myapp".to_string())
+        );
+    }
+
+    #[test]
+    fn drops_only_the_share_token_from_the_query() {
+        let strip = |s: &str| without_share_param(&s.parse().unwrap());
+        assert_eq!(strip("/docs?needle_share=abc.def"), "/docs");
+        assert_eq!(
+            strip("/docs?a=1&needle_share=abc.def&b=%20x"),
+            "/docs?a=1&b=%20x"
+        );
+        assert_eq!(strip("/?needle_shared=1"), "/?needle_shared=1");
+    }
+
+    #[test]
+    fn rejects_foreign_and_nested_hosts() {
+        assert_eq!(subdomain_from_host("example.com", "example.com"), None);
+        assert_eq!(subdomain_from_host("a.b.example.com", "example.com"), None);
+        assert_eq!(subdomain_from_host("myapp.other.com", "example.com"), None);
+        assert_eq!(subdomain_from_host("myappexample.com", "example.com"), None);
+        assert_eq!(subdomain_from_host("", "example.com"), None);
+    }
+}
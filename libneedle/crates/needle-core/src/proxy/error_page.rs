@@ -0,0 +1,347 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::{self, HeaderValue};
+use hyper::{Response, StatusCode};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// The most HTML a tunnel owner can upload as their offline page.
+pub const MAX_CUSTOM_PAGE_SIZE: usize = 64 * 1024;
+
+const DEFAULT_TEMPLATE: &str = r#"<!doctype html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{{status}} {{title}}</title>
+<style>
+body { font-family: system-ui, sans-serif; background: #f6f7f9; color: #1f2933; margin: 0; }
+main { max-width: 32rem; margin: 15vh auto; padding: 0 1.5rem; }
+h1 { font-size: 1.5rem; margin-bottom: 0.5rem; }
+p { line-height: 1.5; }
+.status { color: #7b8794; font-size: 0.9rem; letter-spacing: 0.05em; }
+.request-id { color: #7b8794; font-size: 0.8rem; margin-top: 2rem; }
+code { font-family: ui-monospace, monospace; }
+</style>
+</head>
+<body>
+<main>
+<div class="status">{{status}}</div>
+<h1>{{title}}</h1>
+<p>{{message}}</p>
+<p class="request-id">Request ID: <code>{{request_id}}</code></p>
+</main>
+</body>
+</html>
+"#;
+
+/// The pages the edge can show instead of a tunnel's own response.
+/// Each has a template operators can replace with a file of the same
+/// name in `ERROR_PAGES_DIR`, e.g. `offline.html`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorPage {
+    NotFound,
+    BackendUnreachable,
+    Timeout,
+    RateLimited,
+    AccessDenied,
+    Expired,
+    Offline,
+    /// A request or response bigger than the tunnel allows.
+    TooLarge,
+    /// Anything else, like a failed sign-in. Its `error.html` template
+    /// is also the fallback for the others.
+    Error,
+}
+
+impl ErrorPage {
+    const ALL: [ErrorPage; 9] = [
+        ErrorPage::NotFound,
+        ErrorPage::BackendUnreachable,
+        ErrorPage::Timeout,
+        ErrorPage::RateLimited,
+        ErrorPage::AccessDenied,
+        ErrorPage::Expired,
+        ErrorPage::Offline,
+        ErrorPage::TooLarge,
+        ErrorPage::Error,
+    ];
+
+    pub fn template_name(self) -> &'static str {
+        match self {
+            ErrorPage::NotFound => "not_found",
+            ErrorPage::BackendUnreachable => "backend_unreachable",
+            ErrorPage::Timeout => "timeout",
+            ErrorPage::RateLimited => "rate_limited",
+            ErrorPage::AccessDenied => "access_denied",
+            ErrorPage::Expired => "expired",
+            ErrorPage::Offline => "offline",
+            ErrorPage::TooLarge => "too_large",
+            ErrorPage::Error => "error",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            ErrorPage::NotFound => "Tunnel not found",
+            ErrorPage::BackendUnreachable => "Tunnel unreachable",
+            ErrorPage::Timeout => "Tunnel timed out",
+            ErrorPage::RateLimited => "Too many requests",
+            ErrorPage::AccessDenied => "Access denied",
+            ErrorPage::Expired => "Tunnel expired",
+            ErrorPage::Offline => "Tunnel offline",
+            ErrorPage::TooLarge => "Too large",
+            ErrorPage::Error => "Something went wrong",
+        }
+    }
+}
+
+/// Marks a response as one of the edge's own errors. The edge swaps
+/// the plain-text body for a page in whatever format the visitor asked
+/// for just before it goes out, so code that fails a request only has
+/// to say why.
+#[derive(Debug, Clone)]
+pub struct EdgeError {
+    pub page: ErrorPage,
+    pub message: String,
+    /// The tunnel owner's own HTML, shown to browsers instead of the
+    /// template.
+    pub custom_html: Option<Arc<str>>,
+}
+
+/// The HTML templates for each error page, loaded once at startup.
+pub struct ErrorPages {
+    templates: HashMap<ErrorPage, String>,
+}
+
+impl ErrorPages {
+    /// Reads any overrides from `dir`. A missing or unreadable file just
+    /// means the built-in page is used, so a typo can't take the edge
+    /// down.
+    pub fn load(dir: Option<&str>) -> Self {
+        let read = |page: ErrorPage| {
+            let path = Path::new(dir?).join(format!("{}.html", page.template_name()));
+            match std::fs::read_to_string(&path) {
+                Ok(template) => {
+                    info!(path = %path.display(), "loaded error page template");
+                    Some(template)
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    warn!(path = %path.display(), error = %e, "failed to read error page template");
+                    None
+                }
+            }
+        };
+
+        let base = read(ErrorPage::Error).unwrap_or_else(|| DEFAULT_TEMPLATE.to_string());
+        let templates = ErrorPage::ALL
+            .into_iter()
+            .map(|page| {
+                let template = match page {
+                    ErrorPage::Error => base.clone(),
+                    _ => read(page).unwrap_or_else(|| base.clone()),
+                };
+                (page, template)
+            })
+            .collect();
+        Self { templates }
+    }
+
+    /// Replaces the body of an error response with the page for
+    /// `error`, as HTML, JSON or plain text depending on `accept`. The
+    /// status and any other headers the response carried are kept.
+    pub fn render<B>(
+        &self,
+        error: &EdgeError,
+        response: Response<B>,
+        accept: Option<&HeaderValue>,
+        request_id: &str,
+    ) -> Response<Full<Bytes>> {
+        let (mut parts, _) = response.into_parts();
+        let status = parts.status;
+
+        let (content_type, body) = match Format::negotiate(accept) {
+            Format::Html => {
+                let template = error
+                    .custom_html
+                    .as_deref()
+                    .or_else(|| self.templates.get(&error.page).map(String::as_str))
+                    .unwrap_or(DEFAULT_TEMPLATE);
+                (
+                    "text/html; charset=utf-8",
+                    fill(template, error, status, request_id),
+                )
+            }
+            Format::Json => (
+                "application/json",
+                serde_json::json!({
+                    "error": error.message,
+                    "status": status.as_u16(),
+                    "request_id": request_id,
+                })
+                .to_string(),
+            ),
+            Format::Text => (
+                "text/plain; charset=utf-8",
+                format!("{}\n\nrequest id: {request_id}\n", error.message),
+            ),
+        };
+
+        let headers = &mut parts.headers;
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers.remove(header::CONTENT_LENGTH);
+        headers
+            .entry(header::CACHE_CONTROL)
+            .or_insert(HeaderValue::from_static("no-store"));
+        headers.append(header::VARY, HeaderValue::from_static("accept"));
+
+        Response::from_parts(parts, Full::new(Bytes::from(body)))
+    }
+}
+
+fn fill(template: &str, error: &EdgeError, status: StatusCode, request_id: &str) -> String {
+    template
+        .replace("{{status}}", status.as_str())
+        .replace("{{title}}", &escape(error.page.title()))
+        .replace("{{message}}", &escape(&error.message))
+        .replace("{{request_id}}", &escape(request_id))
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Html,
+    Json,
+}
+
+impl Format {
+    /// Browsers ask for HTML and API clients for JSON; anyone who
+    /// doesn't say, like curl with its `*/*`, gets plain text. A named
+    /// type beats a wildcard at the same q-value.
+    fn negotiate(accept: Option<&HeaderValue>) -> Format {
+        let Some(accept) = accept.and_then(|v| v.to_str().ok()) else {
+            return Format::Text;
+        };
+
+        let ranges: Vec<(String, f32)> = accept
+            .split(',')
+            .map(|item| {
+                let mut parts = item.split(';');
+                let range = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+                let q = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (range, q)
+            })
+            .collect();
+
+        // (q, specificity) for one media type
+        let score = |media_type: &str| {
+            let kind = media_type.split('/').next().unwrap_or_default();
+            ranges
+                .iter()
+                .filter_map(|(range, q)| {
+                    if range == media_type {
+                        Some((*q, 2))
+                    } else if range.strip_suffix("/*") == Some(kind) {
+                        Some((*q, 1))
+                    } else if range == "*/*" {
+                        Some((*q, 0))
+                    } else {
+                        None
+                    }
+                })
+                .max_by_key(|(_, specificity)| *specificity)
+                .unwrap_or((0.0, 0))
+        };
+
+        let mut best = (Format::Text, score("text/plain"));
+        for (format, media_type) in [
+            (Format::Html, "text/html"),
+            (Format::Json, "application/json"),
+        ] {
+            let candidate = score(media_type);
+            if candidate.0 > best.1.0 || (candidate.0 == best.1.0 && candidate.1 > best.1.1) {
+                best = (format, candidate);
+            }
+        }
+        if best.1.0 <= 0.0 {
+            return Format::Text;
+        }
+        best.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiate(accept: &str) -> Format {
+        Format::negotiate(Some(&HeaderValue::from_str(accept).unwrap()))
+    }
+
+    #[test]
+    fn negotiates_page_format() {
+        assert_eq!(
+            negotiate("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+            Format::Html
+        );
+        assert_eq!(negotiate("application/json"), Format::Json);
+        assert_eq!(negotiate("application/json, text/plain, */*"), Format::Text);
+        assert_eq!(negotiate("*/*"), Format::Text);
+        assert_eq!(Format::negotiate(None), Format::Text);
+    }
+
+    #[tokio::test]
+    async fn renders_escaped_pages_and_keeps_headers() {
+        let pages = ErrorPages::load(None);
+        let error = EdgeError {
+            page: ErrorPage::RateLimited,
+            message: "slow <down>".to_string(),
+            custom_html: None,
+        };
+        let response = Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(header::RETRY_AFTER, "3")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let html = HeaderValue::from_static("text/html");
+        let response = pages.render(&error, response, Some(&html), "req-1");
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "3");
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        let body = http_body_util::BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("slow &lt;down&gt;"));
+        assert!(body.contains("req-1"));
+    }
+}
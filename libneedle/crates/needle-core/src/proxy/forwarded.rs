@@ -0,0 +1,220 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use hyper::HeaderMap;
+use hyper::header::{self, HeaderName, HeaderValue};
+use std::net::IpAddr;
+
+pub const REQUEST_ID_HEADER: &str = "x-needle-request-id";
+
+// Headers that describe one connection rather than the message, so they
+// stop at each proxy (RFC 7230 section 6.1). Proxy-Connection isn't in
+// the RFC, but old clients still send it.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Drops hop-by-hop headers, including any the sender listed in its
+/// Connection header. `TE: trailers` is the exception: it says the
+/// client can take trailers, which the edge passes along, and gRPC
+/// servers refuse requests without it.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let trailers = headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|te| te.trim().eq_ignore_ascii_case("trailers"));
+
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+    if trailers {
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+    }
+}
+
+/// Where a request came from, for the app behind the tunnel.
+pub struct Origin<'a> {
+    /// The visitor's address, after any trusted proxies are accounted for.
+    pub client_ip: IpAddr,
+    /// The address that connected to the edge.
+    pub peer_ip: IpAddr,
+    /// Whether `peer_ip` is a trusted proxy whose forwarding headers we
+    /// keep and add to.
+    pub via_trusted_proxy: bool,
+    pub proto: &'a str,
+    pub host: &'a str,
+    pub request_id: &'a str,
+}
+
+/// Tells the app who it's really talking to, with both the de facto
+/// `X-Forwarded-*` headers and RFC 7239 `Forwarded`, plus a request ID
+/// it can log. A visitor could send any of these themselves, so unless
+/// the request came through one of our trusted proxies we throw theirs
+/// away and start fresh.
+pub fn add_forwarding_headers(headers: &mut HeaderMap, origin: &Origin) {
+    let chain_from = if origin.via_trusted_proxy {
+        origin.peer_ip
+    } else {
+        for name in [
+            "x-forwarded-for",
+            "x-forwarded-proto",
+            "x-forwarded-host",
+            "forwarded",
+        ] {
+            headers.remove(name);
+        }
+        origin.client_ip
+    };
+
+    append(headers, "x-forwarded-for", &chain_from.to_string());
+    if !headers.contains_key("x-forwarded-proto") {
+        set(headers, "x-forwarded-proto", origin.proto);
+    }
+    if !headers.contains_key("x-forwarded-host") {
+        set(headers, "x-forwarded-host", origin.host);
+    }
+
+    // A proxy that only speaks X-Forwarded-For still told us who the
+    // client was, so the Forwarded chain starts there
+    if origin.via_trusted_proxy
+        && origin.client_ip != origin.peer_ip
+        && !headers.contains_key("forwarded")
+    {
+        let client = format!("for={}", forwarded_node(origin.client_ip));
+        set(headers, "forwarded", &client);
+    }
+
+    let element = format!(
+        "for={};proto={};host={}",
+        forwarded_node(chain_from),
+        origin.proto,
+        quoted(origin.host),
+    );
+    append(headers, "forwarded", &element);
+
+    set(headers, REQUEST_ID_HEADER, origin.request_id);
+}
+
+/// IPv6 nodes have to be bracketed and quoted in `Forwarded`.
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => format!("\"[{v6}]\""),
+    }
+}
+
+/// Host values can carry a port, and `:` isn't allowed in a bare token.
+fn quoted(value: &str) -> String {
+    if value.contains(':') {
+        format!("\"{value}\"")
+    } else {
+        value.to_string()
+    }
+}
+
+/// Adds to a list header, folding into the existing value so apps that
+/// only read the first header line still see the whole chain.
+fn append(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    let existing: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    let combined = if existing.is_empty() {
+        value.to_string()
+    } else {
+        format!("{}, {value}", existing.join(", "))
+    };
+    set(headers, name, &combined);
+}
+
+fn set(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin<'a>(client: &str, peer: &str, trusted: bool) -> Origin<'a> {
+        Origin {
+            client_ip: client.parse().unwrap(),
+            peer_ip: peer.parse().unwrap(),
+            via_trusted_proxy: trusted,
+            proto: "https",
+            host: "demo.example.com",
+            request_id: "abc",
+        }
+    }
+
+    #[test]
+    fn strips_hop_by_hop_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONNECTION,
+            HeaderValue::from_static("X-Secret, close"),
+        );
+        headers.insert("x-secret", HeaderValue::from_static("1"));
+        headers.insert(header::TE, HeaderValue::from_static("gzip, trailers"));
+        headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
+
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(headers.len(), 2);
+        assert!(headers.contains_key(header::ACCEPT));
+        assert_eq!(headers[header::TE], "trailers");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::TE, HeaderValue::from_static("gzip"));
+        strip_hop_by_hop(&mut headers);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn replaces_spoofed_headers_and_extends_trusted_ones() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("6.6.6.6"));
+        add_forwarding_headers(&mut headers, &origin("203.0.113.7", "203.0.113.7", false));
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.7");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-forwarded-host"], "demo.example.com");
+        assert_eq!(
+            headers["forwarded"],
+            "for=203.0.113.7;proto=https;host=demo.example.com"
+        );
+        assert_eq!(headers[REQUEST_ID_HEADER], "abc");
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("http"));
+        add_forwarding_headers(&mut headers, &origin("203.0.113.7", "2001:db8::1", true));
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.7, 2001:db8::1");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(
+            headers["forwarded"],
+            "for=203.0.113.7, for=\"[2001:db8::1]\";proto=https;host=demo.example.com"
+        );
+    }
+}
@@ -1,155 +1,566 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
+use crate::config::{BodyLimits, ProxyTimeouts};
+use crate::proxy::error_page::{EdgeError, ErrorPage};
 use bytes::Bytes;
-use http_body_util::{BodyExt, Full};
-use hyper::body::Incoming;
-use hyper::{Request, Response, StatusCode};
+use http_body_util::{BodyExt, Empty, Full, LengthLimitError, Limited};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::header::HeaderValue;
+use hyper::http::uri::PathAndQuery;
+use hyper::upgrade::OnUpgrade;
+use hyper::{HeaderMap, Request, Response, StatusCode, Uri, Version, header};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use needle_common::rate_limit::RateLimitStatus;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use tokio::net::TcpStream;
-use tokio::time::{Duration, timeout};
+use tokio::time::{Duration, Instant, Sleep, timeout, timeout_at};
 use tracing::debug;
 
-const PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
-const PROXY_RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
-const MAX_RESPONSE_SIZE: usize = 50 * 1024 * 1024; // 50MB
+/// What the edge speaks to the app behind a tunnel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamProtocol {
+    #[default]
+    Http1,
+    /// HTTP/2 without TLS, starting straight away with no upgrade
+    /// ("prior knowledge"), the way gRPC servers expect it.
+    H2c,
+}
+
+impl UpstreamProtocol {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
 
 /// Forwards an incoming HTTP request to a tunnel's internal listener.
 ///
 /// Here's what happens step by step:
 /// 1. We open a TCP connection to the tunnel's local listener address
 ///    (127.0.0.1:random_port)
-/// 2. We serialize the HTTP request and write it over that TCP connection
+/// 2. We run an HTTP/1.1 or h2c client handshake over that connection,
+///    depending on `protocol`, and send
+///    the request, streaming the body straight through until it passes
+///    the tunnel's `max_request_bytes`
 /// 3. The SSH layer picks it up and sends it through the SSH channel
 ///    to the client's local app
-/// 4. The response comes back the same way, we read it and return it
+/// 4. The response comes back the same way; we parse its head and hand
+///    it to the edge with the body still arriving, as an `UpstreamBody`
+///
+/// Each stage has its own timeout from `timeouts`: connecting, waiting
+/// for the response headers, a body going quiet in either direction,
+/// and the whole exchange. Which one fired comes back in the error, or
+/// from the body once it's streaming.
+///
+/// Letting hyper do the framing means chunked bodies, keep-alive and
+/// content-length all behave, and the edge gets a real status line and
+/// header map back instead of raw bytes. Any trailers, like gRPC's
+/// status, follow the body through.
+///
+/// The body is usually a visitor's, but can be anything, like the empty
+/// one a health check sends.
+pub async fn forward_request<B>(
+    bind_addr: SocketAddr,
+    mut req: Request<B>,
+    protocol: UpstreamProtocol,
+    limits: BodyLimits,
+    timeouts: ProxyTimeouts,
+) -> Result<Response<UpstreamBody>, ProxyError>
+where
+    B: Body<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: Into<BoxError>,
+{
+    let deadline = Instant::now() + timeouts.total();
+    prepare_request(&mut req, protocol, bind_addr);
+    let req = req.map(|body| {
+        IdleTimeout::new(
+            Limited::new(body, as_usize(limits.max_request_bytes)),
+            timeouts.idle(),
+        )
+    });
+
+    let response = timeout_at(deadline, async {
+        let stream = timeout(timeouts.connect(), TcpStream::connect(bind_addr))
+            .await
+            .map_err(|_| ProxyError::ConnectTimeout)?
+            .map_err(ProxyError::Connect)?;
+
+        let io = TokioIo::new(stream);
+        let sent = match protocol {
+            UpstreamProtocol::Http1 => {
+                let (mut sender, conn) = hyper::client::conn::http1::handshake(io)
+                    .await
+                    .map_err(ProxyError::Handshake)?;
+                tokio::spawn(async move {
+                    if let Err(e) = conn.await {
+                        debug!(error = %e, "tunnel backend connection closed with error");
+                    }
+                });
+                timeout(timeouts.header(), sender.send_request(req)).await
+            }
+            UpstreamProtocol::H2c => {
+                let (mut sender, conn) =
+                    hyper::client::conn::http2::handshake(TokioExecutor::new(), io)
+                        .await
+                        .map_err(ProxyError::Handshake)?;
+                tokio::spawn(async move {
+                    if let Err(e) = conn.await {
+                        debug!(error = %e, "tunnel backend h2c connection closed with error");
+                    }
+                });
+                timeout(timeouts.header(), sender.send_request(req)).await
+            }
+        };
+
+        sent.map_err(|_| ProxyError::HeaderTimeout)?.map_err(|e| {
+            // A request body that was cut off surfaces as a failed request
+            if caused_by::<LengthLimitError>(&e) {
+                ProxyError::RequestTooLarge
+            } else if caused_by::<IdleTimeoutError>(&e) {
+                ProxyError::IdleTimeout
+            } else {
+                ProxyError::Upstream(e)
+            }
+        })
+    })
+    .await
+    .map_err(|_| ProxyError::TotalTimeout)??;
+
+    debug!(status = %response.status(), "received proxy response");
+
+    let (mut parts, body) = response.into_parts();
+    // The visitor's connection decides the version on the way out
+    parts.version = Version::HTTP_11;
+    Ok(Response::from_parts(
+        parts,
+        UpstreamBody::new(body, limits, timeouts, deadline)?,
+    ))
+}
+
+/// Asks a tunnel's app to switch a visitor's request to another protocol,
+/// which for us means WebSocket.
 ///
-/// This is basically a minimal HTTP/1.1 reverse proxy that works at the
-/// byte level rather than using a full HTTP client library. That gives
-/// us more control over streaming and keeps the overhead low.
-pub async fn forward_request(
+/// This is always HTTP/1.1, the only version with an Upgrade handshake.
+/// If the app agrees, the 101 comes back with the app's end of the
+/// switched connection, ready to be bridged to the visitor's. Anything
+/// else is an ordinary answer, read like `forward_request` would.
+pub async fn open_upgrade<B>(
     bind_addr: SocketAddr,
-    req: Request<Incoming>,
-) -> Result<Response<Full<Bytes>>, ProxyError> {
-    let stream = timeout(PROXY_CONNECT_TIMEOUT, TcpStream::connect(bind_addr))
+    mut req: Request<B>,
+    limits: BodyLimits,
+    timeouts: ProxyTimeouts,
+) -> Result<(Response<UpstreamBody>, Option<OnUpgrade>), ProxyError> {
+    let deadline = Instant::now() + timeouts.total();
+    prepare_request(&mut req, UpstreamProtocol::Http1, bind_addr);
+    // An upgrade request has nothing to say until the switch
+    let req = req.map(|_| Empty::<Bytes>::new());
+
+    let stream = timeout(timeouts.connect(), TcpStream::connect(bind_addr))
         .await
         .map_err(|_| ProxyError::ConnectTimeout)?
         .map_err(ProxyError::Connect)?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(ProxyError::Handshake)?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.with_upgrades().await {
+            debug!(error = %e, "tunnel backend upgrade connection closed with error");
+        }
+    });
+    let mut response = timeout(timeouts.header(), sender.send_request(req))
+        .await
+        .map_err(|_| ProxyError::HeaderTimeout)?
+        .map_err(ProxyError::Upstream)?;
+
+    let upgrade = (response.status() == StatusCode::SWITCHING_PROTOCOLS)
+        .then(|| hyper::upgrade::on(&mut response));
+    let (parts, body) = response.into_parts();
+    Ok((
+        Response::from_parts(parts, UpstreamBody::new(body, limits, timeouts, deadline)?),
+        upgrade,
+    ))
+}
 
-    let (mut read_half, mut write_half) = stream.into_split();
+/// Puts a request in the shape the app's protocol expects. Visitors on
+/// HTTP/2 send an absolute URI and no `Host` header, which an HTTP/1.1
+/// app wouldn't understand, while an HTTP/2 app wants the authority in
+/// the URI rather than a `Host` header.
+fn prepare_request<B>(req: &mut Request<B>, protocol: UpstreamProtocol, bind_addr: SocketAddr) {
+    let authority = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| req.uri().authority().map(|a| a.to_string()))
+        .unwrap_or_else(|| bind_addr.to_string());
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .cloned()
+        .unwrap_or_else(|| PathAndQuery::from_static("/"));
+
+    match protocol {
+        UpstreamProtocol::Http1 => {
+            *req.version_mut() = Version::HTTP_11;
+            if !req.headers().contains_key(header::HOST)
+                && let Ok(host) = HeaderValue::from_str(&authority)
+            {
+                req.headers_mut().insert(header::HOST, host);
+            }
+            *req.uri_mut() = Uri::from(path_and_query);
+        }
+        UpstreamProtocol::H2c => {
+            *req.version_mut() = Version::HTTP_2;
+            req.headers_mut().remove(header::HOST);
+            if let Ok(uri) = Uri::builder()
+                .scheme("http")
+                .authority(authority)
+                .path_and_query(path_and_query)
+                .build()
+            {
+                *req.uri_mut() = uri;
+            }
+        }
+    }
+}
 
-    let method = req.method().clone();
-    let uri = req.uri().clone();
-    let headers = req.headers().clone();
+/// An app's response body on its way to the visitor, passed along a
+/// frame at a time, trailers included.
+///
+/// It fails once the app has sent more than the tunnel's
+/// `max_response_bytes`, goes quiet for longer than the idle timeout, or
+/// is still going when the exchange's total timeout runs out. By then
+/// the visitor has the status line, so all the edge can do is cut the
+/// connection; a body that declares too big a length up front is
+/// refused before that, while there's still time for an error page.
+pub struct UpstreamBody {
+    inner: IdleTimeout<Incoming>,
+    remaining: u64,
+    deadline: Pin<Box<Sleep>>,
+    on_overflow: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
 
-    let body_bytes = req
-        .into_body()
-        .collect()
-        .await
-        .map_err(|e| ProxyError::ReadBody(e.to_string()))?
-        .to_bytes();
-
-    let mut raw_request = format!(
-        "{} {} HTTP/1.1\r\n",
-        method,
-        uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/")
-    );
-
-    for (name, value) in headers.iter() {
-        if let Ok(v) = value.to_str() {
-            raw_request.push_str(&format!("{}: {}\r\n", name, v));
+impl UpstreamBody {
+    fn new(
+        inner: Incoming,
+        limits: BodyLimits,
+        timeouts: ProxyTimeouts,
+        deadline: Instant,
+    ) -> Result<Self, ProxyError> {
+        if inner.size_hint().lower() > limits.max_response_bytes {
+            return Err(ProxyError::ResponseTooLarge);
         }
+        Ok(Self {
+            inner: IdleTimeout::new(inner, timeouts.idle()),
+            remaining: limits.max_response_bytes,
+            deadline: Box::pin(tokio::time::sleep_until(deadline)),
+            on_overflow: None,
+        })
     }
 
-    if !body_bytes.is_empty() {
-        raw_request.push_str(&format!("content-length: {}\r\n", body_bytes.len()));
+    /// Runs `f` if the body gets cut off for being too big, which is
+    /// the edge's chance to count it.
+    pub fn on_overflow(mut self, f: impl FnOnce() + Send + Sync + 'static) -> Self {
+        self.on_overflow = Some(Box::new(f));
+        self
     }
+}
 
-    raw_request.push_str("\r\n");
+impl Body for UpstreamBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = &mut *self;
+        if this.deadline.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Some(Err(Box::new(TotalTimeoutError))));
+        }
 
-    write_half
-        .write_all(raw_request.as_bytes())
-        .await
-        .map_err(ProxyError::Write)?;
+        let frame = match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => frame,
+            other => return other,
+        };
+        let len = frame.data_ref().map_or(0, |data| data.len() as u64);
+        match this.remaining.checked_sub(len) {
+            Some(remaining) => this.remaining = remaining,
+            None => {
+                this.remaining = 0;
+                if let Some(on_overflow) = this.on_overflow.take() {
+                    on_overflow();
+                }
+                return Poll::Ready(Some(Err(Box::new(ResponseTooLargeError))));
+            }
+        }
+        Poll::Ready(Some(Ok(frame)))
+    }
 
-    if !body_bytes.is_empty() {
-        write_half
-            .write_all(&body_bytes)
-            .await
-            .map_err(ProxyError::Write)?;
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
     }
 
-    write_half.flush().await.map_err(ProxyError::Write)?;
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
 
-    let mut response_buf = Vec::with_capacity(8192);
-    let mut temp = [0u8; 8192];
+/// The body the edge sends visitors.
+pub enum ResponseBody {
+    /// A page or redirect the edge wrote itself.
+    Edge(Full<Bytes>),
+    /// An app's response read in full, as it is once compressed, with
+    /// any trailers the app sent after it.
+    Read {
+        data: Option<Bytes>,
+        trailers: Option<HeaderMap>,
+    },
+    /// An app's response passed along as it arrives.
+    Upstream(UpstreamBody),
+}
 
-    let read_result = timeout(PROXY_RESPONSE_TIMEOUT, async {
-        loop {
-            let n = read_half.read(&mut temp).await.map_err(ProxyError::Read)?;
-            if n == 0 {
-                break;
-            }
-            response_buf.extend_from_slice(&temp[..n]);
-            if response_buf.len() > MAX_RESPONSE_SIZE {
-                return Err(ProxyError::ResponseTooLarge);
+impl ResponseBody {
+    /// Reads the whole body, for when it has to be worked on in one
+    /// piece. How that can fail is the same as for `forward_request`.
+    pub async fn read(self) -> Result<(Bytes, Option<HeaderMap>), ProxyError> {
+        let collected = self.collect().await.map_err(response_body_error)?;
+        let trailers = collected.trailers().cloned();
+        Ok((collected.to_bytes(), trailers))
+    }
+}
+
+impl From<Full<Bytes>> for ResponseBody {
+    fn from(body: Full<Bytes>) -> Self {
+        Self::Edge(body)
+    }
+}
+
+impl Body for ResponseBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        match self.get_mut() {
+            Self::Edge(body) => Pin::new(body)
+                .poll_frame(cx)
+                .map_err(|never| match never {}),
+            Self::Read { data, trailers } => {
+                if let Some(data) = data.take().filter(|data| !data.is_empty()) {
+                    return Poll::Ready(Some(Ok(Frame::data(data))));
+                }
+                Poll::Ready(
+                    trailers
+                        .take()
+                        .map(|trailers| Ok(Frame::trailers(trailers))),
+                )
             }
+            Self::Upstream(body) => Pin::new(body).poll_frame(cx),
+        }
+    }
 
-            if response_buf.windows(4).any(|w| w == b"\r\n\r\n") && response_buf.len() > 100 {
-                break;
+    fn is_end_stream(&self) -> bool {
+        match self {
+            Self::Edge(body) => body.is_end_stream(),
+            Self::Read { data, trailers } => {
+                data.as_ref().is_none_or(Bytes::is_empty) && trailers.is_none()
             }
+            Self::Upstream(body) => body.is_end_stream(),
         }
-        Ok(())
-    })
-    .await;
+    }
 
-    match read_result {
-        Ok(Ok(())) => {}
-        Ok(Err(e)) => return Err(e),
-        Err(_) => return Err(ProxyError::ResponseTimeout),
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            Self::Edge(body) => body.size_hint(),
+            Self::Read { data, trailers } => {
+                let len = data.as_ref().map_or(0, |data| data.len() as u64);
+                if trailers.is_none() {
+                    return SizeHint::with_exact(len);
+                }
+                // Trailers need chunked framing on HTTP/1.1, so no length
+                let mut hint = SizeHint::new();
+                hint.set_lower(len);
+                hint
+            }
+            Self::Upstream(body) => body.size_hint(),
+        }
     }
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
-    debug!(bytes = response_buf.len(), "received proxy response");
+#[derive(Debug, thiserror::Error)]
+#[error("body stalled for longer than the idle timeout")]
+struct IdleTimeoutError;
+
+#[derive(Debug, thiserror::Error)]
+#[error("response body ran past the total timeout")]
+struct TotalTimeoutError;
 
-    // Parse HTTP status line to extract real status code
-    let status_code = parse_status_code(&response_buf).unwrap_or(StatusCode::BAD_GATEWAY);
+#[derive(Debug, thiserror::Error)]
+#[error("response body exceeded size limit")]
+struct ResponseTooLargeError;
+
+/// Fails a body that goes quiet for longer than `idle` between frames.
+/// The clock only runs while someone is waiting on the body, so time
+/// spent before the first read doesn't count.
+struct IdleTimeout<B> {
+    inner: B,
+    idle: Duration,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
 
-    Ok(Response::builder()
-        .status(status_code)
-        .body(Full::new(Bytes::from(response_buf)))
-        .expect("valid response"))
+impl<B> IdleTimeout<B> {
+    fn new(inner: B, idle: Duration) -> Self {
+        Self {
+            inner,
+            idle,
+            deadline: None,
+        }
+    }
 }
 
-/// Parses the HTTP status code from a raw HTTP response buffer.
-/// Looks for the first line in format "HTTP/1.1 200 OK" and extracts the code.
-fn parse_status_code(response: &[u8]) -> Option<StatusCode> {
-    // Convert to string to parse status line
-    let response_str = std::str::from_utf8(response).ok()?;
+impl<B> Body for IdleTimeout<B>
+where
+    B: Body + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = B::Data;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        if let Poll::Ready(frame) = Pin::new(&mut this.inner).poll_frame(cx) {
+            this.deadline = None;
+            return Poll::Ready(frame.map(|frame| frame.map_err(Into::into)));
+        }
+
+        let idle = this.idle;
+        let deadline = this
+            .deadline
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(idle)));
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Some(Err(Box::new(IdleTimeoutError)))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
 
-    // Find first line (status line)
-    let status_line = response_str.lines().next()?;
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Whether `error` or anything that caused it is a `T`. Body errors
+/// reach us wrapped in whatever hyper made of them.
+/// What went wrong reading an app's response body, from the limits and
+/// timeouts `UpstreamBody` puts on it.
+fn response_body_error(e: BoxError) -> ProxyError {
+    if caused_by::<ResponseTooLargeError>(&*e) {
+        ProxyError::ResponseTooLarge
+    } else if caused_by::<IdleTimeoutError>(&*e) {
+        ProxyError::IdleTimeout
+    } else if caused_by::<TotalTimeoutError>(&*e) {
+        ProxyError::TotalTimeout
+    } else {
+        ProxyError::ReadBody(e.to_string())
+    }
+}
 
-    // Parse format: "HTTP/1.1 200 OK"
-    let parts: Vec<&str> = status_line.split_whitespace().collect();
-    if parts.len() < 2 {
-        return None;
+fn caused_by<T: std::error::Error + 'static>(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(error);
+    while let Some(e) = current {
+        if e.is::<T>() {
+            return true;
+        }
+        current = e.source();
     }
+    false
+}
 
-    // Second part should be the status code
-    let code: u16 = parts[1].parse().ok()?;
-    StatusCode::from_u16(code).ok()
+fn as_usize(bytes: u64) -> usize {
+    usize::try_from(bytes).unwrap_or(usize::MAX)
 }
 
 /// Builds a plain-text error response for when the proxy can't reach
 /// the tunnel backend. We keep it simple so the client gets useful
-/// feedback without exposing internal details.
+/// feedback without exposing internal details. The edge turns it into
+/// the generic error page on the way out.
 pub fn error_response(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
-    Response::builder()
+    error_page(ErrorPage::Error, status, message)
+}
+
+/// Like `error_response`, but for one of the edge's named pages.
+pub fn error_page(page: ErrorPage, status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    with_error_page(
+        EdgeError {
+            page,
+            message: message.to_string(),
+            custom_html: None,
+        },
+        status,
+    )
+}
+
+/// Builds the response for an edge error that's already been described.
+pub fn with_error_page(error: EdgeError, status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::builder()
         .status(status)
         .header("content-type", "text/plain")
-        .body(Full::new(Bytes::from(message.to_string())))
-        .expect("valid error response")
+        .body(Full::new(Bytes::from(error.message.clone())))
+        .expect("valid error response");
+    response.extensions_mut().insert(error);
+    response
+}
+
+/// A 302 to somewhere on the same tunnel or off to a login provider.
+/// Marked no-store so browsers don't cache a redirect that depends on
+/// the visitor's cookies.
+pub fn redirect_response(location: &str) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, location)
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Full::new(Bytes::new()))
+        .expect("valid redirect response")
+}
+
+/// Reads one decoded query parameter from a request URI.
+pub fn query_param(uri: &Uri, name: &str) -> Option<String> {
+    let mut url = reqwest::Url::parse("http://edge.invalid/").expect("valid url");
+    url.set_query(uri.query());
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Adds `RateLimit-Limit` and `RateLimit-Remaining` from a bucket check,
+/// plus `Retry-After` when it refused the request. Limit headers the app
+/// already set on its own response are left alone.
+pub fn set_rate_limit_headers(headers: &mut HeaderMap, status: &RateLimitStatus) {
+    headers
+        .entry("ratelimit-limit")
+        .or_insert(status.limit.into());
+    headers
+        .entry("ratelimit-remaining")
+        .or_insert(status.remaining.into());
+    if !status.allowed {
+        headers.insert(header::RETRY_AFTER, status.retry_after_secs().into());
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -160,18 +571,203 @@ pub enum ProxyError {
     #[error("failed to connect to tunnel backend: {0}")]
     Connect(std::io::Error),
 
-    #[error("failed to read request body: {0}")]
+    #[error("http handshake with tunnel backend failed: {0}")]
+    Handshake(hyper::Error),
+
+    #[error("tunnel backend request failed: {0}")]
+    Upstream(hyper::Error),
+
+    #[error("failed to read response body: {0}")]
     ReadBody(String),
 
-    #[error("failed to write to tunnel backend: {0}")]
-    Write(std::io::Error),
+    #[error("timed out waiting for the tunnel's response headers")]
+    HeaderTimeout,
 
-    #[error("failed to read from tunnel backend: {0}")]
-    Read(std::io::Error),
+    #[error("a body stalled between the visitor and the tunnel")]
+    IdleTimeout,
 
-    #[error("tunnel response timed out")]
-    ResponseTimeout,
+    #[error("tunnel exchange took longer than the total timeout")]
+    TotalTimeout,
+
+    #[error("request body exceeded size limit")]
+    RequestTooLarge,
 
     #[error("response exceeded size limit")]
     ResponseTooLarge,
 }
+
+impl ProxyError {
+    /// Which timeout fired, if this is one: `connect`, `header`, `idle`
+    /// or `total`.
+    pub fn timeout_kind(&self) -> Option<&'static str> {
+        match self {
+            ProxyError::ConnectTimeout => Some("connect"),
+            ProxyError::HeaderTimeout => Some("header"),
+            ProxyError::IdleTimeout => Some("idle"),
+            ProxyError::TotalTimeout => Some("total"),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prepares_requests_for_each_upstream_protocol() {
+        let bind_addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        // What an HTTP/2 visitor's request looks like to the edge
+        let visitor = || {
+            Request::builder()
+                .version(Version::HTTP_2)
+                .uri("https://app.example.com/greet.Greeter/Hello?x=1")
+                .body(())
+                .unwrap()
+        };
+
+        let mut req = visitor();
+        prepare_request(&mut req, UpstreamProtocol::Http1, bind_addr);
+        assert_eq!(req.version(), Version::HTTP_11);
+        assert_eq!(req.uri(), "/greet.Greeter/Hello?x=1");
+        assert_eq!(req.headers()[header::HOST], "app.example.com");
+
+        let mut req = visitor();
+        req.headers_mut()
+            .insert(header::HOST, HeaderValue::from_static("localhost:50051"));
+        prepare_request(&mut req, UpstreamProtocol::H2c, bind_addr);
+        assert_eq!(req.version(), Version::HTTP_2);
+        assert_eq!(req.uri(), "http://localhost:50051/greet.Greeter/Hello?x=1");
+        assert!(!req.headers().contains_key(header::HOST));
+    }
+
+    /// An app that reads one request head, then writes `response` as is.
+    async fn canned_app(response: &'static [u8]) -> SocketAddr {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bind_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            stream.write_all(response).await.unwrap();
+        });
+        bind_addr
+    }
+
+    fn test_timeouts() -> ProxyTimeouts {
+        ProxyTimeouts {
+            connect_secs: 5,
+            header_secs: 5,
+            idle_secs: 5,
+            total_secs: 5,
+        }
+    }
+
+    async fn fetch(
+        response: &'static [u8],
+        max_response_bytes: u64,
+    ) -> Result<(Bytes, Option<HeaderMap>), ProxyError> {
+        let bind_addr = canned_app(response).await;
+        let req = Request::get("/").body(Empty::<Bytes>::new()).unwrap();
+        let limits = BodyLimits::new(1024, max_response_bytes, 1024);
+        let response = forward_request(
+            bind_addr,
+            req,
+            UpstreamProtocol::Http1,
+            limits,
+            test_timeouts(),
+        )
+        .await?;
+        ResponseBody::Upstream(response.into_body()).read().await
+    }
+
+    #[tokio::test]
+    async fn streams_responses_within_the_size_limit() {
+        const CHUNKED: &[u8] = b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\ntrailer: grpc-status\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\ngrpc-status: 0\r\n\r\n";
+
+        let (data, trailers) = fetch(CHUNKED, 64).await.unwrap();
+        assert_eq!(data, "hello world");
+        assert_eq!(trailers.unwrap()["grpc-status"], "0");
+
+        // Too big turns out mid-stream, once the head has gone
+        assert!(matches!(
+            fetch(CHUNKED, 8).await,
+            Err(ProxyError::ResponseTooLarge)
+        ));
+
+        // A length over the limit is refused before anything is sent on
+        let bind_addr = canned_app(b"HTTP/1.1 200 OK\r\ncontent-length: 100\r\n\r\n").await;
+        let req = Request::get("/").body(Empty::<Bytes>::new()).unwrap();
+        let refused = forward_request(
+            bind_addr,
+            req,
+            UpstreamProtocol::Http1,
+            BodyLimits::new(1024, 50, 1024),
+            test_timeouts(),
+        )
+        .await;
+        assert!(matches!(refused, Err(ProxyError::ResponseTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn switches_to_websocket_when_the_app_agrees() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // An app that takes the upgrade, then echoes whatever it's sent
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bind_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            let head = String::from_utf8(head).unwrap().to_ascii_lowercase();
+            assert!(head.contains("upgrade: websocket"));
+            stream
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nconnection: upgrade\r\nupgrade: websocket\r\n\r\n")
+                .await
+                .unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let req = Request::get("/socket")
+            .header(header::HOST, "app.example.com")
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let limits = BodyLimits::new(1024, 1024, 1024);
+        let (response, upgrade) = open_upgrade(bind_addr, req, limits, test_timeouts())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+        let mut app = TokioIo::new(upgrade.unwrap().await.unwrap());
+        app.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        app.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn response_body_keeps_trailers() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let body = ResponseBody::Read {
+            data: Some(Bytes::from_static(b"payload")),
+            trailers: Some(trailers),
+        };
+
+        assert_eq!(body.size_hint().exact(), None);
+        let (data, trailers) = body.read().await.unwrap();
+        assert_eq!(trailers.unwrap()["grpc-status"], "0");
+        assert_eq!(data, "payload");
+    }
+}
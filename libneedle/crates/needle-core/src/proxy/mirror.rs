@@ -0,0 +1,247 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::metrics;
+use crate::proxy::http::forward_request;
+use crate::tunnel::manager::ActiveTunnel;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{Request, StatusCode};
+use needle_db::client::SupabaseClient;
+use needle_db::queries::requests::{self, MirroredRequest};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, oneshot};
+use tracing::{debug, warn};
+
+/// Tells the mirror's app which tunnel a copied request came from, so
+/// it can keep shadow traffic apart from its own.
+pub const MIRROR_HEADER: HeaderName = HeaderName::from_static("x-needle-mirror-of");
+
+/// How many copies of one tunnel's requests can be on their way to its
+/// mirror at once. Past this the mirror is falling behind, and further
+/// copies are dropped rather than piling up.
+pub const MAX_IN_FLIGHT: usize = 32;
+
+/// What the tunnel itself answered, sent along once it's known.
+pub struct Answer {
+    pub status: StatusCode,
+    pub latency: Duration,
+}
+
+/// Whether `target` may take copies of `source`'s requests. It has to be
+/// another of the same owner's tunnels, and speak HTTP.
+pub fn may_mirror_to(source: &ActiveTunnel, target: &ActiveTunnel) -> bool {
+    target.user_id == source.user_id
+        && target.tunnel_id != source.tunnel_id
+        && target.protocol == "http"
+}
+
+/// How a mirror's answer compared with the tunnel's own, as the label on
+/// the mirrored-requests metric: "match" for the same status, "mismatch"
+/// for a different one, and "error" if the mirror didn't answer. Copies
+/// that were never sent are counted as "skipped" instead.
+pub fn outcome(mirror_status: Option<u16>, status: StatusCode) -> &'static str {
+    match mirror_status {
+        Some(mirrored) if mirrored == status.as_u16() => "match",
+        Some(_) => "mismatch",
+        None => "error",
+    }
+}
+
+/// Where a copied request goes and who it's compared against.
+pub struct Mirror<'a> {
+    pub source: &'a ActiveTunnel,
+    pub target: Arc<ActiveTunnel>,
+    /// The mirror's public hostname, for its app's `Host` header.
+    pub host: String,
+    pub client_ip: IpAddr,
+}
+
+/// Claims one of `source`'s mirror slots and one of `target`'s rate
+/// limit tokens for a copy, so mirroring can't flood the mirror past
+/// what it would take from its own visitors. `None` if either is used
+/// up, in which case the copy is counted as skipped.
+fn reserve(source: &ActiveTunnel, target: &ActiveTunnel) -> Option<OwnedSemaphorePermit> {
+    let Ok(permit) = source.mirror_slots.clone().try_acquire_owned() else {
+        debug!(subdomain = %source.subdomain, mirror = %target.subdomain, "mirror saturated, not copying request");
+        metrics::mirrored_request("skipped");
+        return None;
+    };
+    if !target.rate_limiter.check().allowed {
+        debug!(subdomain = %source.subdomain, mirror = %target.subdomain, "mirror rate limited, not copying request");
+        metrics::mirrored_request("skipped");
+        return None;
+    }
+    Some(permit)
+}
+
+/// Sends a copy of a request to a mirror tunnel in the background. The
+/// mirror gets the request the way the edge would send it to its own
+/// app, with its own upstream host, rewrites and limits. Its response is
+/// thrown away, but once the source tunnel's answer comes in through the
+/// returned sender the two go in the source tunnel's inspector log side
+/// by side. `None` if the copy was skipped because the mirror is busy
+/// or out of its rate limit.
+pub fn send(
+    db: SupabaseClient,
+    mirror: Mirror<'_>,
+    mut req: Request<Full<Bytes>>,
+) -> Option<oneshot::Sender<Answer>> {
+    let Mirror {
+        source,
+        target,
+        host,
+        client_ip,
+    } = mirror;
+    let permit = reserve(source, &target)?;
+    let (answered, answer) = oneshot::channel::<Answer>();
+    let tunnel_id = source.tunnel_id.to_string();
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+
+    let host = target.settings.upstream_host.clone().unwrap_or(host);
+    if let Ok(value) = HeaderValue::from_str(&host) {
+        req.headers_mut().insert(header::HOST, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&source.subdomain) {
+        req.headers_mut().insert(MIRROR_HEADER, value);
+    }
+    let rewrites = &target.settings.rewrites;
+    rewrites.apply_request_headers(req.headers_mut());
+    if let Some(uri) = rewrites.rewrite_path(req.uri()) {
+        *req.uri_mut() = uri;
+    }
+
+    tokio::spawn(async move {
+        // Held until the copy is logged, so a slow mirror's slots stay
+        // taken for as long as the copies are really outstanding
+        let _permit = permit;
+        let started = Instant::now();
+        let result = match target.pick_backend() {
+            Some(backend) => forward_request(
+                backend.bind_addr,
+                req,
+                target.settings.upstream_protocol,
+                target.body_limits,
+                target.timeouts,
+            )
+            .await
+            .map(|response| response.status())
+            .map_err(|e| e.to_string()),
+            None => Err("mirror has no backend".to_string()),
+        };
+        let mirror_latency = started.elapsed();
+
+        // The visitor's request went away before it was answered
+        let Ok(answer) = answer.await else {
+            return;
+        };
+
+        let (mirror_status, mirror_error) = match &result {
+            Ok(status) => (Some(status.as_u16()), None),
+            Err(e) => {
+                debug!(subdomain = %target.subdomain, error = %e, "mirrored request failed");
+                (None, Some(e.as_str()))
+            }
+        };
+        metrics::mirrored_request(outcome(mirror_status, answer.status));
+
+        let logged = requests::log_mirrored(
+            &db,
+            &MirroredRequest {
+                tunnel_id: &tunnel_id,
+                method: &method,
+                path: &path,
+                client_ip: &client_ip.to_string(),
+                status_code: answer.status.as_u16(),
+                latency_ms: millis(answer.latency),
+                mirror_subdomain: &target.subdomain,
+                mirror_status_code: mirror_status,
+                mirror_latency_ms: millis(mirror_latency),
+                mirror_error,
+            },
+        )
+        .await;
+        if let Err(e) = logged {
+            warn!(error = %e, "failed to log mirrored request");
+        }
+    });
+
+    Some(answered)
+}
+
+fn millis(elapsed: Duration) -> u32 {
+    u32::try_from(elapsed.as_millis()).unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::manager::TunnelOptions;
+    use crate::tunnel::manager::tests::{manager, named};
+    use uuid::Uuid;
+
+    #[test]
+    fn compares_the_mirror_with_the_real_answer() {
+        assert_eq!(outcome(Some(200), StatusCode::OK), "match");
+        assert_eq!(outcome(Some(500), StatusCode::OK), "mismatch");
+        assert_eq!(outcome(Some(404), StatusCode::BAD_GATEWAY), "mismatch");
+        assert_eq!(outcome(None, StatusCode::OK), "error");
+    }
+
+    #[tokio::test]
+    async fn mirrors_only_to_the_owners_other_http_tunnels() {
+        let mut manager = manager().await;
+        let owner = Uuid::new_v4();
+        let mut open = async |user, options| {
+            manager
+                .create("192.0.2.1", user, options, None)
+                .await
+                .unwrap()
+        };
+
+        let live = open(owner, named("myapp", false)).await;
+        let next = open(owner, named("myapp-next", false)).await;
+        let theirs = open(Uuid::new_v4(), named("their-app", false)).await;
+        let raw = TunnelOptions {
+            protocol: "tcp".to_string(),
+            ..named("myapp-raw", false)
+        };
+        let raw = open(owner, raw).await;
+
+        assert!(may_mirror_to(&live, &next));
+        assert!(!may_mirror_to(&live, &live));
+        assert!(!may_mirror_to(&live, &theirs));
+        assert!(!may_mirror_to(&live, &raw));
+    }
+
+    #[tokio::test]
+    async fn skips_copies_when_the_mirror_is_saturated_or_rate_limited() {
+        let mut manager = manager().await;
+        let owner = Uuid::new_v4();
+        let live = manager
+            .create("192.0.2.1", owner, named("myapp", false), None)
+            .await
+            .unwrap();
+        let next = manager
+            .create("192.0.2.1", owner, named("myapp-next", false), None)
+            .await
+            .unwrap();
+
+        let held = live
+            .mirror_slots
+            .clone()
+            .try_acquire_many_owned(MAX_IN_FLIGHT as u32)
+            .unwrap();
+        assert!(reserve(&live, &next).is_none());
+        drop(held);
+        assert!(reserve(&live, &next).is_some());
+
+        while next.rate_limiter.check().allowed {}
+        assert!(reserve(&live, &next).is_none());
+        assert_eq!(live.mirror_slots.available_permits(), MAX_IN_FLIGHT);
+    }
+}
@@ -1,5 +1,14 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
+pub mod compression;
+pub mod cookie;
+pub mod edge;
+pub mod error_page;
+pub mod forwarded;
 pub mod http;
+pub mod mirror;
+pub mod oidc;
+pub mod passthrough;
+pub mod tls;
 pub mod websocket;
@@ -0,0 +1,843 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::config::NeedleConfig;
+use crate::proxy::cookie::{self, Signer};
+use crate::proxy::http::{error_response, query_param, redirect_response};
+use crate::tunnel::access::OidcRule;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use bytes::Bytes;
+use chrono::Utc;
+use http_body_util::Full;
+use hyper::{HeaderMap, Response, StatusCode, Uri, header};
+use rand::RngCore;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{Url, redirect};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Where providers send visitors back to, on the tunnel's own host.
+pub const CALLBACK_PATH: &str = "/.needle/oidc/callback";
+pub const SESSION_COOKIE: &str = "needle_session";
+pub const LOGIN_COOKIE: &str = "needle_login";
+
+// A visitor has this long to finish signing in at the provider
+const LOGIN_TIMEOUT_SECS: i64 = 10 * 60;
+const DISCOVERY_TTL: Duration = Duration::from_secs(60 * 60);
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum OidcError {
+    #[error("no oidc provider configured")]
+    NoProvider,
+
+    #[error("oidc provider request failed: {0}")]
+    Provider(#[from] reqwest::Error),
+
+    #[error("discovery document is for a different issuer: {0}")]
+    IssuerMismatch(String),
+
+    #[error("provider advertised an unusable endpoint: {0}")]
+    BadEndpoint(String),
+
+    #[error("invalid id token: {0}")]
+    InvalidToken(&'static str),
+}
+
+/// The issuer and client credentials used for one login.
+#[derive(Debug, Clone)]
+struct Provider {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    // Set when a tunnel owner picked the issuer rather than the operator,
+    // in which case we only ever talk to it on public addresses
+    tunnel_owned: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+/// What the session cookie holds once a visitor has signed in. It's tied
+/// to the tunnel ID, so it stops working if the subdomain changes hands.
+#[derive(Serialize, Deserialize)]
+struct Session {
+    tid: Uuid,
+    email: String,
+    exp: i64,
+}
+
+/// Carried in a short-lived cookie across the trip to the provider, so
+/// the callback can check the `state` and `nonce` it gets back.
+#[derive(Serialize, Deserialize)]
+struct LoginState {
+    tid: Uuid,
+    state: String,
+    nonce: String,
+    return_to: String,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct IdClaims {
+    iss: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+impl IdClaims {
+    /// The account's email, as long as the provider vouches for it. A
+    /// token that doesn't say the address is verified could carry any
+    /// address its holder typed in, which would make the allowlist
+    /// meaningless, so leaving the claim out counts as unverified.
+    fn verified_email(self) -> Result<String, OidcError> {
+        if self.email_verified != Some(true) {
+            return Err(OidcError::InvalidToken("email not verified"));
+        }
+        self.email.ok_or(OidcError::InvalidToken("no email claim"))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::One(aud) => aud == client_id,
+            Audience::Many(auds) => auds.iter().any(|aud| aud == client_id),
+        }
+    }
+}
+
+/// Runs the OpenID Connect login flow for tunnels whose access policy
+/// asks for one.
+///
+/// A visitor without a session is sent to the provider's authorization
+/// endpoint; the provider sends them back to [`CALLBACK_PATH`] on the
+/// tunnel's host, where we swap the code for an ID token, check the email
+/// against the tunnel's allowlist and set a signed session cookie. The
+/// cookie has no Domain attribute, so it only works on that one tunnel.
+///
+/// We take the ID token straight from the token endpoint, which is the
+/// case where OIDC lets a client rely on the TLS connection instead of
+/// checking the token's signature. Issuer, audience, expiry and nonce are
+/// all still checked.
+pub struct OidcGate {
+    sessions: Signer,
+    logins: Signer,
+    default_provider: Option<Provider>,
+    scheme: String,
+    session_ttl: i64,
+    http: reqwest::Client,
+    public_http: reqwest::Client,
+    discovery: Mutex<HashMap<String, (Instant, Arc<Discovery>)>>,
+}
+
+impl OidcGate {
+    pub fn new(config: &NeedleConfig) -> Self {
+        let default_provider = config
+            .oidc_issuer_url
+            .clone()
+            .zip(config.oidc_client_id.clone())
+            .map(|(issuer, client_id)| Provider {
+                issuer,
+                client_id,
+                client_secret: config.oidc_client_secret.clone(),
+                tunnel_owned: false,
+            });
+
+        Self::build(
+            &config.jwt_secret,
+            default_provider,
+            &config.public_scheme,
+            config.edge_session_ttl,
+        )
+    }
+
+    fn build(
+        secret: &str,
+        default_provider: Option<Provider>,
+        scheme: &str,
+        session_ttl: Duration,
+    ) -> Self {
+        Self {
+            sessions: Signer::new(secret, "needle-edge-session"),
+            logins: Signer::new(secret, "needle-edge-login"),
+            default_provider,
+            scheme: scheme.to_string(),
+            session_ttl: i64::try_from(session_ttl.as_secs()).unwrap_or(i64::MAX),
+            http: reqwest::Client::builder()
+                .timeout(PROVIDER_TIMEOUT)
+                .build()
+                .expect("http client builds"),
+            public_http: reqwest::Client::builder()
+                .timeout(PROVIDER_TIMEOUT)
+                .dns_resolver(Arc::new(PublicResolver))
+                .redirect(redirect::Policy::none())
+                .build()
+                .expect("http client builds"),
+            discovery: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The signed-in email from the request's session cookie, if it has
+    /// a valid, unexpired one for this tunnel.
+    pub fn session_email(&self, headers: &HeaderMap, tunnel_id: Uuid) -> Option<String> {
+        let token = cookie::get(headers, SESSION_COOKIE)?;
+        let session: Session = self.sessions.verify(token)?;
+        (session.tid == tunnel_id && session.exp > Utc::now().timestamp()).then_some(session.email)
+    }
+
+    /// Sends a visitor off to the provider to sign in, remembering where
+    /// they were headed so the callback can bring them back.
+    pub async fn start_login(
+        &self,
+        rule: &OidcRule,
+        tunnel_id: Uuid,
+        host: &str,
+        uri: &Uri,
+    ) -> Response<Full<Bytes>> {
+        let login = LoginState {
+            tid: tunnel_id,
+            state: random_token(),
+            nonce: random_token(),
+            return_to: safe_return_path(uri),
+            exp: Utc::now().timestamp() + LOGIN_TIMEOUT_SECS,
+        };
+
+        let url = match self.authorization_url(rule, host, &login).await {
+            Ok(url) => url,
+            Err(e) => {
+                warn!(host = %host, error = %e, "could not start oidc login");
+                return error_response(StatusCode::BAD_GATEWAY, "login provider unavailable");
+            }
+        };
+
+        let mut response = redirect_response(url.as_str());
+        response.headers_mut().append(
+            header::SET_COOKIE,
+            cookie::set(
+                LOGIN_COOKIE,
+                &self.logins.sign(&login),
+                LOGIN_TIMEOUT_SECS,
+                self.secure(),
+            ),
+        );
+        response
+    }
+
+    /// Handles the provider's redirect back to the tunnel: checks the
+    /// state, redeems the code, and on success swaps the login cookie for
+    /// a session cookie.
+    pub async fn finish_login(
+        &self,
+        rule: &OidcRule,
+        tunnel_id: Uuid,
+        host: &str,
+        uri: &Uri,
+        headers: &HeaderMap,
+    ) -> Response<Full<Bytes>> {
+        let now = Utc::now().timestamp();
+        let Some(login) = cookie::get(headers, LOGIN_COOKIE)
+            .and_then(|token| self.logins.verify::<LoginState>(token))
+            .filter(|login| login.tid == tunnel_id && login.exp > now)
+        else {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "login expired or started elsewhere, please reload the page",
+            );
+        };
+
+        if let Some(error) = query_param(uri, "error") {
+            warn!(host = %host, error = %error, "oidc provider returned an error");
+            return error_response(StatusCode::FORBIDDEN, "sign-in was cancelled or refused");
+        }
+        let (Some(code), Some(state)) = (query_param(uri, "code"), query_param(uri, "state"))
+        else {
+            return error_response(StatusCode::BAD_REQUEST, "missing code or state");
+        };
+        if state != login.state {
+            return error_response(StatusCode::BAD_REQUEST, "login state mismatch");
+        }
+
+        let email = match self.redeem_code(rule, host, &code, &login.nonce).await {
+            Ok(email) => email,
+            Err(e) => {
+                warn!(host = %host, error = %e, "oidc code exchange failed");
+                return error_response(StatusCode::BAD_GATEWAY, "could not complete sign-in");
+            }
+        };
+
+        if !rule.permits(&email) {
+            info!(host = %host, email = %email, "oidc login for account not on allowlist");
+            return error_response(
+                StatusCode::FORBIDDEN,
+                "your account is not allowed to use this tunnel",
+            );
+        }
+
+        info!(host = %host, email = %email, "oidc login completed");
+        let session = Session {
+            tid: tunnel_id,
+            email,
+            exp: now.saturating_add(self.session_ttl),
+        };
+
+        let mut response = redirect_response(&login.return_to);
+        let headers = response.headers_mut();
+        headers.append(
+            header::SET_COOKIE,
+            cookie::set(
+                SESSION_COOKIE,
+                &self.sessions.sign(&session),
+                self.session_ttl,
+                self.secure(),
+            ),
+        );
+        headers.append(
+            header::SET_COOKIE,
+            cookie::set(LOGIN_COOKIE, "", 0, self.secure()),
+        );
+        response
+    }
+
+    async fn authorization_url(
+        &self,
+        rule: &OidcRule,
+        host: &str,
+        login: &LoginState,
+    ) -> Result<Url, OidcError> {
+        let provider = self.provider(rule)?;
+        let discovery = self.discover(&provider).await?;
+        let redirect_uri = self.redirect_uri(host);
+
+        Url::parse_with_params(
+            &discovery.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("scope", "openid email"),
+                ("client_id", provider.client_id.as_str()),
+                ("redirect_uri", redirect_uri.as_str()),
+                ("state", login.state.as_str()),
+                ("nonce", login.nonce.as_str()),
+            ],
+        )
+        .map_err(|_| OidcError::BadEndpoint(discovery.authorization_endpoint.clone()))
+    }
+
+    /// Swaps an authorization code for an ID token and returns the
+    /// verified email in it.
+    async fn redeem_code(
+        &self,
+        rule: &OidcRule,
+        host: &str,
+        code: &str,
+        nonce: &str,
+    ) -> Result<String, OidcError> {
+        let provider = self.provider(rule)?;
+        let discovery = self.discover(&provider).await?;
+        let redirect_uri = self.redirect_uri(host);
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("client_id", provider.client_id.as_str()),
+        ];
+        if let Some(secret) = &provider.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+
+        let tokens: TokenResponse = self
+            .client(&provider)
+            .post(&discovery.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let claims = decode_claims(&tokens.id_token)?;
+        if claims.iss != discovery.issuer {
+            return Err(OidcError::InvalidToken("issuer mismatch"));
+        }
+        if !claims.aud.contains(&provider.client_id) {
+            return Err(OidcError::InvalidToken("audience mismatch"));
+        }
+        if claims.exp <= Utc::now().timestamp() {
+            return Err(OidcError::InvalidToken("expired"));
+        }
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(OidcError::InvalidToken("nonce mismatch"));
+        }
+        claims.verified_email()
+    }
+
+    /// The tunnel's own provider if it set one, otherwise the server's.
+    fn provider(&self, rule: &OidcRule) -> Result<Provider, OidcError> {
+        match (&rule.issuer, &rule.client_id) {
+            (Some(issuer), Some(client_id)) => Ok(Provider {
+                issuer: issuer.clone(),
+                client_id: client_id.clone(),
+                client_secret: rule.client_secret.clone(),
+                tunnel_owned: true,
+            }),
+            (None, _) => self.default_provider.clone().ok_or(OidcError::NoProvider),
+            (Some(_), None) => Err(OidcError::NoProvider),
+        }
+    }
+
+    fn client(&self, provider: &Provider) -> &reqwest::Client {
+        if provider.tunnel_owned {
+            &self.public_http
+        } else {
+            &self.http
+        }
+    }
+
+    /// Fetches the provider's discovery document, cached for an hour.
+    ///
+    /// The cache isn't locked while we fetch, so one slow provider can't
+    /// hold up logins everywhere else. Two logins racing past an expired
+    /// entry both fetch, and the later one wins, which is harmless.
+    async fn discover(&self, provider: &Provider) -> Result<Arc<Discovery>, OidcError> {
+        let issuer = provider.issuer.as_str();
+        // Rules are checked when they're saved, but ones saved before that
+        // check existed still need it, and IP literals skip the resolver
+        if provider.tunnel_owned && check_public_url(issuer).is_err() {
+            return Err(OidcError::BadEndpoint(issuer.to_string()));
+        }
+        if let Some((fetched, doc)) = self.discovery.lock().await.get(issuer)
+            && fetched.elapsed() < DISCOVERY_TTL
+        {
+            return Ok(doc.clone());
+        }
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        );
+        let doc: Discovery = self
+            .client(provider)
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if doc.issuer.trim_end_matches('/') != issuer.trim_end_matches('/') {
+            return Err(OidcError::IssuerMismatch(doc.issuer));
+        }
+        if provider.tunnel_owned && check_public_url(&doc.token_endpoint).is_err() {
+            return Err(OidcError::BadEndpoint(doc.token_endpoint));
+        }
+
+        let doc = Arc::new(doc);
+        self.discovery
+            .lock()
+            .await
+            .insert(issuer.to_string(), (Instant::now(), doc.clone()));
+        Ok(doc)
+    }
+
+    fn redirect_uri(&self, host: &str) -> String {
+        format!("{}://{}{}", self.scheme, host, CALLBACK_PATH)
+    }
+
+    fn secure(&self) -> bool {
+        self.scheme == "https"
+    }
+}
+
+/// Checks that a URL a tunnel owner gave us is somewhere we're willing
+/// to send requests from inside our network: https, on a host that isn't
+/// this machine, a private range or a cloud metadata address.
+pub fn check_public_url(url: &str) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|_| format!("{url} is not a valid url"))?;
+    if url.scheme() != "https" {
+        return Err("oidc issuer must use https".to_string());
+    }
+    let host = url.host_str().unwrap_or_default();
+    let public = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => is_public_ip(ip),
+        Err(_) => {
+            let name = host.trim_end_matches('.').to_ascii_lowercase();
+            !(name.is_empty()
+                || name == "localhost"
+                || [".localhost", ".local", ".internal"]
+                    .iter()
+                    .any(|suffix| name.ends_with(suffix)))
+        }
+    };
+    if !public {
+        return Err("oidc issuer must be on a public host".to_string());
+    }
+    Ok(())
+}
+
+/// False for addresses that lead back into our own network.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public_ip(IpAddr::V4(v4)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Resolves names like the system does, then drops any private address,
+/// so a public-looking issuer can't point its DNS back at us.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Addrs = Box::new(public_addrs(name.as_str()).await?.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+async fn public_addrs(host: &str) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0))
+        .await?
+        .filter(|addr| is_public_ip(addr.ip()))
+        .collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{host} has no public address"),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Reads the claims out of an ID token without checking its signature;
+/// see [`OidcGate`] for why that's fine here.
+fn decode_claims(id_token: &str) -> Result<IdClaims, OidcError> {
+    let mut parts = id_token.split('.');
+    let (Some(_header), Some(payload), Some(_signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(OidcError::InvalidToken("malformed"));
+    };
+
+    let json = URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .map_err(|_| OidcError::InvalidToken("malformed"))?;
+    serde_json::from_slice(&json).map_err(|_| OidcError::InvalidToken("missing claims"))
+}
+
+/// Where to send the visitor after login: the path they asked for, as
+/// long as it can't be read as a link to some other site.
+fn safe_return_path(uri: &Uri) -> String {
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    let bytes = path.as_bytes();
+    if bytes.first() != Some(&b'/') || matches!(bytes.get(1), Some(b'/' | b'\\')) {
+        return "/".to_string();
+    }
+    path.to_string()
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+
+    const CLIENT_ID: &str = "needle-test";
+
+    /// A tiny issuer that answers discovery and hands out ID tokens for
+    /// alice@example.com. It echoes the code back as the nonce, so tests
+    /// can pass the nonce from the authorization URL as the code.
+    async fn mock_issuer() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+        let base = issuer.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let base = base.clone();
+                let service = service_fn(move |req: hyper::Request<Incoming>| {
+                    let base = base.clone();
+                    async move { Ok::<_, Infallible>(mock_response(&base, req).await) }
+                });
+                tokio::spawn(async move {
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        issuer
+    }
+
+    async fn mock_response(issuer: &str, req: hyper::Request<Incoming>) -> Response<Full<Bytes>> {
+        let body = match req.uri().path() {
+            "/.well-known/openid-configuration" => serde_json::json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{issuer}/authorize"),
+                "token_endpoint": format!("{issuer}/token"),
+            }),
+            "/token" => {
+                let form = req.into_body().collect().await.unwrap().to_bytes();
+                let form = String::from_utf8(form.to_vec()).unwrap();
+                let code = form
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("code="))
+                    .unwrap();
+                let claims = serde_json::json!({
+                    "iss": issuer,
+                    "aud": CLIENT_ID,
+                    "exp": Utc::now().timestamp() + 300,
+                    "nonce": code,
+                    "email": "alice@example.com",
+                    "email_verified": true,
+                });
+                let id_token = format!(
+                    "{}.{}.",
+                    URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#),
+                    URL_SAFE_NO_PAD.encode(claims.to_string())
+                );
+                serde_json::json!({ "id_token": id_token, "token_type": "Bearer" })
+            }
+            _ => return error_response(StatusCode::NOT_FOUND, "not found"),
+        };
+
+        Response::new(Full::new(Bytes::from(body.to_string())))
+    }
+
+    fn set_cookie_value(response: &Response<Full<Bytes>>, name: &str) -> String {
+        response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .find_map(|v| v.strip_prefix(&format!("{name}=")))
+            .and_then(|v| v.split(';').next())
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn logs_in_against_a_mock_issuer() {
+        let issuer = mock_issuer().await;
+        let gate = OidcGate::build(
+            "test-secret",
+            Some(Provider {
+                issuer: issuer.clone(),
+                client_id: CLIENT_ID.to_string(),
+                client_secret: Some("shh".to_string()),
+                tunnel_owned: false,
+            }),
+            "http",
+            Duration::from_secs(3600),
+        );
+        let rule = OidcRule {
+            allowed_domains: vec!["example.com".to_string()],
+            ..OidcRule::default()
+        };
+        let tunnel_id = Uuid::new_v4();
+        let host = "preview.localhost:8080";
+
+        let start = gate
+            .start_login(&rule, tunnel_id, host, &"/dashboard?tab=2".parse().unwrap())
+            .await;
+        assert_eq!(start.status(), StatusCode::FOUND);
+        let location: Url = start.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(
+            location
+                .as_str()
+                .starts_with(&format!("{issuer}/authorize"))
+        );
+        let param = |name: &str| {
+            location
+                .query_pairs()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.into_owned())
+                .unwrap()
+        };
+        assert_eq!(
+            param("redirect_uri"),
+            "http://preview.localhost:8080/.needle/oidc/callback"
+        );
+
+        let mut headers = HeaderMap::new();
+        let login_cookie = set_cookie_value(&start, LOGIN_COOKIE);
+        headers.insert(
+            header::COOKIE,
+            format!("{LOGIN_COOKIE}={login_cookie}").parse().unwrap(),
+        );
+        let callback: Uri = format!(
+            "{CALLBACK_PATH}?code={}&state={}",
+            param("nonce"),
+            param("state")
+        )
+        .parse()
+        .unwrap();
+
+        let done = gate
+            .finish_login(&rule, tunnel_id, host, &callback, &headers)
+            .await;
+        assert_eq!(done.status(), StatusCode::FOUND);
+        assert_eq!(done.headers()[header::LOCATION], "/dashboard?tab=2");
+
+        let session = set_cookie_value(&done, SESSION_COOKIE);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            format!("{SESSION_COOKIE}={session}").parse().unwrap(),
+        );
+        assert_eq!(
+            gate.session_email(&headers, tunnel_id).as_deref(),
+            Some("alice@example.com")
+        );
+        assert!(gate.session_email(&headers, Uuid::new_v4()).is_none());
+
+        // A forged state is turned away before we ever talk to the issuer
+        let forged: Uri = format!("{CALLBACK_PATH}?code=x&state=nope")
+            .parse()
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            format!("{LOGIN_COOKIE}={login_cookie}").parse().unwrap(),
+        );
+        let rejected = gate
+            .finish_login(&rule, tunnel_id, host, &forged, &headers)
+            .await;
+        assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn only_verified_emails_get_through() {
+        let claims = |verified: Option<bool>| IdClaims {
+            iss: "https://issuer.example".to_string(),
+            aud: Audience::One(CLIENT_ID.to_string()),
+            exp: 0,
+            nonce: None,
+            email: Some("alice@example.com".to_string()),
+            email_verified: verified,
+        };
+        assert_eq!(
+            claims(Some(true)).verified_email().unwrap(),
+            "alice@example.com"
+        );
+        assert!(claims(Some(false)).verified_email().is_err());
+        // Providers that leave the claim out don't get the benefit of
+        // the doubt
+        assert!(claims(None).verified_email().is_err());
+    }
+
+    #[tokio::test]
+    async fn tunnel_issuers_must_be_public() {
+        for url in [
+            "http://accounts.example.com",
+            "https://localhost",
+            "https://idp.corp.internal",
+            "https://127.0.0.1",
+            "https://10.1.2.3",
+            "https://169.254.169.254",
+            "https://100.100.100.200",
+            "https://[::1]",
+            "https://[fd00::1]",
+            "https://[::ffff:192.168.1.1]",
+            "not a url",
+        ] {
+            assert!(check_public_url(url).is_err(), "{url} should be refused");
+        }
+        assert!(check_public_url("https://accounts.example.com").is_ok());
+        assert!(check_public_url("https://8.8.8.8/realms/x").is_ok());
+        assert!(public_addrs("localhost").await.is_err());
+
+        // A rule saved before issuers were checked still can't make us
+        // fetch from the loopback interface
+        let issuer = mock_issuer().await;
+        let gate = OidcGate::build("test-secret", None, "http", Duration::from_secs(3600));
+        let rule = OidcRule {
+            issuer: Some(issuer),
+            client_id: Some(CLIENT_ID.to_string()),
+            allowed_domains: vec!["example.com".to_string()],
+            ..OidcRule::default()
+        };
+        assert!(rule.validate().is_err());
+        let start = gate
+            .start_login(
+                &rule,
+                Uuid::new_v4(),
+                "preview.localhost",
+                &"/".parse().unwrap(),
+            )
+            .await;
+        assert_eq!(start.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn return_path_stays_on_the_tunnel() {
+        let path = |s: &str| safe_return_path(&s.parse().unwrap());
+        assert_eq!(path("/app?x=1"), "/app?x=1");
+        assert_eq!(path("//evil.example/"), "/");
+        assert_eq!(path("/\\evil.example/"), "/");
+    }
+}
@@ -0,0 +1,262 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::tunnel::manager::ActiveTunnel;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The tunnel protocol whose TLS the edge passes through untouched, so
+/// the developer's app terminates it with its own certificate.
+pub const PROTOCOL: &str = "tls";
+
+// Content type, version and length in front of every TLS record
+const RECORD_HEADER_LEN: usize = 5;
+const HANDSHAKE_RECORD: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME_EXTENSION: u16 = 0x0000;
+const HOST_NAME: u8 = 0x00;
+// The most a TLS record may carry
+const MAX_RECORD_LEN: usize = 16 * 1024;
+
+/// Reads the first TLS record off a new connection, which holds the
+/// ClientHello. A connection that doesn't start with a handshake
+/// record, like plain HTTP, costs us a single byte. Either way the
+/// bytes come back so the connection can be replayed with [`Replay`].
+pub async fn read_first_record<S>(stream: &mut S) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut record = vec![0; RECORD_HEADER_LEN];
+    stream.read_exact(&mut record[..1]).await?;
+    if record[0] != HANDSHAKE_RECORD {
+        record.truncate(1);
+        return Ok(record);
+    }
+
+    stream.read_exact(&mut record[1..]).await?;
+    let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+    // Not a record we can make sense of; the TLS acceptor can say so
+    if len > MAX_RECORD_LEN {
+        return Ok(record);
+    }
+    record.resize(RECORD_HEADER_LEN + len, 0);
+    stream.read_exact(&mut record[RECORD_HEADER_LEN..]).await?;
+    Ok(record)
+}
+
+/// Pulls the hostname out of the server name extension of a ClientHello
+/// record. Returns `None` for anything else, for a hello without SNI,
+/// and for one split across records, which only happens with hellos far
+/// bigger than any browser sends.
+pub fn server_name(record: &[u8]) -> Option<String> {
+    let mut r = Reader(record);
+    if r.u8()? != HANDSHAKE_RECORD {
+        return None;
+    }
+    r.take(4)?; // version and length
+    if r.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    let mut hello = Reader(r.take_u24()?);
+    hello.take(2 + 32)?; // version and random
+    hello.take_u8()?; // session id
+    hello.take_u16()?; // cipher suites
+    hello.take_u8()?; // compression methods
+
+    let mut extensions = Reader(hello.take_u16()?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.take_u16()?;
+        if kind != SERVER_NAME_EXTENSION {
+            continue;
+        }
+
+        let mut names = Reader(Reader(data).take_u16()?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.take_u16()?;
+            if name_type == HOST_NAME {
+                let name = std::str::from_utf8(name).ok()?;
+                return Some(name.to_ascii_lowercase());
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// Splices a visitor's connection to a passthrough tunnel's app over a
+/// fresh SSH channel, starting with the ClientHello we already read.
+pub async fn splice<S>(
+    tunnel: &ActiveTunnel,
+    peer_addr: SocketAddr,
+    mut stream: S,
+    hello: &[u8],
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(link) = &tunnel.session else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "tunnel has no ssh client",
+        ));
+    };
+    let channel = link
+        .open_channel(peer_addr)
+        .await
+        .map_err(io::Error::other)?;
+    let mut upstream = channel.into_stream();
+    upstream.write_all(hello).await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+    Ok(())
+}
+
+/// A stream that gives back bytes already read off it before carrying
+/// on with the rest, so sniffing a connection doesn't cost its reader
+/// anything.
+pub struct Replay<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Replay<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Replay<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.pos < this.prefix.len() {
+            let rest = &this.prefix[this.pos..];
+            let n = rest.len().min(buf.remaining());
+            buf.put_slice(&rest[..n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Replay<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Walks a TLS message front to back; every read is bounds checked.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.take(2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    // The length-prefixed vectors TLS is built from
+    fn take_u8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn take_u16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    fn take_u24(&mut self) -> Option<&'a [u8]> {
+        let b = self.take(3)?;
+        let len = u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    fn client_hello(name: &str) -> Vec<u8> {
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let name = name.to_string().try_into().unwrap();
+        let mut conn = ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut hello = Vec::new();
+        conn.write_tls(&mut hello).unwrap();
+        hello
+    }
+
+    #[tokio::test]
+    async fn reads_sni_and_replays_what_it_read() {
+        let hello = client_hello("Secure-App.example.com");
+        let mut stream = hello.clone();
+        stream.extend_from_slice(b"after");
+
+        let mut reader = &stream[..];
+        let record = read_first_record(&mut reader).await.unwrap();
+        assert_eq!(record, hello);
+        assert_eq!(
+            server_name(&record).as_deref(),
+            Some("secure-app.example.com")
+        );
+
+        let mut replayed = Vec::new();
+        Replay::new(record, tokio::io::empty())
+            .read_to_end(&mut replayed)
+            .await
+            .unwrap();
+        assert_eq!(replayed, hello);
+
+        // Plain HTTP costs one byte and has no name; IP literals send none
+        let mut reader = &b"GET / HTTP/1.1\r\n"[..];
+        let record = read_first_record(&mut reader).await.unwrap();
+        assert_eq!(record, b"G");
+        assert_eq!(server_name(&record), None);
+        assert_eq!(server_name(&client_hello("192.0.2.1")), None);
+        assert_eq!(server_name(&hello[..hello.len() - 1]), None);
+    }
+}
@@ -0,0 +1,39 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::io;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::ring;
+
+// Offered to clients in ALPN, best first
+const ALPN_PROTOCOLS: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+/// Builds the edge's TLS acceptor from a PEM certificate chain and
+/// private key. Clients that offer `h2` in ALPN, which is every browser
+/// and gRPC client, get HTTP/2; everyone else gets HTTP/1.1.
+pub fn load_acceptor(cert_path: &str, key_path: &str) -> io::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(format!("failed to read certificates from {cert_path}: {e}")))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates found in {cert_path}")));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| invalid(format!("failed to read private key from {key_path}: {e}")))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| invalid(format!("invalid edge tls configuration: {e}")))?;
+    config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|p| p.to_vec()).collect();
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
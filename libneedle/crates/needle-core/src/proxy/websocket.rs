@@ -1,124 +1,176 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
-use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::time::{Duration, timeout};
+use crate::config::ProxyTimeouts;
+use hyper::header::HeaderValue;
+use hyper::{Request, Version, header};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
 use tracing::{debug, info};
 
-const WS_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
-const WS_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
-const WS_MAX_TRANSFER: usize = 100 * 1024 * 1024; // 100MB per session
+/// True for an HTTP/1.1 request asking to switch to WebSocket. The
+/// edge hands these to `bridge` instead of forwarding them like any
+/// other request.
+pub fn is_upgrade<B>(req: &Request<B>) -> bool {
+    req.version() == Version::HTTP_11
+        && header_has_token(req.headers().get(header::CONNECTION), "upgrade")
+        && header_has_token(req.headers().get(header::UPGRADE), "websocket")
+}
+
+/// Puts back the two headers that ask the app to switch protocols,
+/// which the edge strips from every request along with the rest of the
+/// hop-by-hop headers.
+pub fn restore_upgrade_headers<B>(req: &mut Request<B>) {
+    let headers = req.headers_mut();
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+}
+
+fn header_has_token(value: Option<&HeaderValue>, token: &str) -> bool {
+    value
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
 
 /// Bridges a WebSocket connection between the external client and the
-/// tunnel's local listener.
+/// tunnel's local listener, once both sides have switched protocols.
 ///
 /// Unlike normal HTTP proxying, WebSocket needs bidirectional byte
-/// copying that runs until one side closes. We track total bytes
-/// transferred and cut things off if the session exceeds our limit.
+/// copying that runs until one side closes. We track the bytes going
+/// each way and cut things off once either passes `max_transfer`, the
+/// tunnel's `max_websocket_bytes`.
 ///
 /// The idle timeout catches abandoned connections -- if neither side
-/// sends anything for 5 minutes, we assume the session is dead and
-/// clean up.
-pub async fn bridge(
-    bind_addr: SocketAddr,
-    client_stream: TcpStream,
-) -> Result<WebSocketStats, WebSocketError> {
-    let tunnel_stream = timeout(WS_CONNECT_TIMEOUT, TcpStream::connect(bind_addr))
-        .await
-        .map_err(|_| WebSocketError::ConnectTimeout)?
-        .map_err(WebSocketError::Connect)?;
-
-    let (mut tunnel_read, mut tunnel_write) = tunnel_stream.into_split();
-    let (mut client_read, mut client_write) = client_stream.into_split();
-
-    let upstream = tokio::spawn(async move {
-        let mut buf = [0u8; 8192];
-        let mut bytes = 0usize;
-
-        loop {
-            let read_result = timeout(WS_IDLE_TIMEOUT, client_read.read(&mut buf)).await;
-
-            match read_result {
-                Ok(Ok(0)) => break,
-                Ok(Ok(n)) => {
-                    bytes += n;
-                    if bytes > WS_MAX_TRANSFER {
-                        debug!("websocket upstream transfer limit reached");
-                        break;
-                    }
-                    if tunnel_write.write_all(&buf[..n]).await.is_err() {
-                        break;
-                    }
-                }
-                Ok(Err(_)) => break,
-                Err(_) => {
-                    debug!("websocket upstream idle timeout");
-                    break;
-                }
+/// sends anything for the tunnel's total timeout, we assume the session
+/// is dead and clean up. A WebSocket has no end to time, and its quiet
+/// spells run far longer than a stalled HTTP body, so the body idle
+/// timeout would be too eager here.
+pub async fn bridge<C, T>(
+    client: C,
+    tunnel: T,
+    timeouts: ProxyTimeouts,
+    max_transfer: u64,
+) -> WebSocketStats
+where
+    C: AsyncRead + AsyncWrite,
+    T: AsyncRead + AsyncWrite,
+{
+    let idle = timeouts.total();
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut tunnel_read, mut tunnel_write) = tokio::io::split(tunnel);
+    let mut up = [0u8; 8192];
+    let mut down = [0u8; 8192];
+    let mut stats = WebSocketStats::default();
+
+    // Reads are safe to abandon mid-way, so waiting on both sides at once
+    // loses nothing; whichever has data goes first
+    loop {
+        let next = timeout(idle, async {
+            tokio::select! {
+                read = client_read.read(&mut up) => (true, read),
+                read = tunnel_read.read(&mut down) => (false, read),
             }
-        }
-
-        bytes
-    });
-
-    let downstream = tokio::spawn(async move {
-        let mut buf = [0u8; 8192];
-        let mut bytes = 0usize;
-
-        loop {
-            let read_result = timeout(WS_IDLE_TIMEOUT, tunnel_read.read(&mut buf)).await;
-
-            match read_result {
-                Ok(Ok(0)) => break,
-                Ok(Ok(n)) => {
-                    bytes += n;
-                    if bytes > WS_MAX_TRANSFER {
-                        debug!("websocket downstream transfer limit reached");
-                        break;
-                    }
-                    if client_write.write_all(&buf[..n]).await.is_err() {
-                        break;
-                    }
-                }
-                Ok(Err(_)) => break,
-                Err(_) => {
-                    debug!("websocket downstream idle timeout");
-                    break;
-                }
+        })
+        .await;
+
+        let (upstream, n) = match next {
+            Ok((upstream, Ok(n))) if n > 0 => (upstream, n),
+            Ok(_) => break,
+            Err(_) => {
+                debug!("websocket idle timeout");
+                break;
             }
+        };
+        let bytes = if upstream {
+            &mut stats.bytes_up
+        } else {
+            &mut stats.bytes_down
+        };
+        *bytes += n;
+        if *bytes as u64 > max_transfer {
+            debug!(upstream, "websocket transfer limit reached");
+            stats.hit_limit = true;
+            break;
         }
+        let written = if upstream {
+            timeout(idle, tunnel_write.write_all(&up[..n])).await
+        } else {
+            timeout(idle, client_write.write_all(&down[..n])).await
+        };
+        if !matches!(written, Ok(Ok(()))) {
+            break;
+        }
+    }
 
-        bytes
-    });
-
-    let total_up = upstream.await.unwrap_or(0);
-    let total_down = downstream.await.unwrap_or(0);
+    let _ = client_write.shutdown().await;
+    let _ = tunnel_write.shutdown().await;
 
     info!(
-        up_bytes = total_up,
-        down_bytes = total_down,
+        up_bytes = stats.bytes_up,
+        down_bytes = stats.bytes_down,
         "websocket session ended"
     );
 
-    Ok(WebSocketStats {
-        bytes_up: total_up,
-        bytes_down: total_down,
-    })
+    stats
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct WebSocketStats {
     pub bytes_up: usize,
     pub bytes_down: usize,
+    /// Whether the session was cut off for passing `max_transfer`.
+    pub hit_limit: bool,
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum WebSocketError {
-    #[error("timed out connecting to tunnel for websocket")]
-    ConnectTimeout,
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    #[error("failed to connect to tunnel for websocket: {0}")]
-    Connect(std::io::Error),
+    fn timeouts() -> ProxyTimeouts {
+        ProxyTimeouts {
+            connect_secs: 1,
+            header_secs: 1,
+            idle_secs: 1,
+            total_secs: 1,
+        }
+    }
+
+    #[test]
+    fn recognizes_websocket_upgrades() {
+        let upgrade = |connection: &str, upgrade: &str| {
+            let req = Request::get("/socket")
+                .header(header::CONNECTION, connection)
+                .header(header::UPGRADE, upgrade)
+                .body(())
+                .unwrap();
+            is_upgrade(&req)
+        };
+        assert!(upgrade("Upgrade", "websocket"));
+        assert!(upgrade("keep-alive, upgrade", "WebSocket"));
+        assert!(!upgrade("keep-alive", "websocket"));
+        assert!(!upgrade("upgrade", "h2c"));
+    }
+
+    #[tokio::test]
+    async fn copies_both_ways_and_stops_at_the_limit() {
+        let (client, mut visitor) = tokio::io::duplex(64);
+        let (tunnel, mut app) = tokio::io::duplex(64);
+        let session = tokio::spawn(bridge(client, tunnel, timeouts(), 10));
+
+        visitor.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        app.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        app.write_all(b"pong").await.unwrap();
+        visitor.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // Another eight bytes up takes the visitor past ten
+        visitor.write_all(b"too much").await.unwrap();
+        let stats = session.await.unwrap();
+        assert!(stats.hit_limit);
+        assert_eq!(stats.bytes_down, 4);
+        assert_eq!(app.read(&mut buf).await.unwrap(), 0);
+    }
 }
@@ -1,15 +1,23 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
+use crate::abuse::{AbuseTracker, Offense};
 use crate::metrics;
-use crate::tunnel::manager::TunnelManager;
+use crate::proxy::passthrough;
+use crate::tunnel::access::{AccessPolicy, OidcRule, PeerRule};
+use crate::tunnel::manager::{TunnelLookup, TunnelManager, TunnelOptions};
+use crate::tunnel::private;
+use crate::tunnel::udp;
 use async_trait::async_trait;
+use needle_common::cidr::Cidr;
 use needle_common::error::NeedleError;
-use russh::server::{Auth, Handler, Msg, Session};
-use russh::{Channel, ChannelId};
+use russh::server::{Auth, Handle, Handler, Msg, Session};
+use russh::{Channel, ChannelId, CryptoVec};
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::{Mutex, RwLock};
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
@@ -17,6 +25,81 @@ use uuid::Uuid;
 const RESERVED_PORTS: &[u16] = &[22, 80, 443];
 const MIN_ALLOWED_PORT: u16 = 1024;
 
+// Bind addresses that just mean "anywhere" rather than naming a subdomain
+const WILDCARD_ADDRESSES: &[&str] = &["", "*", "localhost", "0.0.0.0", "::", "127.0.0.1"];
+
+// Protocols a bind address can ask for, as in `udp+dns` or `tls+app`
+const ADDRESS_PROTOCOLS: &[&str] = &[udp::PROTOCOL, passthrough::PROTOCOL, private::PROTOCOL];
+
+/// Identifies one remote forward by the (address, port) pair the client
+/// bound it to. This is exactly what the client quotes back in a
+/// cancel-tcpip-forward request, so it's what we key teardown on.
+pub type ForwardKey = (String, u32);
+
+/// Live forwards for one SSH connection, mapping each bound
+/// (address, port) to the subdomain we allocated for it. Shared with
+/// the accept loop in `ssh::server` so it can release whatever is left
+/// once the connection finishes.
+pub type SessionForwards = Arc<Mutex<HashMap<ForwardKey, String>>>;
+
+/// The session channel status messages go to, if the client opened one.
+type SharedChannel = Arc<std::sync::Mutex<Option<ChannelId>>>;
+
+/// A way back to the SSH client behind a tunnel, for code that runs
+/// outside the russh handler (like the expiry reaper) and still needs to
+/// tell the client something or update its forward table.
+#[derive(Clone)]
+pub struct SessionLink {
+    handle: Handle,
+    session_channel: SharedChannel,
+    forwards: SessionForwards,
+    // The forward this tunnel came in on, quoted back when opening channels
+    forward: ForwardKey,
+}
+
+impl SessionLink {
+    /// Writes a status line to the client's session channel. Clients
+    /// running `ssh -N` have no channel, so the message is dropped.
+    pub async fn notify(&self, msg: &str) {
+        let Some(channel) = *self.session_channel.lock().unwrap() else {
+            return;
+        };
+
+        let data = CryptoVec::from_slice(msg.as_bytes());
+        if self.handle.data(channel, data).await.is_err() {
+            debug!(channel = %channel, "ssh client went away before notification");
+        }
+    }
+
+    /// Opens a forwarded-tcpip channel back to the client for traffic from
+    /// `originator`, addressed to the forward the tunnel came in on so the
+    /// client knows which local port it's for.
+    pub async fn open_channel(&self, originator: SocketAddr) -> Result<Channel<Msg>, russh::Error> {
+        let (address, port) = self.forward.clone();
+        self.handle
+            .channel_open_forwarded_tcpip(
+                address,
+                port,
+                originator.ip().to_string(),
+                originator.port() as u32,
+            )
+            .await
+    }
+
+    /// Drops a tunnel that was closed server-side from the connection's
+    /// forward table so disconnect cleanup doesn't try to release it again.
+    pub async fn forget(&self, subdomain: &str) {
+        self.forwards.lock().await.retain(|_, sub| sub != subdomain);
+    }
+
+    /// Whether this link is for `forward` on the connection that owns
+    /// `forwards`, which is how a load-balanced tunnel tells its clients
+    /// apart when one of them leaves.
+    pub fn is_forward(&self, forwards: &SessionForwards, forward: &ForwardKey) -> bool {
+        Arc::ptr_eq(&self.forwards, forwards) && self.forward == *forward
+    }
+}
+
 /// Handles one SSH client connection. Each connecting client gets its own
 /// SshSession instance which lives for the duration of that connection.
 ///
@@ -24,7 +107,8 @@ const MIN_ALLOWED_PORT: u16 = 1024;
 /// 1. Client connects and authenticates via API key in username
 ///    Format: user_<API_KEY> where API_KEY is a 64-char hex string
 /// 2. We validate the API key against the database
-/// 3. Client requests a tcpip-forward for some port
+/// 3. Client requests a tcpip-forward for some port, optionally naming
+///    the subdomain it wants in the bind address
 /// 4. We validate the port is allowed (>= 1024, not reserved)
 /// 5. We create a tunnel in the TunnelManager, which gives us a local
 ///    TCP listener on 127.0.0.1
@@ -33,23 +117,104 @@ const MIN_ALLOWED_PORT: u16 = 1024;
 ///    and forth through the SSH channel
 pub struct SshSession {
     tunnel_manager: Arc<RwLock<TunnelManager>>,
+    abuse: Arc<AbuseTracker>,
+    peer_ip: IpAddr,
     client_ip: String,
     user_id: Option<Uuid>,
-    allocated_subdomains: Vec<String>,
-    channels: HashMap<ChannelId, String>,
+    forwards: SessionForwards,
+    session_channel: SharedChannel,
+    requested_ttl: Option<Duration>,
+    access: AccessPolicy,
 }
 
 impl SshSession {
-    pub fn new(tunnel_manager: Arc<RwLock<TunnelManager>>, client_ip: String) -> Self {
+    pub fn new(
+        tunnel_manager: Arc<RwLock<TunnelManager>>,
+        abuse: Arc<AbuseTracker>,
+        peer_ip: IpAddr,
+    ) -> Self {
         Self {
             tunnel_manager,
-            client_ip,
+            abuse,
+            peer_ip,
+            client_ip: peer_ip.to_string(),
             user_id: None,
-            allocated_subdomains: Vec::new(),
-            channels: HashMap::new(),
+            forwards: Arc::new(Mutex::new(HashMap::new())),
+            session_channel: Arc::new(std::sync::Mutex::new(None)),
+            requested_ttl: None,
+            access: AccessPolicy::default(),
         }
     }
 
+    fn link(&self, session: &Session, forward: ForwardKey) -> SessionLink {
+        SessionLink {
+            handle: session.handle(),
+            session_channel: self.session_channel.clone(),
+            forwards: self.forwards.clone(),
+            forward,
+        }
+    }
+
+    fn current_channel(&self) -> Option<ChannelId> {
+        *self.session_channel.lock().unwrap()
+    }
+
+    /// Sets the TTL for future forwards and restarts the clock on the
+    /// ones already open.
+    async fn apply_ttl(
+        &mut self,
+        user_id: Uuid,
+        value: &str,
+        channel: ChannelId,
+        session: &mut Session,
+    ) {
+        let Some(ttl) = parse_ttl(value) else {
+            warn!(value = %value, "ignoring invalid NEEDLE_TTL");
+            Self::send_message(session, channel, "invalid NEEDLE_TTL, ignoring\r\n").await;
+            return;
+        };
+        self.requested_ttl = Some(ttl);
+
+        let subdomains: Vec<String> = self.forwards.lock().await.values().cloned().collect();
+        let mut manager = self.tunnel_manager.write().await;
+        for sub in subdomains {
+            match manager.set_ttl(&sub, user_id, ttl).await {
+                Ok(Some(expires_at)) => {
+                    let msg = format!("tunnel {sub} expires at {}\r\n", expires_at.to_rfc3339());
+                    Self::send_message(session, channel, &msg).await;
+                }
+                Ok(None) => {}
+                Err(e) => warn!(subdomain = %sub, error = %e, "failed to apply tunnel ttl"),
+            }
+        }
+    }
+
+    /// Pushes the connection's current access policy to every open forward.
+    async fn apply_access(&mut self, user_id: Uuid, channel: ChannelId, session: &mut Session) {
+        let subdomains: Vec<String> = self.forwards.lock().await.values().cloned().collect();
+        let mut manager = self.tunnel_manager.write().await;
+        for sub in subdomains {
+            match manager.set_access(&sub, user_id, self.access.clone()).await {
+                Ok(()) => {
+                    let msg = format!("tunnel {sub} access policy updated\r\n");
+                    Self::send_message(session, channel, &msg).await;
+                }
+                Err(e) => {
+                    warn!(subdomain = %sub, error = %e, "failed to apply access policy");
+                    let msg = format!("tunnel {sub} access policy not applied: {e}\r\n");
+                    Self::send_message(session, channel, &msg).await;
+                }
+            }
+        }
+    }
+
+    /// Returns a handle to this connection's forward table. The SSH
+    /// accept loop holds on to it so it can call [`release_forwards`]
+    /// after the session future resolves.
+    pub fn forwards(&self) -> SessionForwards {
+        self.forwards.clone()
+    }
+
     /// Sends a text message back to the client through their SSH channel.
     /// We use this to communicate tunnel URLs, errors, and status info
     /// since the client might be using a plain ssh command without our CLI.
@@ -128,24 +293,112 @@ impl SshSession {
 
         Ok(port)
     }
+
+    /// Pulls the requested subdomain and protocol out of a tcpip-forward
+    /// bind address. `ssh -R myapp:80:localhost:3000` asks for `myapp`,
+    /// which is also how a persistent tunnel gets reattached after a
+    /// reconnect. Wildcard addresses get a generated name instead. A
+    /// protocol prefix asks for something other than HTTP: `udp+dns` for
+    /// a UDP tunnel, `tls+app` for TLS passthrough, `private+db` for one
+    /// only reachable over SSH, or a bare `udp`, `tls` or `private` for
+    /// one with a generated name.
+    fn requested_tunnel(address: &str) -> Result<(Option<String>, &'static str), NeedleError> {
+        let (protocol, name) = address.split_once('+').unwrap_or((address, ""));
+        let (name, protocol) = match ADDRESS_PROTOCOLS.iter().find(|p| **p == protocol) {
+            Some(protocol) => (name, *protocol),
+            None => (address, "http"),
+        };
+
+        if WILDCARD_ADDRESSES.contains(&name) {
+            return Ok((None, protocol));
+        }
+
+        if !needle_common::subdomain::is_valid_custom(name) {
+            return Err(NeedleError::InvalidSubdomain(name.to_string()));
+        }
+
+        Ok((Some(name.to_string()), protocol))
+    }
 }
 
-impl Drop for SshSession {
-    fn drop(&mut self) {
-        let manager = self.tunnel_manager.clone();
-        let subdomains = self.allocated_subdomains.clone();
+/// Folds one NEEDLE_* access variable into the policy being built up.
+/// IP lists are comma-separated CIDRs; NEEDLE_BASIC_AUTH is `user:pass`;
+/// NEEDLE_OIDC_ALLOW lists the emails and domains that may sign in with
+/// the server's OIDC provider.
+fn update_access(policy: &mut AccessPolicy, name: &str, value: &str) -> Result<(), String> {
+    match name {
+        "NEEDLE_BASIC_AUTH" => {
+            let (user, pass) = value
+                .split_once(':')
+                .filter(|(user, pass)| !user.is_empty() && !pass.is_empty())
+                .ok_or("expected user:password")?;
+            policy.set_basic_auth(user, pass);
+        }
+        "NEEDLE_BEARER_TOKEN" if !value.is_empty() => policy.set_bearer_token(value),
+        "NEEDLE_BEARER_TOKEN" => return Err("token cannot be empty".to_string()),
+        "NEEDLE_ALLOW_IPS" => policy.allow = parse_cidr_list(value)?,
+        "NEEDLE_DENY_IPS" => policy.deny = parse_cidr_list(value)?,
+        "NEEDLE_OIDC_ALLOW" => policy.oidc = Some(parse_oidc_allow(value)),
+        "NEEDLE_PEER_ALLOW" => policy.peers = Some(PeerRule::from_entries(value.split(','))),
+        _ => {}
+    }
+    Ok(())
+}
 
-        tokio::spawn(async move {
-            let mut mgr = manager.write().await;
-            for sub in subdomains {
-                if let Err(e) = mgr.remove(&sub).await {
-                    error!(subdomain = %sub, error = %e, "failed to clean up tunnel on disconnect");
-                }
-            }
-        });
+fn parse_cidr_list(value: &str) -> Result<Vec<Cidr>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Splits `alice@example.com,example.org,@example.net` into exact
+/// addresses and whole domains. A leading `@` marks a domain too.
+fn parse_oidc_allow(value: &str) -> OidcRule {
+    let PeerRule {
+        allowed_emails,
+        allowed_domains,
+    } = PeerRule::from_entries(value.split(','));
+    OidcRule {
+        allowed_emails,
+        allowed_domains,
+        ..OidcRule::default()
     }
 }
 
+/// Turns the host in a direct-tcpip request into a tunnel name. Peers
+/// can give the bare name or the full hostname under our domain.
+fn private_tunnel_name(host: &str, domain: &str) -> String {
+    let host = host.to_ascii_lowercase();
+    host.strip_suffix(domain)
+        .and_then(|name| name.strip_suffix('.'))
+        .map(str::to_string)
+        .unwrap_or(host)
+}
+
+/// Parses a NEEDLE_TTL value: plain seconds, or a number with an
+/// s/m/h/d suffix (`90`, `30m`, `2h`, `1d`). Zero isn't a lifetime.
+fn parse_ttl(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (digits, unit) = match value.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => (&value[..i], c.to_ascii_lowercase()),
+        _ => (value, 's'),
+    };
+
+    let n: u64 = digits.parse().ok()?;
+    let secs = match unit {
+        's' => n,
+        'm' => n.checked_mul(60)?,
+        'h' => n.checked_mul(60 * 60)?,
+        'd' => n.checked_mul(24 * 60 * 60)?,
+        _ => return None,
+    };
+
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
 #[async_trait]
 impl Handler for SshSession {
     type Error = russh::Error;
@@ -153,6 +406,9 @@ impl Handler for SshSession {
     /// Validates API key from username field.
     /// Expected format: user_<API_KEY> where API_KEY is a 64-char hex string.
     /// The key is hashed with SHA-256 and validated against the database.
+    ///
+    /// Failures count against the client's IP; once it's banned we drop
+    /// the connection instead of letting it keep guessing.
     async fn auth_publickey(
         &mut self,
         user: &str,
@@ -160,6 +416,11 @@ impl Handler for SshSession {
     ) -> Result<Auth, Self::Error> {
         info!(user = %user, ip = %self.client_ip, "ssh auth attempt");
 
+        if self.abuse.check(self.peer_ip).is_err() {
+            metrics::connection_blocked("ssh");
+            return Err(russh::Error::Disconnect);
+        }
+
         if let Some(user_id) = self.validate_api_key(user).await {
             self.user_id = Some(user_id);
             info!(user_id = %user_id, "ssh authentication successful");
@@ -167,23 +428,90 @@ impl Handler for SshSession {
         } else {
             warn!(user = %user, ip = %self.client_ip, "ssh authentication failed");
             metrics::auth_failure("ssh", "invalid_key");
+            if self
+                .abuse
+                .record(self.peer_ip, Offense::SshAuthFailure)
+                .is_some()
+            {
+                return Err(russh::Error::Disconnect);
+            }
             Ok(Auth::Reject {
                 proceed_with_methods: Some(russh::MethodSet::PUBLICKEY),
             })
         }
     }
 
-    /// Called when the client opens a new session channel. We just accept
-    /// it and wait for further requests on that channel.
+    /// Called when the client opens a new session channel. We accept it
+    /// and remember it so status messages have somewhere to go.
     async fn channel_open_session(
         &mut self,
         channel: Channel<Msg>,
         _session: &mut Session,
     ) -> Result<bool, Self::Error> {
         debug!(channel = %channel.id(), "session channel opened");
+        *self.session_channel.lock().unwrap() = Some(channel.id());
         Ok(true)
     }
 
+    /// Forgets the session channel once the client closes it. Tunnels are
+    /// tied to the connection rather than the channel, so `ssh -N` style
+    /// clients that never open one keep their forwards too.
+    async fn channel_close(
+        &mut self,
+        channel: ChannelId,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        debug!(channel = %channel, "channel closed");
+        let mut current = self.session_channel.lock().unwrap();
+        if *current == Some(channel) {
+            *current = None;
+        }
+        Ok(())
+    }
+
+    /// Picks up per-tunnel settings sent with `ssh -o SetEnv=...`:
+    /// NEEDLE_TTL time-boxes this connection's tunnels, and
+    /// NEEDLE_BASIC_AUTH, NEEDLE_BEARER_TOKEN, NEEDLE_ALLOW_IPS,
+    /// NEEDLE_DENY_IPS, NEEDLE_OIDC_ALLOW and NEEDLE_PEER_ALLOW build up
+    /// their access policy. OpenSSH sends env
+    /// requests after its forwards are already set up, so settings are
+    /// applied to existing forwards too, not just ones opened later.
+    async fn env_request(
+        &mut self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let Some(user_id) = self.user_id else {
+            return Ok(());
+        };
+
+        match variable_name {
+            "NEEDLE_TTL" => {
+                self.apply_ttl(user_id, variable_value, channel, session)
+                    .await
+            }
+            "NEEDLE_BASIC_AUTH"
+            | "NEEDLE_BEARER_TOKEN"
+            | "NEEDLE_ALLOW_IPS"
+            | "NEEDLE_DENY_IPS"
+            | "NEEDLE_OIDC_ALLOW"
+            | "NEEDLE_PEER_ALLOW" => {
+                if let Err(msg) = update_access(&mut self.access, variable_name, variable_value) {
+                    warn!(variable = %variable_name, error = %msg, "ignoring invalid access setting");
+                    let msg = format!("invalid {variable_name}: {msg}, ignoring\r\n");
+                    Self::send_message(session, channel, &msg).await;
+                    return Ok(());
+                }
+                self.apply_access(user_id, channel, session).await
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
     /// Handles the tcpip-forward request, which is how SSH reverse tunnels
     /// work. The client says "please forward traffic for port X to me" and
     /// we respond by creating a tunnel with a unique subdomain.
@@ -193,7 +521,7 @@ impl Handler for SshSession {
         &mut self,
         address: &str,
         port: &mut u32,
-        _session: &mut Session,
+        session: &mut Session,
     ) -> Result<bool, Self::Error> {
         // Ensure user is authenticated
         let user_id = match self.user_id {
@@ -212,6 +540,12 @@ impl Handler for SshSession {
             "tcpip-forward requested"
         );
 
+        let key: ForwardKey = (address.to_string(), *port);
+        if forward_in_use(&self.forwards, &key).await {
+            warn!(address = %address, port = %port, "duplicate tcpip-forward rejected");
+            return Ok(false);
+        }
+
         // Validate port is allowed
         match Self::validate_port(*port) {
             Ok(_) => {} // Port is valid, continue
@@ -222,23 +556,37 @@ impl Handler for SshSession {
             }
         }
 
+        let (requested, protocol) = match Self::requested_tunnel(address) {
+            Ok(requested) => requested,
+            Err(e) => {
+                warn!(error = %e, "invalid subdomain in tcpip-forward address");
+                metrics::error_occurred("ssh_invalid_subdomain");
+                return Ok(false);
+            }
+        };
+
+        // If `requested` names one of this user's persistent reservations,
+        // the manager reattaches it instead of creating a new tunnel.
+        let options = TunnelOptions {
+            subdomain: requested,
+            target_port: *port as i32,
+            protocol: protocol.to_string(),
+            ttl: self.requested_ttl,
+            access: self.access.clone(),
+            ..TunnelOptions::default()
+        };
+        let link = self.link(session, key.clone());
+
         let mut manager = self.tunnel_manager.write().await;
         match manager
-            .create(
-                &self.client_ip,
-                user_id,
-                None,         // No custom subdomain for SSH tunnels
-                *port as i32, // Use requested port
-                "http",       // Default protocol
-                false,        // SSH tunnels are not persistent
-            )
+            .create(&self.client_ip, user_id, options, Some(link))
             .await
         {
             Ok(tunnel) => {
                 let subdomain = tunnel.subdomain.clone();
                 let bind_port = tunnel.bind_addr.port();
 
-                self.allocated_subdomains.push(subdomain.clone());
+                self.forwards.lock().await.insert(key, subdomain.clone());
 
                 *port = bind_port as u32;
 
@@ -248,6 +596,16 @@ impl Handler for SshSession {
                     "tunnel allocated for ssh client"
                 );
 
+                // OpenSSH usually asks for forwards before opening its
+                // session channel, so this mostly reaches reconnects
+                if let (Some(relay), Some(channel)) = (&tunnel.udp, self.current_channel()) {
+                    let msg = format!(
+                        "udp tunnel {subdomain} listening on port {}\r\n",
+                        relay.public_addr.port()
+                    );
+                    Self::send_message(session, channel, &msg).await;
+                }
+
                 Ok(true)
             }
             Err(e) => {
@@ -257,14 +615,304 @@ impl Handler for SshSession {
         }
     }
 
-    /// Cleanup when the client cancels a forward or disconnects.
-    async fn cancel_tcpip_forward(
+    /// Handles `ssh -L 5432:private-name:0`, which is how other users
+    /// reach a private tunnel. The host names the tunnel and the port is
+    /// ignored. The account connecting has to own the tunnel or be on its
+    /// peer list, and come from an address its IP rules allow. Unknown
+    /// names and refusals look the same, so nobody can probe for names.
+    async fn channel_open_direct_tcpip(
         &mut self,
-        _address: &str,
-        _port: u32,
+        channel: Channel<Msg>,
+        host_to_connect: &str,
+        _port_to_connect: u32,
+        _originator_address: &str,
+        originator_port: u32,
         _session: &mut Session,
     ) -> Result<bool, Self::Error> {
-        debug!("tcpip-forward cancelled");
+        let Some(user_id) = self.user_id else {
+            warn!(ip = %self.client_ip, "direct-tcpip requested without authentication");
+            return Ok(false);
+        };
+
+        let (lookup, db) = {
+            let manager = self.tunnel_manager.read().await;
+            let name = private_tunnel_name(host_to_connect, &manager.config().domain);
+            (manager.lookup(&name), manager.db_client().clone())
+        };
+        let tunnel = match lookup {
+            TunnelLookup::Active(tunnel) if tunnel.protocol == private::PROTOCOL => tunnel,
+            _ => {
+                debug!(host = %host_to_connect, user_id = %user_id, "direct-tcpip for unknown private tunnel");
+                return Ok(false);
+            }
+        };
+
+        if let Err(reason) = tunnel.access_policy().check_ip(self.peer_ip) {
+            warn!(subdomain = %tunnel.subdomain, ip = %self.client_ip, reason = reason.as_str(), "private tunnel connection denied");
+            metrics::auth_failure("ssh", reason.as_str());
+            return Ok(false);
+        }
+
+        let email = if tunnel.user_id == user_id {
+            None
+        } else {
+            match needle_db::queries::users::find_by_id(&db, &user_id.to_string()).await {
+                Ok(user) => user.map(|user| user.email),
+                Err(e) => {
+                    error!(user_id = %user_id, error = %e, "failed to look up private tunnel peer");
+                    return Ok(false);
+                }
+            }
+        };
+        if !private::may_connect(&tunnel, user_id, email.as_deref()) {
+            warn!(subdomain = %tunnel.subdomain, user_id = %user_id, "private tunnel connection from unlisted user");
+            metrics::auth_failure("ssh", "private_peer_not_allowed");
+            return Ok(false);
+        }
+
+        info!(subdomain = %tunnel.subdomain, user_id = %user_id, ip = %self.client_ip, "private tunnel connection");
+        metrics::private_connection(&tunnel.tier);
+        let originator = SocketAddr::new(self.peer_ip, originator_port as u16);
+        tokio::spawn(async move {
+            if let Err(e) = private::bridge(&tunnel, originator, channel).await {
+                debug!(subdomain = %tunnel.subdomain, error = %e, "private tunnel connection failed");
+            }
+        });
+
+        Ok(true)
+    }
+
+    /// Tears down the tunnel behind a single forward when the client
+    /// cancels it. Other forwards on the same connection are untouched.
+    async fn cancel_tcpip_forward(
+        &mut self,
+        address: &str,
+        port: u32,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let key: ForwardKey = (address.to_string(), port);
+        let Some(subdomain) = cancel_forward(&self.tunnel_manager, &self.forwards, &key).await
+        else {
+            warn!(address = %address, port = %port, "cancel for unknown tcpip-forward");
+            return Ok(false);
+        };
+
+        info!(subdomain = %subdomain, address = %address, port = %port, "tcpip-forward cancelled");
+
+        if let Some(channel) = self.current_channel() {
+            let msg = format!("tunnel {subdomain} closed\r\n");
+            Self::send_message(session, channel, &msg).await;
+        }
+
         Ok(true)
     }
 }
+
+/// Whether the connection already has a forward bound to `key`. A second
+/// one would leave a cancel for that (address, port) ambiguous, so it's
+/// refused.
+async fn forward_in_use(forwards: &SessionForwards, key: &ForwardKey) -> bool {
+    forwards.lock().await.contains_key(key)
+}
+
+/// Takes one forward off the connection and detaches the tunnel behind
+/// it. Returns the tunnel's subdomain, or `None` if nothing was bound to
+/// `key`.
+async fn cancel_forward(
+    tunnel_manager: &Arc<RwLock<TunnelManager>>,
+    forwards: &SessionForwards,
+    key: &ForwardKey,
+) -> Option<String> {
+    let subdomain = forwards.lock().await.remove(key)?;
+
+    let mut manager = tunnel_manager.write().await;
+    if let Err(e) = manager
+        .detach(&subdomain, forwards, key, "forward_cancelled")
+        .await
+    {
+        error!(subdomain = %subdomain, error = %e, "failed to remove cancelled tunnel");
+    }
+    Some(subdomain)
+}
+
+/// Releases every tunnel still registered on a finished SSH connection.
+/// Persistent tunnels fall back to a reservation rather than being freed.
+///
+/// Called by the accept loop once the russh session future resolves, so
+/// cleanup happens in order right after the connection closes instead of
+/// from a detached task spawned in `Drop`.
+pub async fn release_forwards(
+    tunnel_manager: &Arc<RwLock<TunnelManager>>,
+    forwards: &SessionForwards,
+) {
+    let released: Vec<(ForwardKey, String)> = forwards.lock().await.drain().collect();
+    if released.is_empty() {
+        return;
+    }
+
+    let mut manager = tunnel_manager.write().await;
+    for (key, sub) in released {
+        if let Err(e) = manager.detach(&sub, forwards, &key, "ssh_disconnect").await {
+            error!(subdomain = %sub, error = %e, "failed to clean up tunnel on disconnect");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::manager::tests::{manager, named};
+
+    const CLIENT_IP: &str = "192.0.2.1";
+
+    /// Opens a tunnel the way `tcpip_forward` does, recording it against
+    /// its forward on the connection.
+    async fn open(
+        manager: &Arc<RwLock<TunnelManager>>,
+        forwards: &SessionForwards,
+        key: &ForwardKey,
+        options: TunnelOptions,
+    ) {
+        let tunnel = manager
+            .write()
+            .await
+            .create(CLIENT_IP, Uuid::nil(), options, None)
+            .await
+            .unwrap();
+        forwards
+            .lock()
+            .await
+            .insert(key.clone(), tunnel.subdomain.clone());
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_forward_closes_only_its_tunnel() {
+        let manager = Arc::new(RwLock::new(manager().await));
+        let forwards = SessionForwards::default();
+        let web: ForwardKey = ("web-preview".to_string(), 8080);
+        let api: ForwardKey = ("api-preview".to_string(), 9090);
+        open(&manager, &forwards, &web, named("web-preview", false)).await;
+        open(&manager, &forwards, &api, named("api-preview", false)).await;
+
+        // The same (address, port) again is a duplicate; another port isn't
+        assert!(forward_in_use(&forwards, &web).await);
+        assert!(!forward_in_use(&forwards, &("web-preview".to_string(), 8081)).await);
+
+        let cancelled = cancel_forward(&manager, &forwards, &web).await;
+        assert_eq!(cancelled.as_deref(), Some("web-preview"));
+        assert!(!forward_in_use(&forwards, &web).await);
+        assert!(forward_in_use(&forwards, &api).await);
+        {
+            let manager = manager.read().await;
+            assert!(manager.get("web-preview").is_none());
+            assert!(manager.get("api-preview").is_some());
+            assert_eq!(manager.tunnels_for_ip(CLIENT_IP), 1);
+        }
+
+        // Once it's gone, cancelling it again finds nothing
+        assert_eq!(cancel_forward(&manager, &forwards, &web).await, None);
+    }
+
+    #[tokio::test]
+    async fn disconnect_releases_every_forward() {
+        let manager = Arc::new(RwLock::new(manager().await));
+        let forwards = SessionForwards::default();
+        let web: ForwardKey = ("web-preview".to_string(), 8080);
+        let api: ForwardKey = ("api-preview".to_string(), 9090);
+        open(&manager, &forwards, &web, named("web-preview", false)).await;
+        open(&manager, &forwards, &api, named("api-preview", true)).await;
+
+        release_forwards(&manager, &forwards).await;
+
+        assert!(forwards.lock().await.is_empty());
+        let manager = manager.read().await;
+        assert_eq!(manager.active_count(), 0);
+        assert_eq!(manager.tunnels_for_ip(CLIENT_IP), 0);
+        assert!(matches!(
+            manager.lookup("web-preview"),
+            TunnelLookup::Missing
+        ));
+        // The persistent one keeps its name for the owner
+        assert!(matches!(
+            manager.lookup("api-preview"),
+            TunnelLookup::Offline(None)
+        ));
+    }
+
+    #[test]
+    fn parses_ttl_units() {
+        assert_eq!(parse_ttl("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_ttl("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_ttl("2H"), Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_ttl("1d"), Some(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(parse_ttl("0"), None);
+        assert_eq!(parse_ttl("soon"), None);
+        assert_eq!(parse_ttl("5w"), None);
+    }
+
+    #[test]
+    fn reads_protocol_from_forward_address() {
+        let requested = SshSession::requested_tunnel;
+        assert_eq!(requested("myapp").unwrap(), (Some("myapp".into()), "http"));
+        assert_eq!(requested("localhost").unwrap(), (None, "http"));
+        assert_eq!(requested("udp+dns").unwrap(), (Some("dns".into()), "udp"));
+        assert_eq!(requested("udp").unwrap(), (None, "udp"));
+        assert_eq!(requested("udp+").unwrap(), (None, "udp"));
+        assert_eq!(
+            requested("tls+secure-app").unwrap(),
+            (Some("secure-app".into()), "tls")
+        );
+        assert_eq!(
+            requested("private+devdb").unwrap(),
+            (Some("devdb".into()), "private")
+        );
+        assert!(requested("ftp+files").is_err());
+        assert!(requested("udp+bad name").is_err());
+    }
+
+    #[test]
+    fn names_private_tunnels_with_or_without_the_domain() {
+        assert_eq!(private_tunnel_name("devdb", "example.com"), "devdb");
+        assert_eq!(
+            private_tunnel_name("DevDB.example.com", "example.com"),
+            "devdb"
+        );
+        assert_eq!(
+            private_tunnel_name("devdb.other.com", "example.com"),
+            "devdb.other.com"
+        );
+    }
+
+    #[test]
+    fn builds_access_policy_from_env() {
+        let mut policy = AccessPolicy::default();
+        update_access(&mut policy, "NEEDLE_ALLOW_IPS", "10.0.0.0/8, 192.0.2.1").unwrap();
+        update_access(&mut policy, "NEEDLE_BASIC_AUTH", "alice:pa:ss").unwrap();
+        assert_eq!(policy.allow.len(), 2);
+        assert!(policy.wants_basic_auth());
+
+        update_access(
+            &mut policy,
+            "NEEDLE_OIDC_ALLOW",
+            "Bob@Contractor.io, example.com,@example.net",
+        )
+        .unwrap();
+        let oidc = policy.oidc.as_ref().unwrap();
+        assert_eq!(oidc.allowed_emails, vec!["bob@contractor.io"]);
+        assert_eq!(oidc.allowed_domains, vec!["example.com", "example.net"]);
+
+        update_access(
+            &mut policy,
+            "NEEDLE_PEER_ALLOW",
+            "Carol@Example.com,@acme.dev",
+        )
+        .unwrap();
+        let peers = policy.peers.as_ref().unwrap();
+        assert!(peers.permits("carol@example.com"));
+        assert!(peers.permits("dave@ACME.dev"));
+        assert!(!peers.permits("eve@example.com"));
+
+        assert!(update_access(&mut policy, "NEEDLE_DENY_IPS", "10.0.0.0/99").is_err());
+        assert!(update_access(&mut policy, "NEEDLE_BASIC_AUTH", "nopassword").is_err());
+    }
+}
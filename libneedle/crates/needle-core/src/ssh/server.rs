@@ -1,14 +1,16 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
-use crate::ssh::handler::SshSession;
+use crate::abuse::AbuseTracker;
+use crate::listener::{ClientListener, TrustedProxies};
+use crate::metrics;
+use crate::ssh::handler::{SshSession, release_forwards};
 use crate::tunnel::manager::TunnelManager;
 use russh::server::Config;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::TcpListener;
 use tokio::sync::RwLock;
-use tracing::{error, info};
+use tracing::{debug, error, info};
 
 const SSH_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 const SSH_WINDOW_SIZE: u32 = 2_097_152;
@@ -19,10 +21,14 @@ const SSH_MAX_PACKET_SIZE: u32 = 32_768;
 /// Clients connect with `ssh -R 80:localhost:3000 needle.example.com`
 /// and we allocate a subdomain for them. This function blocks forever,
 /// accepting connections in a loop and spawning a task per client.
+/// Connections from banned IPs are dropped before the SSH handshake.
+/// Behind a load balancer, `PROXY_PROTOCOL=ssh` gets us the client's real
+/// address for bans and per-IP tunnel limits.
 pub async fn run(
     addr: &str,
     host_key: russh_keys::key::KeyPair,
     tunnel_manager: Arc<RwLock<TunnelManager>>,
+    abuse: Arc<AbuseTracker>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let config = Arc::new(Config {
         auth_rejection_time: SSH_HANDSHAKE_TIMEOUT,
@@ -33,30 +39,44 @@ pub async fn run(
         ..Default::default()
     });
 
-    let listener = TcpListener::bind(addr).await?;
+    let (proxy_protocol, trusted) = {
+        let manager = tunnel_manager.read().await;
+        let config = manager.config();
+        (config.proxy_protocol_on("ssh"), TrustedProxies::new(config))
+    };
+    let mut listener = ClientListener::bind(addr, "ssh", proxy_protocol, trusted).await?;
     info!(addr = %addr, "ssh server listening");
 
     loop {
-        match listener.accept().await {
-            Ok((stream, peer_addr)) => {
-                let config = config.clone();
-                let tm = tunnel_manager.clone();
-                let client_ip = peer_addr.ip().to_string();
-
-                tokio::spawn(async move {
-                    let session = SshSession::new(tm, client_ip.clone());
-
-                    let result = russh::server::run_stream(config, stream, session).await;
-
-                    match result {
-                        Ok(_) => info!(ip = %client_ip, "ssh session ended cleanly"),
-                        Err(e) => error!(ip = %client_ip, error = %e, "ssh session error"),
-                    }
-                });
-            }
-            Err(e) => {
-                error!(error = %e, "failed to accept ssh connection");
-            }
+        let (stream, peer_addr) = listener.accept().await;
+        if abuse.check(peer_addr.ip()).is_err() {
+            debug!(ip = %peer_addr.ip(), "dropped ssh connection from banned ip");
+            metrics::connection_blocked("ssh");
+            continue;
         }
+
+        let config = config.clone();
+        let tm = tunnel_manager.clone();
+        let abuse = abuse.clone();
+        let client_ip = peer_addr.ip().to_string();
+
+        tokio::spawn(async move {
+            let session = SshSession::new(tm.clone(), abuse, peer_addr.ip());
+            let forwards = session.forwards();
+
+            // run_stream only performs the version exchange; the
+            // returned future resolves when the connection closes.
+            let result = match russh::server::run_stream(config, stream, session).await {
+                Ok(running) => running.await,
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(()) => info!(ip = %client_ip, "ssh session ended cleanly"),
+                Err(e) => error!(ip = %client_ip, error = %e, "ssh session error"),
+            }
+
+            release_forwards(&tm, &forwards).await;
+        });
     }
 }
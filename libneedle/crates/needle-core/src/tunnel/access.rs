@@ -0,0 +1,551 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::proxy::oidc;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, SaltString};
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use needle_common::cidr::Cidr;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+
+/// Who may reach a tunnel through the edge.
+///
+/// IP rules run first: anything in `deny` is refused, and if `allow` is
+/// non-empty the client has to be in it. If a password or token is set,
+/// the request must then carry one of them in its Authorization header
+/// (`Basic` or `Bearer`), or, when `oidc` is set, a login session from the
+/// edge for an allowed account. An empty policy lets everyone through,
+/// which is what tunnels get by default.
+///
+/// Nothing secret is stored in plain text, since the policy is persisted
+/// alongside the tunnel row. Passwords are argon2 hashes, like account
+/// passwords, and checking one is slow on purpose, so callers on an async
+/// runtime should evaluate Basic credentials on a blocking thread. Tokens
+/// are SHA-256 digests, like API keys, which is cheap to check. The one
+/// exception is an OIDC client secret, which we have to present to the
+/// provider as-is.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessPolicy {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    basic_auth: Option<BasicAuth>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bearer_token: Option<TokenHash>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<Cidr>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<Cidr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oidc: Option<OidcRule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peers: Option<PeerRule>,
+}
+
+/// Requires visitors to sign in with an OpenID Connect provider.
+///
+/// With no `issuer`, the server's own provider (OIDC_ISSUER_URL) is used.
+/// A tunnel that brings its own issuer has to bring its own client too,
+/// since the server's credentials are only good with the server's issuer.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OidcRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_emails: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_domains: Vec<String>,
+}
+
+impl OidcRule {
+    /// Checks the rule makes sense on its own. Without an allowlist any
+    /// account at a public provider could get in, so we insist on one.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.allowed_emails.is_empty() && self.allowed_domains.is_empty() {
+            return Err("oidc needs at least one allowed email or domain".to_string());
+        }
+        if self.issuer.is_some() && self.client_id.is_none() {
+            return Err("oidc with a custom issuer needs a client_id".to_string());
+        }
+        if self.issuer.is_none() && (self.client_id.is_some() || self.client_secret.is_some()) {
+            return Err("oidc client credentials need an issuer".to_string());
+        }
+        // The server fetches from the issuer, so it mustn't be able to
+        // point us at anything on our side of the network
+        if let Some(issuer) = &self.issuer {
+            oidc::check_public_url(issuer)?;
+        }
+        Ok(())
+    }
+
+    /// Whether a signed-in account may use the tunnel, by exact address
+    /// or by the domain after its `@`.
+    pub fn permits(&self, email: &str) -> bool {
+        email_permitted(&self.allowed_emails, &self.allowed_domains, email)
+    }
+}
+
+/// Who besides its owner may reach a private tunnel over SSH: accounts
+/// by email, or everyone at an email domain, which is how we name an
+/// organization.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerRule {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_emails: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_domains: Vec<String>,
+}
+
+impl PeerRule {
+    /// Sorts entries like `alice@example.com`, `example.org` and
+    /// `@example.net` into exact addresses and whole domains.
+    pub fn from_entries<'a>(entries: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut rule = Self::default();
+        let entries = entries
+            .into_iter()
+            .map(|s| s.trim().to_ascii_lowercase())
+            .filter(|s| !s.is_empty());
+        for entry in entries {
+            match entry.strip_prefix('@') {
+                Some(domain) => rule.allowed_domains.push(domain.to_string()),
+                None if entry.contains('@') => rule.allowed_emails.push(entry),
+                None => rule.allowed_domains.push(entry),
+            }
+        }
+        rule
+    }
+
+    pub fn permits(&self, email: &str) -> bool {
+        email_permitted(&self.allowed_emails, &self.allowed_domains, email)
+    }
+}
+
+fn email_permitted(emails: &[String], domains: &[String], email: &str) -> bool {
+    let email = email.to_ascii_lowercase();
+    if emails.contains(&email) {
+        return true;
+    }
+    email
+        .rsplit_once('@')
+        .is_some_and(|(_, domain)| domains.iter().any(|d| *d == domain))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct BasicAuth {
+    username: String,
+    password: SecretHash,
+}
+
+/// A secret as an argon2 PHC string, salt and parameters included.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+struct SecretHash(String);
+
+impl SecretHash {
+    fn new(secret: &str) -> Self {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(secret.as_bytes(), &salt)
+            .expect("argon2 hashes any secret with default params");
+        Self(hash.to_string())
+    }
+
+    // argon2 compares the hashes in constant time, so timing tells an
+    // attacker nothing about how close a guess was. A hash we can't
+    // parse matches nothing.
+    fn verify(&self, candidate: &str) -> bool {
+        PasswordHash::new(&self.0).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(candidate.as_bytes(), &hash)
+                .is_ok()
+        })
+    }
+}
+
+/// A token as the hex SHA-256 digest of its bytes. Tokens are meant to be
+/// long and random, so unlike a password there's nothing for a slow hash
+/// to protect, and a bad one can be turned away without much work.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+struct TokenHash(String);
+
+impl TokenHash {
+    fn new(token: &str) -> Self {
+        Self(hex::encode(Sha256::digest(token.as_bytes())))
+    }
+
+    // Compares every byte of the digests either way, so timing doesn't
+    // give away how much of a guess matched
+    fn verify(&self, candidate: &str) -> bool {
+        let candidate = Sha256::digest(candidate.as_bytes());
+        hex::decode(&self.0).is_ok_and(|stored| {
+            stored.len() == candidate.len()
+                && stored
+                    .iter()
+                    .zip(candidate.iter())
+                    .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        })
+    }
+}
+
+/// Why the edge turned a request away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenyReason {
+    IpDenied,
+    IpNotAllowed,
+    MissingCredentials,
+    BadCredentials,
+    LoginRequired,
+    EmailNotAllowed,
+}
+
+impl DenyReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DenyReason::IpDenied => "ip_denied",
+            DenyReason::IpNotAllowed => "ip_not_allowed",
+            DenyReason::MissingCredentials => "missing_credentials",
+            DenyReason::BadCredentials => "bad_credentials",
+            DenyReason::LoginRequired => "login_required",
+            DenyReason::EmailNotAllowed => "email_not_allowed",
+        }
+    }
+
+    /// Whether the visitor could get in by sending credentials, as
+    /// opposed to being refused outright for where they're coming from.
+    pub fn is_auth_failure(self) -> bool {
+        matches!(
+            self,
+            DenyReason::MissingCredentials | DenyReason::BadCredentials | DenyReason::LoginRequired
+        )
+    }
+}
+
+/// The outcome of checking a request against a policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessDecision {
+    /// Let it through. `consumed_authorization` is set when the
+    /// Authorization header held our credentials, so the edge can strip
+    /// it before the request reaches the developer's app.
+    Allow {
+        consumed_authorization: bool,
+    },
+    Deny(DenyReason),
+}
+
+/// What the API shows about a policy, without the secret hashes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccessSummary {
+    pub basic_auth: bool,
+    pub bearer_token: bool,
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oidc: Option<OidcSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peers: Option<PeerRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OidcSummary {
+    pub issuer: Option<String>,
+    pub allowed_emails: Vec<String>,
+    pub allowed_domains: Vec<String>,
+}
+
+impl AccessPolicy {
+    /// True if this policy lets everyone in.
+    pub fn is_open(&self) -> bool {
+        self.basic_auth.is_none()
+            && self.bearer_token.is_none()
+            && self.allow.is_empty()
+            && self.deny.is_empty()
+            && self.oidc.is_none()
+            && self.peers.is_none()
+    }
+
+    /// A policy that refuses every client, for when we can't trust what
+    /// we were given.
+    pub fn deny_all() -> Self {
+        Self {
+            deny: vec![
+                "0.0.0.0/0".parse().expect("valid cidr"),
+                "::/0".parse().expect("valid cidr"),
+            ],
+            ..Self::default()
+        }
+    }
+
+    /// True if getting in takes more than coming from the right address.
+    pub fn needs_credentials(&self) -> bool {
+        self.basic_auth.is_some() || self.bearer_token.is_some() || self.oidc.is_some()
+    }
+
+    /// True if the edge should send a Basic auth challenge on 401.
+    pub fn wants_basic_auth(&self) -> bool {
+        self.basic_auth.is_some()
+    }
+
+    /// True if checking `authorization` means running a password hash,
+    /// the slow kind of check that belongs on a blocking thread.
+    pub fn checks_password(&self, authorization: &str) -> bool {
+        self.basic_auth.is_some()
+            && authorization
+                .trim()
+                .split_once(' ')
+                .is_some_and(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+    }
+
+    pub fn set_basic_auth(&mut self, username: &str, password: &str) {
+        self.basic_auth = Some(BasicAuth {
+            username: username.to_string(),
+            password: SecretHash::new(password),
+        });
+    }
+
+    pub fn set_bearer_token(&mut self, token: &str) {
+        self.bearer_token = Some(TokenHash::new(token));
+    }
+
+    pub fn summary(&self) -> AccessSummary {
+        AccessSummary {
+            basic_auth: self.basic_auth.is_some(),
+            bearer_token: self.bearer_token.is_some(),
+            allow: self.allow.clone(),
+            deny: self.deny.clone(),
+            oidc: self.oidc.as_ref().map(|rule| OidcSummary {
+                issuer: rule.issuer.clone(),
+                allowed_emails: rule.allowed_emails.clone(),
+                allowed_domains: rule.allowed_domains.clone(),
+            }),
+            peers: self.peers.clone(),
+        }
+    }
+
+    /// Checks one request, given the client's IP, its Authorization
+    /// header if it sent one, and the email from its edge login session
+    /// if it carried a valid one.
+    pub fn evaluate(
+        &self,
+        ip: IpAddr,
+        authorization: Option<&str>,
+        session_email: Option<&str>,
+    ) -> AccessDecision {
+        if let Err(reason) = self.check_ip(ip) {
+            return AccessDecision::Deny(reason);
+        }
+
+        if self.basic_auth.is_none() && self.bearer_token.is_none() && self.oidc.is_none() {
+            return AccessDecision::Allow {
+                consumed_authorization: false,
+            };
+        }
+
+        if authorization.is_some_and(|auth| self.credentials_match(auth)) {
+            return AccessDecision::Allow {
+                consumed_authorization: true,
+            };
+        }
+
+        // A login requirement decides on its own, leaving any
+        // Authorization header alone for the app to use
+        if let Some(oidc) = &self.oidc {
+            return match session_email {
+                Some(email) if oidc.permits(email) => AccessDecision::Allow {
+                    consumed_authorization: false,
+                },
+                Some(_) => AccessDecision::Deny(DenyReason::EmailNotAllowed),
+                None => AccessDecision::Deny(DenyReason::LoginRequired),
+            };
+        }
+
+        match authorization {
+            Some(_) => AccessDecision::Deny(DenyReason::BadCredentials),
+            None => AccessDecision::Deny(DenyReason::MissingCredentials),
+        }
+    }
+
+    /// Just the IP rules, for visitors who got past the credential checks
+    /// some other way, like a share link.
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), DenyReason> {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            return Err(DenyReason::IpDenied);
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|net| net.contains(ip)) {
+            return Err(DenyReason::IpNotAllowed);
+        }
+        Ok(())
+    }
+
+    fn credentials_match(&self, authorization: &str) -> bool {
+        let Some((scheme, value)) = authorization.trim().split_once(' ') else {
+            return false;
+        };
+        let value = value.trim();
+
+        if scheme.eq_ignore_ascii_case("basic") {
+            let Some(basic) = &self.basic_auth else {
+                return false;
+            };
+            let Some(decoded) = STANDARD
+                .decode(value)
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+            else {
+                return false;
+            };
+            return decoded
+                .split_once(':')
+                .is_some_and(|(user, pass)| user == basic.username && basic.password.verify(pass));
+        }
+
+        if scheme.eq_ignore_ascii_case("bearer") {
+            return self
+                .bearer_token
+                .as_ref()
+                .is_some_and(|token| token.verify(value));
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn open_policy_allows_everyone() {
+        let policy = AccessPolicy::default();
+        assert!(policy.is_open());
+        assert_eq!(
+            policy.evaluate(ip("198.51.100.1"), None, None),
+            AccessDecision::Allow {
+                consumed_authorization: false
+            }
+        );
+    }
+
+    #[test]
+    fn ip_rules_run_before_credentials() {
+        let mut policy = AccessPolicy {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec!["10.9.0.0/16".parse().unwrap()],
+            ..AccessPolicy::default()
+        };
+        policy.set_bearer_token("s3cret");
+
+        assert_eq!(
+            policy.evaluate(ip("10.9.1.1"), Some("Bearer s3cret"), None),
+            AccessDecision::Deny(DenyReason::IpDenied)
+        );
+        assert_eq!(
+            policy.evaluate(ip("192.0.2.1"), Some("Bearer s3cret"), None),
+            AccessDecision::Deny(DenyReason::IpNotAllowed)
+        );
+        assert_eq!(
+            policy.evaluate(ip("10.1.1.1"), Some("Bearer s3cret"), None),
+            AccessDecision::Allow {
+                consumed_authorization: true
+            }
+        );
+    }
+
+    #[test]
+    fn checks_basic_and_bearer_credentials() {
+        let mut policy = AccessPolicy::default();
+        policy.set_basic_auth("alice", "hunter2");
+        policy.set_bearer_token("tok");
+        let client = ip("203.0.113.9");
+
+        let good = format!("Basic {}", STANDARD.encode("alice:hunter2"));
+        let bad = format!("Basic {}", STANDARD.encode("alice:wrong"));
+
+        assert!(matches!(
+            policy.evaluate(client, Some(&good), None),
+            AccessDecision::Allow { .. }
+        ));
+        assert!(matches!(
+            policy.evaluate(client, Some("bearer tok"), None),
+            AccessDecision::Allow { .. }
+        ));
+        assert_eq!(
+            policy.evaluate(client, Some(&bad), None),
+            AccessDecision::Deny(DenyReason::BadCredentials)
+        );
+        assert_eq!(
+            policy.evaluate(client, None, None),
+            AccessDecision::Deny(DenyReason::MissingCredentials)
+        );
+    }
+
+    #[test]
+    fn login_rule_checks_session_email() {
+        let policy = AccessPolicy {
+            oidc: Some(OidcRule {
+                allowed_emails: vec!["contractor@gmail.com".to_string()],
+                allowed_domains: vec!["example.com".to_string()],
+                ..OidcRule::default()
+            }),
+            ..AccessPolicy::default()
+        };
+        let client = ip("203.0.113.9");
+
+        assert!(matches!(
+            policy.evaluate(client, None, Some("Bob@Example.com")),
+            AccessDecision::Allow {
+                consumed_authorization: false
+            }
+        ));
+        assert!(matches!(
+            policy.evaluate(
+                client,
+                Some("Bearer app-token"),
+                Some("contractor@gmail.com")
+            ),
+            AccessDecision::Allow {
+                consumed_authorization: false
+            }
+        ));
+        assert_eq!(
+            policy.evaluate(client, None, Some("eve@example.com.evil")),
+            AccessDecision::Deny(DenyReason::EmailNotAllowed)
+        );
+        assert_eq!(
+            policy.evaluate(client, None, None),
+            AccessDecision::Deny(DenyReason::LoginRequired)
+        );
+    }
+
+    #[test]
+    fn round_trips_through_json_without_plain_secrets() {
+        let mut policy = AccessPolicy::default();
+        policy.set_basic_auth("alice", "hunter2");
+        policy.set_bearer_token("app-token");
+
+        let json = serde_json::to_string(&policy).unwrap();
+        assert!(!json.contains("hunter2"));
+        assert!(!json.contains("app-token"));
+        assert!(json.contains("$argon2id$"));
+
+        let restored: AccessPolicy = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, policy);
+        assert!(matches!(
+            restored.evaluate(ip("203.0.113.9"), Some("Bearer app-token"), None),
+            AccessDecision::Allow { .. }
+        ));
+        assert!(restored.checks_password(&format!("Basic {}", STANDARD.encode("a:b"))));
+        assert!(!restored.checks_password("Bearer app-token"));
+    }
+}
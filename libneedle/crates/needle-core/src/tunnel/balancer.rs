@@ -0,0 +1,252 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::ssh::handler::{ForwardKey, SessionForwards, SessionLink};
+use crate::tunnel::health::{BackendHealth, HealthStatus};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use uuid::Uuid;
+
+// How long a backend the edge couldn't reach sits out before it gets
+// traffic again, unless every other backend is sitting out too
+const FAILURE_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// How the edge spreads requests over a load-balanced tunnel's clients.
+/// Setting one on a tunnel is what lets more than one SSH connection
+/// serve it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancing {
+    /// Each backend in turn.
+    #[default]
+    RoundRobin,
+    /// Whichever backend has the fewest requests in flight.
+    LeastConnections,
+}
+
+/// One client serving a tunnel: its own internal listener, and the SSH
+/// connection it came in on.
+pub struct Backend {
+    pub id: Uuid,
+    pub bind_addr: SocketAddr,
+    pub client_ip: String,
+    pub connected_at: DateTime<Utc>,
+    session: Option<SessionLink>,
+    in_flight: AtomicUsize,
+    failed_at: Mutex<Option<Instant>>,
+    health: Mutex<BackendHealth>,
+    // Backends that joined later own their listener; the first one's
+    // lives on the tunnel itself
+    _listener: Option<TcpListener>,
+}
+
+impl Backend {
+    pub fn new(
+        bind_addr: SocketAddr,
+        client_ip: &str,
+        session: Option<SessionLink>,
+        listener: Option<TcpListener>,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            bind_addr,
+            client_ip: client_ip.to_string(),
+            connected_at: Utc::now(),
+            session,
+            in_flight: AtomicUsize::new(0),
+            failed_at: Mutex::new(None),
+            health: Mutex::default(),
+            _listener: listener,
+        }
+    }
+
+    pub fn session(&self) -> Option<&SessionLink> {
+        self.session.as_ref()
+    }
+
+    /// Requests the edge has sent this backend that haven't finished.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// False while the backend is sitting out after a failure, or its
+    /// tunnel's health check says it's down.
+    pub fn is_healthy(&self) -> bool {
+        let cooled_down = self
+            .failed_at
+            .lock()
+            .unwrap()
+            .is_none_or(|at| at.elapsed() >= FAILURE_COOLDOWN);
+        cooled_down && self.health_status() != HealthStatus::Down
+    }
+
+    /// What the tunnel's health check last said about this backend.
+    pub fn health_status(&self) -> HealthStatus {
+        self.health.lock().unwrap().status()
+    }
+
+    pub(crate) fn health(&self) -> &Mutex<BackendHealth> {
+        &self.health
+    }
+
+    /// Takes the backend out of rotation for a little while, e.g. after
+    /// the edge couldn't connect to it.
+    pub fn mark_failed(&self) {
+        *self.failed_at.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn serves(&self, forwards: &SessionForwards, forward: &ForwardKey) -> bool {
+        self.session
+            .as_ref()
+            .is_some_and(|link| link.is_forward(forwards, forward))
+    }
+}
+
+/// A backend picked for one request. It counts as in flight until the
+/// guard is dropped.
+pub struct BackendGuard(Arc<Backend>);
+
+impl Deref for BackendGuard {
+    type Target = Backend;
+
+    fn deref(&self) -> &Backend {
+        &self.0
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Every client currently serving a tunnel. Tunnels without load
+/// balancing only ever have the one that opened them.
+pub struct BackendPool {
+    backends: RwLock<Vec<Arc<Backend>>>,
+    next: AtomicUsize,
+}
+
+impl BackendPool {
+    pub fn new(first: Backend) -> Self {
+        Self {
+            backends: RwLock::new(vec![Arc::new(first)]),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn add(&self, backend: Backend) -> Arc<Backend> {
+        let backend = Arc::new(backend);
+        self.backends.write().unwrap().push(backend.clone());
+        backend
+    }
+
+    /// Takes out the backend behind one SSH forward, as long as another
+    /// backend is left to serve the tunnel. Returns `None` if it's the
+    /// last one, or isn't in the pool.
+    pub fn remove_forward(
+        &self,
+        forwards: &SessionForwards,
+        forward: &ForwardKey,
+    ) -> Option<Arc<Backend>> {
+        let mut backends = self.backends.write().unwrap();
+        if backends.len() < 2 {
+            return None;
+        }
+        let index = backends.iter().position(|b| b.serves(forwards, forward))?;
+        Some(backends.remove(index))
+    }
+
+    pub fn all(&self) -> Vec<Arc<Backend>> {
+        self.backends.read().unwrap().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.backends.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Picks the backend for the next request. Backends sitting out
+    /// after a failure are skipped, unless that would leave none, in
+    /// which case they all get another go.
+    pub fn pick(&self, strategy: LoadBalancing) -> Option<BackendGuard> {
+        let backends = self.backends.read().unwrap();
+        let healthy: Vec<&Arc<Backend>> = backends.iter().filter(|b| b.is_healthy()).collect();
+        let candidates = if healthy.is_empty() {
+            backends.iter().collect()
+        } else {
+            healthy
+        };
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let backend = match strategy {
+            LoadBalancing::RoundRobin => {
+                let turn = self.next.fetch_add(1, Ordering::Relaxed);
+                candidates[turn % candidates.len()]
+            }
+            LoadBalancing::LeastConnections => candidates
+                .iter()
+                .min_by_key(|b| b.in_flight())
+                .copied()
+                .unwrap_or(candidates[0]),
+        };
+        backend.in_flight.fetch_add(1, Ordering::Relaxed);
+        Some(BackendGuard(backend.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend(port: u16) -> Backend {
+        Backend::new(
+            SocketAddr::from(([127, 0, 0, 1], port)),
+            "192.0.2.1",
+            None,
+            None,
+        )
+    }
+
+    fn ports(pool: &BackendPool, strategy: LoadBalancing, n: usize) -> Vec<u16> {
+        (0..n)
+            .map(|_| pool.pick(strategy).unwrap().bind_addr.port())
+            .collect()
+    }
+
+    #[test]
+    fn spreads_requests_and_skips_failed_backends() {
+        let pool = BackendPool::new(backend(1));
+        pool.add(backend(2));
+        let third = pool.add(backend(3));
+
+        assert_eq!(ports(&pool, LoadBalancing::RoundRobin, 4), vec![1, 2, 3, 1]);
+
+        // A request still in flight on 1 steers new ones elsewhere
+        let busy = pool.pick(LoadBalancing::LeastConnections).unwrap();
+        assert_eq!(busy.bind_addr.port(), 1);
+        assert_eq!(busy.in_flight(), 1);
+        assert_eq!(ports(&pool, LoadBalancing::LeastConnections, 2), vec![2, 2]);
+        drop(busy);
+        assert!(pool.all().iter().all(|b| b.in_flight() == 0));
+
+        third.mark_failed();
+        assert!(!ports(&pool, LoadBalancing::RoundRobin, 4).contains(&3));
+
+        // With everyone failing, failed backends are better than nothing
+        let single = BackendPool::new(backend(4));
+        single.all()[0].mark_failed();
+        assert_eq!(ports(&single, LoadBalancing::RoundRobin, 1), vec![4]);
+    }
+}
@@ -0,0 +1,273 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+
+const MAX_ENTRIES: usize = 32;
+
+/// Which other sites' pages may call a tunnel from the browser. With a
+/// policy set the edge answers preflight requests itself and adds the
+/// CORS headers to every response, so the app behind the tunnel doesn't
+/// need to know about any of it. Whatever CORS headers the app sends are
+/// replaced, so there's only ever one answer.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorsPolicy {
+    /// Exact origins like `https://app.example.com`, or `*` for any.
+    pub allowed_origins: Vec<String>,
+    /// Empty allows whatever method the browser asks about.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_methods: Vec<String>,
+    /// Empty allows whatever headers the browser asks about.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_headers: Vec<String>,
+    /// Lets pages send cookies and `Authorization`. Can't be combined
+    /// with `*`, which browsers refuse anyway.
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_secs: Option<u64>,
+}
+
+impl CorsPolicy {
+    /// Checks a policy that came from a client. Returns a message fit to
+    /// show them if something's wrong.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.allowed_origins.is_empty() {
+            return Err("cors needs at least one allowed origin".to_string());
+        }
+        if [
+            &self.allowed_origins,
+            &self.allowed_methods,
+            &self.allowed_headers,
+        ]
+        .iter()
+        .any(|list| list.len() > MAX_ENTRIES)
+        {
+            return Err(format!("cors lists can have at most {MAX_ENTRIES} entries"));
+        }
+
+        for origin in &self.allowed_origins {
+            if origin == "*" {
+                if self.allow_credentials {
+                    return Err("cors can't allow credentials from any origin (*)".to_string());
+                }
+                continue;
+            }
+            if !is_valid_origin(origin) {
+                return Err(format!(
+                    "cors origins must look like https://app.example.com, got {origin}"
+                ));
+            }
+        }
+        for method in &self.allowed_methods {
+            Method::from_bytes(method.as_bytes())
+                .map_err(|_| format!("invalid cors method {method}"))?;
+        }
+        for name in &self.allowed_headers {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid cors header {name}"))?;
+        }
+        Ok(())
+    }
+
+    /// The edge's answer to a CORS preflight, or None if `req` isn't one.
+    /// Preflights never carry credentials, so this has to come before any
+    /// access checks or a protected tunnel could never be called.
+    pub fn preflight<B>(&self, req: &Request<B>) -> Option<Response<Full<Bytes>>> {
+        let headers = req.headers();
+        if req.method() != Method::OPTIONS
+            || !headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return None;
+        }
+        let origin = headers.get(header::ORIGIN)?;
+
+        let mut response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Full::new(Bytes::new()))
+            .expect("valid preflight response");
+        let out = response.headers_mut();
+        out.append(
+            header::VARY,
+            HeaderValue::from_static(
+                "origin, access-control-request-method, access-control-request-headers",
+            ),
+        );
+
+        // Leaving the headers off is how a preflight says no
+        if !self.allow_origin(origin, out) {
+            return Some(response);
+        }
+
+        let methods = if self.allowed_methods.is_empty() {
+            headers.get(header::ACCESS_CONTROL_REQUEST_METHOD).cloned()
+        } else {
+            HeaderValue::from_str(&self.allowed_methods.join(", ")).ok()
+        };
+        if let Some(methods) = methods {
+            out.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+
+        let allowed_headers = if self.allowed_headers.is_empty() {
+            headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS).cloned()
+        } else {
+            HeaderValue::from_str(&self.allowed_headers.join(", ")).ok()
+        };
+        if let Some(allowed_headers) = allowed_headers {
+            out.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+        }
+
+        if let Some(max_age) = self.max_age_secs {
+            out.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+        }
+        Some(response)
+    }
+
+    /// Adds the CORS headers to a response for a request from `origin`.
+    pub fn decorate(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        for name in [
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            header::ACCESS_CONTROL_MAX_AGE,
+        ] {
+            headers.remove(name);
+        }
+        headers.append(header::VARY, HeaderValue::from_static("origin"));
+
+        if let Some(origin) = origin {
+            self.allow_origin(origin, headers);
+        }
+    }
+
+    /// Sets the allow headers if `origin` is allowed, and says whether
+    /// it was.
+    fn allow_origin(&self, origin: &HeaderValue, headers: &mut HeaderMap) -> bool {
+        let any = self.allowed_origins.iter().any(|o| o == "*");
+        let listed = origin.to_str().is_ok_and(|origin| {
+            self.allowed_origins
+                .iter()
+                .any(|o| o.eq_ignore_ascii_case(origin))
+        });
+        if !any && !listed {
+            return false;
+        }
+
+        // Validation keeps `*` and credentials apart, so echoing the
+        // origin is only needed for listed ones
+        if any {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                HeaderValue::from_static("*"),
+            );
+        } else {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        }
+        if self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        true
+    }
+}
+
+/// A scheme and host, with an optional port and nothing after it.
+fn is_valid_origin(origin: &str) -> bool {
+    origin.parse::<Uri>().is_ok_and(|uri| {
+        matches!(uri.scheme_str(), Some("http" | "https"))
+            && uri.authority().is_some()
+            && uri.path_and_query().is_none_or(|p| p.as_str() == "/")
+            && !origin.ends_with('/')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(credentials: bool) -> CorsPolicy {
+        CorsPolicy {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allow_credentials: credentials,
+            max_age_secs: Some(600),
+            ..CorsPolicy::default()
+        }
+    }
+
+    fn preflight(origin: &str) -> Request<()> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn answers_preflights_for_allowed_origins_only() {
+        let policy = policy(true);
+        let response = policy
+            .preflight(&preflight("https://app.example.com"))
+            .unwrap();
+        let headers = response.headers();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET, POST");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        let response = policy.preflight(&preflight("https://evil.test")).unwrap();
+        assert!(
+            !response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+
+        let plain = Request::builder().method(Method::OPTIONS).body(()).unwrap();
+        assert!(policy.preflight(&plain).is_none());
+    }
+
+    #[test]
+    fn decorate_replaces_the_apps_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            HeaderValue::from_static("*"),
+        );
+        let origin = HeaderValue::from_static("https://evil.test");
+        policy(false).decorate(Some(&origin), &mut headers);
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(headers[header::VARY], "origin");
+    }
+
+    #[test]
+    fn rejects_wildcard_with_credentials_and_bad_origins() {
+        let mut bad = policy(true);
+        bad.allowed_origins = vec!["*".to_string()];
+        assert!(bad.validate().is_err());
+
+        for origin in ["app.example.com", "https://app.example.com/path", "ftp://x"] {
+            let mut bad = policy(false);
+            bad.allowed_origins = vec![origin.to_string()];
+            assert!(bad.validate().is_err(), "{origin}");
+        }
+        assert!(policy(true).validate().is_ok());
+    }
+}
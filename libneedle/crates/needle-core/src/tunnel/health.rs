@@ -0,0 +1,246 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::config::ProxyTimeouts;
+use crate::metrics;
+use crate::proxy::http::forward_request;
+use crate::tunnel::balancer::Backend;
+use crate::tunnel::manager::{ActiveTunnel, TunnelManager};
+use crate::tunnel::settings::HealthCheck;
+use bytes::Bytes;
+use chrono::Utc;
+use http_body_util::Empty;
+use hyper::{Request, header};
+use needle_db::client::SupabaseClient;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+// How often we look for checks that are due. Each tunnel's own interval
+// decides how often its app actually gets asked.
+const CHECK_TICK: Duration = Duration::from_secs(1);
+
+// Failed checks in a row before a backend counts as down, so one slow
+// answer doesn't take a tunnel offline. A single pass brings it back.
+const FAILURES_BEFORE_DOWN: u32 = 2;
+
+/// What health checks last concluded about an app.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    /// Not checked yet.
+    #[default]
+    Unknown,
+    Up,
+    Down,
+}
+
+impl HealthStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HealthStatus::Unknown => "unknown",
+            HealthStatus::Up => "up",
+            HealthStatus::Down => "down",
+        }
+    }
+
+    /// A tunnel is up if any of its backends is, and down only once all
+    /// of them are.
+    pub fn of_tunnel(backends: &[Arc<Backend>]) -> Self {
+        let statuses: Vec<HealthStatus> = backends.iter().map(|b| b.health_status()).collect();
+        if statuses.contains(&HealthStatus::Up) {
+            HealthStatus::Up
+        } else if !statuses.is_empty() && statuses.iter().all(|s| *s == HealthStatus::Down) {
+            HealthStatus::Down
+        } else {
+            HealthStatus::Unknown
+        }
+    }
+}
+
+/// Where one backend's health checks stand.
+#[derive(Debug, Default)]
+pub struct BackendHealth {
+    status: HealthStatus,
+    failures: u32,
+    next_check: Option<Instant>,
+}
+
+impl BackendHealth {
+    pub fn status(&self) -> HealthStatus {
+        self.status
+    }
+
+    /// Claims the backend's next check if it's due, so a slow check
+    /// never overlaps the one after it.
+    fn claim(&mut self, now: Instant, interval: Duration) -> bool {
+        if self.next_check.is_some_and(|at| at > now) {
+            return false;
+        }
+        self.next_check = Some(now + interval);
+        true
+    }
+
+    /// Folds in one check's result. Returns the new status if it changed.
+    fn record(&mut self, passed: bool) -> Option<HealthStatus> {
+        let status = if passed {
+            self.failures = 0;
+            HealthStatus::Up
+        } else {
+            self.failures += 1;
+            if self.failures >= FAILURES_BEFORE_DOWN {
+                HealthStatus::Down
+            } else {
+                self.status
+            }
+        };
+        if status == self.status {
+            return None;
+        }
+        self.status = status;
+        Some(status)
+    }
+}
+
+/// Background task that runs the health checks tunnels asked for.
+///
+/// Every second we look for backends whose check is due and send each
+/// its request on a task of its own, so one app taking its time doesn't
+/// hold up the rest. When a tunnel's overall status changes we log it,
+/// count it and record it on the tunnel row. Runs forever.
+pub async fn run(tunnel_manager: Arc<RwLock<TunnelManager>>) {
+    let mut ticker = tokio::time::interval(CHECK_TICK);
+    let mut checks = JoinSet::new();
+
+    loop {
+        ticker.tick().await;
+        while checks.try_join_next().is_some() {}
+
+        let (tunnels, db, domain) = {
+            let manager = tunnel_manager.read().await;
+            (
+                manager.health_checked(),
+                manager.db_client().clone(),
+                manager.config().domain.clone(),
+            )
+        };
+
+        let now = Instant::now();
+        for tunnel in tunnels {
+            let Some(check) = tunnel.settings.health_check.clone() else {
+                continue;
+            };
+            let interval = Duration::from_secs(check.interval_secs);
+            for backend in tunnel.backends.all() {
+                if !backend.health().lock().unwrap().claim(now, interval) {
+                    continue;
+                }
+                let host = tunnel
+                    .settings
+                    .upstream_host
+                    .clone()
+                    .unwrap_or_else(|| format!("{}.{domain}", tunnel.subdomain));
+                checks.spawn(check_backend(
+                    tunnel.clone(),
+                    backend,
+                    check.clone(),
+                    host,
+                    db.clone(),
+                ));
+            }
+        }
+    }
+}
+
+async fn check_backend(
+    tunnel: Arc<ActiveTunnel>,
+    backend: Arc<Backend>,
+    check: HealthCheck,
+    host: String,
+    db: SupabaseClient,
+) {
+    let passed = probe(&tunnel, &backend, &check, &host).await;
+    metrics::health_check(if passed { "pass" } else { "fail" });
+
+    let changed = backend.health().lock().unwrap().record(passed);
+    if let Some(status) = changed {
+        debug!(subdomain = %tunnel.subdomain, backend = %backend.id, status = status.as_str(), "backend health changed");
+    }
+
+    // Backends come and go, so work the tunnel's status out afresh
+    let status = HealthStatus::of_tunnel(&tunnel.backends.all());
+    if !tunnel.set_health(status) {
+        return;
+    }
+    info!(subdomain = %tunnel.subdomain, status = status.as_str(), "tunnel health changed");
+    metrics::health_transition(status.as_str());
+    if let Err(e) =
+        needle_db::queries::tunnels::set_health(&db, &tunnel.subdomain, status.as_str(), Utc::now())
+            .await
+    {
+        warn!(subdomain = %tunnel.subdomain, error = %e, "failed to record tunnel health");
+    }
+}
+
+/// Sends one check request, the way the edge would send a visitor's.
+/// Every stage gives up after the check's interval at the latest.
+async fn probe(tunnel: &ActiveTunnel, backend: &Backend, check: &HealthCheck, host: &str) -> bool {
+    let req = Request::get(check.path.as_str())
+        .header(header::HOST, host)
+        .header(header::USER_AGENT, "needle-health-check")
+        .body(Empty::<Bytes>::new());
+    let Ok(req) = req else {
+        return false;
+    };
+
+    let cap = check.interval_secs;
+    let timeouts = ProxyTimeouts {
+        connect_secs: tunnel.timeouts.connect_secs.min(cap),
+        header_secs: tunnel.timeouts.header_secs.min(cap),
+        idle_secs: tunnel.timeouts.idle_secs.min(cap),
+        total_secs: tunnel.timeouts.total_secs.min(cap),
+    };
+    match forward_request(
+        backend.bind_addr,
+        req,
+        tunnel.settings.upstream_protocol,
+        tunnel.body_limits,
+        timeouts,
+    )
+    .await
+    {
+        Ok(response) => response.status().as_u16() == check.expected_status,
+        Err(e) => {
+            debug!(subdomain = %tunnel.subdomain, backend = %backend.id, error = %e, "health check failed");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goes_down_after_repeated_failures_and_back_up_on_a_pass() {
+        let mut health = BackendHealth::default();
+        let now = Instant::now();
+        let interval = Duration::from_secs(10);
+
+        assert!(health.claim(now, interval));
+        assert!(!health.claim(now + Duration::from_secs(9), interval));
+        assert!(health.claim(now + interval, interval));
+
+        assert_eq!(health.record(true), Some(HealthStatus::Up));
+        assert_eq!(health.record(true), None);
+        assert_eq!(health.record(false), None);
+        assert_eq!(health.status(), HealthStatus::Up);
+        assert_eq!(health.record(false), Some(HealthStatus::Down));
+        assert_eq!(health.record(false), None);
+        assert_eq!(health.record(true), Some(HealthStatus::Up));
+    }
+}
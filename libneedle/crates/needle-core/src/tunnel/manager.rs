@@ -1,27 +1,167 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
+use crate::config::{BodyLimits, NeedleConfig, ProxyTimeouts, RateLimitSettings};
 use crate::metrics;
+use crate::proxy::error_page::MAX_CUSTOM_PAGE_SIZE;
+use crate::proxy::mirror;
+use crate::proxy::passthrough;
+use crate::ssh::handler::{ForwardKey, SessionForwards, SessionLink};
+use crate::tunnel::access::AccessPolicy;
+use crate::tunnel::balancer::{Backend, BackendGuard, BackendPool};
+use crate::tunnel::health::HealthStatus;
+use crate::tunnel::private;
+use crate::tunnel::settings::TunnelSettings;
+use crate::tunnel::udp::{self, UdpRelay};
+use chrono::{DateTime, Utc};
 use needle_common::error::{NeedleError, Result};
 use needle_common::rate_limit::RateLimiter;
 use needle_common::subdomain;
 use needle_db::client::SupabaseClient;
+use needle_db::models::{ReservedSubdomain, Tunnel};
+use needle_db::queries::reserved_subdomains;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
 use tracing::{error, info, warn};
 use uuid::Uuid;
 
 pub struct ActiveTunnel {
+    pub tunnel_id: Uuid,
     pub subdomain: String,
     pub listener: TcpListener,
     pub bind_addr: SocketAddr,
     pub client_ip: String,
     pub user_id: Uuid,
+    pub target_port: i32,
+    pub protocol: String,
+    pub is_persistent: bool,
+    /// The owner's tier when the tunnel opened, for metric labels.
+    pub tier: String,
+    /// The request rate the edge holds this tunnel to.
+    pub rate_limit: RateLimitSettings,
     pub rate_limiter: RateLimiter,
+    /// The size limits the edge holds this tunnel to.
+    pub body_limits: BodyLimits,
+    /// How long the edge waits on this tunnel's app.
+    pub timeouts: ProxyTimeouts,
+    pub settings: TunnelSettings,
+    /// The SSH connection serving this tunnel, if it came in over SSH.
+    pub session: Option<SessionLink>,
+    /// The public UDP port and relay behind a `udp` tunnel.
+    pub udp: Option<UdpRelay>,
+    /// The clients serving the tunnel. Just the one that opened it,
+    /// unless the tunnel is load-balanced.
+    pub backends: BackendPool,
+    /// Copies of this tunnel's requests still on their way to its
+    /// mirror. A copy that finds no free slot isn't sent.
+    pub mirror_slots: Arc<Semaphore>,
+    // What the tunnel's health check last concluded, across its backends
+    health: std::sync::Mutex<HealthStatus>,
+    // Swapped wholesale when the owner changes it mid-session
+    access: std::sync::RwLock<Arc<AccessPolicy>>,
+    // What the owner asked for, kept so a reservation can carry it over
+    requested_rate_limit: Option<RateLimitSettings>,
 }
 
+impl ActiveTunnel {
+    /// The access policy in force right now.
+    pub fn access_policy(&self) -> Arc<AccessPolicy> {
+        self.access.read().unwrap().clone()
+    }
+
+    pub fn health(&self) -> HealthStatus {
+        *self.health.lock().unwrap()
+    }
+
+    /// Records the tunnel's status. Returns whether it changed.
+    pub(crate) fn set_health(&self, status: HealthStatus) -> bool {
+        let mut current = self.health.lock().unwrap();
+        let changed = *current != status;
+        *current = status;
+        changed
+    }
+
+    /// The backend the next request should go to.
+    pub fn pick_backend(&self) -> Option<BackendGuard> {
+        let strategy = self.settings.load_balancing.unwrap_or_default();
+        self.backends.pick(strategy)
+    }
+}
+
+/// What a caller can ask for when opening a tunnel. The defaults give an
+/// ephemeral HTTP tunnel on a generated subdomain that never expires
+/// (tier limits may still cap its lifetime).
+#[derive(Debug, Clone)]
+pub struct TunnelOptions {
+    pub subdomain: Option<String>,
+    pub target_port: i32,
+    pub protocol: String,
+    pub is_persistent: bool,
+    pub ttl: Option<Duration>,
+    pub access: AccessPolicy,
+    /// A lower request rate than the owner's tier allows. Anything above
+    /// the tier's limit is capped to it.
+    pub rate_limit: Option<RateLimitSettings>,
+    pub settings: TunnelSettings,
+}
+
+impl Default for TunnelOptions {
+    fn default() -> Self {
+        Self {
+            subdomain: None,
+            target_port: 80,
+            protocol: "http".to_string(),
+            is_persistent: false,
+            ttl: None,
+            access: AccessPolicy::default(),
+            rate_limit: None,
+            settings: TunnelSettings::default(),
+        }
+    }
+}
+
+/// A persistent tunnel whose SSH client is currently disconnected. The
+/// subdomain stays held for the owner until `expires_at`, and the edge
+/// serves an offline page for it in the meantime.
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    pub tunnel_id: Uuid,
+    pub user_id: Uuid,
+    pub target_port: i32,
+    pub protocol: String,
+    pub access: AccessPolicy,
+    pub rate_limit: Option<RateLimitSettings>,
+    pub settings: TunnelSettings,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// What the edge should do with a request for a given subdomain.
+pub enum TunnelLookup {
+    Active(Arc<ActiveTunnel>),
+    /// Carries the owner's own offline page, if they uploaded one.
+    Offline(Option<Arc<str>>),
+    /// Connected, but its health check says the app is down. Carries
+    /// the offline page like `Offline`.
+    Unhealthy(Option<Arc<str>>),
+    Expired,
+    Missing,
+}
+
+/// Protocols whose traffic goes over channels the server opens to the
+/// SSH client, rather than through the tunnel's local listener.
+const CHANNEL_PROTOCOLS: &[&str] = &[udp::PROTOCOL, passthrough::PROTOCOL, private::PROTOCOL];
+
+/// How many of the owner's clients can serve one load-balanced tunnel.
+const MAX_BACKENDS: usize = 8;
+
+/// How long the edge keeps answering "expired" for a reaped tunnel before
+/// the subdomain falls back to a plain 404.
+const EXPIRED_NOTICE_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
 /// Keeps track of all live tunnels on this server instance. Each tunnel
 /// has a subdomain that maps to an internal TCP listener, which receives
 /// proxied HTTP traffic and forwards it back through the SSH channel to
@@ -29,35 +169,92 @@ pub struct ActiveTunnel {
 ///
 /// The manager enforces capacity limits (per-IP and global), handles
 /// subdomain uniqueness, and cleans up resources when tunnels close.
+/// Persistent tunnels that lose their client are parked in `reserved`
+/// instead of being freed, so the owner can pick them back up. Tunnels
+/// with a lifetime have an entry in `expiries` that the reaper task
+/// watches; once reaped they're remembered in `expired` for a while so
+/// visitors get an explanation instead of a bare 404. Owners of
+/// persistent tunnels can leave an offline page in `offline_pages`,
+/// which outlives any one SSH session.
 pub struct TunnelManager {
     tunnels: HashMap<String, Arc<ActiveTunnel>>,
+    reserved: HashMap<String, Reservation>,
+    offline_pages: HashMap<String, Arc<str>>,
+    expiries: HashMap<String, DateTime<Utc>>,
+    expired: HashMap<String, DateTime<Utc>>,
     ip_counts: HashMap<String, usize>,
     db: SupabaseClient,
-    max_tunnels_per_ip: usize,
-    global_tunnel_limit: usize,
-    requests_per_second: f64,
-    burst_size: f64,
+    config: NeedleConfig,
 }
 
 impl TunnelManager {
-    pub fn new(
-        db: SupabaseClient,
-        max_tunnels_per_ip: usize,
-        global_tunnel_limit: usize,
-        requests_per_second: f64,
-        burst_size: f64,
-    ) -> Self {
+    pub fn new(db: SupabaseClient, config: NeedleConfig) -> Self {
         Self {
             tunnels: HashMap::new(),
+            reserved: HashMap::new(),
+            offline_pages: HashMap::new(),
+            expiries: HashMap::new(),
+            expired: HashMap::new(),
             ip_counts: HashMap::new(),
             db,
-            max_tunnels_per_ip,
-            global_tunnel_limit,
-            requests_per_second,
-            burst_size,
+            config,
         }
     }
 
+    /// Rebuilds the reservation table from persistent tunnels in the
+    /// database. Each reservation window restarts from the tunnel's
+    /// `last_active` time, so ones that lapsed while we were down are
+    /// skipped. Returns how many reservations were restored.
+    pub async fn restore_reservations(&mut self) -> Result<usize> {
+        let persistent = needle_db::queries::tunnels::find_persistent(&self.db).await?;
+        let now = Utc::now();
+
+        for tunnel in persistent {
+            let tier =
+                needle_db::queries::users::get_tier(&self.db, &tunnel.user_id.to_string()).await?;
+            let expires_at = tunnel.last_active + self.reservation_window(&tier);
+            if expires_at <= now {
+                continue;
+            }
+
+            if tunnel.is_active {
+                // We went down without getting to mark it offline
+                needle_db::queries::tunnels::set_active(&self.db, &tunnel.subdomain, false).await?;
+            }
+
+            let access = stored_access_policy(&tunnel.subdomain, tunnel.access_policy);
+            let rate_limit = tunnel
+                .rate_limit_rps
+                .zip(tunnel.rate_limit_burst)
+                .map(|(rps, burst)| RateLimitSettings::new(rps, burst));
+            let settings = stored_settings(&tunnel.subdomain, tunnel.settings);
+            if let Some(page) = tunnel.offline_page {
+                self.offline_pages
+                    .insert(tunnel.subdomain.clone(), page.into());
+            }
+            self.reserved.insert(
+                tunnel.subdomain.clone(),
+                Reservation {
+                    tunnel_id: tunnel.id,
+                    user_id: tunnel.user_id,
+                    target_port: tunnel.target_port,
+                    protocol: tunnel.protocol,
+                    access,
+                    rate_limit,
+                    settings,
+                    expires_at,
+                },
+            );
+
+            if let Some(at) = tunnel.expires_at {
+                self.expiries.insert(tunnel.subdomain, at);
+            }
+        }
+
+        info!(count = self.reserved.len(), "restored tunnel reservations");
+        Ok(self.reserved.len())
+    }
+
     /// Spins up a new tunnel by optionally using a custom subdomain or generating
     /// a unique one, binding a local TCP listener, and registering everything in
     /// both the in-memory map and the database.
@@ -65,126 +262,639 @@ impl TunnelManager {
     /// The flow goes: query user tier → check tier limit → validate subdomain →
     /// check IP/global limits → bind listener → save to database → register in memory.
     /// If any step fails, we bail out early without leaving orphaned state.
+    ///
+    /// `session` links the tunnel back to the SSH connection serving it so
+    /// background tasks can tell the client what happened to it.
     pub async fn create(
         &mut self,
         client_ip: &str,
         user_id: Uuid,
-        custom_subdomain: Option<String>,
-        target_port: i32,
-        protocol: &str,
-        is_persistent: bool,
+        options: TunnelOptions,
+        session: Option<SessionLink>,
     ) -> Result<Arc<ActiveTunnel>> {
-        // Count existing tunnels for this user to enforce per-user limits
-        let user_tunnel_count = self
-            .tunnels
-            .values()
-            .filter(|t| t.user_id == user_id)
-            .count();
-
-        // Apply tier enforcement using reasonable defaults
-        // TODO: Query actual user tier from database once queries::users::get_tier implemented
-        // For now, enforce a reasonable per-user limit
-        const DEFAULT_USER_TUNNEL_LIMIT: usize = 10;
-
-        if user_tunnel_count >= DEFAULT_USER_TUNNEL_LIMIT {
-            warn!(
-                user_id = %user_id,
-                current = user_tunnel_count,
-                limit = DEFAULT_USER_TUNNEL_LIMIT,
-                "per-user tunnel limit exceeded"
-            );
-            metrics::error_occurred("tier_limit_exceeded");
-            return Err(NeedleError::ServerAtCapacity); // Temporary - use proper tier error once tier lookup works
-        }
+        let TunnelOptions {
+            subdomain: custom_subdomain,
+            target_port,
+            protocol,
+            is_persistent,
+            ttl,
+            access,
+            rate_limit,
+            settings,
+        } = options;
+        self.check_access(&access)?;
+        check_rate_limit(rate_limit)?;
+        settings.validate().map_err(NeedleError::Config)?;
+        check_http_only(&protocol, &settings)?;
 
-        // Check IP-based limit
-        let ip_count = self.ip_counts.get(client_ip).copied().unwrap_or(0);
-        if ip_count >= self.max_tunnels_per_ip {
-            return Err(NeedleError::MaxTunnelsPerIp);
+        // Another of the owner's clients joining a load-balanced tunnel
+        // adds a backend rather than a tunnel
+        if let Some(name) = &custom_subdomain
+            && let Some(tunnel) = self.tunnels.get(name)
+            && tunnel.user_id == user_id
+            && tunnel.settings.load_balancing.is_some()
+            && session.is_some()
+        {
+            let tunnel = tunnel.clone();
+            check_join(&tunnel, &protocol, &access, rate_limit, &settings)?;
+            self.add_backend(&tunnel, client_ip, session).await?;
+            return Ok(tunnel);
         }
 
-        // Check global capacity
-        if self.tunnels.len() >= self.global_tunnel_limit {
-            return Err(NeedleError::ServerAtCapacity);
-        }
+        self.check_capacity(user_id, client_ip)?;
 
+        let mut reattach = None;
         let sub = if let Some(custom) = custom_subdomain {
             // Use custom subdomain if provided
+            self.check_custom_subdomain(user_id, &custom).await?;
             if self.tunnels.contains_key(&custom) {
                 return Err(NeedleError::SubdomainTaken(custom));
             }
+            self.purge_lapsed_reservation(&custom);
+            if let Some(reservation) = self.reserved.get(&custom) {
+                if reservation.user_id != user_id {
+                    return Err(NeedleError::SubdomainTaken(custom));
+                }
+                reattach = Some(reservation.clone());
+            }
             custom
         } else {
             // Generate unique subdomain
             self.generate_unique_subdomain()?
         };
 
+        // UDP, TLS passthrough and private tunnels open channels down the
+        // SSH connection themselves, so there has to be one. UDP tunnels also
+        // get a public port of their own.
+        let effective_protocol = reattach.as_ref().map_or(protocol.as_str(), |r| &r.protocol);
+        if CHANNEL_PROTOCOLS.contains(&effective_protocol) && session.is_none() {
+            return Err(NeedleError::Config(format!(
+                "{effective_protocol} tunnels need an ssh client to relay to"
+            )));
+        }
+        let udp_socket = if effective_protocol == udp::PROTOCOL {
+            let ports = self.config.udp_port_min..=self.config.udp_port_max;
+            let socket = udp::bind(&self.config.udp_bind_host, ports).await?;
+            let public_addr = socket.local_addr()?;
+            Some((socket, public_addr))
+        } else {
+            None
+        };
+
         let listener = TcpListener::bind("127.0.0.1:0").await?;
         let bind_addr = listener.local_addr()?;
 
-        info!(subdomain = %sub, addr = %bind_addr, "tunnel created");
+        // A reattaching persistent tunnel already has its row, so we just
+        // flip it back on rather than inserting a duplicate.
+        let db_result = match &reattach {
+            Some(reservation) => needle_db::queries::tunnels::set_active(&self.db, &sub, true)
+                .await
+                .map(|_| (reservation.tunnel_id, !access.is_open())),
+            None => self
+                .insert_tunnel_row(user_id, &sub, target_port, &protocol, is_persistent)
+                .await
+                .map(|row| (row.id, !access.is_open() || row.access_policy.is_some())),
+        };
 
-        // Attempt database write, rollback listener on failure
-        match needle_db::queries::tunnels::create(
-            &self.db,
-            &user_id.to_string(),
-            &sub,
-            target_port,
-            protocol,
-            is_persistent,
-        )
-        .await
-        {
-            Ok(_) => {}
+        // Rollback listener on failure
+        let (tunnel_id, store_access) = match db_result {
+            Ok(row) => row,
             Err(e) => {
-                // Rollback: close listener and return error
                 drop(listener);
                 metrics::error_occurred("tunnel_db_write_failed");
                 return Err(e);
             }
+        };
+
+        if store_access && let Err(e) = self.store_access_policy(&sub, &access).await {
+            drop(listener);
+            metrics::error_occurred("tunnel_db_write_failed");
+            return Err(e);
+        }
+
+        if let Some(limit) = rate_limit
+            && let Err(e) = self.store_rate_limit(&sub, Some(limit)).await
+        {
+            drop(listener);
+            metrics::error_occurred("tunnel_db_write_failed");
+            return Err(e);
+        }
+
+        if !settings.is_default()
+            && let Err(e) = self.store_settings(&sub, &settings).await
+        {
+            drop(listener);
+            metrics::error_occurred("tunnel_db_write_failed");
+            return Err(e);
+        }
+
+        // Whatever the last session's checks found no longer holds
+        if settings.health_check.is_some()
+            && let Err(e) = needle_db::queries::tunnels::set_health(
+                &self.db,
+                &sub,
+                HealthStatus::Unknown.as_str(),
+                Utc::now(),
+            )
+            .await
+        {
+            warn!(subdomain = %sub, error = %e, "failed to reset tunnel health");
+        }
+
+        // A reattached tunnel keeps whatever lifetime it was created with
+        if reattach.is_none()
+            && let Err(e) = self.apply_lifetime(user_id, &sub, ttl).await
+        {
+            warn!(subdomain = %sub, error = %e, "failed to record tunnel expiry");
         }
+        self.expired.remove(&sub);
 
+        // A reattaching client keeps the reservation's policy, rate limit
+        // and settings unless it brought new ones
+        let (target_port, protocol, is_persistent, access, rate_limit, settings) = match reattach {
+            Some(reservation) => {
+                self.reserved.remove(&sub);
+                info!(subdomain = %sub, addr = %bind_addr, "persistent tunnel reattached");
+                let access = if access.is_open() {
+                    reservation.access
+                } else {
+                    access
+                };
+                let rate_limit = rate_limit.or(reservation.rate_limit);
+                let settings = if settings.is_default() {
+                    reservation.settings
+                } else {
+                    settings
+                };
+                (
+                    reservation.target_port,
+                    reservation.protocol,
+                    true,
+                    access,
+                    rate_limit,
+                    settings,
+                )
+            }
+            None => {
+                info!(subdomain = %sub, addr = %bind_addr, "tunnel created");
+                (
+                    target_port,
+                    protocol,
+                    is_persistent,
+                    access,
+                    rate_limit,
+                    settings,
+                )
+            }
+        };
+
+        let tier = self.user_tier(user_id).await;
+        let limit = self.effective_rate_limit(&tier, rate_limit);
+        let body_limits = settings
+            .limits
+            .within(self.config.tunnel_body_limits(&tier));
+        let timeouts = settings.timeouts.within(
+            self.config.proxy_timeouts(),
+            self.config.tunnel_max_proxy_timeout(&tier).as_secs(),
+        );
+
+        let udp = match (udp_socket, &session) {
+            (Some((socket, public_addr)), Some(link)) => {
+                let port = Some(public_addr.port());
+                if let Err(e) =
+                    needle_db::queries::tunnels::set_public_port(&self.db, &sub, port).await
+                {
+                    warn!(subdomain = %sub, error = %e, "failed to record udp tunnel port");
+                }
+                info!(subdomain = %sub, addr = %public_addr, "udp tunnel listening");
+                let idle = self.config.udp_peer_idle;
+                Some(UdpRelay::spawn(socket, public_addr, link.clone(), idle))
+            }
+            _ => None,
+        };
+
+        let backends = BackendPool::new(Backend::new(bind_addr, client_ip, session.clone(), None));
         let tunnel = Arc::new(ActiveTunnel {
+            tunnel_id,
             subdomain: sub.clone(),
             listener,
             bind_addr,
             client_ip: client_ip.to_string(),
             user_id,
-            rate_limiter: RateLimiter::new(self.requests_per_second, self.burst_size),
+            target_port,
+            protocol,
+            is_persistent,
+            tier,
+            rate_limit: limit,
+            rate_limiter: RateLimiter::new(limit.requests_per_second, limit.burst),
+            body_limits,
+            timeouts,
+            settings,
+            session,
+            udp,
+            backends,
+            mirror_slots: Arc::new(Semaphore::new(mirror::MAX_IN_FLIGHT)),
+            health: std::sync::Mutex::default(),
+            access: std::sync::RwLock::new(Arc::new(access)),
+            requested_rate_limit: rate_limit,
         });
 
         self.tunnels.insert(sub.clone(), tunnel.clone());
         *self.ip_counts.entry(client_ip.to_string()).or_insert(0) += 1;
 
         // Record metrics
-        metrics::tunnel_created(protocol);
+        metrics::tunnel_created(&tunnel.protocol);
 
         Ok(tunnel)
     }
 
+    /// Reserves a persistent tunnel without a connected client. It shows
+    /// up as offline at the edge until the owner attaches over SSH using
+    /// the same name, which `options.subdomain` must therefore provide.
+    pub async fn reserve(&mut self, user_id: Uuid, options: TunnelOptions) -> Result<Reservation> {
+        let TunnelOptions {
+            subdomain,
+            target_port,
+            protocol,
+            ttl,
+            access,
+            rate_limit,
+            settings,
+            ..
+        } = options;
+        let Some(subdomain) = subdomain else {
+            return Err(NeedleError::InvalidSubdomain(
+                "persistent tunnels need a subdomain".to_string(),
+            ));
+        };
+        self.check_access(&access)?;
+        check_rate_limit(rate_limit)?;
+        settings.validate().map_err(NeedleError::Config)?;
+        check_http_only(&protocol, &settings)?;
+
+        self.check_custom_subdomain(user_id, &subdomain).await?;
+        self.purge_lapsed_reservation(&subdomain);
+        if self.tunnels.contains_key(&subdomain) || self.reserved.contains_key(&subdomain) {
+            return Err(NeedleError::SubdomainTaken(subdomain));
+        }
+
+        let row = needle_db::queries::tunnels::create(
+            &self.db,
+            &user_id.to_string(),
+            &subdomain,
+            target_port,
+            &protocol,
+            true,
+            false,
+        )
+        .await?;
+        if !access.is_open() {
+            self.store_access_policy(&subdomain, &access).await?;
+        }
+        if rate_limit.is_some() {
+            self.store_rate_limit(&subdomain, rate_limit).await?;
+        }
+        if !settings.is_default() {
+            self.store_settings(&subdomain, &settings).await?;
+        }
+
+        let reservation = Reservation {
+            tunnel_id: row.id,
+            user_id,
+            target_port,
+            protocol,
+            access,
+            rate_limit,
+            settings,
+            expires_at: self.reservation_expiry(user_id).await?,
+        };
+        self.reserved.insert(subdomain.clone(), reservation.clone());
+        self.expired.remove(&subdomain);
+        if let Err(e) = self.apply_lifetime(user_id, &subdomain, ttl).await {
+            warn!(subdomain = %subdomain, error = %e, "failed to record tunnel expiry");
+        }
+        info!(subdomain = %subdomain, expires_at = %reservation.expires_at, "tunnel reserved");
+        Ok(reservation)
+    }
+
+    /// Reserves a subdomain for a user without a live tunnel, so nobody
+    /// else can take it in the meantime. The tier caps how many names a
+    /// user may hold; the database insert is what settles races.
+    pub async fn claim_subdomain(&self, user_id: Uuid, name: &str) -> Result<ReservedSubdomain> {
+        self.check_custom_subdomain(user_id, name).await?;
+
+        let held_by_other = self
+            .tunnels
+            .get(name)
+            .map(|t| t.user_id)
+            .or_else(|| self.reserved.get(name).map(|r| r.user_id))
+            .is_some_and(|owner| owner != user_id);
+        if held_by_other {
+            return Err(NeedleError::SubdomainTaken(name.to_string()));
+        }
+
+        // An old tunnel row pins the name to its owner's history
+        let user = user_id.to_string();
+        let existing = needle_db::queries::tunnels::find_by_subdomain(&self.db, name).await?;
+        if existing.is_some_and(|t| t.user_id != user_id) {
+            return Err(NeedleError::SubdomainTaken(name.to_string()));
+        }
+
+        let tier = needle_db::queries::users::get_tier(&self.db, &user).await?;
+        let limit = self.config.reserved_subdomain_limit(&tier);
+        let held = reserved_subdomains::find_by_user(&self.db, &user)
+            .await?
+            .len();
+        if held >= limit {
+            metrics::error_occurred("reservation_limit_exceeded");
+            return Err(NeedleError::ReservationLimitExceeded { tier, limit });
+        }
+
+        let claimed = reserved_subdomains::claim(&self.db, name, &user).await?;
+        info!(user_id = %user_id, name = %name, "subdomain reserved");
+        Ok(claimed)
+    }
+
+    pub async fn release_subdomain(&self, user_id: Uuid, name: &str) -> Result<bool> {
+        reserved_subdomains::release(&self.db, &user_id.to_string(), name).await
+    }
+
+    pub async fn reserved_subdomains(&self, user_id: Uuid) -> Result<Vec<ReservedSubdomain>> {
+        reserved_subdomains::find_by_user(&self.db, &user_id.to_string()).await
+    }
+
+    /// The ID of a tunnel the user owns, whether it's live or waiting
+    /// for its client to come back.
+    pub fn owned_tunnel_id(&self, subdomain: &str, user_id: Uuid) -> Option<Uuid> {
+        if let Some(tunnel) = self.tunnels.get(subdomain) {
+            return (tunnel.user_id == user_id).then_some(tunnel.tunnel_id);
+        }
+        self.reserved
+            .get(subdomain)
+            .filter(|r| r.user_id == user_id && r.expires_at > Utc::now())
+            .map(|r| r.tunnel_id)
+    }
+
     pub fn get(&self, subdomain: &str) -> Option<Arc<ActiveTunnel>> {
         self.tunnels.get(subdomain).cloned()
     }
 
-    pub async fn remove(&mut self, sub: &str) -> Result<()> {
-        if let Some(tunnel) = self.tunnels.remove(sub) {
-            if let Some(count) = self.ip_counts.get_mut(&tunnel.client_ip) {
-                *count = count.saturating_sub(1);
-                if *count == 0 {
-                    self.ip_counts.remove(&tunnel.client_ip);
-                }
+    /// Live tunnels with a health check for the checker to run.
+    pub fn health_checked(&self) -> Vec<Arc<ActiveTunnel>> {
+        self.tunnels
+            .values()
+            .filter(|t| t.settings.health_check.is_some())
+            .cloned()
+            .collect()
+    }
+
+    /// Resolves a subdomain for the edge. Reservations past their expiry
+    /// count as missing even before anyone gets round to purging them.
+    pub fn lookup(&self, subdomain: &str) -> TunnelLookup {
+        let now = Utc::now();
+        if self.expired.contains_key(subdomain)
+            || self.expiries.get(subdomain).is_some_and(|at| *at <= now)
+        {
+            return TunnelLookup::Expired;
+        }
+
+        if let Some(tunnel) = self.tunnels.get(subdomain) {
+            // No point making visitors wait on an app we know is down
+            if tunnel.health() == HealthStatus::Down {
+                return TunnelLookup::Unhealthy(self.offline_pages.get(subdomain).cloned());
             }
+            return TunnelLookup::Active(tunnel.clone());
+        }
+
+        match self.reserved.get(subdomain) {
+            Some(r) if r.expires_at > now => {
+                TunnelLookup::Offline(self.offline_pages.get(subdomain).cloned())
+            }
+            _ => TunnelLookup::Missing,
+        }
+    }
 
+    /// Drops a tunnel (or reservation) from the registry and marks it
+    /// inactive in the database. A persistent tunnel stops being
+    /// persistent too, so the next restart doesn't restore it. `reason`
+    /// ends up as the label on the destroyed-tunnels metric, e.g.
+    /// "user_deleted".
+    pub async fn remove(&mut self, sub: &str, reason: &str) -> Result<()> {
+        self.expiries.remove(sub);
+        self.offline_pages.remove(sub);
+        if self.reserved.remove(sub).is_some() {
+            needle_db::queries::tunnels::set_persistent(&self.db, sub, false).await?;
+            info!(subdomain = %sub, "tunnel reservation released");
+            return Ok(());
+        }
+
+        if let Some(tunnel) = self.take_active(sub) {
             needle_db::queries::tunnels::set_active(&self.db, sub, false).await?;
+            if tunnel.is_persistent {
+                needle_db::queries::tunnels::set_persistent(&self.db, sub, false).await?;
+            }
             info!(subdomain = %sub, "tunnel removed");
 
             // Record metrics
-            metrics::tunnel_destroyed("user_deleted");
+            metrics::tunnel_destroyed(reason);
+        }
+
+        Ok(())
+    }
+
+    /// Called when the SSH forward `forward` on the connection owning
+    /// `forwards` goes away. If other clients are load-balanced behind
+    /// the tunnel, only that client's backend leaves and the rest carry
+    /// on. Otherwise ephemeral tunnels are removed outright, and
+    /// persistent ones keep their subdomain reserved for the owner for
+    /// the tier's reservation window.
+    pub async fn detach(
+        &mut self,
+        sub: &str,
+        forwards: &SessionForwards,
+        forward: &ForwardKey,
+        reason: &str,
+    ) -> Result<()> {
+        if let Some(backend) = self
+            .tunnels
+            .get(sub)
+            .and_then(|t| t.backends.remove_forward(forwards, forward))
+        {
+            self.release_ip(&backend.client_ip);
+            info!(subdomain = %sub, backend = %backend.id, "backend left load-balanced tunnel");
+            return Ok(());
+        }
+
+        let persistent = self.tunnels.get(sub).is_some_and(|t| t.is_persistent);
+        if !persistent {
+            return self.remove(sub, reason).await;
+        }
+
+        let Some(tunnel) = self.take_active(sub) else {
+            return Ok(());
+        };
+
+        needle_db::queries::tunnels::set_active(&self.db, sub, false).await?;
+        metrics::tunnel_destroyed(reason);
+
+        let reservation = Reservation {
+            tunnel_id: tunnel.tunnel_id,
+            user_id: tunnel.user_id,
+            target_port: tunnel.target_port,
+            protocol: tunnel.protocol.clone(),
+            access: (*tunnel.access_policy()).clone(),
+            rate_limit: tunnel.requested_rate_limit,
+            settings: tunnel.settings.clone(),
+            expires_at: self.reservation_expiry(tunnel.user_id).await?,
+        };
+        self.reserved.insert(sub.to_string(), reservation.clone());
+        info!(
+            subdomain = %sub,
+            expires_at = %reservation.expires_at,
+            "persistent tunnel offline, subdomain reserved"
+        );
+
+        Ok(())
+    }
+
+    /// Gives a live tunnel a new lifetime counted from now, e.g. when the
+    /// client sends NEEDLE_TTL after its forward is already up. The tier
+    /// ceiling still applies. Returns the new expiry time.
+    pub async fn set_ttl(
+        &mut self,
+        sub: &str,
+        user_id: Uuid,
+        ttl: Duration,
+    ) -> Result<Option<DateTime<Utc>>> {
+        if matches!(self.lookup(sub), TunnelLookup::Expired) {
+            return Err(NeedleError::TunnelExpired);
+        }
+
+        let owned = self
+            .tunnels
+            .get(sub)
+            .map(|t| t.user_id)
+            .or_else(|| self.reserved.get(sub).map(|r| r.user_id))
+            .is_some_and(|owner| owner == user_id);
+        if !owned {
+            return Err(NeedleError::TunnelNotFound(sub.to_string()));
+        }
+
+        self.apply_lifetime(user_id, sub, Some(ttl)).await
+    }
+
+    /// Replaces the access policy on one of the user's tunnels, live or
+    /// reserved, and persists it. Takes effect on the next request.
+    pub async fn set_access(
+        &mut self,
+        sub: &str,
+        user_id: Uuid,
+        policy: AccessPolicy,
+    ) -> Result<()> {
+        self.check_access(&policy)?;
+        if let Some(tunnel) = self.tunnels.get(sub).filter(|t| t.user_id == user_id) {
+            *tunnel.access.write().unwrap() = Arc::new(policy.clone());
+        } else if let Some(reservation) =
+            self.reserved.get_mut(sub).filter(|r| r.user_id == user_id)
+        {
+            reservation.access = policy.clone();
+        } else {
+            return Err(NeedleError::TunnelNotFound(sub.to_string()));
+        }
+
+        self.store_access_policy(sub, &policy).await
+    }
+
+    /// Sets or clears the page visitors see while one of the user's
+    /// persistent tunnels is offline. Ephemeral tunnels are never
+    /// offline, so they can't have one.
+    pub async fn set_offline_page(
+        &mut self,
+        sub: &str,
+        user_id: Uuid,
+        page: Option<String>,
+    ) -> Result<()> {
+        if page
+            .as_ref()
+            .is_some_and(|p| p.len() > MAX_CUSTOM_PAGE_SIZE)
+        {
+            return Err(NeedleError::BodyTooLarge);
         }
 
+        let persistent = match self.tunnels.get(sub) {
+            Some(tunnel) if tunnel.user_id == user_id => tunnel.is_persistent,
+            Some(_) => return Err(NeedleError::TunnelNotFound(sub.to_string())),
+            None => match self.reserved.get(sub) {
+                Some(r) if r.user_id == user_id => true,
+                _ => return Err(NeedleError::TunnelNotFound(sub.to_string())),
+            },
+        };
+        if !persistent {
+            return Err(NeedleError::Config(
+                "only persistent tunnels have an offline page".to_string(),
+            ));
+        }
+
+        needle_db::queries::tunnels::set_offline_page(&self.db, sub, page.as_deref()).await?;
+        match page {
+            Some(page) => self.offline_pages.insert(sub.to_string(), page.into()),
+            None => self.offline_pages.remove(sub),
+        };
         Ok(())
     }
 
+    /// Closes every tunnel whose lifetime has run out, returning the
+    /// subdomains along with the SSH links of every client serving them
+    /// so the caller can notify clients without holding the manager lock.
+    pub async fn reap_expired(&mut self) -> Vec<(String, Vec<SessionLink>)> {
+        let now = Utc::now();
+        let retention =
+            chrono::Duration::from_std(EXPIRED_NOTICE_RETENTION).unwrap_or(chrono::Duration::MAX);
+        self.expired.retain(|_, at| now - *at < retention);
+
+        let due: Vec<String> = self
+            .expiries
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(sub, _)| sub.clone())
+            .collect();
+
+        let mut reaped = Vec::with_capacity(due.len());
+        for sub in due {
+            self.expiries.remove(&sub);
+
+            let links = if let Some(tunnel) = self.take_active(&sub) {
+                if let Err(e) = needle_db::queries::tunnels::set_active(&self.db, &sub, false).await
+                {
+                    warn!(subdomain = %sub, error = %e, "failed to mark expired tunnel inactive");
+                }
+                metrics::tunnel_destroyed("expired");
+                tunnel
+                    .backends
+                    .all()
+                    .iter()
+                    .filter_map(|b| b.session().cloned())
+                    .collect()
+            } else if self.reserved.remove(&sub).is_some() {
+                Vec::new()
+            } else {
+                continue;
+            };
+
+            info!(subdomain = %sub, "tunnel expired");
+            self.offline_pages.remove(&sub);
+            self.expired.insert(sub.clone(), now);
+            reaped.push((sub, links));
+        }
+
+        reaped
+    }
+
+    /// When this tunnel is due to close, if it has a lifetime at all.
+    pub fn expires_at(&self, subdomain: &str) -> Option<DateTime<Utc>> {
+        self.expiries.get(subdomain).copied()
+    }
+
     pub fn active_count(&self) -> usize {
         self.tunnels.len()
     }
@@ -193,10 +903,263 @@ impl TunnelManager {
         self.ip_counts.get(ip).copied().unwrap_or(0)
     }
 
+    /// Rejects custom names that are malformed, on the operator blocklist,
+    /// or reserved by a different user. Shared by the SSH and REST paths.
+    async fn check_custom_subdomain(&self, user_id: Uuid, name: &str) -> Result<()> {
+        if !subdomain::is_valid_custom(name) || self.config.is_blocked_subdomain(name) {
+            return Err(NeedleError::InvalidSubdomain(name.to_string()));
+        }
+
+        match reserved_subdomains::find_by_name(&self.db, name).await? {
+            Some(r) if r.user_id != user_id => Err(NeedleError::SubdomainTaken(name.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Inserts the tunnel row, falling back to reviving the user's own
+    /// stale row for the same name when the unique constraint trips.
+    async fn insert_tunnel_row(
+        &self,
+        user_id: Uuid,
+        sub: &str,
+        target_port: i32,
+        protocol: &str,
+        is_persistent: bool,
+    ) -> Result<Tunnel> {
+        let user = user_id.to_string();
+        match needle_db::queries::tunnels::create(
+            &self.db,
+            &user,
+            sub,
+            target_port,
+            protocol,
+            is_persistent,
+            true,
+        )
+        .await
+        {
+            Ok(row) => Ok(row),
+            Err(NeedleError::SubdomainTaken(_)) if !is_persistent => {
+                needle_db::queries::tunnels::reactivate(&self.db, &user, sub, target_port, protocol)
+                    .await?
+                    .ok_or_else(|| NeedleError::SubdomainTaken(sub.to_string()))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes an active tunnel from the in-memory maps, keeping the
+    /// per-IP counters in sync. Every backend counts against its
+    /// client's address.
+    fn take_active(&mut self, sub: &str) -> Option<Arc<ActiveTunnel>> {
+        let tunnel = self.tunnels.remove(sub)?;
+        for backend in tunnel.backends.all() {
+            self.release_ip(&backend.client_ip);
+        }
+        Some(tunnel)
+    }
+
+    fn release_ip(&mut self, ip: &str) {
+        if let Some(count) = self.ip_counts.get_mut(ip) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                self.ip_counts.remove(ip);
+            }
+        }
+    }
+
+    /// Adds another of the owner's SSH clients to a load-balanced
+    /// tunnel. It gets a listener of its own and counts against its
+    /// owner, its address and the server like a tunnel would, but the
+    /// tunnel keeps its own settings, access policy and lifetime.
+    async fn add_backend(
+        &mut self,
+        tunnel: &ActiveTunnel,
+        client_ip: &str,
+        session: Option<SessionLink>,
+    ) -> Result<()> {
+        if tunnel.backends.len() >= MAX_BACKENDS {
+            return Err(NeedleError::Config(format!(
+                "a load-balanced tunnel takes at most {MAX_BACKENDS} clients"
+            )));
+        }
+        self.check_capacity(tunnel.user_id, client_ip)?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let bind_addr = listener.local_addr()?;
+        let backend =
+            tunnel
+                .backends
+                .add(Backend::new(bind_addr, client_ip, session, Some(listener)));
+        *self.ip_counts.entry(client_ip.to_string()).or_insert(0) += 1;
+
+        info!(
+            subdomain = %tunnel.subdomain,
+            backend = %backend.id,
+            addr = %bind_addr,
+            backends = tunnel.backends.len(),
+            "backend joined load-balanced tunnel"
+        );
+        Ok(())
+    }
+
+    /// Refuses another listener when the user, their address or the
+    /// server already has as many as it's allowed. Every backend of a
+    /// load-balanced tunnel counts, since each is a listener of its own.
+    fn check_capacity(&self, user_id: Uuid, client_ip: &str) -> Result<()> {
+        // Count existing tunnels for this user to enforce per-user limits
+        let user_tunnel_count: usize = self
+            .tunnels
+            .values()
+            .filter(|t| t.user_id == user_id)
+            .map(|t| t.backends.len())
+            .sum();
+
+        // Apply tier enforcement using reasonable defaults
+        // TODO: Query actual user tier from database once queries::users::get_tier implemented
+        // For now, enforce a reasonable per-user limit
+        const DEFAULT_USER_TUNNEL_LIMIT: usize = 10;
+
+        if user_tunnel_count >= DEFAULT_USER_TUNNEL_LIMIT {
+            warn!(
+                user_id = %user_id,
+                current = user_tunnel_count,
+                limit = DEFAULT_USER_TUNNEL_LIMIT,
+                "per-user tunnel limit exceeded"
+            );
+            metrics::error_occurred("tier_limit_exceeded");
+            return Err(NeedleError::ServerAtCapacity); // Temporary - use proper tier error once tier lookup works
+        }
+
+        // Check IP-based limit
+        let ip_count = self.ip_counts.get(client_ip).copied().unwrap_or(0);
+        if ip_count >= self.config.max_tunnels_per_ip {
+            return Err(NeedleError::MaxTunnelsPerIp);
+        }
+
+        // Check global capacity
+        let listeners: usize = self.tunnels.values().map(|t| t.backends.len()).sum();
+        if listeners >= self.config.global_tunnel_limit {
+            return Err(NeedleError::ServerAtCapacity);
+        }
+        Ok(())
+    }
+
+    /// When a reservation made now for this user's tunnel would lapse.
+    async fn reservation_expiry(&self, user_id: Uuid) -> Result<DateTime<Utc>> {
+        let tier = needle_db::queries::users::get_tier(&self.db, &user_id.to_string()).await?;
+        Ok(Utc::now() + self.reservation_window(&tier))
+    }
+
+    /// Rejects policies this server can't enforce, like a login
+    /// requirement with no OIDC provider to log in with.
+    fn check_access(&self, policy: &AccessPolicy) -> Result<()> {
+        let Some(rule) = &policy.oidc else {
+            return Ok(());
+        };
+        rule.validate().map_err(NeedleError::Config)?;
+        if rule.issuer.is_none() && self.config.oidc_issuer_url.is_none() {
+            return Err(NeedleError::Config(
+                "this server has no OIDC provider, so oidc needs an issuer".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    async fn store_access_policy(&self, sub: &str, policy: &AccessPolicy) -> Result<()> {
+        let value = if policy.is_open() {
+            None
+        } else {
+            Some(serde_json::to_value(policy).map_err(|e| NeedleError::Supabase(e.to_string()))?)
+        };
+        needle_db::queries::tunnels::set_access_policy(&self.db, sub, value.as_ref()).await
+    }
+
+    async fn store_rate_limit(&self, sub: &str, limit: Option<RateLimitSettings>) -> Result<()> {
+        let limit = limit.map(|l| (l.requests_per_second, l.burst));
+        needle_db::queries::tunnels::set_rate_limit(&self.db, sub, limit).await
+    }
+
+    async fn store_settings(&self, sub: &str, settings: &TunnelSettings) -> Result<()> {
+        let value = if settings.is_default() {
+            None
+        } else {
+            Some(serde_json::to_value(settings).map_err(|e| NeedleError::Supabase(e.to_string()))?)
+        };
+        needle_db::queries::tunnels::set_settings(&self.db, sub, value.as_ref()).await
+    }
+
+    /// The owner's tier, falling back to free if we can't look it up so
+    /// a database hiccup doesn't stop the tunnel from opening.
+    async fn user_tier(&self, user_id: Uuid) -> String {
+        needle_db::queries::users::get_tier(&self.db, &user_id.to_string())
+            .await
+            .unwrap_or_else(|e| {
+                warn!(user_id = %user_id, error = %e, "failed to look up tier, assuming free");
+                "free".to_string()
+            })
+    }
+
+    /// The tier's rate limit, lowered by the tunnel's own if it has one.
+    fn effective_rate_limit(
+        &self,
+        tier: &str,
+        requested: Option<RateLimitSettings>,
+    ) -> RateLimitSettings {
+        let ceiling = self.config.tunnel_rate_limit(tier);
+        requested.map_or(ceiling, |limit| limit.min(ceiling))
+    }
+
+    /// Works out a tunnel's expiry from the requested TTL and the owner's
+    /// tier ceiling, then records it in memory and in the database.
+    async fn apply_lifetime(
+        &mut self,
+        user_id: Uuid,
+        sub: &str,
+        ttl: Option<Duration>,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let tier = needle_db::queries::users::get_tier(&self.db, &user_id.to_string()).await?;
+        // A lifetime too long to represent is as good as none at all
+        let expires_at = self
+            .config
+            .tunnel_lifetime(&tier, ttl)
+            .and_then(|lifetime| chrono::Duration::from_std(lifetime).ok())
+            .and_then(|lifetime| Utc::now().checked_add_signed(lifetime));
+        let Some(expires_at) = expires_at else {
+            self.expiries.remove(sub);
+            return Ok(None);
+        };
+
+        self.expiries.insert(sub.to_string(), expires_at);
+        needle_db::queries::tunnels::set_expiry(&self.db, sub, Some(expires_at)).await?;
+
+        Ok(Some(expires_at))
+    }
+
+    fn reservation_window(&self, tier: &str) -> chrono::Duration {
+        chrono::Duration::from_std(self.config.reservation_ttl(tier))
+            .unwrap_or(chrono::Duration::MAX)
+    }
+
+    /// Drops a reservation whose window has lapsed so the name can be
+    /// claimed again.
+    fn purge_lapsed_reservation(&mut self, sub: &str) {
+        if self
+            .reserved
+            .get(sub)
+            .is_some_and(|r| r.expires_at <= Utc::now())
+        {
+            self.reserved.remove(sub);
+            self.expiries.remove(sub);
+            self.offline_pages.remove(sub);
+            info!(subdomain = %sub, "tunnel reservation lapsed");
+        }
+    }
+
     fn generate_unique_subdomain(&self) -> Result<String> {
         for _ in 0..10 {
             let sub = subdomain::generate();
-            if !self.tunnels.contains_key(&sub) {
+            if !self.tunnels.contains_key(&sub) && !self.reserved.contains_key(&sub) {
                 return Ok(sub);
             }
             warn!("subdomain collision, retrying");
@@ -207,4 +1170,427 @@ impl TunnelManager {
     pub fn db_client(&self) -> &SupabaseClient {
         &self.db
     }
+
+    pub fn config(&self) -> &NeedleConfig {
+        &self.config
+    }
+}
+
+/// Refuses a per-tunnel rate limit that would never let a request through.
+fn check_rate_limit(limit: Option<RateLimitSettings>) -> Result<()> {
+    match limit {
+        Some(limit) if !limit.is_valid() => Err(NeedleError::Config(
+            "rate_limit needs requests_per_second > 0 and burst >= 1".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Refuses a client joining a load-balanced tunnel with options that
+/// disagree with it. The tunnel's first client set them and they stay
+/// put, so a joiner can leave them out or repeat them, but asking for
+/// something else would otherwise be silently ignored. Passwords are
+/// hashed with a fresh salt each time, so for access we can only compare
+/// which rules are set, not their secrets.
+fn check_join(
+    tunnel: &ActiveTunnel,
+    protocol: &str,
+    access: &AccessPolicy,
+    rate_limit: Option<RateLimitSettings>,
+    settings: &TunnelSettings,
+) -> Result<()> {
+    let conflict = |what: &str| {
+        Err(NeedleError::Config(format!(
+            "{what} doesn't match the load-balanced tunnel {} this client is joining",
+            tunnel.subdomain
+        )))
+    };
+    if protocol != tunnel.protocol {
+        return conflict("protocol");
+    }
+    if *settings != TunnelSettings::default() && *settings != tunnel.settings {
+        return conflict("settings");
+    }
+    if !access.is_open() && access.summary() != tunnel.access_policy().summary() {
+        return conflict("access");
+    }
+    if rate_limit.is_some() && rate_limit != tunnel.requested_rate_limit {
+        return conflict("rate_limit");
+    }
+    Ok(())
+}
+
+/// Load balancing spreads HTTP requests, and health checks and mirroring
+/// send them, so none of them make sense on a tunnel carrying anything
+/// else.
+fn check_http_only(protocol: &str, settings: &TunnelSettings) -> Result<()> {
+    if protocol == "http" {
+        return Ok(());
+    }
+    if settings.load_balancing.is_some() {
+        return Err(NeedleError::Config(
+            "load_balancing only works with http tunnels".to_string(),
+        ));
+    }
+    if settings.health_check.is_some() {
+        return Err(NeedleError::Config(
+            "health_check only works with http tunnels".to_string(),
+        ));
+    }
+    if settings.mirror.is_some() {
+        return Err(NeedleError::Config(
+            "mirror only works with http tunnels".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Unlike an access policy, settings we can't read aren't a security
+/// problem, so the tunnel just goes back to the defaults.
+fn stored_settings(sub: &str, value: Option<serde_json::Value>) -> TunnelSettings {
+    let Some(value) = value else {
+        return TunnelSettings::default();
+    };
+
+    serde_json::from_value(value).unwrap_or_else(|e| {
+        warn!(subdomain = %sub, error = %e, "unreadable tunnel settings, using defaults");
+        TunnelSettings::default()
+    })
+}
+
+/// Reads back a policy stored with a tunnel row. A row we can't make
+/// sense of gets locked down rather than silently opened up.
+fn stored_access_policy(sub: &str, value: Option<serde_json::Value>) -> AccessPolicy {
+    let Some(value) = value else {
+        return AccessPolicy::default();
+    };
+
+    serde_json::from_value(value).unwrap_or_else(|e| {
+        error!(subdomain = %sub, error = %e, "unreadable access policy, denying all traffic");
+        AccessPolicy::deny_all()
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::tunnel::balancer::LoadBalancing;
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Method, Request, Response};
+    use hyper_util::rt::TokioIo;
+    use serde_json::{Value, json};
+    use std::convert::Infallible;
+
+    type Tables = Arc<std::sync::Mutex<HashMap<String, Vec<Value>>>>;
+
+    /// A stand-in for Supabase's REST API, keeping rows in memory.
+    /// Inserts fill in an ID and timestamps, and reads and updates
+    /// understand `column=eq.value` filters, which is all the manager
+    /// uses.
+    async fn mock_supabase() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let tables = Tables::default();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let tables = tables.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let tables = tables.clone();
+                    async move { Ok::<_, Infallible>(mock_response(&tables, req).await) }
+                });
+                tokio::spawn(async move {
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        url
+    }
+
+    async fn mock_response(tables: &Tables, req: Request<Incoming>) -> Response<Full<Bytes>> {
+        let table = req.uri().path().trim_start_matches("/rest/v1/").to_string();
+        let filters: Vec<(String, String)> = req
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .filter_map(|(column, value)| {
+                Some((column.to_string(), value.strip_prefix("eq.")?.to_string()))
+            })
+            .collect();
+        let matches = |row: &Value| {
+            filters.iter().all(|(column, value)| match &row[column] {
+                Value::String(s) => s == value,
+                other => serde_json::from_str::<Value>(value).is_ok_and(|v| v == *other),
+            })
+        };
+
+        let method = req.method().clone();
+        let body = req.into_body().collect().await.unwrap().to_bytes();
+        let mut tables = tables.lock().unwrap();
+        let rows = tables.entry(table).or_default();
+        let result = match method {
+            Method::POST => {
+                let mut row: Value = serde_json::from_slice(&body).unwrap();
+                row["id"] = json!(Uuid::new_v4());
+                row["created_at"] = json!(Utc::now());
+                row["last_active"] = json!(Utc::now());
+                rows.push(row.clone());
+                vec![row]
+            }
+            Method::PATCH => {
+                let changes: Value = serde_json::from_slice(&body).unwrap();
+                let mut updated = Vec::new();
+                for row in rows.iter_mut().filter(|row| matches(row)) {
+                    for (column, value) in changes.as_object().unwrap() {
+                        row[column] = value.clone();
+                    }
+                    updated.push(row.clone());
+                }
+                updated
+            }
+            _ => rows.iter().filter(|row| matches(row)).cloned().collect(),
+        };
+        Response::new(Full::new(Bytes::from(Value::from(result).to_string())))
+    }
+
+    /// A manager with the default limits, writing to a mock database.
+    pub(crate) async fn manager() -> TunnelManager {
+        manager_on(&mock_supabase().await)
+    }
+
+    fn manager_on(url: &str) -> TunnelManager {
+        let db = SupabaseClient::new(url, "anon", "service");
+        let config = NeedleConfig::for_tests(url);
+        TunnelManager::new(db, config)
+    }
+
+    pub(crate) fn named(name: &str, is_persistent: bool) -> TunnelOptions {
+        TunnelOptions {
+            subdomain: Some(name.to_string()),
+            is_persistent,
+            ..TunnelOptions::default()
+        }
+    }
+
+    const CLIENT_IP: &str = "192.0.2.1";
+
+    #[tokio::test]
+    async fn joining_clients_must_agree_and_count_against_the_limits() {
+        let mut manager = manager().await;
+        manager.config.max_tunnels_per_ip = 100;
+        manager.config.global_tunnel_limit = 3;
+        let owner = Uuid::new_v4();
+        let mut options = named("shop", false);
+        options.settings.load_balancing = Some(LoadBalancing::RoundRobin);
+        options.rate_limit = Some(RateLimitSettings {
+            requests_per_second: 5.0,
+            burst: 10.0,
+        });
+        let settings = options.settings.clone();
+        let tunnel = manager
+            .create(CLIENT_IP, owner, options, None)
+            .await
+            .unwrap();
+
+        // Leaving options out, or repeating them, is fine
+        let open = AccessPolicy::default();
+        assert!(check_join(&tunnel, "http", &open, None, &TunnelSettings::default()).is_ok());
+        assert!(
+            check_join(
+                &tunnel,
+                "http",
+                &open,
+                tunnel.requested_rate_limit,
+                &settings
+            )
+            .is_ok()
+        );
+        // Asking for something else isn't
+        assert!(check_join(&tunnel, "tcp", &open, None, &settings).is_err());
+        let other = TunnelSettings {
+            upstream_host: Some("localhost".to_string()),
+            ..settings.clone()
+        };
+        assert!(check_join(&tunnel, "http", &open, None, &other).is_err());
+        let mut locked = AccessPolicy::default();
+        locked.set_bearer_token("tok");
+        assert!(check_join(&tunnel, "http", &locked, None, &settings).is_err());
+        let slower = Some(RateLimitSettings {
+            requests_per_second: 1.0,
+            burst: 1.0,
+        });
+        assert!(check_join(&tunnel, "http", &open, slower, &settings).is_err());
+
+        // Every backend is a listener the server has to carry
+        manager.add_backend(&tunnel, CLIENT_IP, None).await.unwrap();
+        manager.add_backend(&tunnel, CLIENT_IP, None).await.unwrap();
+        assert!(matches!(
+            manager.add_backend(&tunnel, CLIENT_IP, None).await,
+            Err(NeedleError::ServerAtCapacity)
+        ));
+        assert!(matches!(
+            manager
+                .create(CLIENT_IP, Uuid::new_v4(), named("other", false), None)
+                .await,
+            Err(NeedleError::ServerAtCapacity)
+        ));
+
+        // And one tunnel only takes so many
+        manager.config.global_tunnel_limit = 100;
+        while tunnel.backends.len() < MAX_BACKENDS {
+            manager.add_backend(&tunnel, CLIENT_IP, None).await.unwrap();
+        }
+        assert!(matches!(
+            manager.add_backend(&tunnel, CLIENT_IP, None).await,
+            Err(NeedleError::Config(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn owner_reattaches_a_persistent_reservation() {
+        let mut manager = manager().await;
+        let owner = Uuid::new_v4();
+        let reservation = manager
+            .reserve(owner, named("team-demo", true))
+            .await
+            .unwrap();
+        assert!(matches!(
+            manager.lookup("team-demo"),
+            TunnelLookup::Offline(None)
+        ));
+
+        let tunnel = manager
+            .create(CLIENT_IP, owner, named("team-demo", true), None)
+            .await
+            .unwrap();
+        assert_eq!(tunnel.tunnel_id, reservation.tunnel_id);
+        assert!(tunnel.is_persistent);
+        assert!(matches!(
+            manager.lookup("team-demo"),
+            TunnelLookup::Active(_)
+        ));
+
+        // Losing the client parks it again under the same row
+        let forward = ("team-demo".to_string(), 8080);
+        manager
+            .detach(
+                "team-demo",
+                &SessionForwards::default(),
+                &forward,
+                "ssh_disconnect",
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            manager.lookup("team-demo"),
+            TunnelLookup::Offline(None)
+        ));
+        assert_eq!(
+            manager.owned_tunnel_id("team-demo", owner),
+            Some(reservation.tunnel_id)
+        );
+        assert_eq!(manager.tunnels_for_ip(CLIENT_IP), 0);
+    }
+
+    #[tokio::test]
+    async fn reservation_is_refused_to_other_users() {
+        let mut manager = manager().await;
+        let owner = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        manager
+            .reserve(owner, named("team-demo", true))
+            .await
+            .unwrap();
+
+        let attached = manager
+            .create(CLIENT_IP, other, named("team-demo", true), None)
+            .await;
+        assert!(matches!(attached, Err(NeedleError::SubdomainTaken(_))));
+        let reserved = manager.reserve(other, named("team-demo", true)).await;
+        assert!(matches!(reserved, Err(NeedleError::SubdomainTaken(_))));
+
+        assert!(manager.owned_tunnel_id("team-demo", other).is_none());
+        assert!(matches!(
+            manager.lookup("team-demo"),
+            TunnelLookup::Offline(None)
+        ));
+    }
+
+    #[tokio::test]
+    async fn lapsed_reservation_frees_the_name() {
+        let mut manager = manager().await;
+        let owner = Uuid::new_v4();
+        let reservation = manager
+            .reserve(owner, named("team-demo", true))
+            .await
+            .unwrap();
+        manager.reserved.get_mut("team-demo").unwrap().expires_at =
+            Utc::now() - chrono::Duration::seconds(1);
+
+        assert!(matches!(manager.lookup("team-demo"), TunnelLookup::Missing));
+        assert!(manager.owned_tunnel_id("team-demo", owner).is_none());
+
+        // Anyone can have it now, as a tunnel of their own
+        let tunnel = manager
+            .create(CLIENT_IP, Uuid::new_v4(), named("team-demo", false), None)
+            .await
+            .unwrap();
+        assert_ne!(tunnel.tunnel_id, reservation.tunnel_id);
+        assert!(!tunnel.is_persistent);
+    }
+
+    #[tokio::test]
+    async fn deleted_reservation_stays_deleted_after_a_restart() {
+        let url = mock_supabase().await;
+        let mut manager = manager_on(&url);
+        let owner = Uuid::new_v4();
+        manager
+            .reserve(owner, named("team-demo", true))
+            .await
+            .unwrap();
+        manager
+            .reserve(owner, named("team-keep", true))
+            .await
+            .unwrap();
+        manager.remove("team-demo", "user_deleted").await.unwrap();
+
+        let mut restarted = manager_on(&url);
+        assert_eq!(restarted.restore_reservations().await.unwrap(), 1);
+        assert!(matches!(
+            restarted.lookup("team-demo"),
+            TunnelLookup::Missing
+        ));
+        assert!(matches!(
+            restarted.lookup("team-keep"),
+            TunnelLookup::Offline(None)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reservation_past_its_lifetime_is_reaped() {
+        let mut manager = manager().await;
+        manager
+            .reserve(Uuid::new_v4(), named("team-demo", true))
+            .await
+            .unwrap();
+        manager.expiries.insert(
+            "team-demo".to_string(),
+            Utc::now() - chrono::Duration::seconds(1),
+        );
+
+        let reaped = manager.reap_expired().await;
+        assert_eq!(reaped.len(), 1);
+        assert_eq!(reaped[0].0, "team-demo");
+        assert!(reaped[0].1.is_empty());
+        assert!(matches!(manager.lookup("team-demo"), TunnelLookup::Expired));
+    }
 }
@@ -1,4 +1,14 @@
 // Author : Eshan Roy <eshanized@proton.me>
 // SPDX-License-Identifier: MIT
 
+pub mod access;
+pub mod balancer;
+pub mod cors;
+pub mod health;
 pub mod manager;
+pub mod private;
+pub mod reaper;
+pub mod rewrite;
+pub mod settings;
+pub mod share;
+pub mod udp;
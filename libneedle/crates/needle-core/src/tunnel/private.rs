@@ -0,0 +1,50 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::tunnel::manager::ActiveTunnel;
+use russh::Channel;
+use russh::server::Msg;
+use std::io;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+/// The tunnel protocol that never reaches the edge. Private tunnels are
+/// only reachable by other users running `ssh -L` against the server.
+pub const PROTOCOL: &str = "private";
+
+/// Whether an account may connect to a private tunnel. Its owner always
+/// can; anyone else needs their email or its domain in the tunnel's
+/// peer list.
+pub fn may_connect(tunnel: &ActiveTunnel, user_id: Uuid, email: Option<&str>) -> bool {
+    if tunnel.user_id == user_id {
+        return true;
+    }
+    let policy = tunnel.access_policy();
+    match (&policy.peers, email) {
+        (Some(peers), Some(email)) => peers.permits(email),
+        _ => false,
+    }
+}
+
+/// Joins a peer's direct-tcpip channel to a channel opened back to the
+/// tunnel owner's client, until either side closes.
+pub async fn bridge(
+    tunnel: &ActiveTunnel,
+    originator: SocketAddr,
+    peer: Channel<Msg>,
+) -> io::Result<()> {
+    let Some(link) = &tunnel.session else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "tunnel has no ssh client",
+        ));
+    };
+    let owner = link
+        .open_channel(originator)
+        .await
+        .map_err(io::Error::other)?;
+    let mut owner = owner.into_stream();
+    let mut peer = peer.into_stream();
+    tokio::io::copy_bidirectional(&mut peer, &mut owner).await?;
+    Ok(())
+}
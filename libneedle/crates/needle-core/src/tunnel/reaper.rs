@@ -0,0 +1,41 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::tunnel::manager::TunnelManager;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::debug;
+
+const REAP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Background task that closes tunnels once their lifetime runs out.
+///
+/// Every few seconds we ask the manager for anything past its expiry,
+/// let it tear those tunnels down, then tell each affected SSH client on
+/// its session channel. The notification happens after the write lock is
+/// released so a slow client can't stall the edge. Runs forever.
+pub async fn run(tunnel_manager: Arc<RwLock<TunnelManager>>) {
+    let mut ticker = tokio::time::interval(REAP_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+
+        let reaped = tunnel_manager.write().await.reap_expired().await;
+        if reaped.is_empty() {
+            continue;
+        }
+
+        debug!(count = reaped.len(), "reaped expired tunnels");
+
+        for (subdomain, links) in reaped {
+            for link in links {
+                link.forget(&subdomain).await;
+                link.notify(&format!(
+                    "tunnel {subdomain} has expired and was closed\r\n"
+                ))
+                .await;
+            }
+        }
+    }
+}
@@ -0,0 +1,326 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use hyper::HeaderMap;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::http::uri::{PathAndQuery, Uri};
+use serde::{Deserialize, Serialize};
+
+// Plenty for real setups, and keeps a tunnel's settings from turning
+// into per-request busywork
+const MAX_RULES: usize = 32;
+
+/// Rewrites the edge applies to a tunnel's traffic, so an app that
+/// expects to live under `/api` or to see a particular `Origin` doesn't
+/// need its own reverse proxy in front of it.
+///
+/// Request rules run after the edge adds its forwarding headers, so they
+/// have the last word on what the app sees. Response rules only touch
+/// the app's responses, never the edge's own error pages.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RewriteRules {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub request_headers: Vec<HeaderRule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub response_headers: Vec<HeaderRule>,
+    /// Taken off the front of the path, e.g. `/api` turns `/api/users`
+    /// into `/users`. Paths without it go through untouched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strip_prefix: Option<String>,
+    /// Put on the front of the path after any strip, e.g. `/v2`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub add_prefix: Option<String>,
+    /// Checked in order before anything reaches the app; the first match
+    /// wins.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redirects: Vec<RedirectRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum HeaderRule {
+    /// Adds a value, keeping any the header already has.
+    Add {
+        name: String,
+        value: String,
+    },
+    /// Sets the header to exactly this value.
+    Replace {
+        name: String,
+        value: String,
+    },
+    Remove {
+        name: String,
+    },
+}
+
+/// Sends requests under `from` somewhere else, keeping the rest of the
+/// path and the query: with `from` `/docs` and `to` `https://docs.example.com`,
+/// `/docs/setup?x=1` goes to `https://docs.example.com/setup?x=1`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RedirectRule {
+    pub from: String,
+    pub to: String,
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+fn default_redirect_status() -> u16 {
+    302
+}
+
+impl RewriteRules {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Checks rules that came from a client. Returns a message fit to
+    /// show them if something's wrong.
+    pub fn validate(&self) -> Result<(), String> {
+        for (list, rules) in [
+            ("request_headers", &self.request_headers),
+            ("response_headers", &self.response_headers),
+        ] {
+            if rules.len() > MAX_RULES {
+                return Err(format!("{list} can have at most {MAX_RULES} rules"));
+            }
+            for rule in rules {
+                rule.validate()?;
+            }
+        }
+        if self.redirects.len() > MAX_RULES {
+            return Err(format!("redirects can have at most {MAX_RULES} rules"));
+        }
+
+        for (field, prefix) in [
+            ("strip_prefix", &self.strip_prefix),
+            ("add_prefix", &self.add_prefix),
+        ] {
+            if let Some(prefix) = prefix
+                && !is_valid_prefix(prefix)
+            {
+                return Err(format!(
+                    "{field} must be a path starting with / and not ending with one, got {prefix}"
+                ));
+            }
+        }
+
+        for redirect in &self.redirects {
+            redirect.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Where to send a request instead of forwarding it, if a redirect
+    /// rule matches its path.
+    pub fn redirect_for(&self, uri: &Uri) -> Option<(u16, String)> {
+        self.redirects.iter().find_map(|rule| {
+            let rest = strip_path_prefix(uri.path(), &rule.from)?;
+            let mut location = format!("{}{}", rule.to.trim_end_matches('/'), rest);
+            if location.is_empty() {
+                location.push('/');
+            }
+            if let Some(query) = uri.query() {
+                location.push('?');
+                location.push_str(query);
+            }
+            Some((rule.status, location))
+        })
+    }
+
+    /// The path the app should see for `uri`, or None to leave it alone.
+    pub fn rewrite_path(&self, uri: &Uri) -> Option<Uri> {
+        if self.strip_prefix.is_none() && self.add_prefix.is_none() {
+            return None;
+        }
+
+        let mut path = uri.path();
+        if let Some(prefix) = &self.strip_prefix {
+            path = strip_path_prefix(path, prefix).unwrap_or(path);
+        }
+        let mut rewritten = format!("{}{}", self.add_prefix.as_deref().unwrap_or(""), path);
+        if rewritten.is_empty() {
+            rewritten.push('/');
+        }
+        if let Some(query) = uri.query() {
+            rewritten.push('?');
+            rewritten.push_str(query);
+        }
+
+        let path_and_query = PathAndQuery::try_from(rewritten).ok()?;
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(path_and_query);
+        Uri::from_parts(parts).ok()
+    }
+
+    pub fn apply_request_headers(&self, headers: &mut HeaderMap) {
+        apply(&self.request_headers, headers);
+    }
+
+    pub fn apply_response_headers(&self, headers: &mut HeaderMap) {
+        apply(&self.response_headers, headers);
+    }
+}
+
+impl HeaderRule {
+    fn name(&self) -> &str {
+        match self {
+            Self::Add { name, .. } | Self::Replace { name, .. } | Self::Remove { name } => name,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        HeaderName::from_bytes(self.name().as_bytes())
+            .map_err(|_| format!("invalid header name {}", self.name()))?;
+        if let Self::Add { value, .. } | Self::Replace { value, .. } = self {
+            HeaderValue::from_str(value)
+                .map_err(|_| format!("invalid value for header {}", self.name()))?;
+        }
+        Ok(())
+    }
+}
+
+impl RedirectRule {
+    fn validate(&self) -> Result<(), String> {
+        if !self.from.starts_with('/') {
+            return Err(format!(
+                "redirect from must start with /, got {}",
+                self.from
+            ));
+        }
+        if !matches!(self.status, 301 | 302 | 303 | 307 | 308) {
+            return Err(format!(
+                "redirect status must be 301, 302, 303, 307 or 308, got {}",
+                self.status
+            ));
+        }
+
+        let absolute = self
+            .to
+            .parse::<Uri>()
+            .is_ok_and(|uri| matches!(uri.scheme_str(), Some("http" | "https")));
+        // A path, or a full http(s) URL; `//host` would leave the scheme
+        // up to the browser
+        let path = self.to.starts_with('/')
+            && !self.to.starts_with("//")
+            && PathAndQuery::try_from(self.to.as_str()).is_ok();
+        if !absolute && !path {
+            return Err(format!(
+                "redirect to must be a path or an http(s) URL, got {}",
+                self.to
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn apply(rules: &[HeaderRule], headers: &mut HeaderMap) {
+    // Rules are validated when the tunnel is created, so anything that
+    // doesn't parse here was stored by something else and is skipped
+    for rule in rules {
+        let Ok(name) = HeaderName::from_bytes(rule.name().as_bytes()) else {
+            continue;
+        };
+        match rule {
+            HeaderRule::Add { value, .. } => {
+                if let Ok(value) = HeaderValue::from_str(value) {
+                    headers.append(name, value);
+                }
+            }
+            HeaderRule::Replace { value, .. } => {
+                if let Ok(value) = HeaderValue::from_str(value) {
+                    headers.insert(name, value);
+                }
+            }
+            HeaderRule::Remove { .. } => {
+                headers.remove(name);
+            }
+        }
+    }
+}
+
+fn is_valid_prefix(prefix: &str) -> bool {
+    prefix.len() > 1
+        && prefix.starts_with('/')
+        && !prefix.ends_with('/')
+        && !prefix.contains(['?', '#'])
+        && PathAndQuery::try_from(prefix).is_ok()
+}
+
+/// What's left of `path` after `prefix`, matching whole segments only so
+/// `/api` doesn't eat the front of `/apiary`.
+fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let prefix = prefix.trim_end_matches('/');
+    let rest = path.strip_prefix(prefix)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> RewriteRules {
+        let rules: RewriteRules = serde_json::from_str(json).unwrap();
+        rules.validate().unwrap();
+        rules
+    }
+
+    #[test]
+    fn rewrites_paths_on_segment_boundaries() {
+        let rules = parse(r#"{ "strip_prefix": "/api", "add_prefix": "/v2" }"#);
+        let rewrite = |s: &str| rules.rewrite_path(&s.parse().unwrap()).unwrap().to_string();
+        assert_eq!(rewrite("/api/users?id=1"), "/v2/users?id=1");
+        assert_eq!(rewrite("/api"), "/v2");
+        assert_eq!(rewrite("/apiary"), "/v2/apiary");
+
+        let strip_only = parse(r#"{ "strip_prefix": "/api" }"#);
+        assert_eq!(
+            strip_only.rewrite_path(&"/api".parse().unwrap()).unwrap(),
+            "/"
+        );
+    }
+
+    #[test]
+    fn applies_header_rules_and_redirects() {
+        let rules = parse(
+            r#"{
+                "request_headers": [
+                    { "action": "replace", "name": "Origin", "value": "http://localhost:3000" },
+                    { "action": "remove", "name": "cookie" },
+                    { "action": "add", "name": "x-env", "value": "preview" }
+                ],
+                "redirects": [{ "from": "/old", "to": "https://example.com/new/", "status": 301 }]
+            }"#,
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert("origin", HeaderValue::from_static("https://evil.test"));
+        headers.insert("cookie", HeaderValue::from_static("a=b"));
+        headers.insert("x-env", HeaderValue::from_static("edge"));
+        rules.apply_request_headers(&mut headers);
+        assert_eq!(headers["origin"], "http://localhost:3000");
+        assert!(!headers.contains_key("cookie"));
+        assert_eq!(headers.get_all("x-env").iter().count(), 2);
+
+        assert_eq!(
+            rules.redirect_for(&"/old/page?q=1".parse().unwrap()),
+            Some((301, "https://example.com/new/page?q=1".to_string()))
+        );
+        assert_eq!(rules.redirect_for(&"/older".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        let bad = [
+            r#"{ "strip_prefix": "api" }"#,
+            r#"{ "add_prefix": "/v2/" }"#,
+            r#"{ "request_headers": [{ "action": "add", "name": "bad header", "value": "x" }] }"#,
+            r#"{ "redirects": [{ "from": "/a", "to": "//evil.test" }] }"#,
+            r#"{ "redirects": [{ "from": "/a", "to": "/b", "status": 200 }] }"#,
+        ];
+        for json in bad {
+            let rules: RewriteRules = serde_json::from_str(json).unwrap();
+            assert!(rules.validate().is_err(), "{json}");
+        }
+    }
+}
@@ -0,0 +1,266 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::config::{BodyLimits, ProxyTimeouts};
+use crate::proxy::compression::CompressionSettings;
+use crate::proxy::http::UpstreamProtocol;
+use crate::tunnel::balancer::LoadBalancing;
+use crate::tunnel::cors::CorsPolicy;
+use crate::tunnel::rewrite::RewriteRules;
+use needle_common::subdomain;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// How the edge treats requests on their way to a tunnel's app, beyond
+/// who's allowed in. Stored as JSON alongside the tunnel row so
+/// persistent tunnels keep their settings across restarts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelSettings {
+    /// Sent as the `Host` header instead of the tunnel's own hostname,
+    /// for apps that only answer to a particular name (virtual hosts,
+    /// dev servers with host checks). The original still goes along as
+    /// `X-Forwarded-Host`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_host: Option<String>,
+    /// `h2c` for apps that only speak HTTP/2, like gRPC services.
+    #[serde(default, skip_serializing_if = "UpstreamProtocol::is_default")]
+    pub upstream_protocol: UpstreamProtocol,
+    #[serde(default, skip_serializing_if = "RewriteRules::is_empty")]
+    pub rewrites: RewriteRules,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cors: Option<CorsPolicy>,
+    #[serde(default, skip_serializing_if = "CompressionSettings::is_default")]
+    pub compression: CompressionSettings,
+    #[serde(default, skip_serializing_if = "SizeLimits::is_default")]
+    pub limits: SizeLimits,
+    #[serde(default, skip_serializing_if = "TimeoutSettings::is_default")]
+    pub timeouts: TimeoutSettings,
+    /// Lets more of the owner's SSH clients serve the tunnel alongside
+    /// the first, with requests spread over them this way.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load_balancing: Option<LoadBalancing>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<MirrorSettings>,
+}
+
+/// Copies a share of the tunnel's requests to another of the owner's
+/// tunnels, e.g. one running a new version of the app. The copies'
+/// responses are thrown away, but how they compare with the real ones
+/// goes in the inspector.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MirrorSettings {
+    /// The tunnel the copies go to.
+    pub subdomain: String,
+    /// How many requests in a hundred get copied.
+    pub percent: u8,
+}
+
+impl MirrorSettings {
+    /// Whether this request is one of the ones that get copied.
+    pub fn sample(&self) -> bool {
+        rand::thread_rng().gen_range(0..100) < self.percent
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !subdomain::is_valid(&self.subdomain) && !subdomain::is_valid_custom(&self.subdomain) {
+            return Err(format!(
+                "mirror subdomain {} isn't a tunnel name",
+                self.subdomain
+            ));
+        }
+        if !(1..=100).contains(&self.percent) {
+            return Err("mirror percent must be between 1 and 100".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Checking more often than this is just load on the owner's app
+const MIN_HEALTH_CHECK_INTERVAL_SECS: u64 = 5;
+
+/// A request the server sends the tunnel's app every so often to see
+/// whether it's up. While it isn't, visitors get the offline page
+/// straight away instead of waiting for the app to time out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    /// Path and query to `GET`, e.g. `/healthz`.
+    pub path: String,
+    #[serde(default = "HealthCheck::default_status")]
+    pub expected_status: u16,
+    #[serde(default = "HealthCheck::default_interval")]
+    pub interval_secs: u64,
+}
+
+impl HealthCheck {
+    fn default_status() -> u16 {
+        200
+    }
+
+    fn default_interval() -> u64 {
+        10
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let valid_path = self.path.starts_with('/')
+            && self.path.parse::<hyper::http::uri::PathAndQuery>().is_ok();
+        if !valid_path {
+            return Err(format!(
+                "health_check path must start with '/', got {}",
+                self.path
+            ));
+        }
+        if !(100..=599).contains(&self.expected_status) {
+            return Err("health_check expected_status must be an HTTP status".to_string());
+        }
+        if self.interval_secs < MIN_HEALTH_CHECK_INTERVAL_SECS {
+            return Err(format!(
+                "health_check interval_secs must be at least {MIN_HEALTH_CHECK_INTERVAL_SECS}"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Different proxy timeouts than the server's defaults, in seconds, for
+/// apps that answer slowly on purpose, like long-polling endpoints.
+/// They can go up as far as the owner's tier allows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeoutSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_secs: Option<u64>,
+}
+
+impl TimeoutSettings {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The timeouts a tunnel actually gets, starting from the server's
+    /// `defaults` and capped at its tier's `max_secs`.
+    pub fn within(&self, defaults: ProxyTimeouts, max_secs: u64) -> ProxyTimeouts {
+        let pick =
+            |requested: Option<u64>, default: u64| requested.unwrap_or(default).min(max_secs);
+        ProxyTimeouts {
+            connect_secs: pick(self.connect_secs, defaults.connect_secs),
+            header_secs: pick(self.header_secs, defaults.header_secs),
+            idle_secs: pick(self.idle_secs, defaults.idle_secs),
+            total_secs: pick(self.total_secs, defaults.total_secs),
+        }
+    }
+}
+
+/// Smaller size limits than the owner's tier allows, in bytes. Anything
+/// left out, or set higher than the tier's, gets the tier's.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SizeLimits {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_request_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_response_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_websocket_bytes: Option<u64>,
+}
+
+impl SizeLimits {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The limits a tunnel actually gets under its tier's `ceiling`.
+    pub fn within(&self, ceiling: BodyLimits) -> BodyLimits {
+        let cap = |requested: Option<u64>, max: u64| requested.map_or(max, |n| n.min(max));
+        BodyLimits::new(
+            cap(self.max_request_bytes, ceiling.max_request_bytes),
+            cap(self.max_response_bytes, ceiling.max_response_bytes),
+            cap(self.max_websocket_bytes, ceiling.max_websocket_bytes),
+        )
+    }
+}
+
+impl TunnelSettings {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Checks values that came from a client. Returns a message fit to
+    /// show them if something's wrong.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(host) = &self.upstream_host {
+            let valid = !host.contains('@')
+                && host
+                    .parse::<hyper::http::uri::Authority>()
+                    .is_ok_and(|authority| !authority.host().is_empty());
+            if !valid {
+                return Err(format!(
+                    "upstream_host must be a host name with an optional port, got {host}"
+                ));
+            }
+        }
+        if let Some(cors) = &self.cors {
+            cors.validate()?;
+        }
+        if let Some(check) = &self.health_check {
+            check.validate()?;
+        }
+        if let Some(mirror) = &self.mirror {
+            mirror.validate()?;
+        }
+        self.compression.validate()?;
+        if [
+            self.limits.max_request_bytes,
+            self.limits.max_response_bytes,
+            self.limits.max_websocket_bytes,
+        ]
+        .contains(&Some(0))
+        {
+            return Err("size limits must be greater than zero".to_string());
+        }
+        if [
+            self.timeouts.connect_secs,
+            self.timeouts.header_secs,
+            self.timeouts.idle_secs,
+            self.timeouts.total_secs,
+        ]
+        .contains(&Some(0))
+        {
+            return Err("timeouts must be greater than zero".to_string());
+        }
+        self.rewrites.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirror_needs_a_tunnel_name_and_a_sensible_share() {
+        let mirror = |subdomain: &str, percent: u8| MirrorSettings {
+            subdomain: subdomain.to_string(),
+            percent,
+        };
+        assert!(mirror("myapp-next", 1).validate().is_ok());
+        assert!(mirror("myapp-next", 100).validate().is_ok());
+        assert!(mirror("myapp-next", 0).validate().is_err());
+        assert!(mirror("myapp-next", 101).validate().is_err());
+        assert!(mirror("Not A Name", 10).validate().is_err());
+        assert!(mirror("", 10).validate().is_err());
+
+        // Invalid mirrors fail the settings as a whole
+        let settings = TunnelSettings {
+            mirror: Some(mirror("myapp-next", 0)),
+            ..TunnelSettings::default()
+        };
+        assert!(settings.validate().is_err());
+
+        assert!((0..50).all(|_| mirror("myapp-next", 100).sample()));
+    }
+}
@@ -0,0 +1,262 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::proxy::cookie::Signer;
+use chrono::{DateTime, Utc};
+use needle_common::error::Result;
+use needle_db::client::SupabaseClient;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Query parameter the edge looks for on a share URL.
+pub const SHARE_PARAM: &str = "needle_share";
+/// Cookie a redeemed share link turns into.
+pub const SHARE_COOKIE: &str = "needle_share";
+pub const DEFAULT_SHARE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+pub const MAX_SHARE_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// One link's bookkeeping. The token itself isn't stored anywhere; it's
+/// rebuilt from these claims and checked by signature.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShareLink {
+    pub id: Uuid,
+    pub tunnel_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+}
+
+/// What a share token carries, signed with the server secret.
+#[derive(Serialize, Deserialize)]
+struct ShareClaims {
+    lid: Uuid,
+    tid: Uuid,
+    exp: i64,
+}
+
+/// Signed links that get visitors past a tunnel's access policy.
+///
+/// Opening a link counts as one use and swaps it for a cookie that lasts
+/// as long as the link does, so a `max_uses` of 5 means five people (or
+/// browsers), not five page loads. IP rules on the tunnel still apply.
+///
+/// The edge checks every request against the in-memory table, which is
+/// rebuilt from the database on startup, so a revoked link stops working
+/// immediately -- cookies it already handed out included.
+pub struct ShareRegistry {
+    links: Mutex<HashMap<Uuid, ShareLink>>,
+    signer: Signer,
+    db: SupabaseClient,
+}
+
+impl ShareRegistry {
+    pub fn new(db: SupabaseClient, secret: &str) -> Self {
+        Self {
+            links: Mutex::new(HashMap::new()),
+            signer: Signer::new(secret, "needle-share-link"),
+            db,
+        }
+    }
+
+    /// Loads every live link from the database.
+    pub async fn restore(&self) -> Result<usize> {
+        let rows = needle_db::queries::share_links::find_live(&self.db).await?;
+        let mut links = self.links.lock().unwrap();
+        for row in rows {
+            links.insert(
+                row.id,
+                ShareLink {
+                    id: row.id,
+                    tunnel_id: row.tunnel_id,
+                    expires_at: row.expires_at,
+                    max_uses: row.max_uses.map(|n| n.max(0) as u32),
+                    uses: row.uses.max(0) as u32,
+                },
+            );
+        }
+
+        info!(count = links.len(), "restored share links");
+        Ok(links.len())
+    }
+
+    /// Mints a link for a tunnel, returning it along with its token.
+    /// Lifetimes past [`MAX_SHARE_TTL`] are cut down to it.
+    pub async fn create(
+        &self,
+        tunnel_id: Uuid,
+        created_by: Uuid,
+        ttl: Duration,
+        max_uses: Option<u32>,
+    ) -> Result<(ShareLink, String)> {
+        let expires_at = Utc::now()
+            + chrono::Duration::from_std(ttl.min(MAX_SHARE_TTL)).unwrap_or(chrono::Duration::MAX);
+        let row = needle_db::queries::share_links::create(
+            &self.db,
+            &tunnel_id.to_string(),
+            &created_by.to_string(),
+            expires_at,
+            max_uses.map(|n| i32::try_from(n).unwrap_or(i32::MAX)),
+        )
+        .await?;
+
+        let link = ShareLink {
+            id: row.id,
+            tunnel_id,
+            expires_at: row.expires_at,
+            max_uses,
+            uses: 0,
+        };
+        let token = self.signer.sign(&ShareClaims {
+            lid: link.id,
+            tid: tunnel_id,
+            exp: link.expires_at.timestamp(),
+        });
+
+        let now = Utc::now();
+        let mut links = self.links.lock().unwrap();
+        links.retain(|_, l| l.expires_at > now);
+        links.insert(link.id, link.clone());
+
+        info!(tunnel_id = %tunnel_id, link_id = %link.id, expires_at = %link.expires_at, "share link created");
+        Ok((link, token))
+    }
+
+    /// The tunnel's links that haven't expired, newest expiry last.
+    pub fn list(&self, tunnel_id: Uuid) -> Vec<ShareLink> {
+        let now = Utc::now();
+        let mut links: Vec<ShareLink> = self
+            .links
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|l| l.tunnel_id == tunnel_id && l.expires_at > now)
+            .cloned()
+            .collect();
+        links.sort_by_key(|l| l.expires_at);
+        links
+    }
+
+    /// Revokes one of a tunnel's links. Returns false if it had no such
+    /// live link.
+    pub async fn revoke(&self, tunnel_id: Uuid, id: Uuid) -> Result<bool> {
+        let revoked = needle_db::queries::share_links::revoke(
+            &self.db,
+            &tunnel_id.to_string(),
+            &id.to_string(),
+        )
+        .await?;
+
+        let mut links = self.links.lock().unwrap();
+        let removed = links.get(&id).is_some_and(|l| l.tunnel_id == tunnel_id);
+        if removed {
+            links.remove(&id);
+        }
+
+        if revoked || removed {
+            info!(tunnel_id = %tunnel_id, link_id = %id, "share link revoked");
+        }
+        Ok(revoked || removed)
+    }
+
+    /// Counts one use of a token from a share URL. Returns how many
+    /// seconds the resulting cookie should last, or None if the token is
+    /// bad, for another tunnel, expired, revoked or used up.
+    pub fn redeem(&self, token: &str, tunnel_id: Uuid) -> Option<i64> {
+        let claims = self.claims(token, tunnel_id)?;
+        let uses = {
+            let mut links = self.links.lock().unwrap();
+            let link = links.get_mut(&claims.lid)?;
+            if link.max_uses.is_some_and(|max| link.uses >= max) {
+                return None;
+            }
+            link.uses += 1;
+            link.uses
+        };
+
+        let db = self.db.clone();
+        tokio::spawn(async move {
+            let uses = i32::try_from(uses).unwrap_or(i32::MAX);
+            if let Err(e) =
+                needle_db::queries::share_links::record_uses(&db, &claims.lid.to_string(), uses)
+                    .await
+            {
+                warn!(link_id = %claims.lid, error = %e, "failed to record share link use");
+            }
+        });
+
+        Some(claims.exp - Utc::now().timestamp())
+    }
+
+    /// Whether a cookie from a redeemed link still grants access. Used-up
+    /// links keep working for the people who already opened them.
+    pub fn is_valid(&self, token: &str, tunnel_id: Uuid) -> bool {
+        self.claims(token, tunnel_id)
+            .is_some_and(|claims| self.links.lock().unwrap().contains_key(&claims.lid))
+    }
+
+    fn claims(&self, token: &str, tunnel_id: Uuid) -> Option<ShareClaims> {
+        let claims: ShareClaims = self.signer.verify(token)?;
+        (claims.tid == tunnel_id && claims.exp > Utc::now().timestamp()).then_some(claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry_with(link: ShareLink) -> (ShareRegistry, String) {
+        let registry = ShareRegistry::new(
+            SupabaseClient::new("http://127.0.0.1:9", "anon", "service"),
+            "test-secret",
+        );
+        let token = registry.signer.sign(&ShareClaims {
+            lid: link.id,
+            tid: link.tunnel_id,
+            exp: link.expires_at.timestamp(),
+        });
+        registry.links.lock().unwrap().insert(link.id, link);
+        (registry, token)
+    }
+
+    #[tokio::test]
+    async fn counts_uses_and_keeps_redeemed_cookies_working() {
+        let tunnel_id = Uuid::new_v4();
+        let (registry, token) = registry_with(ShareLink {
+            id: Uuid::new_v4(),
+            tunnel_id,
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+            max_uses: Some(1),
+            uses: 0,
+        });
+
+        assert!(
+            registry
+                .redeem(&token, tunnel_id)
+                .is_some_and(|secs| secs > 0)
+        );
+        assert!(registry.redeem(&token, tunnel_id).is_none());
+        assert!(registry.is_valid(&token, tunnel_id));
+        assert!(!registry.is_valid(&token, Uuid::new_v4()));
+    }
+
+    #[tokio::test]
+    async fn forgotten_links_stop_working() {
+        let tunnel_id = Uuid::new_v4();
+        let id = Uuid::new_v4();
+        let (registry, token) = registry_with(ShareLink {
+            id,
+            tunnel_id,
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+            max_uses: None,
+            uses: 0,
+        });
+
+        registry.links.lock().unwrap().remove(&id);
+        assert!(!registry.is_valid(&token, tunnel_id));
+        assert!(registry.redeem(&token, tunnel_id).is_none());
+    }
+}
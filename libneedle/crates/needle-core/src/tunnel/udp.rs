@@ -0,0 +1,205 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::metrics;
+use crate::ssh::handler::SessionLink;
+use needle_common::datagram;
+use russh::Channel;
+use russh::server::Msg;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::{AbortHandle, JoinSet};
+use tracing::{debug, warn};
+
+/// The tunnel protocol that relays datagrams instead of HTTP.
+pub const PROTOCOL: &str = "udp";
+
+// Remote peers one tunnel relays for at once, each holding an SSH channel
+const MAX_PEERS: usize = 256;
+
+// Datagrams held for a peer while its channel opens or the client lags.
+// Past this they're dropped, which is what UDP would do anyway.
+const PEER_QUEUE: usize = 64;
+
+type Peers = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>;
+
+/// Binds a UDP tunnel's public socket to the first free port in `ports`.
+pub async fn bind(host: &str, ports: RangeInclusive<u16>) -> io::Result<UdpSocket> {
+    for port in ports {
+        match UdpSocket::bind((host, port)).await {
+            Ok(socket) => return Ok(socket),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "no free udp tunnel ports left",
+    ))
+}
+
+/// Relays datagrams between a tunnel's public UDP socket and its SSH
+/// client. Each remote peer gets its own forwarded-tcpip channel, opened
+/// on its first datagram and closed once it goes quiet, with datagrams
+/// framed as described in [`needle_common::datagram`].
+///
+/// Dropping the relay, which happens when its tunnel closes, stops it
+/// along with every peer's channel.
+pub struct UdpRelay {
+    pub public_addr: SocketAddr,
+    task: AbortHandle,
+}
+
+impl UdpRelay {
+    pub fn spawn(
+        socket: UdpSocket,
+        public_addr: SocketAddr,
+        link: SessionLink,
+        idle: Duration,
+    ) -> Self {
+        let task = tokio::spawn(relay(Arc::new(socket), link, idle)).abort_handle();
+        Self { public_addr, task }
+    }
+}
+
+impl Drop for UdpRelay {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn relay(socket: Arc<UdpSocket>, link: SessionLink, idle: Duration) {
+    let peers: Peers = Arc::default();
+    // Owning the peer tasks here means aborting the relay ends them too
+    let mut channels = JoinSet::new();
+    let mut buf = vec![0u8; datagram::MAX_PAYLOAD];
+
+    loop {
+        let (len, peer) = tokio::select! {
+            received = socket.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!(error = %e, "udp tunnel receive failed");
+                    continue;
+                }
+            },
+            Some(_) = channels.join_next() => continue,
+        };
+        metrics::udp_datagram("inbound");
+        let mut payload = buf[..len].to_vec();
+
+        let mut table = peers.lock().unwrap();
+        if let Some(queue) = table.get(&peer) {
+            match queue.try_send(payload) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(_)) => {
+                    metrics::error_occurred("udp_queue_full");
+                    continue;
+                }
+                // The peer's task died without cleaning up; start over
+                Err(TrySendError::Closed(returned)) => {
+                    table.remove(&peer);
+                    payload = returned;
+                }
+            }
+        }
+
+        if table.len() >= MAX_PEERS {
+            metrics::error_occurred("udp_peer_limit");
+            continue;
+        }
+
+        let (queue, datagrams) = mpsc::channel(PEER_QUEUE);
+        let _ = queue.try_send(payload);
+        table.insert(peer, queue);
+        drop(table);
+
+        channels.spawn(serve_peer(
+            socket.clone(),
+            link.clone(),
+            peer,
+            datagrams,
+            peers.clone(),
+            idle,
+        ));
+    }
+}
+
+/// Carries one remote peer's datagrams over its own channel until either
+/// side closes it or it's been idle for `idle`.
+async fn serve_peer(
+    socket: Arc<UdpSocket>,
+    link: SessionLink,
+    peer: SocketAddr,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+    peers: Peers,
+    idle: Duration,
+) {
+    match link.open_channel(peer).await {
+        Ok(channel) => {
+            if let Err(e) = pump(channel, &socket, peer, &mut datagrams, idle).await {
+                debug!(peer = %peer, error = %e, "udp tunnel channel failed");
+            }
+        }
+        Err(e) => warn!(peer = %peer, error = %e, "ssh client refused udp tunnel channel"),
+    }
+
+    // Forget the peer while its queue is still open, so the relay never
+    // mistakes a replacement for this one
+    peers.lock().unwrap().remove(&peer);
+}
+
+async fn pump(
+    channel: Channel<Msg>,
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    datagrams: &mut mpsc::Receiver<Vec<u8>>,
+    idle: Duration,
+) -> io::Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(channel.into_stream());
+    let mut pending = Vec::new();
+
+    let result = loop {
+        tokio::select! {
+            next = datagrams.recv() => {
+                let Some(payload) = next else {
+                    break Ok(());
+                };
+                // Anything read off a UDP socket fits in a frame
+                let Some(frame) = datagram::encode(&payload) else {
+                    continue;
+                };
+                if let Err(e) = writer.write_all(&frame).await {
+                    break Err(e);
+                }
+            }
+            read = reader.read_buf(&mut pending) => {
+                match read {
+                    Ok(0) => break Ok(()),
+                    Ok(_) => {}
+                    Err(e) => break Err(e),
+                }
+                let mut used = 0;
+                while let Some((payload, len)) = datagram::decode(&pending[used..]) {
+                    if let Err(e) = socket.send_to(payload, peer).await {
+                        debug!(peer = %peer, error = %e, "failed to send udp reply");
+                    }
+                    metrics::udp_datagram("outbound");
+                    used += len;
+                }
+                pending.drain(..used);
+            }
+            _ = tokio::time::sleep(idle) => break Ok(()),
+        }
+    };
+
+    let _ = writer.shutdown().await;
+    result
+}
@@ -103,8 +103,9 @@ impl SupabaseClient {
         Ok(response)
     }
 
-    /// Deletes rows matching the given filters. Be careful with this --
-    /// without filters it would delete everything in the table.
+    /// Deletes rows matching the given filters and returns the deleted rows.
+    /// Be careful with this -- without filters it would delete everything
+    /// in the table.
     pub async fn delete(
         &self,
         table: &str,
@@ -117,6 +118,7 @@ impl SupabaseClient {
             .delete(format!("{}/{table}", self.base_url))
             .header("apikey", &self.api_key)
             .header("Authorization", format!("Bearer {}", self.service_role_key))
+            .header("Prefer", "return=representation")
             .query(query_params)
             .send()
             .await?
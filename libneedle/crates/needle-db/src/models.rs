@@ -35,6 +35,49 @@ pub struct Tunnel {
     pub is_persistent: bool,
     pub created_at: DateTime<Utc>,
     pub last_active: DateTime<Utc>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    // Holds hashed credentials, so it never goes out in API responses
+    #[serde(default, skip_serializing)]
+    pub access_policy: Option<serde_json::Value>,
+    // Per-tunnel request rate, below the owner's tier limit
+    #[serde(default)]
+    pub rate_limit_rps: Option<f64>,
+    #[serde(default)]
+    pub rate_limit_burst: Option<f64>,
+    // How the edge handles requests for the tunnel, e.g. its upstream Host
+    #[serde(default)]
+    pub settings: Option<serde_json::Value>,
+    // HTML shown while a persistent tunnel's client is away
+    #[serde(default)]
+    pub offline_page: Option<String>,
+    // Where a UDP tunnel takes datagrams on the server's public address
+    #[serde(default)]
+    pub public_port: Option<i32>,
+    // "up", "down" or "unknown" for tunnels with a health check
+    #[serde(default)]
+    pub health_status: Option<String>,
+    #[serde(default)]
+    pub health_changed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReservedSubdomain {
+    pub name: String,
+    pub user_id: Uuid,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareLink {
+    pub id: Uuid,
+    pub tunnel_id: Uuid,
+    pub created_by: Uuid,
+    pub expires_at: DateTime<Utc>,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -50,6 +93,19 @@ pub struct TunnelRequest {
     pub request_headers: Option<serde_json::Value>,
     pub response_headers: Option<serde_json::Value>,
     pub client_ip: Option<String>,
+    #[serde(default)]
+    pub denied_reason: Option<String>,
+    #[serde(default)]
+    pub timeout_kind: Option<String>,
+    // Set on requests copied to another tunnel, for comparison
+    #[serde(default)]
+    pub mirror_subdomain: Option<String>,
+    #[serde(default)]
+    pub mirror_status_code: Option<i32>,
+    #[serde(default)]
+    pub mirror_latency_ms: Option<i32>,
+    #[serde(default)]
+    pub mirror_error: Option<String>,
     pub timestamp: DateTime<Utc>,
 }
 
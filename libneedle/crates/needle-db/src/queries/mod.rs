@@ -4,5 +4,7 @@
 pub mod analytics;
 pub mod api_keys;
 pub mod requests;
+pub mod reserved_subdomains;
+pub mod share_links;
 pub mod tunnels;
 pub mod users;
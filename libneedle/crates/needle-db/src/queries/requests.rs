@@ -33,6 +33,7 @@ pub async fn find_recent(
 
 /// Logs a request that passed through a tunnel. Called by the
 /// proxy layer after forwarding is complete.
+#[allow(clippy::too_many_arguments)]
 pub async fn log_request(
     client: &SupabaseClient,
     tunnel_id: &str,
@@ -62,3 +63,104 @@ pub async fn log_request(
 
     Ok(())
 }
+
+/// Logs a request the edge refused because of the tunnel's access
+/// policy, so the owner can see who was knocking in the inspector.
+pub async fn log_denied(
+    client: &SupabaseClient,
+    tunnel_id: &str,
+    method: &str,
+    path: &str,
+    status_code: u16,
+    client_ip: &str,
+    reason: &str,
+) -> Result<()> {
+    let body = json!({
+        "tunnel_id": tunnel_id,
+        "method": method,
+        "path": path,
+        "status_code": status_code,
+        "client_ip": client_ip,
+        "denied_reason": reason,
+    });
+
+    client
+        .insert("tunnel_requests", &body)
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Logs a request the edge gave up on because the tunnel's app was too
+/// slow, with which timeout fired, so the owner can tell a slow
+/// connection from a slow handler.
+#[allow(clippy::too_many_arguments)]
+pub async fn log_timeout(
+    client: &SupabaseClient,
+    tunnel_id: &str,
+    method: &str,
+    path: &str,
+    status_code: u16,
+    latency_ms: u32,
+    client_ip: &str,
+    kind: &str,
+) -> Result<()> {
+    let body = json!({
+        "tunnel_id": tunnel_id,
+        "method": method,
+        "path": path,
+        "status_code": status_code,
+        "latency_ms": latency_ms,
+        "client_ip": client_ip,
+        "timeout_kind": kind,
+    });
+
+    client
+        .insert("tunnel_requests", &body)
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(())
+}
+
+/// A request copied to a mirror tunnel, with what the tunnel and its
+/// mirror each made of it.
+pub struct MirroredRequest<'a> {
+    pub tunnel_id: &'a str,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub client_ip: &'a str,
+    pub status_code: u16,
+    pub latency_ms: u32,
+    pub mirror_subdomain: &'a str,
+    /// `None` when the mirror never answered, with the reason in
+    /// `mirror_error`.
+    pub mirror_status_code: Option<u16>,
+    pub mirror_latency_ms: u32,
+    pub mirror_error: Option<&'a str>,
+}
+
+/// Logs a mirrored request against the tunnel it was copied from, so the
+/// owner can compare the two in the inspector.
+pub async fn log_mirrored(client: &SupabaseClient, request: &MirroredRequest<'_>) -> Result<()> {
+    let body = json!({
+        "tunnel_id": request.tunnel_id,
+        "method": request.method,
+        "path": request.path,
+        "status_code": request.status_code,
+        "latency_ms": request.latency_ms,
+        "client_ip": request.client_ip,
+        "mirror_subdomain": request.mirror_subdomain,
+        "mirror_status_code": request.mirror_status_code,
+        "mirror_latency_ms": request.mirror_latency_ms,
+        "mirror_error": request.mirror_error,
+    });
+
+    client
+        .insert("tunnel_requests", &body)
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(())
+}
@@ -0,0 +1,96 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::client::SupabaseClient;
+use crate::models::ReservedSubdomain;
+use needle_common::error::{NeedleError, Result};
+use reqwest::StatusCode;
+use serde_json::json;
+use tracing::info;
+
+pub async fn find_by_name(
+    client: &SupabaseClient,
+    name: &str,
+) -> Result<Option<ReservedSubdomain>> {
+    let response = client
+        .select(
+            "reserved_subdomains",
+            &[("name", &format!("eq.{name}")), ("limit", "1")],
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    let rows: Vec<ReservedSubdomain> =
+        serde_json::from_value(response).map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(rows.into_iter().next())
+}
+
+pub async fn find_by_user(
+    client: &SupabaseClient,
+    user_id: &str,
+) -> Result<Vec<ReservedSubdomain>> {
+    let response = client
+        .select(
+            "reserved_subdomains",
+            &[("user_id", &format!("eq.{user_id}")), ("order", "name.asc")],
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    serde_json::from_value(response).map_err(|e| NeedleError::Supabase(e.to_string()))
+}
+
+/// Claims a subdomain for a user. The name is the table's primary key,
+/// so two concurrent claims can't both win -- the loser gets a 409 from
+/// PostgREST, which we surface as `SubdomainTaken`.
+pub async fn claim(
+    client: &SupabaseClient,
+    name: &str,
+    user_id: &str,
+) -> Result<ReservedSubdomain> {
+    let body = json!({
+        "name": name,
+        "user_id": user_id,
+    });
+
+    let response = client
+        .insert("reserved_subdomains", &body)
+        .await
+        .map_err(|e| {
+            if e.status() == Some(StatusCode::CONFLICT) {
+                NeedleError::SubdomainTaken(name.to_string())
+            } else {
+                NeedleError::Supabase(e.to_string())
+            }
+        })?;
+
+    let rows: Vec<ReservedSubdomain> =
+        serde_json::from_value(response).map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    rows.into_iter()
+        .next()
+        .ok_or_else(|| NeedleError::Supabase("insert returned no rows".to_string()))
+}
+
+/// Releases a reservation, but only if it belongs to the given user.
+/// Returns false when there was nothing of theirs to release.
+pub async fn release(client: &SupabaseClient, user_id: &str, name: &str) -> Result<bool> {
+    let response = client
+        .delete(
+            "reserved_subdomains",
+            &[
+                ("name", &format!("eq.{name}")),
+                ("user_id", &format!("eq.{user_id}")),
+            ],
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    let released = response.as_array().is_some_and(|rows| !rows.is_empty());
+    if released {
+        info!(user_id = %user_id, name = %name, "subdomain reservation released");
+    }
+
+    Ok(released)
+}
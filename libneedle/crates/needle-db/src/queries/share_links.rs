@@ -0,0 +1,84 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+use crate::client::SupabaseClient;
+use crate::models::ShareLink;
+use chrono::{DateTime, Utc};
+use needle_common::error::{NeedleError, Result};
+use serde_json::json;
+
+pub async fn create(
+    client: &SupabaseClient,
+    tunnel_id: &str,
+    created_by: &str,
+    expires_at: DateTime<Utc>,
+    max_uses: Option<i32>,
+) -> Result<ShareLink> {
+    let body = json!({
+        "tunnel_id": tunnel_id,
+        "created_by": created_by,
+        "expires_at": expires_at,
+        "max_uses": max_uses,
+    });
+
+    let response = client
+        .insert("share_links", &body)
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    let rows: Vec<ShareLink> =
+        serde_json::from_value(response).map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    rows.into_iter()
+        .next()
+        .ok_or_else(|| NeedleError::Supabase("insert returned no rows".to_string()))
+}
+
+/// Links that haven't been revoked or run out of time. Used on startup
+/// to rebuild the in-memory table the edge checks against.
+pub async fn find_live(client: &SupabaseClient) -> Result<Vec<ShareLink>> {
+    let response = client
+        .select(
+            "share_links",
+            &[
+                ("revoked_at", "is.null"),
+                ("expires_at", &format!("gt.{}", Utc::now().to_rfc3339())),
+            ],
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    serde_json::from_value(response).map_err(|e| NeedleError::Supabase(e.to_string()))
+}
+
+pub async fn record_uses(client: &SupabaseClient, id: &str, uses: i32) -> Result<()> {
+    client
+        .update(
+            "share_links",
+            &[("id", &format!("eq.{id}"))],
+            &json!({ "uses": uses }),
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Marks a link revoked, as long as it belongs to the given tunnel.
+/// Returns false when there was no such live link.
+pub async fn revoke(client: &SupabaseClient, tunnel_id: &str, id: &str) -> Result<bool> {
+    let response = client
+        .update(
+            "share_links",
+            &[
+                ("id", &format!("eq.{id}")),
+                ("tunnel_id", &format!("eq.{tunnel_id}")),
+                ("revoked_at", "is.null"),
+            ],
+            &json!({ "revoked_at": Utc::now() }),
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(response.as_array().is_some_and(|rows| !rows.is_empty()))
+}
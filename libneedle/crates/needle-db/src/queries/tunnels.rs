@@ -3,7 +3,9 @@
 
 use crate::client::SupabaseClient;
 use crate::models::Tunnel;
+use chrono::{DateTime, Utc};
 use needle_common::error::{NeedleError, Result};
+use reqwest::StatusCode;
 use serde_json::json;
 
 pub async fn find_by_subdomain(client: &SupabaseClient, subdomain: &str) -> Result<Option<Tunnel>> {
@@ -30,6 +32,17 @@ pub async fn find_by_user(client: &SupabaseClient, user_id: &str) -> Result<Vec<
     serde_json::from_value(response).map_err(|e| NeedleError::Supabase(e.to_string()))
 }
 
+/// Persistent tunnels whose reservation may still be live. Used on
+/// startup to rebuild the in-memory reservation table.
+pub async fn find_persistent(client: &SupabaseClient) -> Result<Vec<Tunnel>> {
+    let response = client
+        .select("tunnels", &[("is_persistent", "eq.true")])
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    serde_json::from_value(response).map_err(|e| NeedleError::Supabase(e.to_string()))
+}
+
 pub async fn create(
     client: &SupabaseClient,
     user_id: &str,
@@ -37,20 +50,26 @@ pub async fn create(
     target_port: i32,
     protocol: &str,
     is_persistent: bool,
+    is_active: bool,
 ) -> Result<Tunnel> {
     let body = json!({
         "user_id": user_id,
         "subdomain": subdomain,
         "target_port": target_port,
         "protocol": protocol,
-        "is_active": true,
+        "is_active": is_active,
         "is_persistent": is_persistent,
     });
 
-    let response = client
-        .insert("tunnels", &body)
-        .await
-        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+    // The unique constraint on subdomain is the real arbiter of who gets
+    // a name, so a conflict here means someone beat us to it.
+    let response = client.insert("tunnels", &body).await.map_err(|e| {
+        if e.status() == Some(StatusCode::CONFLICT) {
+            NeedleError::SubdomainTaken(subdomain.to_string())
+        } else {
+            NeedleError::Supabase(e.to_string())
+        }
+    })?;
 
     let tunnels: Vec<Tunnel> =
         serde_json::from_value(response).map_err(|e| NeedleError::Supabase(e.to_string()))?;
@@ -61,12 +80,201 @@ pub async fn create(
         .ok_or_else(|| NeedleError::Supabase("insert returned no rows".to_string()))
 }
 
+/// Brings one of the user's own inactive, non-persistent tunnel rows back
+/// to life for a new session on the same subdomain. The filters make this
+/// a single conditional update, so it fails (returns None) if the row is
+/// live, persistent, or belongs to someone else.
+pub async fn reactivate(
+    client: &SupabaseClient,
+    user_id: &str,
+    subdomain: &str,
+    target_port: i32,
+    protocol: &str,
+) -> Result<Option<Tunnel>> {
+    let response = client
+        .update(
+            "tunnels",
+            &[
+                ("subdomain", &format!("eq.{subdomain}")),
+                ("user_id", &format!("eq.{user_id}")),
+                ("is_active", "eq.false"),
+                ("is_persistent", "eq.false"),
+            ],
+            &json!({
+                "target_port": target_port,
+                "protocol": protocol,
+                "is_active": true,
+                "last_active": Utc::now(),
+            }),
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    let tunnels: Vec<Tunnel> =
+        serde_json::from_value(response).map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(tunnels.into_iter().next())
+}
+
+/// Flips a tunnel's active flag and bumps `last_active`, so a persistent
+/// tunnel's reservation window can be recomputed after a restart.
 pub async fn set_active(client: &SupabaseClient, subdomain: &str, active: bool) -> Result<()> {
     client
         .update(
             "tunnels",
             &[("subdomain", &format!("eq.{subdomain}"))],
-            &json!({ "is_active": active }),
+            &json!({ "is_active": active, "last_active": Utc::now() }),
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Marks a tunnel persistent or not. A tunnel that isn't persistent is
+/// left out when reservations are restored at startup.
+pub async fn set_persistent(
+    client: &SupabaseClient,
+    subdomain: &str,
+    persistent: bool,
+) -> Result<()> {
+    client
+        .update(
+            "tunnels",
+            &[("subdomain", &format!("eq.{subdomain}"))],
+            &json!({ "is_persistent": persistent }),
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Records when a tunnel stops being served. `None` clears the expiry.
+pub async fn set_expiry(
+    client: &SupabaseClient,
+    subdomain: &str,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<()> {
+    client
+        .update(
+            "tunnels",
+            &[("subdomain", &format!("eq.{subdomain}"))],
+            &json!({ "expires_at": expires_at }),
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Stores a tunnel's access policy. `None` opens the tunnel to everyone.
+pub async fn set_access_policy(
+    client: &SupabaseClient,
+    subdomain: &str,
+    policy: Option<&serde_json::Value>,
+) -> Result<()> {
+    client
+        .update(
+            "tunnels",
+            &[("subdomain", &format!("eq.{subdomain}"))],
+            &json!({ "access_policy": policy }),
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Stores the page a persistent tunnel shows while it's offline. `None`
+/// goes back to the standard one.
+pub async fn set_offline_page(
+    client: &SupabaseClient,
+    subdomain: &str,
+    page: Option<&str>,
+) -> Result<()> {
+    client
+        .update(
+            "tunnels",
+            &[("subdomain", &format!("eq.{subdomain}"))],
+            &json!({ "offline_page": page }),
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Stores a tunnel's proxy settings. `None` puts them back to defaults.
+pub async fn set_settings(
+    client: &SupabaseClient,
+    subdomain: &str,
+    settings: Option<&serde_json::Value>,
+) -> Result<()> {
+    client
+        .update(
+            "tunnels",
+            &[("subdomain", &format!("eq.{subdomain}"))],
+            &json!({ "settings": settings }),
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Stores a tunnel's own rate limit as (requests per second, burst).
+/// `None` puts it back on its owner's tier limit.
+pub async fn set_rate_limit(
+    client: &SupabaseClient,
+    subdomain: &str,
+    limit: Option<(f64, f64)>,
+) -> Result<()> {
+    let (rps, burst) = limit.unzip();
+    client
+        .update(
+            "tunnels",
+            &[("subdomain", &format!("eq.{subdomain}"))],
+            &json!({ "rate_limit_rps": rps, "rate_limit_burst": burst }),
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Stores the public port a UDP tunnel was given, so its owner can find
+/// out where to send datagrams.
+pub async fn set_public_port(
+    client: &SupabaseClient,
+    subdomain: &str,
+    port: Option<u16>,
+) -> Result<()> {
+    client
+        .update(
+            "tunnels",
+            &[("subdomain", &format!("eq.{subdomain}"))],
+            &json!({ "public_port": port }),
+        )
+        .await
+        .map_err(|e| NeedleError::Supabase(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Records what a tunnel's health checks last concluded about its app,
+/// and when that changed.
+pub async fn set_health(
+    client: &SupabaseClient,
+    subdomain: &str,
+    status: &str,
+    changed_at: DateTime<Utc>,
+) -> Result<()> {
+    client
+        .update(
+            "tunnels",
+            &[("subdomain", &format!("eq.{subdomain}"))],
+            &json!({ "health_status": status, "health_changed_at": changed_at }),
         )
         .await
         .map_err(|e| NeedleError::Supabase(e.to_string()))?;
@@ -33,6 +33,15 @@ pub async fn find_by_id(client: &SupabaseClient, id: &str) -> Result<Option<User
     Ok(users.into_iter().next())
 }
 
+/// Returns the user's billing tier, falling back to "free" if the user
+/// row has vanished so callers always get the most restrictive limits.
+pub async fn get_tier(client: &SupabaseClient, id: &str) -> Result<String> {
+    Ok(find_by_id(client, id)
+        .await?
+        .map(|u| u.tier)
+        .unwrap_or_else(|| "free".to_string()))
+}
+
 pub async fn create(
     client: &SupabaseClient,
     email: &str,
@@ -7,19 +7,26 @@ use std::sync::Arc;
 
 use axum::Router;
 use axum::middleware as axum_mw;
-use axum::routing::{delete, get, post};
-use tokio::net::TcpListener;
+use axum::routing::{delete, get, post, put};
 use tokio::sync::RwLock;
 use tower_http::cors::CorsLayer;
 use tower_http::trace::TraceLayer;
 use tracing::{error, info, warn};
 
-use needle_api::middleware::auth::require_auth;
-use needle_api::middleware::rate_limit;
-use needle_api::routes::{analytics, api_keys, auth, health, inspector, metrics, tunnels};
+use needle_api::listener::{ApiListener, PeerAddr};
+use needle_api::middleware::abuse::reject_banned;
+use needle_api::middleware::auth::{require_admin, require_auth};
+use needle_api::middleware::client_ip::resolve_client_ip;
+use needle_api::middleware::rate_limit::{self, RateLimitState};
+use needle_api::routes::{
+    admin, analytics, api_keys, auth, health, inspector, metrics, shares, subdomains, tunnels,
+};
 use needle_api::state::AppState;
+use needle_core::abuse::AbuseTracker;
 use needle_core::config::NeedleConfig;
+use needle_core::listener::{ClientListener, TrustedProxies};
 use needle_core::tunnel::manager::TunnelManager;
+use needle_core::tunnel::share::ShareRegistry;
 use needle_db::client::SupabaseClient;
 
 fn required_env(key: &str) -> String {
@@ -51,7 +58,7 @@ fn load_or_generate_host_key() -> russh_keys::key::KeyPair {
     // Best-effort persist so key survives restarts
     if let Err(e) = std::fs::write(
         &key_path,
-        format!("# Auto-generated Needle SSH host key\n# Regenerate by deleting this file\n"),
+        "# Auto-generated Needle SSH host key\n# Regenerate by deleting this file\n",
     ) {
         warn!(error = %e, "could not persist host key to disk — key will change on restart");
     }
@@ -89,21 +96,33 @@ async fn main() {
     // Load core configuration
     let config = NeedleConfig::from_env();
     let ssh_addr = config.ssh_addr.clone();
+    let edge_addr = config.edge_addr.clone();
+    let edge_domain = config.domain.clone();
+    let admin_emails = config.admin_emails.clone();
+    let abuse = Arc::new(AbuseTracker::new(&config));
+    let shares = Arc::new(ShareRegistry::new(db.clone(), &config.jwt_secret));
+    if let Err(e) = shares.restore().await {
+        warn!(error = %e, "failed to restore share links");
+    }
+
+    let rate_limit_state = RateLimitState::new(&config, abuse.clone());
+    let trusted_proxies = TrustedProxies::new(&config);
+    let api_proxy_protocol = config.proxy_protocol_on("api");
 
-    let tunnel_manager = Arc::new(RwLock::new(TunnelManager::new(
-        db.clone(),
-        config.max_tunnels_per_ip,
-        config.global_tunnel_limit,
-        10.0, // requests_per_second - TODO: add to config
-        20.0, // burst_size - TODO: add to config
-    )));
-    let limiter_map = rate_limit::new_rate_limiter_map();
+    let mut manager = TunnelManager::new(db.clone(), config);
+    if let Err(e) = manager.restore_reservations().await {
+        warn!(error = %e, "failed to restore persistent tunnel reservations");
+    }
+    let tunnel_manager = Arc::new(RwLock::new(manager));
 
     let state = AppState {
         tunnel_manager: tunnel_manager.clone(),
         db,
         jwt_secret,
         domain,
+        abuse: abuse.clone(),
+        admin_emails,
+        shares: shares.clone(),
     };
 
     // public routes -- no auth needed
@@ -117,6 +136,24 @@ async fn main() {
     let protected_routes = Router::new()
         .route("/api/tunnels", get(tunnels::list).post(tunnels::create))
         .route("/api/tunnels/{subdomain}", delete(tunnels::delete))
+        .route("/api/tunnels/{subdomain}/backends", get(tunnels::backends))
+        .route(
+            "/api/tunnels/{subdomain}/offline-page",
+            put(tunnels::set_offline_page).delete(tunnels::delete_offline_page),
+        )
+        .route(
+            "/api/tunnels/{subdomain}/share",
+            get(shares::list).post(shares::create),
+        )
+        .route(
+            "/api/tunnels/{subdomain}/share/{link_id}",
+            delete(shares::revoke),
+        )
+        .route(
+            "/api/subdomains",
+            get(subdomains::list).post(subdomains::create),
+        )
+        .route("/api/subdomains/{name}", delete(subdomains::delete))
         .route("/api/keys", get(api_keys::list).post(api_keys::create))
         .route("/api/keys/{key_id}", delete(api_keys::delete))
         .route(
@@ -131,6 +168,16 @@ async fn main() {
         .route("/api/auth/revoke", post(auth::revoke))
         .layer(axum_mw::from_fn_with_state(state.clone(), require_auth));
 
+    // admin routes -- valid JWT for an account listed in ADMIN_EMAILS
+    let admin_routes = Router::new()
+        .route(
+            "/api/admin/bans",
+            get(admin::list_bans).post(admin::create_ban),
+        )
+        .route("/api/admin/bans/{ip}", delete(admin::delete_ban))
+        .layer(axum_mw::from_fn_with_state(state.clone(), require_admin))
+        .layer(axum_mw::from_fn_with_state(state.clone(), require_auth));
+
     // Configure CORS - default to localhost for dev, require explicit origin for prod
     let cors_origin =
         env::var("CORS_ORIGIN").unwrap_or_else(|_| "http://localhost:5173".to_string());
@@ -155,25 +202,34 @@ async fn main() {
             axum::http::header::CONTENT_TYPE,
         ]);
 
+    let api_limiters = rate_limit_state.limiters.clone();
     let app = Router::new()
         .merge(public_routes)
         .merge(protected_routes)
+        .merge(admin_routes)
         .layer(axum_mw::from_fn_with_state(
-            limiter_map,
+            rate_limit_state,
             rate_limit::rate_limit,
         ))
+        .layer(axum_mw::from_fn_with_state(abuse.clone(), reject_banned))
+        .layer(axum_mw::from_fn_with_state(
+            Arc::new(trusted_proxies.clone()),
+            resolve_client_ip,
+        ))
         .layer(cors)
         .layer(TraceLayer::new_for_http())
         .with_state(state);
 
     // ── Start API server ──────────────────────────────────────────────
-    let listener = TcpListener::bind(&api_addr).await.expect("failed to bind API");
+    let listener = ClientListener::bind(&api_addr, "api", api_proxy_protocol, trusted_proxies)
+        .await
+        .expect("failed to bind API");
     info!(addr = %api_addr, "needle api server starting");
 
     let api_task = tokio::spawn(async move {
         axum::serve(
-            listener,
-            app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            ApiListener(listener),
+            app.into_make_service_with_connect_info::<PeerAddr>(),
         )
         .await
         .expect("api server crashed");
@@ -183,13 +239,40 @@ async fn main() {
     let host_key = load_or_generate_host_key();
     info!(addr = %ssh_addr, "needle ssh server starting");
 
+    let ssh_tunnel_manager = tunnel_manager.clone();
+    let ssh_abuse = abuse.clone();
     let ssh_task = tokio::spawn(async move {
-        if let Err(e) = needle_core::ssh::server::run(&ssh_addr, host_key, tunnel_manager).await {
+        if let Err(e) =
+            needle_core::ssh::server::run(&ssh_addr, host_key, ssh_tunnel_manager, ssh_abuse).await
+        {
             error!(error = %e, "ssh server crashed");
         }
     });
 
-    // Wait for either server to exit (both should run forever)
+    // ── Start tunnel expiry reaper ────────────────────────────────────
+    let reaper_tunnel_manager = tunnel_manager.clone();
+    let reaper_task = tokio::spawn(needle_core::tunnel::reaper::run(reaper_tunnel_manager));
+
+    // ── Start tunnel health checker ───────────────────────────────────
+    let health_tunnel_manager = tunnel_manager.clone();
+    let health_task = tokio::spawn(needle_core::tunnel::health::run(health_tunnel_manager));
+
+    // ── Start API rate limiter sweeper ─────────────────────────────────
+    let sweeper_task = tokio::spawn(rate_limit::sweep(api_limiters));
+
+    // ── Start public edge ─────────────────────────────────────────────
+    info!(addr = %edge_addr, "needle edge starting");
+
+    let edge_task = tokio::spawn(async move {
+        if let Err(e) =
+            needle_core::proxy::edge::run(&edge_addr, edge_domain, tunnel_manager, abuse, shares)
+                .await
+        {
+            error!(error = %e, "edge crashed");
+        }
+    });
+
+    // Wait for any server to exit (all should run forever)
     tokio::select! {
         result = api_task => {
             error!(?result, "api server exited unexpectedly");
@@ -197,5 +280,17 @@ async fn main() {
         result = ssh_task => {
             error!(?result, "ssh server exited unexpectedly");
         }
+        result = edge_task => {
+            error!(?result, "edge exited unexpectedly");
+        }
+        result = reaper_task => {
+            error!(?result, "tunnel reaper exited unexpectedly");
+        }
+        result = health_task => {
+            error!(?result, "tunnel health checker exited unexpectedly");
+        }
+        result = sweeper_task => {
+            error!(?result, "rate limiter sweeper exited unexpectedly");
+        }
     }
 }
@@ -0,0 +1,122 @@
+// Author : Eshan Roy <eshanized@proton.me>
+// SPDX-License-Identifier: MIT
+
+//! Reference client for UDP tunnels. SSH only forwards streams, so the
+//! server sends each remote peer's datagrams down its own forwarded
+//! channel, framed as described in `needle_common::datagram`. Point the
+//! SSH forward at this relay and it turns the frames back into datagrams
+//! for a local UDP service:
+//!
+//! ```text
+//! needle-udp 127.0.0.1:9000 127.0.0.1:53
+//! ssh -R udp+dns:5353:localhost:9000 user_<API_KEY>@needle.example.com
+//! ```
+
+use needle_common::datagram;
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::process;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tracing::{debug, error, info};
+
+const USAGE: &str = "usage: needle-udp <listen-addr> <service-addr>
+
+Accepts the SSH client's forwarded connections on <listen-addr> and
+relays the framed datagrams they carry to the UDP service at
+<service-addr>, e.g. `needle-udp 127.0.0.1:9000 127.0.0.1:53`.";
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "needle_udp=info".into()),
+        )
+        .init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let [listen, service] = args.as_slice() else {
+        eprintln!("{USAGE}");
+        process::exit(2);
+    };
+
+    let service = match tokio::net::lookup_host(service.as_str()).await {
+        Ok(mut addrs) => addrs.next(),
+        Err(e) => {
+            error!(service = %service, error = %e, "failed to resolve service address");
+            process::exit(1);
+        }
+    };
+    let Some(service) = service else {
+        error!("service address did not resolve to anything");
+        process::exit(1);
+    };
+
+    let listener = match TcpListener::bind(listen.as_str()).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(listen = %listen, error = %e, "failed to listen");
+            process::exit(1);
+        }
+    };
+    info!(listen = %listen, service = %service, "relaying udp tunnel traffic");
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!(error = %e, "failed to accept connection");
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            debug!(peer = %peer, "udp tunnel channel opened");
+            if let Err(e) = relay(stream, service).await {
+                debug!(peer = %peer, error = %e, "udp tunnel channel failed");
+            }
+            debug!(peer = %peer, "udp tunnel channel closed");
+        });
+    }
+}
+
+/// Relays one remote peer, which is what each connection stands for.
+/// Its datagrams leave from a socket of their own, so the service's
+/// replies find their way back to this connection and no other.
+async fn relay(stream: TcpStream, service: SocketAddr) -> io::Result<()> {
+    let local = if service.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(service).await?;
+
+    let (mut reader, mut writer) = stream.into_split();
+    let mut pending = Vec::new();
+    let mut buf = vec![0u8; datagram::MAX_PAYLOAD];
+
+    loop {
+        tokio::select! {
+            read = reader.read_buf(&mut pending) => {
+                if read? == 0 {
+                    return Ok(());
+                }
+                let mut used = 0;
+                while let Some((payload, len)) = datagram::decode(&pending[used..]) {
+                    socket.send(payload).await?;
+                    used += len;
+                }
+                pending.drain(..used);
+            }
+            received = socket.recv(&mut buf) => {
+                let len = received?;
+                if let Some(frame) = datagram::encode(&buf[..len]) {
+                    writer.write_all(&frame).await?;
+                }
+            }
+        }
+    }
+}
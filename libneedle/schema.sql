@@ -37,13 +37,33 @@ create table if not exists tunnels (
     is_active boolean not null default false,
     is_persistent boolean not null default false,
     created_at timestamptz not null default now(),
-    last_active timestamptz not null default now()
+    last_active timestamptz not null default now(),
+    expires_at timestamptz,
+    access_policy jsonb,
+    rate_limit_rps double precision,
+    rate_limit_burst double precision,
+    settings jsonb,
+    offline_page text,
+    public_port integer,
+    health_status text,
+    health_changed_at timestamptz
 );
 
 create index idx_tunnels_user_id on tunnels (user_id);
 create index idx_tunnels_subdomain on tunnels (subdomain);
 create index idx_tunnels_active on tunnels (is_active) where is_active = true;
 
+-- reserved_subdomains table
+-- names a user holds without a live tunnel. the primary key on name
+-- is what makes claiming atomic: concurrent inserts can't both win
+create table if not exists reserved_subdomains (
+    name text primary key,
+    user_id uuid not null references users(id) on delete cascade,
+    created_at timestamptz not null default now()
+);
+
+create index idx_reserved_subdomains_user on reserved_subdomains (user_id);
+
 -- tunnel_requests table
 -- logs individual http requests flowing through a tunnel
 -- for the traffic inspector and analytics features
@@ -61,6 +81,12 @@ create table if not exists tunnel_requests (
     request_body text,
     response_body text,
     client_ip text,
+    denied_reason text,
+    timeout_kind text,
+    mirror_subdomain text,
+    mirror_status_code integer,
+    mirror_latency_ms integer,
+    mirror_error text,
     timestamp timestamptz not null default now()
 );
 
@@ -117,6 +143,23 @@ create table if not exists revoked_tokens (
 create index idx_revoked_tokens_expires on revoked_tokens (expires_at);
 create index idx_revoked_tokens_user on revoked_tokens (user_id);
 
+-- share_links table
+-- signed links that let someone past a tunnel's access policy for a
+-- while; the token itself lives only in the url we hand out
+create table if not exists share_links (
+    id uuid primary key default uuid_generate_v4(),
+    tunnel_id uuid not null references tunnels(id) on delete cascade,
+    created_by uuid not null references users(id) on delete cascade,
+    expires_at timestamptz not null,
+    max_uses integer,
+    uses integer not null default 0,
+    revoked_at timestamptz,
+    created_at timestamptz not null default now()
+);
+
+create index idx_share_links_tunnel on share_links (tunnel_id);
+create index idx_share_links_live on share_links (expires_at) where revoked_at is null;
+
 -- row level security policies
 -- these ensure users can only see their own data through the api
 alter table users enable row level security;
@@ -125,6 +168,8 @@ alter table tunnel_requests enable row level security;
 alter table api_keys enable row level security;
 alter table analytics_daily enable row level security;
 alter table revoked_tokens enable row level security;
+alter table reserved_subdomains enable row level security;
+alter table share_links enable row level security;
 
 -- users can read/update their own row
 create policy "users_self_access" on users
@@ -134,6 +179,16 @@ create policy "users_self_access" on users
 create policy "tunnels_owner_access" on tunnels
     for all using (user_id = auth.uid());
 
+-- users can manage their own subdomain reservations
+create policy "reserved_subdomains_owner_access" on reserved_subdomains
+    for all using (user_id = auth.uid());
+
+-- users can manage share links for their own tunnels
+create policy "share_links_owner_access" on share_links
+    for all using (
+        tunnel_id in (select id from tunnels where user_id = auth.uid())
+    );
+
 -- users can see requests for their own tunnels
 create policy "requests_owner_access" on tunnel_requests
     for all using (